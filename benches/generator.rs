@@ -0,0 +1,36 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! The cost of generating the keys and values of each request, which every
+//! worker pays once per request sent.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+use rpc_perf::{Config, Outcome};
+
+/// A config with a zipf keyspace of ten million keys, which are formatted on
+/// demand rather than drawn from a pool
+const CONFIG: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/configs/redis_resp.toml");
+
+fn keys(c: &mut Criterion) {
+    let config = Config::new(Some(CONFIG));
+    let mut rng = SmallRng::seed_from_u64(0);
+    let mut group = c.benchmark_group("generator");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("key", |b| {
+        b.iter(|| {
+            config
+                .choose_keyspace(&mut rng)
+                .generate_key(&mut rng, None, &mut Outcome::default())
+        })
+    });
+    group.bench_function("value", |b| {
+        b.iter(|| config.choose_keyspace(&mut rng).generate_value(&mut rng))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, keys);
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! The cost of recording a latency with each backend, which happens for every
+//! response.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rpc_perf::{Duration, Instant, Latency, LatencyBackend};
+
+fn increment(c: &mut Criterion) {
+    let mut group = c.benchmark_group("latency");
+    group.throughput(Throughput::Elements(1));
+    for (name, backend) in [
+        ("heatmap", LatencyBackend::Heatmap),
+        ("histogram", LatencyBackend::Histogram),
+        ("tdigest", LatencyBackend::Tdigest),
+    ] {
+        // the span and resolution of a heatmap which accumulates each window
+        let latency = Latency::new(
+            backend,
+            30,
+            Duration::from_secs(60),
+            Duration::from_millis(1000),
+        );
+        let mut value = 0u64;
+        group.bench_function(BenchmarkId::new("increment", name), |b| {
+            b.iter(|| {
+                // spread the values over the buckets a typical run fills
+                value = (value + 7919) % 10_000_000;
+                latency.increment(Instant::now(), value, 1)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, increment);
+criterion_main!(benches);
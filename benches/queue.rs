@@ -0,0 +1,69 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! The throughput of the work queue which rpc-replay feeds requests to its
+//! workers through.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mpmc::Queue;
+use rpc_perf::Instant;
+
+/// A request of the size a typical cache request has, with the time it was
+/// queued, like the work items of rpc-replay
+struct WorkItem {
+    request: Vec<u8>,
+    queued: Instant,
+}
+
+/// Items moved through the queue in each iteration
+const BATCH: usize = 1024;
+
+fn throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("queue");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    group.bench_function(BenchmarkId::new("work_item", "single_thread"), |b| {
+        let queue = Queue::<WorkItem>::with_capacity(BATCH);
+        b.iter(|| {
+            for _ in 0..BATCH {
+                let _ = queue.push(WorkItem {
+                    request: vec![0; 64],
+                    queued: Instant::now(),
+                });
+            }
+            while let Some(item) = queue.pop() {
+                criterion::black_box((item.request, item.queued));
+            }
+        })
+    });
+    group.bench_function(BenchmarkId::new("work_item", "two_threads"), |b| {
+        let queue = Queue::<WorkItem>::with_capacity(BATCH);
+        b.iter(|| {
+            std::thread::scope(|scope| {
+                let consumer = queue.clone();
+                scope.spawn(move || {
+                    let mut received = 0;
+                    while received < BATCH {
+                        if let Some(item) = consumer.pop() {
+                            criterion::black_box((item.request, item.queued));
+                            received += 1;
+                        }
+                    }
+                });
+                for _ in 0..BATCH {
+                    let mut item = WorkItem {
+                        request: vec![0; 64],
+                        queued: Instant::now(),
+                    };
+                    while let Err(rejected) = queue.push(item) {
+                        item = rejected;
+                    }
+                }
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
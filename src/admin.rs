@@ -2,32 +2,255 @@
 // Licensed under the Apache License, Version 2.0
 // http://www.apache.org/licenses/LICENSE-2.0
 
-use crate::config_file::OutputFormat;
+use crate::affinity::Affinity;
+use crate::baseline::Baseline;
+use crate::checkpoint::Checkpoint;
+use crate::config_file::{Accumulation, OutputFormat, Protocol, Role, Verb, Verbosity};
+use crate::controller::{RateController, RateSearch, SearchStep};
+use crate::distributed::{Coordinator, Merged, Reporter};
+use crate::endpoints::{Cutover, EndpointCounts, EndpointStats};
+use crate::host::{HostStats, Interface};
+use crate::hot_set;
 use crate::metrics::*;
+use crate::png;
+use crate::prefill::Prefill;
 use crate::Arc;
 use crate::Config;
+use crate::Latency;
+use chrono::{DateTime, SecondsFormat, Utc};
 use heatmap::Heatmap;
 use ratelimit::Ratelimiter;
 use ringlog::Drain;
 use serde_derive::Serialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
 use std::time::Instant;
+use strum::IntoEnumIterator;
 use waterfall::WaterfallBuilder;
 
 use std::net::SocketAddr;
 use std::time::Duration;
 use tiny_http::{Method, Response, Server};
 
+/// What one of a command's targets limits
+#[derive(Clone, Copy, PartialEq)]
+enum SlaKind {
+    /// the latency at the percentile
+    Latency(f64),
+    ErrorRate,
+    Rate,
+}
+
+/// The result of checking one of a command's targets
+#[derive(Serialize, Clone)]
+struct SlaVerdict {
+    #[serde(skip)]
+    verb: Verb,
+    #[serde(skip)]
+    kind: SlaKind,
+    command: &'static str,
+    /// a latency percentile, such as p99, in microseconds, the error rate as
+    /// a percentage, or the rate of responses per second
+    metric: String,
+    /// the limit, which is the lowest acceptable rate and the highest
+    /// acceptable value of the others
+    target: f64,
+    /// the value in the latest window
+    value: f64,
+    /// whether the latest window met the target
+    pass: bool,
+    windows: u64,
+    windows_failed: u64,
+    /// windows which overlapped a blackout and were not checked
+    windows_excluded: u64,
+}
+
+impl SlaVerdict {
+    /// The target as it is logged, eg: get p99 <= 1000 us
+    fn criterion(&self) -> String {
+        match self.kind {
+            SlaKind::Latency(_) => {
+                format!("{} {} <= {} us", self.command, self.metric, self.target)
+            }
+            SlaKind::ErrorRate => format!("{} error rate <= {} %", self.command, self.target),
+            SlaKind::Rate => format!("{} rate >= {} rps", self.command, self.target),
+        }
+    }
+
+    /// The value in the latest window as it is logged
+    fn measured(&self) -> String {
+        match self.kind {
+            SlaKind::Latency(_) => format!("{} us", self.value),
+            SlaKind::ErrorRate => format!("{:.2} %", self.value),
+            SlaKind::Rate => format!("{:.2} rps", self.value),
+        }
+    }
+}
+
+/// A command's results over the measured part of the run
+#[derive(Serialize)]
+struct CommandSummary {
+    command: &'static str,
+    count: u64,
+    rate: f64,
+    success: f64,
+    hitrate: f64,
+    p50: u64,
+    p99: u64,
+    p999: u64,
+    max: u64,
+}
+
+/// The share of the hit-rate before a namespace bump which the hit-rate has
+/// recovered once it regains
+const RECOVERED: f64 = 0.9;
+
+/// The latency percentiles reported for each window
+const PERCENTILES: [(&str, f64); 7] = [
+    ("p25", 25.0),
+    ("p50", 50.0),
+    ("p75", 75.0),
+    ("p90", 90.0),
+    ("p99", 99.0),
+    ("p999", 99.9),
+    ("p9999", 99.99),
+];
+
+/// The hit-rate after a namespace bump is sampled over this long
+const RECOVERY_SAMPLE: Duration = Duration::from_secs(1);
+
+/// Bumps the namespaces of the keyspaces which have one, and follows the
+/// hit-rate of gets after each bump until it recovers. The hit-rate is of the
+/// gets to every keyspace.
+#[derive(Default)]
+struct Invalidation {
+    // the gets and hits when the current version started
+    start: (u64, u64),
+    bump: Option<Bump>,
+}
+
+/// A namespace bump whose hit-rate has not recovered yet
+struct Bump {
+    version: u64,
+    at: Instant,
+    // the hit-rate of the previous version
+    before: f64,
+    lowest: f64,
+    // the gets and hits at the start of the current sample
+    sample: (Instant, u64, u64),
+}
+
+impl Invalidation {
+    fn update(&mut self, config: &Config, elapsed: Duration) {
+        let now = Instant::now();
+        let (gets, hits) = (REQUEST_GET.value(), RESPONSE_HIT.value());
+        let hit_rate = |gets: u64, hits: u64| {
+            if gets == 0 {
+                0.0
+            } else {
+                100.0 * hits as f64 / gets as f64
+            }
+        };
+
+        let mut version = None;
+        for keyspace in config.keyspaces() {
+            version = keyspace.advance_namespace(elapsed).or(version);
+        }
+        if let Some(version) = version {
+            if let Some(bump) = self.bump.take() {
+                info!(
+                    "Namespace: version {} did not recover from {:.2} % before the next bump",
+                    bump.version, bump.lowest
+                );
+            }
+            NAMESPACE_VERSION.set(version as i64);
+            let before = hit_rate(gets - self.start.0, hits - self.start.1);
+            self.start = (gets, hits);
+            self.bump = Some(Bump {
+                version,
+                at: now,
+                before,
+                lowest: before,
+                sample: (now, gets, hits),
+            });
+            return;
+        }
+
+        let bump = match self.bump.as_mut() {
+            Some(bump) if now - bump.sample.0 >= RECOVERY_SAMPLE => bump,
+            _ => return,
+        };
+        let (_, sample_gets, sample_hits) = bump.sample;
+        bump.sample = (now, gets, hits);
+        if gets == sample_gets {
+            return;
+        }
+        let rate = hit_rate(gets - sample_gets, hits - sample_hits);
+        bump.lowest = bump.lowest.min(rate);
+        if rate >= bump.before * RECOVERED {
+            let recovery = now - bump.at;
+            NAMESPACE_RECOVERY.set(recovery.as_millis() as i64);
+            info!(
+                "Namespace: version {} hit-rate fell from {:.2} % to {:.2} % and recovered in {:.1}s",
+                bump.version,
+                bump.before,
+                bump.lowest,
+                recovery.as_secs_f64()
+            );
+            self.bump = None;
+        }
+    }
+}
+
 pub struct Admin {
+    affinity: Option<Arc<Affinity>>,
     config: Option<Arc<Config>>,
     snapshot: Snapshot,
-    connect_heatmap: Option<Arc<Heatmap>>,
+    // the snapshot at the start of the latest window
+    previous: Snapshot,
+    connect_heatmap: Option<Arc<Latency>>,
+    handshake_heatmap: Option<Arc<Latency>>,
+    session_lifetime_heatmap: Option<Arc<Latency>>,
+    queue_delay_heatmap: Option<Arc<Latency>>,
+    // latency of the loopback reference server and how late its probes woke
+    calibration_heatmaps: Option<(Arc<Latency>, Arc<Latency>)>,
+    corrected_heatmap: Option<Arc<Latency>>,
+    endpoint_heatmaps: Vec<(String, Arc<Latency>)>,
+    endpoint_stats: Option<Arc<EndpointStats>>,
+    cutover: Option<Arc<Cutover>>,
+    command_heatmaps: Vec<(Verb, Arc<Latency>)>,
+    command_waterfalls: Vec<(Verb, Arc<Heatmap>)>,
+    // latency for each command over the measured part of the run
+    summary_heatmaps: Vec<(Verb, Arc<Latency>)>,
+    write_size_heatmap: Option<Arc<Latency>>,
+    write_element_heatmap: Option<Arc<Latency>>,
+    response_field_heatmap: Option<Arc<Latency>>,
     reconnect_ratelimit: Option<Arc<Ratelimiter>>,
-    request_heatmap: Option<Arc<Heatmap>>,
+    request_heatmap: Option<Arc<Latency>>,
     request_ratelimit: Option<Arc<Ratelimiter>>,
+    rate_controller: Option<RateController>,
+    rate_search: Option<RateSearch>,
+    // the most recent step of the rate search, reported with the window
+    search_step: Option<SearchStep>,
+    prefill: Option<Arc<Prefill>>,
+    // the windows which completed before a restart, when resuming
+    resumed: u64,
     request_waterfall: Option<Arc<Heatmap>>,
     server: Option<Server>,
     log: Box<dyn Drain>,
+    verbosity: Verbosity,
+    json_output: Option<File>,
+    sla: Vec<SlaVerdict>,
+    // the summary of an earlier run which this run is compared against
+    baseline: Option<Baseline>,
+    // whether the latest window overlaps a blackout
+    blackout: bool,
+    // the agents' reports are merged when this is the controller of a
+    // distributed run
+    coordinator: Option<Coordinator>,
+    // sends each window to the controller when this is an agent
+    reporter: Option<Reporter>,
 }
 
 impl Admin {
@@ -37,17 +260,92 @@ impl Admin {
             .general()
             .admin()
             .map(|admin_addr| Server::http(admin_addr).unwrap());
+        let verbosity = config.general().verbosity();
+        let json_output = config.general().json_output().map(|path| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .expect("failed to open json output file")
+        });
+
+        let sla = config
+            .sla()
+            .iter()
+            .flat_map(|sla| {
+                [
+                    sla.latency()
+                        .map(|latency| (SlaKind::Latency(sla.percentile()), latency as f64)),
+                    sla.error_rate()
+                        .map(|error_rate| (SlaKind::ErrorRate, error_rate)),
+                    sla.rate().map(|rate| (SlaKind::Rate, rate)),
+                ]
+                .into_iter()
+                .flatten()
+                .map(|(kind, target)| SlaVerdict {
+                    verb: sla.verb(),
+                    kind,
+                    command: sla.verb().into(),
+                    metric: match kind {
+                        SlaKind::Latency(percentile) => format!("p{}", percentile),
+                        SlaKind::ErrorRate => "error_rate".to_string(),
+                        SlaKind::Rate => "rate".to_string(),
+                    },
+                    target,
+                    value: 0.0,
+                    pass: true,
+                    windows: 0,
+                    windows_failed: 0,
+                    windows_excluded: 0,
+                })
+            })
+            .collect();
+
+        let coordinator = (config.distributed().role() == Some(Role::Controller))
+            .then(|| Coordinator::new(&config));
+        let reporter = match config.distributed().role() {
+            Some(Role::Agent) => config.distributed().controller().map(Reporter::new),
+            _ => None,
+        };
 
         Self {
+            affinity: None,
             config: Some(config),
+            previous: snapshot.clone(),
             snapshot,
             connect_heatmap: None,
+            handshake_heatmap: None,
+            session_lifetime_heatmap: None,
+            queue_delay_heatmap: None,
+            calibration_heatmaps: None,
+            corrected_heatmap: None,
+            endpoint_heatmaps: Vec::new(),
+            endpoint_stats: None,
+            cutover: None,
+            command_heatmaps: Vec::new(),
+            command_waterfalls: Vec::new(),
+            summary_heatmaps: Vec::new(),
+            write_size_heatmap: None,
+            write_element_heatmap: None,
+            response_field_heatmap: None,
             reconnect_ratelimit: None,
             request_heatmap: None,
             request_ratelimit: None,
+            rate_controller: None,
+            rate_search: None,
+            search_step: None,
+            prefill: None,
+            resumed: 0,
             request_waterfall: None,
             server,
             log,
+            verbosity,
+            json_output,
+            sla,
+            baseline: None,
+            blackout: false,
+            coordinator,
+            reporter,
         }
     }
 
@@ -56,27 +354,128 @@ impl Admin {
         let server = admin_addr.map(|admin_addr| Server::http(admin_addr).unwrap());
 
         Self {
+            affinity: None,
             config: None,
+            previous: snapshot.clone(),
             snapshot,
             connect_heatmap: None,
+            handshake_heatmap: None,
+            session_lifetime_heatmap: None,
+            queue_delay_heatmap: None,
+            calibration_heatmaps: None,
+            corrected_heatmap: None,
+            endpoint_heatmaps: Vec::new(),
+            endpoint_stats: None,
+            cutover: None,
+            command_heatmaps: Vec::new(),
+            command_waterfalls: Vec::new(),
+            summary_heatmaps: Vec::new(),
+            write_size_heatmap: None,
+            write_element_heatmap: None,
+            response_field_heatmap: None,
             reconnect_ratelimit: None,
             request_heatmap: None,
             request_ratelimit: None,
+            rate_controller: None,
+            rate_search: None,
+            search_step: None,
+            prefill: None,
+            resumed: 0,
             request_waterfall: None,
             server,
             log,
+            verbosity: Verbosity::default(),
+            json_output: None,
+            sla: Vec::new(),
+            baseline: None,
+            blackout: false,
+            coordinator: None,
+            reporter: None,
         }
     }
 
-    pub fn set_connect_heatmap(&mut self, heatmap: Option<Arc<Heatmap>>) {
+    pub fn set_affinity(&mut self, affinity: Option<Arc<Affinity>>) {
+        self.affinity = affinity;
+    }
+
+    pub fn set_connect_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
         self.connect_heatmap = heatmap;
     }
 
+    pub fn set_handshake_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
+        self.handshake_heatmap = heatmap;
+    }
+
+    pub fn set_session_lifetime_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
+        self.session_lifetime_heatmap = heatmap;
+    }
+
+    pub fn set_corrected_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
+        self.corrected_heatmap = heatmap;
+    }
+
+    pub fn set_queue_delay_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
+        self.queue_delay_heatmap = heatmap;
+    }
+
+    /// Provide heatmaps for the latency of the loopback reference server and
+    /// the delay of its probes waking up
+    pub fn set_calibration_heatmaps(&mut self, heatmaps: Option<(Arc<Latency>, Arc<Latency>)>) {
+        self.calibration_heatmaps = heatmaps;
+    }
+
+    /// Provide the request latency heatmap for each endpoint along with the
+    /// endpoint's name
+    pub fn set_endpoint_heatmaps(&mut self, heatmaps: Vec<(String, Arc<Latency>)>) {
+        self.endpoint_heatmaps = heatmaps;
+    }
+
+    /// Provide the counters for each endpoint
+    pub fn set_endpoint_stats(&mut self, stats: Option<Arc<EndpointStats>>) {
+        self.endpoint_stats = stats;
+    }
+
+    /// Provide the cutover, to report how much of the load has moved
+    pub fn set_cutover(&mut self, cutover: Option<Arc<Cutover>>) {
+        self.cutover = cutover;
+    }
+
+    /// Provide the request latency heatmap for each command
+    pub fn set_command_heatmaps(&mut self, heatmaps: Vec<(Verb, Arc<Latency>)>) {
+        self.command_heatmaps = heatmaps;
+    }
+
+    /// Provide the waterfall heatmap for each command
+    pub fn set_command_waterfalls(&mut self, heatmaps: Vec<(Verb, Arc<Heatmap>)>) {
+        self.command_waterfalls = heatmaps;
+    }
+
+    /// Provide the request latency heatmap for each command over the
+    /// measured part of the run
+    pub fn set_summary_heatmaps(&mut self, heatmaps: Vec<(Verb, Arc<Latency>)>) {
+        self.summary_heatmaps = heatmaps;
+    }
+
+    /// Provide the heatmap of collection write request sizes
+    pub fn set_write_size_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
+        self.write_size_heatmap = heatmap;
+    }
+
+    /// Provide the heatmap of elements written by collection write requests
+    pub fn set_write_element_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
+        self.write_element_heatmap = heatmap;
+    }
+
+    /// Provide the heatmap of fields returned by hash get all responses
+    pub fn set_response_field_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
+        self.response_field_heatmap = heatmap;
+    }
+
     pub fn set_reconnect_ratelimit(&mut self, ratelimiter: Option<Arc<Ratelimiter>>) {
         self.reconnect_ratelimit = ratelimiter;
     }
 
-    pub fn set_request_heatmap(&mut self, heatmap: Option<Arc<Heatmap>>) {
+    pub fn set_request_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
         self.request_heatmap = heatmap;
     }
 
@@ -84,11 +483,87 @@ impl Admin {
         self.request_ratelimit = ratelimiter;
     }
 
+    pub fn set_rate_controller(&mut self, controller: Option<RateController>) {
+        self.rate_controller = controller;
+    }
+
+    /// Search for the highest rate which meets a latency target, ending the
+    /// run once it is found
+    pub fn set_rate_search(&mut self, search: Option<RateSearch>) {
+        self.rate_search = search;
+    }
+
+    /// Delay the start of the run until the prefill has completed
+    pub fn set_prefill(&mut self, prefill: Option<Arc<Prefill>>) {
+        self.prefill = prefill;
+    }
+
+    /// Continue the workload from the checkpoint file, skipping the windows
+    /// which completed and the keys which were prefilled before the restart
+    pub fn resume(&mut self) -> Result<(), String> {
+        let config = self.config.as_ref().ok_or("nothing to resume")?;
+        let file = config
+            .checkpoint()
+            .file()
+            .ok_or("resuming needs a checkpoint file")?;
+        let checkpoint = Checkpoint::load(&file)?;
+        checkpoint.restore(config, self.prefill.as_deref())?;
+        self.resumed = checkpoint.windows();
+        WINDOW.add(self.resumed);
+        Ok(())
+    }
+
+    /// Save the state of the workload, if checkpoints are enabled
+    fn checkpoint(&self, windows: u64) {
+        let config = match self.config.as_ref() {
+            Some(config) => config,
+            None => return,
+        };
+        if let Some(file) = config.checkpoint().file() {
+            let checkpoint = Checkpoint::capture(config, windows, self.prefill.as_deref());
+            if let Err(e) = checkpoint.save(&file) {
+                error!("failed to write checkpoint: {}: {}", file, e);
+            }
+        }
+    }
+
     pub fn set_request_waterfall(&mut self, heatmap: Option<Arc<Heatmap>>) {
         self.request_waterfall = heatmap;
     }
 
-    pub fn run(mut self) {
+    /// Compare the summary at the end of the run against the last summary in
+    /// the file, failing the run if any command regressed
+    pub fn set_baseline(&mut self, file: &str) -> Result<(), String> {
+        let config = self.config.as_ref().ok_or("nothing to compare")?;
+        if config.general().windows().is_none() {
+            return Err("a run without a fixed number of windows has no summary to compare".into());
+        }
+        self.baseline = Some(Baseline::load(file, *config.baseline())?);
+        Ok(())
+    }
+
+    pub fn set_verbosity(&mut self, verbosity: Verbosity) {
+        // the quiet summary is printed once the last window ends
+        if verbosity == Verbosity::Quiet
+            && self
+                .config
+                .as_ref()
+                .is_some_and(|config| config.general().windows().is_none())
+        {
+            fatal!("quiet output needs a fixed number of windows");
+        }
+        self.verbosity = verbosity;
+    }
+
+    /// Runs the admin loop until the run completes. Returns an error with the
+    /// reason if the run was stopped early or regressed against the baseline.
+    pub fn run(mut self) -> Result<(), String> {
+        if let Some(coordinator) = self.coordinator.take() {
+            return self.run_controller(coordinator);
+        }
+
+        self.wait_for_prefill();
+
         let mut next = Instant::now()
             + match self.config.as_ref() {
                 Some(config) => config.general().interval(),
@@ -96,11 +571,55 @@ impl Admin {
             };
         let mut snapshot =
             Snapshot::new(self.connect_heatmap.as_ref(), self.request_heatmap.as_ref());
+        snapshot.host = self.host_stats();
+        snapshot.endpoints = self.endpoint_counts();
+        self.assertion_counts(&mut snapshot);
+        self.snapshot.host = snapshot.host.clone();
+        self.previous = self.snapshot.clone();
+
+        // used to summarize the entire run when running quietly
+        let initial = snapshot.clone();
+        // the command summary starts once the warmup windows have passed
+        let warmup = self
+            .config
+            .as_ref()
+            .map(|config| config.general().warmup() as u64)
+            .unwrap_or(0);
+        let mut measured = initial.clone();
+        let started = Utc::now();
+        // a resumed run continues ramps from where they were
+        let start = match self.config.as_ref() {
+            Some(config) => Instant::now()
+                .checked_sub(config.general().interval() * self.resumed as u32)
+                .unwrap_or_else(Instant::now),
+            None => Instant::now(),
+        };
+        let mut failed_windows = 0;
+        let mut abort = None;
+        let mut invalidation = Invalidation::default();
+        let namespaced = self.config.as_ref().is_some_and(|config| {
+            config
+                .keyspaces()
+                .iter()
+                .any(|keyspace| keyspace.has_namespace())
+        });
 
         loop {
             while Instant::now() < next {
                 clocksource::refresh_clock();
                 let _ = self.log.flush();
+                if let Some(ref mut controller) = self.rate_controller {
+                    controller.update(start.elapsed());
+                }
+                if let (true, Some(config)) = (namespaced, self.config.as_ref()) {
+                    invalidation.update(config, start.elapsed());
+                }
+                if let Some(ref affinity) = self.affinity {
+                    AFFINITY_SKEW.set(affinity.skew() as i64);
+                }
+                if let Some(ref cutover) = self.cutover {
+                    CUTOVER_SHARE.set((cutover.share() * 100.0) as i64);
+                }
                 snapshot =
                     Snapshot::new(self.connect_heatmap.as_ref(), self.request_heatmap.as_ref());
                 if let Some(ref server) = self.server {
@@ -128,6 +647,12 @@ impl Admin {
                                     let _ = request
                                         .respond(Response::from_string(self.snapshot.json()));
                                 }
+                                "/stats.json" | "/admin/stats.json" => {
+                                    debug!("Serving throughput stats");
+                                    let _ = request.respond(Response::from_string(
+                                        self.snapshot.throughput(&self.previous),
+                                    ));
+                                }
                                 "/vars" => {
                                     debug!("Serving human readable stats");
                                     let _ = request
@@ -160,7 +685,11 @@ impl Admin {
                                     request.as_reader().read_to_string(&mut content).unwrap();
                                     if let Ok(rate) = content.parse() {
                                         if let Some(ref ratelimiter) = self.request_ratelimit {
+                                            // an explicit rate overrides any ramp
+                                            self.rate_controller = None;
+                                            self.rate_search = None;
                                             ratelimiter.set_rate(rate);
+                                            REQUEST_RATELIMIT.set(rate as i64);
                                             let _ = request.respond(Response::empty(200));
                                         } else {
                                             let _ = request.respond(Response::empty(400));
@@ -183,94 +712,1131 @@ impl Admin {
                 }
                 std::thread::sleep(std::time::Duration::from_millis(10));
             }
+            snapshot.host = self.host_stats();
+            snapshot.endpoints = self.endpoint_counts();
+            self.assertion_counts(&mut snapshot);
             next += match self.config.as_ref() {
                 Some(config) => config.general().interval(),
                 None => Duration::from_secs(60),
             };
 
             let window = WINDOW.value();
-            let output_format = self
+            let max_window = self
                 .config
-                .as_deref()
-                .map(|config| config.general().output_format())
+                .as_ref()
+                .and_then(|config| config.general().windows());
+            let mut last_window = max_window
+                .map(|max_window| window >= max_window as u64)
+                .unwrap_or(false);
+
+            self.blackout = self
+                .config
+                .as_ref()
+                .is_some_and(|config| config.is_blackout(window));
+
+            if let Some(config) = self.config.as_ref().filter(|_| !self.blackout) {
+                if let Some(threshold) = config.abort().error_rate() {
+                    let error_rate = Self::error_rate(&self.snapshot, &snapshot);
+                    if error_rate > threshold {
+                        failed_windows += 1;
+                    } else {
+                        failed_windows = 0;
+                    }
+                    if failed_windows >= config.abort().windows() {
+                        let reason = format!(
+                            "error rate above {:.2} % for {} consecutive windows",
+                            threshold, failed_windows
+                        );
+                        error!("stopping run: {}", reason);
+                        abort = Some(reason);
+                        last_window = true;
+                    }
+                }
+            }
+
+            self.check_sla(&snapshot, window >= warmup);
+
+            if let Some(ref mut search) = self.rate_search {
+                let latency = self
+                    .request_heatmap
+                    .as_ref()
+                    .and_then(|heatmap| heatmap.percentile(search.percentile()).ok())
+                    .map(|b| b.high())
+                    .unwrap_or(0);
+                let achieved = snapshot.rate(&self.snapshot, RESPONSE.name());
+                self.search_step = Some(search.update(latency, achieved));
+                if search.is_complete() {
+                    last_window = true;
+                }
+            }
+
+            match self.verbosity {
+                Verbosity::Quiet => {
+                    if last_window {
+                        // the request heatmap only covers the recent windows
+                        let mut summary = snapshot.clone();
+                        summary.request_percentiles = self.run_percentiles();
+                        self.emit(window, &initial, &summary);
+                    }
+                }
+                Verbosity::Normal | Verbosity::Verbose => {
+                    self.emit(window, &self.snapshot, &snapshot);
+                }
+            }
+
+            if self.json_output.is_some() || self.reporter.is_some() {
+                let json = self.json(window, &self.snapshot, &snapshot);
+                if let Some(ref mut file) = self.json_output {
+                    if let Err(e) = writeln!(file, "{}", json) {
+                        error!("failed to write json output: {}", e);
+                    }
+                }
+                if let Some(ref reporter) = self.reporter {
+                    reporter.report(json);
+                }
+            }
+
+            WINDOW.increment();
+            self.previous = std::mem::replace(&mut self.snapshot, snapshot.clone());
+            if window + 1 == warmup {
+                measured = snapshot.clone();
+            }
+            self.clear_latencies(window, warmup);
+
+            let every = self
+                .config
+                .as_ref()
+                .map(|config| config.checkpoint().windows() as u64)
+                .unwrap_or(1);
+            if (window + 1).is_multiple_of(every) || last_window {
+                self.checkpoint(window + 1);
+            }
+
+            if last_window {
+                // only the windows since resuming are measured
+                let windows = (window + 1).saturating_sub(warmup.max(self.resumed));
+                let summary = self.summarize(windows, &measured, &snapshot);
+                let regression = self.compare_baseline(summary.as_deref());
+                self.report_hot_keys();
+                if let Some(ref search) = self.rate_search {
+                    match (search.is_complete(), search.sustainable()) {
+                        (true, Some(rate)) => {
+                            info!(
+                                "Rate Search Summary: highest sustainable rate: {} rps with p{} <= {} us",
+                                rate,
+                                search.percentile(),
+                                search.target()
+                            );
+                        }
+                        (true, None) => {
+                            info!(
+                                "Rate Search Summary: none found, no rate tried had p{} <= {} us",
+                                search.percentile(),
+                                search.target()
+                            );
+                        }
+                        (false, Some(rate)) => {
+                            info!(
+                                "Rate Search Summary: incomplete, highest sustainable rate so far: {} rps with p{} <= {} us",
+                                rate,
+                                search.percentile(),
+                                search.target()
+                            );
+                        }
+                        (false, None) => {
+                            info!(
+                                "Rate Search Summary: incomplete, none found so far with p{} <= {} us",
+                                search.percentile(),
+                                search.target()
+                            );
+                        }
+                    }
+                }
+                let mut violations = Vec::new();
+                for verdict in &self.sla {
+                    let excluded = if verdict.windows_excluded > 0 {
+                        format!(", {} excluded by blackouts", verdict.windows_excluded)
+                    } else {
+                        String::new()
+                    };
+                    info!(
+                        "SLA Summary: {}: {} ({} of {} windows failed{})",
+                        verdict.criterion(),
+                        if verdict.windows_failed == 0 {
+                            "pass"
+                        } else {
+                            "fail"
+                        },
+                        verdict.windows_failed,
+                        verdict.windows,
+                        excluded
+                    );
+                    if verdict.windows_failed > 0 {
+                        violations.push(verdict.criterion());
+                    }
+                }
+                let violated = (!violations.is_empty())
+                    .then(|| format!("sla not met: {}", violations.join(", ")));
+                if let Some(ref heatmap) = self.request_waterfall {
+                    if let Some(file) = self.config.as_ref().and_then(|c| c.waterfall().file()) {
+                        self.waterfall(&file, heatmap, None, started);
+                    }
+                }
+                for (verb, heatmap) in &self.command_waterfalls {
+                    if let Some(file) = self
+                        .config
+                        .as_ref()
+                        .and_then(|c| c.waterfall().command_file(*verb))
+                    {
+                        self.waterfall(&file, heatmap, Some(*verb), started);
+                    }
+                }
+                if let Some(config) = self.config.as_ref() {
+                    if let Some(directory) = config.bundle().directory() {
+                        // flush so the debug log is complete before it is bundled
+                        let _ = self.log.flush();
+                        let summary = self.json(window, &initial, &snapshot);
+                        match crate::bundle::write(
+                            config,
+                            directory.as_ref(),
+                            started,
+                            &summary,
+                            abort.as_deref(),
+                        ) {
+                            Ok(path) => info!("wrote bundle: {}", path.display()),
+                            Err(e) => error!("failed to write bundle: {}", e),
+                        }
+                    }
+                }
+                if let Some(reporter) = self.reporter.take() {
+                    reporter.finish();
+                }
+                // make sure the final output is written before we exit
+                let _ = self.log.flush();
+                return match abort.or(regression).or(violated) {
+                    Some(reason) => Err(reason),
+                    None => Ok(()),
+                };
+            }
+        }
+    }
+
+    /// Check each command's targets against the latest window. The values
+    /// are still reported for a window in a blackout or the warmup, but they
+    /// are only counted against the targets once the run is measured.
+    fn check_sla(&mut self, snapshot: &Snapshot, measured: bool) {
+        let previous = &self.snapshot;
+        for verdict in self.sla.iter_mut() {
+            let verb = verdict.verb;
+            let count = |kind| snapshot.delta_count(previous, &Snapshot::command_name(kind, verb));
+            verdict.value = match verdict.kind {
+                SlaKind::ErrorRate => {
+                    // requests which timed out had no response
+                    let timeouts = count("response_timeout");
+                    let attempts = count("response") + timeouts;
+                    if attempts == 0 {
+                        0.0
+                    } else {
+                        100.0 * (count("response_ex") + timeouts) as f64 / attempts as f64
+                    }
+                }
+                SlaKind::Rate => snapshot.rate(previous, &Snapshot::command_name("response", verb)),
+                SlaKind::Latency(percentile) => self
+                    .command_heatmaps
+                    .iter()
+                    .find(|(command, _)| *command == verb)
+                    .and_then(|(_, heatmap)| heatmap.percentile(percentile).ok())
+                    .map(|b| b.high() as f64)
+                    .unwrap_or(0.0),
+            };
+            verdict.pass = if verdict.kind == SlaKind::Rate {
+                verdict.value >= verdict.target
+            } else {
+                verdict.value <= verdict.target
+            };
+            if self.blackout {
+                verdict.windows_excluded += 1;
+                continue;
+            }
+            if !measured {
+                continue;
+            }
+            verdict.windows += 1;
+            if !verdict.pass {
+                verdict.windows_failed += 1;
+            }
+        }
+    }
+
+    /// Start the latencies over along with the span their percentiles cover.
+    /// Heatmaps slide over their span by themselves, so this only affects the
+    /// other metrics backends.
+    fn clear_latencies(&self, window: u64, warmup: u64) {
+        let accumulation = self
+            .config
+            .as_ref()
+            .map(|config| config.general().accumulation())
+            .unwrap_or_default();
+        if accumulation == Accumulation::Window {
+            let latencies = [
+                &self.connect_heatmap,
+                &self.handshake_heatmap,
+                &self.session_lifetime_heatmap,
+                &self.queue_delay_heatmap,
+                &self.corrected_heatmap,
+                &self.write_size_heatmap,
+                &self.write_element_heatmap,
+                &self.response_field_heatmap,
+                &self.request_heatmap,
+            ]
+            .into_iter()
+            .flatten()
+            .chain(
+                self.calibration_heatmaps
+                    .iter()
+                    .flat_map(|(loopback, wakeup)| [loopback, wakeup]),
+            )
+            .chain(self.endpoint_heatmaps.iter().map(|(_, latency)| latency))
+            .chain(self.command_heatmaps.iter().map(|(_, latency)| latency));
+            for latency in latencies {
+                latency.clear();
+            }
+        }
+        // the summary only covers the windows after the warmup
+        if window + 1 == warmup {
+            for (_, latency) in &self.summary_heatmaps {
+                latency.clear();
+            }
+        }
+    }
+
+    /// The request latency percentiles over the measured part of the run,
+    /// from the summary heatmaps of every command together
+    fn run_percentiles(&self) -> Vec<(String, u64)> {
+        let mut buckets: Vec<(u64, u32)> = self
+            .summary_heatmaps
+            .iter()
+            .flat_map(|(_, heatmap)| heatmap.buckets())
+            .collect();
+        buckets.sort_unstable();
+        let total: u64 = buckets.iter().map(|(_, count)| *count as u64).sum();
+        PERCENTILES
+            .iter()
+            .map(|(label, percentile)| {
+                let needed = ((percentile / 100.0) * total as f64).ceil() as u64;
+                let mut seen = 0;
+                let value = buckets
+                    .iter()
+                    .find(|(_, count)| {
+                        seen += *count as u64;
+                        seen >= needed.max(1)
+                    })
+                    .map(|(value, _)| *value)
+                    .unwrap_or(0);
+                (label.to_string(), value)
+            })
+            .collect()
+    }
+
+    /// Log the most requested keys of each zipf keyspace which reports them,
+    /// with the share of requests each took and the share the model expects
+    fn report_hot_keys(&self) {
+        let config = match self.config.as_ref() {
+            Some(config) => config,
+            None => return,
+        };
+        for keyspace in config.keyspaces() {
+            let (hot_keys, exponent) = match (keyspace.hot_keys(), keyspace.zipf_exponent()) {
+                (Some(hot_keys), Some(exponent)) => (hot_keys, exponent),
+                _ => continue,
+            };
+            let total = hot_keys.total();
+            if total == 0 {
+                continue;
+            }
+            let cardinality = keyspace.cardinality();
+            let top = hot_keys.top();
+            let requests: u64 = top.iter().map(|(_, count)| count).sum();
+            info!(
+                "Hot Keys: keyspace {}: {} keys took {:.2} % of {} requests, expected {:.2} %",
+                keyspace.index(),
+                top.len(),
+                100.0 * requests as f64 / total as f64,
+                total,
+                100.0 * hot_set::hit_rate(cardinality, exponent, top.len() as u64)
+            );
+            for (rank, (index, count)) in top.iter().enumerate() {
+                info!(
+                    "Hot Keys: keyspace {} #{}: {} (model rank {}) {:.3} % expected {:.3} %",
+                    keyspace.index(),
+                    rank + 1,
+                    String::from_utf8_lossy(&keyspace.key(*index)),
+                    index,
+                    100.0 * *count as f64 / total as f64,
+                    100.0 * hot_set::share(cardinality, exponent, *index as u64)
+                );
+            }
+        }
+    }
+
+    /// Render the heatmap as a waterfall into the file
+    /// Render the waterfall of all requests, or of a single command, which
+    /// may have its own palette and scale. With metadata, the details of the
+    /// run are embedded in the image as text.
+    fn waterfall(&self, file: &str, heatmap: &Heatmap, verb: Option<Verb>, started: DateTime<Utc>) {
+        let config = match self.config.as_ref() {
+            Some(config) => config,
+            None => return,
+        };
+        let waterfall = config.waterfall();
+        let (palette, scale) = match verb {
+            Some(verb) => (
+                waterfall.command_palette(verb),
+                waterfall.command_scale(verb),
+            ),
+            None => (waterfall.palette(), waterfall.scale()),
+        };
+
+        WaterfallBuilder::new(file)
+            .label(100, "100ns")
+            .label(1000, "1us")
+            .label(10000, "10us")
+            .label(100000, "100us")
+            .label(1000000, "1ms")
+            .label(10000000, "10ms")
+            .label(100000000, "100ms")
+            .scale(scale)
+            .palette(palette)
+            .build(heatmap);
+
+        if waterfall.metadata() {
+            let command = verb.map(<&str>::from).unwrap_or("request");
+            let ratelimit = config
+                .request()
+                .ratelimit()
+                .map(|ratelimit| format!("{} rps", ratelimit))
+                .unwrap_or_else(|| "none".to_string());
+            let endpoints: Vec<String> = config
+                .endpoints()
+                .iter()
+                .map(|endpoint| endpoint.to_string())
+                .collect();
+            let description = [
+                format!("run id: {}", config.run_id()),
+                format!("config: {}", config.file()),
+                format!("protocol: {:?}", config.general().protocol()),
+                format!("endpoints: {}", endpoints.join(", ")),
+                format!("ratelimit: {}", ratelimit),
+                format!("threads: {}", config.general().threads()),
+                format!("poolsize: {}", config.connection().poolsize()),
+                format!(
+                    "started: {}",
+                    started.to_rfc3339_opts(SecondsFormat::Secs, true)
+                ),
+            ];
+            let text = [
+                ("Title", format!("{} latency", command)),
+                (
+                    "Software",
+                    format!("{} {}", crate::config::NAME, crate::config::VERSION),
+                ),
+                (
+                    "Creation Time",
+                    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                ),
+                ("Description", description.join("\n")),
+            ];
+            if let Err(e) = png::add_text(file, &text) {
+                error!("failed to add metadata to the waterfall: {}", e);
+            }
+        }
+    }
+
+    /// The percentage of requests and connects which resulted in an error
+    /// during the window. A window without any activity and without any open
+    /// connections is treated as entirely failed, as the target is down.
+    fn error_rate(previous: &Snapshot, snapshot: &Snapshot) -> f64 {
+        let attempts = snapshot.delta_count(previous, REQUEST.name())
+            + snapshot.delta_count(previous, CONNECT.name());
+        let errors = snapshot.delta_count(previous, REQUEST_EX.name())
+            + snapshot.delta_count(previous, RESPONSE_EX.name())
+            + snapshot.delta_count(previous, CONNECT_EX.name());
+        if attempts == 0 {
+            if OPEN.value() > 0 {
+                0.0
+            } else {
+                100.0
+            }
+        } else {
+            100.0 * errors as f64 / attempts as f64
+        }
+    }
+
+    /// Whether the values of responses are being validated
+    fn validate(&self) -> bool {
+        self.config
+            .as_ref()
+            .map(|config| config.general().validate())
+            .unwrap_or(false)
+    }
+
+    /// Reads the host's network counters, if enabled
+    fn host_stats(&self) -> Option<HostStats> {
+        self.config
+            .as_ref()
+            .filter(|config| config.general().host_stats())
+            .map(|_| HostStats::read())
+    }
+
+    /// Reads the counters for each endpoint, if there are several
+    fn endpoint_counts(&self) -> Vec<(String, EndpointCounts)> {
+        self.endpoint_stats
+            .as_ref()
+            .map(|stats| stats.counts())
+            .unwrap_or_default()
+    }
+
+    /// Adds the failures of each http assertion to the snapshot's counters
+    fn assertion_counts(&self, snapshot: &mut Snapshot) {
+        let config = match self.config.as_ref() {
+            Some(config) => config,
+            None => return,
+        };
+        for (name, failures) in config.assertions().failures() {
+            snapshot.counters.insert(
+                Snapshot::assertion_name(&name),
+                SnapshotEntry {
+                    description: None,
+                    value: failures,
+                },
+            );
+        }
+    }
+
+    /// Blocks until the workers have stored every key, logging the progress
+    /// each second. The snapshot is then reset so that the prefill is not
+    /// included in the first window.
+    fn wait_for_prefill(&mut self) {
+        let prefill = match self.prefill.clone() {
+            Some(prefill) => prefill,
+            None => return,
+        };
+        let start = Instant::now();
+        let mut next = start + Duration::from_secs(1);
+        let interval = self
+            .config
+            .as_ref()
+            .map(|config| config.general().interval())
+            .unwrap_or(Duration::from_secs(60));
+        let mut next_checkpoint = start + interval;
+        info!("prefilling keyspaces");
+        while !prefill.is_complete() {
+            clocksource::refresh_clock();
+            if Instant::now() >= next {
+                let (stored, total) = prefill.progress();
+                info!("prefill: {} of {} keys", stored, total);
+                next += Duration::from_secs(1);
+            }
+            if Instant::now() >= next_checkpoint {
+                self.checkpoint(self.resumed);
+                next_checkpoint += interval;
+            }
+            let _ = self.log.flush();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        info!(
+            "prefill complete: {} keys in {:.2} s",
+            prefill.progress().1,
+            start.elapsed().as_secs_f64()
+        );
+        let _ = self.log.flush();
+        self.snapshot = Snapshot::new(None, None);
+        self.previous = self.snapshot.clone();
+    }
+
+    /// Runs the controller of a distributed run, which reports the merged
+    /// results of the agents until every window has been reported.
+    fn run_controller(mut self, mut coordinator: Coordinator) -> Result<(), String> {
+        let (interval, windows) = match self.config.as_ref() {
+            Some(config) => (
+                config.general().interval(),
+                config.general().windows().unwrap_or(0) as u64,
+            ),
+            None => return Ok(()),
+        };
+        info!("waiting for {} agents", coordinator.agents());
+
+        // the run ends after the window numbered by its windows
+        let mut reported = 0;
+        while reported < windows + 1 {
+            clocksource::refresh_clock();
+            let _ = self.log.flush();
+            if let Some(ref server) = self.server {
+                while let Ok(Some(request)) = server.try_recv() {
+                    coordinator.handle(request);
+                }
+            }
+            // windows are reported without agents which are more than an
+            // interval behind the others
+            for merged in coordinator.merged(interval) {
+                self.emit_merged(&merged);
+                reported += 1;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // make sure the final output is written before we exit
+        let _ = self.log.flush();
+        Ok(())
+    }
+
+    fn emit_merged(&mut self, merged: &Merged) {
+        info!("-----");
+        info!("Window: {} Agents: {}", merged.window, merged.agents);
+        info!(
+            "Requests: Sent: {} Errors: {} Responses: Received: {} Errors: {} Connects: {} Errors: {}",
+            merged.request_count,
+            merged.request_errors,
+            merged.response_count,
+            merged.response_errors,
+            merged.connect_count,
+            merged.connect_errors
+        );
+        info!(
+            "Rate: Request: {:.2} rps Response: {:.2} rps",
+            merged.request_rate, merged.response_rate
+        );
+        let hit_rate = if merged.get_count == 0 {
+            0.0
+        } else {
+            100.0 * merged.hit_count as f64 / merged.get_count as f64
+        };
+        info!("Hit-rate: {:.2} %", hit_rate);
+        let latency: Vec<String> = merged
+            .request_latency
+            .iter()
+            .map(|(label, value)| format!("{}: {}", label, value))
+            .collect();
+        info!("Response Latency (us): {}", latency.join(" "));
+
+        if let Some(ref mut file) = self.json_output {
+            match serde_json::to_string(merged) {
+                Ok(json) => {
+                    if let Err(e) = writeln!(file, "{}", json) {
+                        error!("failed to write json output: {}", e);
+                    }
+                }
+                Err(e) => error!("failed to serialize merged window: {}", e),
+            }
+        }
+    }
+
+    /// Report each command's results over the measured part of the run, as
+    /// an aligned table or as a line of JSON. Returns the JSON, if there are
+    /// any commands to summarize.
+    fn summarize(
+        &mut self,
+        windows: u64,
+        measured: &Snapshot,
+        snapshot: &Snapshot,
+    ) -> Option<String> {
+        #[derive(Serialize)]
+        struct JsonSummary<'a> {
+            windows: u64,
+            duration: f64,
+            commands: &'a [CommandSummary],
+        }
+
+        #[derive(Serialize)]
+        struct Json<'a> {
+            summary: JsonSummary<'a>,
+        }
+
+        if self.summary_heatmaps.is_empty() {
+            return None;
+        }
+
+        let commands: Vec<CommandSummary> = self
+            .summary_heatmaps
+            .iter()
+            .map(|(verb, heatmap)| {
+                let responses = Snapshot::command_name("response", *verb);
+                let percentile = |percentile| {
+                    heatmap
+                        .percentile(percentile)
+                        .map(|b| b.high())
+                        .unwrap_or(0)
+                };
+                CommandSummary {
+                    command: (*verb).into(),
+                    count: snapshot.delta_count(measured, &responses),
+                    rate: snapshot.rate(measured, &responses),
+                    success: snapshot.success_rate(
+                        measured,
+                        &responses,
+                        &Snapshot::command_name("response_ex", *verb),
+                    ),
+                    hitrate: snapshot.hitrate(
+                        measured,
+                        &responses,
+                        &Snapshot::command_name("response_hit", *verb),
+                    ),
+                    p50: percentile(50.0),
+                    p99: percentile(99.0),
+                    p999: percentile(99.9),
+                    max: percentile(100.0),
+                }
+            })
+            .collect();
+
+        let json = serde_json::to_string(&Json {
+            summary: JsonSummary {
+                windows,
+                duration: (snapshot.timestamp - measured.timestamp).as_secs_f64(),
+                commands: &commands,
+            },
+        })
+        .expect("Failed to serialize summary");
+        if let Some(ref mut file) = self.json_output {
+            if let Err(e) = writeln!(file, "{}", json) {
+                error!("failed to write json output: {}", e);
+            }
+        }
+
+        let output_format = self
+            .config
+            .as_deref()
+            .map(|config| config.general().output_format())
+            .unwrap_or_default();
+        if output_format == OutputFormat::Json {
+            println!("{}", json);
+            return Some(json);
+        }
+
+        info!("-----");
+        info!("Command Summary: {} windows", windows);
+        info!(
+            "{:<12} {:>12} {:>12} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9}",
+            "Command", "Count", "Rate", "Success %", "Hit %", "p50", "p99", "p999", "Max"
+        );
+        for command in &commands {
+            info!(
+                "{:<12} {:>12} {:>12.2} {:>8.2}% {:>8.2}% {:>9} {:>9} {:>9} {:>9}",
+                command.command,
+                command.count,
+                command.rate,
+                command.success,
+                command.hitrate,
+                command.p50,
+                command.p99,
+                command.p999,
+                command.max
+            );
+        }
+        info!("Rate is in responses per second, and latencies are in microseconds");
+        Some(json)
+    }
+
+    /// Log how each command compares to the baseline, returning the reason
+    /// the run failed if any regressed
+    fn compare_baseline(&self, summary: Option<&str>) -> Option<String> {
+        let baseline = self.baseline.as_ref()?;
+        // a run without a summary to compare is an error, not a regression
+        let checks = match summary
+            .ok_or_else(|| "the run has no summary".to_string())
+            .and_then(|summary| baseline.compare(summary))
+        {
+            Ok(checks) => checks,
+            Err(e) => {
+                error!("failed to compare against the baseline: {}", e);
+                return Some(format!("failed to compare against the baseline: {e}"));
+            }
+        };
+        for check in &checks {
+            let current = check
+                .current
+                .map(|current| format!("{:.2}", current))
+                .unwrap_or_else(|| "missing".to_string());
+            let change = check
+                .change()
+                .map(|change| format!(" ({:+.2} %)", change))
                 .unwrap_or_default();
+            info!(
+                "Baseline Summary: {} {}: {} vs {:.2}{}: {}",
+                check.command,
+                check.metric,
+                current,
+                check.baseline,
+                change,
+                if check.pass { "pass" } else { "fail" }
+            );
+        }
+        let failed = checks.iter().filter(|check| !check.pass).count();
+        (failed > 0).then(|| {
+            format!(
+                "{} of {} metrics regressed against the baseline: {}",
+                failed,
+                checks.len(),
+                baseline.file()
+            )
+        })
+    }
+
+    fn emit(&self, window: u64, previous: &Snapshot, snapshot: &Snapshot) {
+        let output_format = self
+            .config
+            .as_deref()
+            .map(|config| config.general().output_format())
+            .unwrap_or_default();
+
+        match output_format {
+            OutputFormat::Log => self.emit_log(window, previous, snapshot),
+            OutputFormat::Json => self.emit_json(window, previous, snapshot),
+        }
+    }
+
+    fn emit_log(&self, window: u64, previous: &Snapshot, snapshot: &Snapshot) {
+        info!("-----");
+        if self.verbosity == Verbosity::Quiet {
+            info!("Summary: {} windows", window + 1);
+        } else if self.blackout {
+            info!("Window: {} (blackout)", window);
+        } else {
+            info!("Window: {}", window);
+        }
+        info!(
+            "Connections: Attempts: {} Opened: {} Errors: {} Timeouts: {} Open: {}",
+            snapshot.delta_count(previous, CONNECT.name()),
+            snapshot.delta_count(previous, SESSION.name()),
+            snapshot.delta_count(previous, CONNECT_EX.name()),
+            snapshot.delta_count(previous, CONNECT_TIMEOUT.name()),
+            OPEN.value()
+        );
+
+        let lifetime = self
+            .config
+            .as_ref()
+            .and_then(|config| config.connection().lifetime());
+        if self.reconnect_ratelimit.is_some() || lifetime.is_some() {
+            info!(
+                "Reconnects: Expired: {} Abandoned Requests: {} Drain Timeouts: {}",
+                snapshot.delta_count(previous, SESSION_EXPIRED.name()),
+                snapshot.delta_count(previous, RECONNECT_ABANDONED.name()),
+                snapshot.delta_count(previous, RECONNECT_DRAIN_TIMEOUT.name())
+            );
+        }
+
+        let slow_start = self
+            .config
+            .as_ref()
+            .and_then(|config| config.connection().slow_start());
+        if slow_start.is_some() {
+            info!(
+                "Slow Start: Started: {} Warming: {} Deferred: {}",
+                snapshot.delta_count(previous, SLOW_START.name()),
+                SLOW_START_WARMING.value(),
+                snapshot.delta_count(previous, SLOW_START_DEFERRED.name())
+            );
+        }
+
+        let request_rate = snapshot.rate(previous, REQUEST.name());
+        let response_rate = snapshot.rate(previous, RESPONSE.name());
+        let connect_rate = snapshot.rate(previous, CONNECT.name());
+
+        info!(
+            "Rate: Request: {:.2} rps Response: {:.2} rps Connect: {:.2} cps",
+            request_rate, response_rate, connect_rate
+        );
+
+        let request_success = snapshot.success_rate(previous, REQUEST.name(), REQUEST_EX.name());
+        let response_success = snapshot.success_rate(previous, RESPONSE.name(), RESPONSE_EX.name());
+        let connect_success = snapshot.success_rate(previous, CONNECT.name(), CONNECT_EX.name());
+
+        info!(
+            "Success: Request: {:.2} % Response: {:.2} % Connect: {:.2} %",
+            request_success, response_success, connect_success
+        );
+
+        // requests the server rejected because it was rate limiting or
+        // overloaded are offered load which was not accepted
+        let throttled_rate = snapshot.rate(previous, RESPONSE_THROTTLED.name());
+        let accepted_rate =
+            (response_rate - throttled_rate - snapshot.rate(previous, RESPONSE_EX.name())).max(0.0);
+        info!(
+            "Throughput: Offered: {:.2} rps Accepted: {:.2} rps Throttled: {:.2} rps",
+            request_rate, accepted_rate, throttled_rate
+        );
+
+        info!(
+            "Batching: Requests/Write: {:.2}",
+            snapshot.requests_per_write(previous)
+        );
+
+        if let Some(ref cutover) = self.cutover {
+            info!(
+                "Cutover: Planned: {:.2} % Actual: {:.2} %",
+                cutover.share() * 100.0,
+                snapshot.cutover_share(previous, cutover.first())
+            );
+        }
+
+        let hit_rate = snapshot.hitrate(previous, REQUEST_GET.name(), RESPONSE_HIT.name());
+
+        info!("Hit-rate: {:.2} %", hit_rate);
+
+        // a multi-key get is a hit when any of its keys are found, so the keys
+        // are counted separately
+        if self
+            .config
+            .as_ref()
+            .is_some_and(|config| config.has_multi_get())
+        {
+            let keys = snapshot.delta_count(previous, GET_KEY.name());
+            let gets = snapshot.delta_count(previous, REQUEST_GET.name());
+            info!(
+                "Keys: Requested: {} Per Get: {:.2} Hit-rate: {:.2} %",
+                keys,
+                if gets == 0 {
+                    0.0
+                } else {
+                    keys as f64 / gets as f64
+                },
+                snapshot.hitrate(previous, GET_KEY.name(), GET_KEY_HIT.name())
+            );
+        }
 
-            match output_format {
-                OutputFormat::Log => self.emit_log(window, &snapshot),
-                OutputFormat::Json => self.emit_json(window, &snapshot),
-            }
+        if self.validate() {
+            info!(
+                "Validation: Checked: {} Corrupt: {} Wrong Key: {} Stale: {}",
+                snapshot.delta_count(previous, RESPONSE_VALIDATED.name()),
+                snapshot.delta_count(previous, RESPONSE_CORRUPT.name()),
+                snapshot.delta_count(previous, RESPONSE_WRONG_KEY.name()),
+                snapshot.delta_count(previous, RESPONSE_STALE.name())
+            );
+        }
 
-            WINDOW.increment();
-            self.snapshot = snapshot.clone();
+        if self.config.as_ref().is_some_and(|config| {
+            matches!(
+                config.general().protocol(),
+                Protocol::Redis | Protocol::RedisInline | Protocol::RedisResp
+            )
+        }) {
+            info!(
+                "Redis: Moved: {} Ask: {} Pushed: Pub/Sub: {} Invalidate: {} Other: {}",
+                snapshot.delta_count(previous, RESPONSE_MOVED.name()),
+                snapshot.delta_count(previous, RESPONSE_ASK.name()),
+                snapshot.delta_count(previous, PUSH_PUBSUB.name()),
+                snapshot.delta_count(previous, PUSH_INVALIDATE.name()),
+                snapshot.delta_count(previous, PUSH_OTHER.name())
+            );
+        }
 
-            if let Some(max_window) = self
-                .config
-                .as_ref()
-                .and_then(|config| config.general().windows())
-            {
-                if window >= max_window as u64 {
-                    if let Some(ref heatmap) = self.request_waterfall {
-                        if let Some(file) = self.config.as_ref().and_then(|c| c.waterfall().file())
-                        {
-                            let config = self.config.as_ref().unwrap();
-                            let scale = config.waterfall().scale();
-                            let palette = config.waterfall().palette();
-
-                            WaterfallBuilder::new(&file)
-                                .label(100, "100ns")
-                                .label(1000, "1us")
-                                .label(10000, "10us")
-                                .label(100000, "100us")
-                                .label(1000000, "1ms")
-                                .label(10000000, "10ms")
-                                .label(100000000, "100ms")
-                                .scale(scale)
-                                .palette(palette)
-                                .build(&**heatmap);
-                        }
-                    }
-                    break;
+        if self
+            .config
+            .as_ref()
+            .is_some_and(|config| config.verbs().contains(&Verb::Transaction))
+        {
+            let sent =
+                snapshot.delta_count(previous, &Snapshot::request_command_name(Verb::Transaction));
+            let aborted = snapshot.delta_count(previous, TRANSACTION_ABORT.name());
+            info!(
+                "Transactions: Sent: {} Aborted: {} ({:.2} %)",
+                sent,
+                aborted,
+                if sent == 0 {
+                    0.0
+                } else {
+                    100.0 * aborted as f64 / sent as f64
                 }
-            }
+            );
         }
-    }
 
-    fn emit_log(&self, window: u64, snapshot: &Snapshot) {
-        info!("-----");
-        info!("Window: {}", window);
-        info!(
-            "Connections: Attempts: {} Opened: {} Errors: {} Timeouts: {} Open: {}",
-            snapshot.delta_count(&self.snapshot, CONNECT.name()),
-            snapshot.delta_count(&self.snapshot, SESSION.name()),
-            snapshot.delta_count(&self.snapshot, CONNECT_EX.name()),
-            snapshot.delta_count(&self.snapshot, CONNECT_TIMEOUT.name()),
-            OPEN.value()
-        );
+        if self
+            .config
+            .as_ref()
+            .is_some_and(|config| config.verbs().contains(&Verb::Cas))
+        {
+            let success = snapshot.delta_count(previous, CAS_SUCCESS.name());
+            let conflict = snapshot.delta_count(previous, CAS_CONFLICT.name());
+            let swaps = success + conflict;
+            info!(
+                "Compare-and-swap: Success: {} Conflict: {} ({:.2} %)",
+                success,
+                conflict,
+                if swaps == 0 {
+                    0.0
+                } else {
+                    100.0 * conflict as f64 / swaps as f64
+                }
+            );
+        }
+
+        if let Some(config) = self
+            .config
+            .as_ref()
+            .filter(|config| !config.assertions().is_empty())
+        {
+            let failures: Vec<String> = config
+                .assertions()
+                .failures()
+                .iter()
+                .map(|(name, _)| {
+                    format!(
+                        "{}: {}",
+                        name,
+                        snapshot.delta_count(previous, &Snapshot::assertion_name(name))
+                    )
+                })
+                .collect();
+            info!(
+                "Assertions: Failed: {} ({})",
+                snapshot.delta_count(previous, RESPONSE_ASSERTION_FAILED.name()),
+                failures.join(" ")
+            );
+        }
 
-        let request_rate = snapshot.rate(&self.snapshot, REQUEST.name());
-        let response_rate = snapshot.rate(&self.snapshot, RESPONSE.name());
-        let connect_rate = snapshot.rate(&self.snapshot, CONNECT.name());
+        if self
+            .config
+            .as_ref()
+            .is_some_and(|config| config.verbs().contains(&Verb::Evalsha))
+        {
+            info!(
+                "Scripts: Sent: {} NOSCRIPT: {} Fallbacks: {}",
+                snapshot.delta_count(previous, &Snapshot::request_command_name(Verb::Evalsha)),
+                snapshot.delta_count(previous, RESPONSE_NOSCRIPT.name()),
+                snapshot.delta_count(previous, SCRIPT_FALLBACK.name())
+            );
+        }
 
-        info!(
-            "Rate: Request: {:.2} rps Response: {:.2} rps Connect: {:.2} cps",
-            request_rate, response_rate, connect_rate
-        );
+        if self
+            .config
+            .as_ref()
+            .is_some_and(|config| config.general().protocol() == Protocol::Dns)
+        {
+            info!(
+                "DNS: NOERROR: {} NXDOMAIN: {} SERVFAIL: {} Truncated: {} Lost: {}",
+                snapshot.delta_count(previous, RESPONSE_NOERROR.name()),
+                snapshot.delta_count(previous, RESPONSE_NXDOMAIN.name()),
+                snapshot.delta_count(previous, RESPONSE_SERVFAIL.name()),
+                snapshot.delta_count(previous, RESPONSE_TRUNCATED.name()),
+                snapshot.delta_count(previous, RESPONSE_TIMEOUT.name())
+            );
+        }
 
-        let request_success =
-            snapshot.success_rate(&self.snapshot, REQUEST.name(), REQUEST_EX.name());
-        let response_success =
-            snapshot.success_rate(&self.snapshot, RESPONSE.name(), RESPONSE_EX.name());
-        let connect_success =
-            snapshot.success_rate(&self.snapshot, CONNECT.name(), CONNECT_EX.name());
+        if self.config.as_ref().is_some_and(|config| {
+            config.general().protocol() == Protocol::Ping && config.datagram_timeout().is_some()
+        }) {
+            let lost = snapshot.delta_count(previous, RESPONSE_TIMEOUT.name());
+            let sent = snapshot.delta_count(previous, REQUEST.name());
+            info!(
+                "Ping: Lost: {} Loss: {:.2} %",
+                lost,
+                if sent == 0 {
+                    0.0
+                } else {
+                    100.0 * lost as f64 / sent as f64
+                }
+            );
+        }
 
-        info!(
-            "Success: Request: {:.2} % Response: {:.2} % Connect: {:.2} %",
-            request_success, response_success, connect_success
-        );
+        if self
+            .config
+            .as_ref()
+            .is_some_and(|config| config.datagram_grace().is_some())
+        {
+            info!(
+                "Timeouts: {} Late: {} No Response: {}",
+                snapshot.delta_count(previous, RESPONSE_TIMEOUT.name()),
+                snapshot.delta_count(previous, RESPONSE_LATE.name()),
+                snapshot.delta_count(previous, RESPONSE_MISSING.name())
+            );
+        }
 
-        let hit_rate = snapshot.hitrate(&self.snapshot, REQUEST_GET.name(), RESPONSE_HIT.name());
+        // datagram timeouts are reported with their protocol
+        if let Some(config) = self.config.as_ref().filter(|config| {
+            config.datagram_timeout().is_none() && config.request().timeouts().next().is_some()
+        }) {
+            let commands: Vec<String> = config
+                .verbs()
+                .into_iter()
+                .filter(|verb| config.request_timeout(*verb).is_some())
+                .map(|verb| {
+                    format!(
+                        "{}: {}",
+                        <&str>::from(verb),
+                        snapshot.delta_count(
+                            previous,
+                            &Snapshot::command_name("response_timeout", verb)
+                        )
+                    )
+                })
+                .collect();
+            info!(
+                "Timeouts: {} {}",
+                snapshot.delta_count(previous, RESPONSE_TIMEOUT.name()),
+                commands.join(" ")
+            );
+        }
 
-        info!("Hit-rate: {:.2} %", hit_rate);
+        if self
+            .config
+            .as_ref()
+            .is_some_and(|config| config.has_edge_cases())
+        {
+            let requests = snapshot.delta_count(previous, EDGE_CASE_REQUEST.name());
+            let responses = snapshot.delta_count(previous, EDGE_CASE_RESPONSE.name());
+            let percent = |count: u64| {
+                if responses == 0 {
+                    0.0
+                } else {
+                    100.0 * count as f64 / responses as f64
+                }
+            };
+            info!(
+                "Edge Cases: Requests: {} Responses: {} Hit: {:.2} % Error: {:.2} % Closed: {}",
+                requests,
+                responses,
+                percent(snapshot.delta_count(previous, EDGE_CASE_RESPONSE_HIT.name())),
+                percent(snapshot.delta_count(previous, EDGE_CASE_RESPONSE_EX.name())),
+                snapshot.delta_count(previous, EDGE_CASE_CLOSE.name())
+            );
+        }
+
+        // stickiness can only be checked when the backends name themselves
+        if self
+            .config
+            .as_ref()
+            .and_then(|config| config.stickiness())
+            .is_some_and(|stickiness| stickiness.identity().is_some())
+        {
+            let checked = snapshot.delta_count(previous, RESPONSE_STICKY.name());
+            let violations = snapshot.delta_count(previous, RESPONSE_STICKY_VIOLATION.name());
+            info!(
+                "Stickiness: Checked: {} Violations: {} ({:.2} %)",
+                checked,
+                violations,
+                if checked == 0 {
+                    0.0
+                } else {
+                    100.0 * violations as f64 / checked as f64
+                }
+            );
+        }
+
+        if self.verbosity == Verbosity::Verbose {
+            let mut commands = Vec::new();
+            for verb in Verb::iter() {
+                let counter = Snapshot::request_command_name(verb);
+                if snapshot.delta_count(previous, &counter) > 0 {
+                    commands.push(format!(
+                        "{}: {:.2} rps",
+                        <&'static str>::from(verb),
+                        snapshot.rate(previous, &counter)
+                    ));
+                }
+            }
+            info!("Commands: {}", commands.join(" "));
+        }
 
         if let Some(ref heatmap) = self.connect_heatmap {
             let p25 = heatmap.percentile(25.0).map(|b| b.high()).unwrap_or(0);
@@ -286,7 +1852,41 @@ impl Admin {
             );
         }
 
-        if let Some(ref heatmap) = self.request_heatmap {
+        if let Some(ref heatmap) = self.handshake_heatmap {
+            let p25 = heatmap.percentile(25.0).map(|b| b.high()).unwrap_or(0);
+            let p50 = heatmap.percentile(50.0).map(|b| b.high()).unwrap_or(0);
+            let p75 = heatmap.percentile(75.0).map(|b| b.high()).unwrap_or(0);
+            let p90 = heatmap.percentile(90.0).map(|b| b.high()).unwrap_or(0);
+            let p99 = heatmap.percentile(99.0).map(|b| b.high()).unwrap_or(0);
+            let p999 = heatmap.percentile(99.9).map(|b| b.high()).unwrap_or(0);
+            let p9999 = heatmap.percentile(99.99).map(|b| b.high()).unwrap_or(0);
+            info!(
+                "Handshake Latency (us): p25: {} p50: {} p75: {} p90: {} p99: {} p999: {} p9999: {}",
+                p25, p50, p75, p90, p99, p999, p9999
+            );
+        }
+
+        if let Some(ref heatmap) = self.session_lifetime_heatmap {
+            let p50 = heatmap.percentile(50.0).map(|b| b.high()).unwrap_or(0);
+            let p90 = heatmap.percentile(90.0).map(|b| b.high()).unwrap_or(0);
+            let p99 = heatmap.percentile(99.0).map(|b| b.high()).unwrap_or(0);
+            let max = heatmap.percentile(100.0).map(|b| b.high()).unwrap_or(0);
+            info!(
+                "Session Lifetime (ms): p50: {} p90: {} p99: {} max: {}",
+                p50, p90, p99, max
+            );
+        }
+
+        if !snapshot.request_percentiles.is_empty() {
+            let latency: Vec<String> = snapshot
+                .request_percentiles
+                .iter()
+                .map(|(label, value)| format!("{}: {}", label, value))
+                .collect();
+            info!("Response Latency (us): {}", latency.join(" "));
+        }
+
+        if let Some(ref heatmap) = self.corrected_heatmap {
             let p25 = heatmap.percentile(25.0).map(|b| b.high()).unwrap_or(0);
             let p50 = heatmap.percentile(50.0).map(|b| b.high()).unwrap_or(0);
             let p75 = heatmap.percentile(75.0).map(|b| b.high()).unwrap_or(0);
@@ -295,13 +1895,325 @@ impl Admin {
             let p999 = heatmap.percentile(99.9).map(|b| b.high()).unwrap_or(0);
             let p9999 = heatmap.percentile(99.99).map(|b| b.high()).unwrap_or(0);
             info!(
-                "Response Latency (us): p25: {} p50: {} p75: {} p90: {} p99: {} p999: {} p9999: {}",
+                "Corrected Latency (us): p25: {} p50: {} p75: {} p90: {} p99: {} p999: {} p9999: {}",
                 p25, p50, p75, p90, p99, p999, p9999
             );
         }
+
+        if let Some(ref heatmap) = self.queue_delay_heatmap {
+            let p50 = heatmap.percentile(50.0).map(|b| b.high()).unwrap_or(0);
+            let p90 = heatmap.percentile(90.0).map(|b| b.high()).unwrap_or(0);
+            let p99 = heatmap.percentile(99.0).map(|b| b.high()).unwrap_or(0);
+            let p999 = heatmap.percentile(99.9).map(|b| b.high()).unwrap_or(0);
+            let max = heatmap.percentile(100.0).map(|b| b.high()).unwrap_or(0);
+            info!(
+                "Queue Delay (us): p50: {} p90: {} p99: {} p999: {} max: {} Backlog: {}",
+                p50,
+                p90,
+                p99,
+                p999,
+                max,
+                REQUEST_BACKLOG.value()
+            );
+        }
+
+        // a replay waits to read the trace while its work queue is full
+        if self.config.is_none() && self.queue_delay_heatmap.is_some() {
+            info!(
+                "Work Queue: Full: {} Wait (us): {}",
+                snapshot.delta_count(previous, REQUEST_QUEUE_FULL.name()),
+                snapshot.delta_count(previous, REQUEST_QUEUE_WAIT.name())
+            );
+        }
+
+        // generators fall behind when they are starved of cpu, and wait when
+        // the workers can't keep up with the schedule
+        if self
+            .config
+            .as_ref()
+            .is_some_and(|config| config.general().generator_threads() > 0)
+        {
+            info!(
+                "Generators: Behind: {} Schedule Full: {} Wait (us): {}",
+                snapshot.delta_count(previous, REQUEST_GENERATOR_BEHIND.name()),
+                snapshot.delta_count(previous, REQUEST_QUEUE_FULL.name()),
+                snapshot.delta_count(previous, REQUEST_QUEUE_WAIT.name())
+            );
+        }
+
+        if let Some((ref loopback, ref wakeup)) = self.calibration_heatmaps {
+            let percentile = |heatmap: &Latency, percentile| {
+                heatmap
+                    .percentile(percentile)
+                    .map(|b| b.high())
+                    .unwrap_or(0)
+            };
+            info!(
+                "Calibration (us): p50: {} p99: {} p999: {} max: {} Wakeup Delay (us): p99: {} max: {} Failed: {}",
+                percentile(loopback, 50.0),
+                percentile(loopback, 99.0),
+                percentile(loopback, 99.9),
+                percentile(loopback, 100.0),
+                percentile(wakeup, 99.0),
+                percentile(wakeup, 100.0),
+                snapshot.delta_count(previous, CALIBRATION_PROBE_EX.name())
+            );
+        }
+
+        let collection_writes = snapshot.delta_count(previous, COLLECTION_WRITE.name());
+        if collection_writes > 0 {
+            let elements = snapshot.delta_count(previous, COLLECTION_WRITE_ELEMENT.name());
+            let spread = |heatmap: &Option<Arc<Latency>>| match heatmap {
+                Some(heatmap) => (
+                    heatmap.percentile(50.0).map(|b| b.high()).unwrap_or(0),
+                    heatmap.percentile(99.0).map(|b| b.high()).unwrap_or(0),
+                    heatmap.percentile(100.0).map(|b| b.high()).unwrap_or(0),
+                ),
+                None => (0, 0, 0),
+            };
+            let (p50, p99, max) = spread(&self.write_size_heatmap);
+            let (elements_p50, elements_p99, elements_max) = spread(&self.write_element_heatmap);
+            info!(
+                "Collection Writes: Commands: {} Elements: {} Elements/Command: mean: {:.2} p50: {} p99: {} max: {} Bytes/Command: p50: {} p99: {} max: {}",
+                collection_writes,
+                elements,
+                elements as f64 / collection_writes as f64,
+                elements_p50,
+                elements_p99,
+                elements_max,
+                p50,
+                p99,
+                max
+            );
+        }
+
+        if let (Some(host), Some(previous_host)) = (&snapshot.host, &previous.host) {
+            let seconds = (snapshot.timestamp - previous.timestamp).as_secs_f64();
+            let deltas = host.interface_deltas(previous_host);
+            // loopback traffic is excluded from the totals as it never
+            // reaches the nic
+            let mut total = Interface::default();
+            for (name, delta) in deltas.iter().filter(|(name, _)| name.as_str() != "lo") {
+                total.rx_bytes += delta.rx_bytes;
+                total.rx_packets += delta.rx_packets;
+                total.tx_bytes += delta.tx_bytes;
+                total.tx_packets += delta.tx_packets;
+                if self.verbosity == Verbosity::Verbose {
+                    info!(
+                        "Host Interface {}: Rx: {:.2} Mbps {:.0} pps Tx: {:.2} Mbps {:.0} pps",
+                        name,
+                        delta.rx_bytes as f64 * 8.0 / 1_000_000.0 / seconds,
+                        delta.rx_packets as f64 / seconds,
+                        delta.tx_bytes as f64 * 8.0 / 1_000_000.0 / seconds,
+                        delta.tx_packets as f64 / seconds
+                    );
+                }
+            }
+            info!(
+                "Host Network: Rx: {:.2} Mbps {:.0} pps Tx: {:.2} Mbps {:.0} pps TCP Retransmits: {} Conntrack: {}",
+                total.rx_bytes as f64 * 8.0 / 1_000_000.0 / seconds,
+                total.rx_packets as f64 / seconds,
+                total.tx_bytes as f64 * 8.0 / 1_000_000.0 / seconds,
+                total.tx_packets as f64 / seconds,
+                host.tcp_retransmit_delta(previous_host)
+                    .map(|r| r.to_string())
+                    .unwrap_or_else(|| "n/a".to_string()),
+                host.conntrack
+                    .map(|c| c.to_string())
+                    .unwrap_or_else(|| "n/a".to_string())
+            );
+        }
+
+        if let Some(ref heatmap) = self.response_field_heatmap {
+            let responses =
+                snapshot.delta_count(previous, &Snapshot::request_command_name(Verb::Hgetall));
+            let fields = snapshot.delta_count(previous, RESPONSE_FIELD.name());
+            let mean = if responses > 0 {
+                fields as f64 / responses as f64
+            } else {
+                0.0
+            };
+            info!(
+                "Hash Get All: Requests: {} Fields: {} Fields/Response: mean: {:.2} p50: {} p99: {} max: {}",
+                responses,
+                fields,
+                mean,
+                heatmap.percentile(50.0).map(|b| b.high()).unwrap_or(0),
+                heatmap.percentile(99.0).map(|b| b.high()).unwrap_or(0),
+                heatmap.percentile(100.0).map(|b| b.high()).unwrap_or(0)
+            );
+        }
+
+        if self.config.as_ref().is_some_and(|config| {
+            // the thrift cache protocol does not count the elements
+            config.verbs().contains(&Verb::Lrange)
+                && config.general().protocol() != Protocol::ThriftCache
+        }) {
+            let responses =
+                snapshot.delta_count(previous, &Snapshot::request_command_name(Verb::Lrange));
+            let elements = snapshot.delta_count(previous, RESPONSE_ELEMENT.name());
+            info!(
+                "List Range: Requests: {} Elements: {} Elements/Response: mean: {:.2}",
+                responses,
+                elements,
+                if responses > 0 {
+                    elements as f64 / responses as f64
+                } else {
+                    0.0
+                }
+            );
+        }
+
+        if self.config.as_ref().is_some_and(|config| {
+            config.verbs().contains(&Verb::Smembers) || config.verbs().contains(&Verb::Zrange)
+        }) {
+            let responses = snapshot
+                .delta_count(previous, &Snapshot::request_command_name(Verb::Smembers))
+                + snapshot.delta_count(previous, &Snapshot::request_command_name(Verb::Zrange));
+            let members = snapshot.delta_count(previous, RESPONSE_MEMBER.name());
+            info!(
+                "Set Members: Requests: {} Members: {} Members/Response: mean: {:.2}",
+                responses,
+                members,
+                if responses > 0 {
+                    members as f64 / responses as f64
+                } else {
+                    0.0
+                }
+            );
+        }
+
+        // a breakdown is only useful for a mix of commands, or for commands
+        // sent in several steps which are kept out of the response latency
+        let composite = self
+            .command_heatmaps
+            .iter()
+            .any(|(verb, _)| verb.is_multi_step());
+        if self.command_heatmaps.len() > 1 || composite {
+            for (verb, heatmap) in &self.command_heatmaps {
+                let p50 = heatmap.percentile(50.0).map(|b| b.high()).unwrap_or(0);
+                let p90 = heatmap.percentile(90.0).map(|b| b.high()).unwrap_or(0);
+                let p99 = heatmap.percentile(99.0).map(|b| b.high()).unwrap_or(0);
+                let p999 = heatmap.percentile(99.9).map(|b| b.high()).unwrap_or(0);
+                info!(
+                    "Command {} Latency (us): p50: {} p90: {} p99: {} p999: {}",
+                    <&str>::from(*verb),
+                    p50,
+                    p90,
+                    p99,
+                    p999
+                );
+            }
+        }
+
+        for verdict in &self.sla {
+            info!(
+                "SLA: {}: {} {}",
+                verdict.criterion(),
+                verdict.measured(),
+                if self.blackout {
+                    "excluded"
+                } else if verdict.pass {
+                    "pass"
+                } else {
+                    "fail"
+                }
+            );
+        }
+
+        if let (Some(search), Some(step)) = (&self.rate_search, &self.search_step) {
+            info!(
+                "Rate Search: Rate: {} rps Achieved: {:.2} rps p{}: {} us {}",
+                step.rate,
+                step.achieved,
+                search.percentile(),
+                step.latency,
+                if step.pass { "pass" } else { "fail" }
+            );
+        }
+
+        if !self.endpoint_heatmaps.is_empty() {
+            // the spread between the fastest and slowest endpoint highlights
+            // imbalance across the target's shards
+            let mut spread = Vec::new();
+            for (label, percentile) in [("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("p999", 99.9)]
+            {
+                let latencies = self.endpoint_latencies(percentile);
+                let fastest = latencies.iter().map(|(_, l)| *l).min().unwrap_or(0);
+                let slowest = latencies.iter().map(|(_, l)| *l).max().unwrap_or(0);
+                spread.push(format!("{label}: {fastest} / {slowest}"));
+            }
+            info!("Endpoint Latency Spread (us): {}", spread.join(" "));
+
+            if let Some((endpoint, latency)) = self
+                .endpoint_latencies(99.0)
+                .into_iter()
+                .max_by_key(|(_, latency)| *latency)
+            {
+                info!("Slowest Endpoint: {} p99: {} us", endpoint, latency);
+            }
+
+            let errors: Vec<(&str, EndpointCounts)> = snapshot
+                .endpoint_deltas(previous)
+                .into_iter()
+                .filter(|(_, delta)| delta.errors() > 0)
+                .collect();
+            if let Some((endpoint, delta)) = errors.iter().max_by_key(|(_, delta)| delta.errors()) {
+                info!(
+                    "Endpoint Errors: {} of {} endpoints had errors, most: {} with {} errors",
+                    errors.len(),
+                    self.endpoint_heatmaps.len(),
+                    endpoint,
+                    delta.errors()
+                );
+            }
+
+            if self.verbosity == Verbosity::Verbose {
+                for (endpoint, delta) in snapshot.endpoint_deltas(previous) {
+                    info!(
+                        "Endpoint {}: Connects: {} Errors: {} Requests: {} Errors: {} Responses: {} Errors: {}",
+                        endpoint,
+                        delta.connect,
+                        delta.connect_ex,
+                        delta.request,
+                        delta.request_ex,
+                        delta.response,
+                        delta.response_ex
+                    );
+                }
+                for (endpoint, heatmap) in &self.endpoint_heatmaps {
+                    let p50 = heatmap.percentile(50.0).map(|b| b.high()).unwrap_or(0);
+                    let p90 = heatmap.percentile(90.0).map(|b| b.high()).unwrap_or(0);
+                    let p99 = heatmap.percentile(99.0).map(|b| b.high()).unwrap_or(0);
+                    let p999 = heatmap.percentile(99.9).map(|b| b.high()).unwrap_or(0);
+                    info!(
+                        "Endpoint {} Latency (us): p50: {} p90: {} p99: {} p999: {}",
+                        endpoint, p50, p90, p99, p999
+                    );
+                }
+            }
+        }
+    }
+
+    /// The request latency at the percentile for each endpoint which has
+    /// received responses.
+    fn endpoint_latencies(&self, percentile: f64) -> Vec<(&str, u64)> {
+        self.endpoint_heatmaps
+            .iter()
+            .filter_map(|(endpoint, heatmap)| {
+                heatmap
+                    .percentile(percentile)
+                    .ok()
+                    .map(|bucket| (endpoint.as_str(), bucket.high()))
+            })
+            .collect()
     }
 
-    fn emit_json(&self, window: u64, snapshot: &Snapshot) {
+    fn emit_json(&self, window: u64, previous: &Snapshot, snapshot: &Snapshot) {
+        println!("{}", self.json(window, previous, snapshot));
+    }
+
+    fn json(&self, window: u64, previous: &Snapshot, snapshot: &Snapshot) -> String {
         #[derive(Serialize)]
         struct Bucket {
             value: u64,
@@ -315,84 +2227,283 @@ impl Admin {
             errors: u64,
             timeouts: u64,
             open: i64,
+            expired: u64,
+            reconnect_abandoned: u64,
+            reconnect_drain_timeouts: u64,
+            slow_start: u64,
+            slow_start_warming: i64,
+            slow_start_deferred: u64,
+        }
+
+        #[derive(Serialize)]
+        struct CutoverShare {
+            planned: f64,
+            actual: f64,
+        }
+
+        #[derive(Serialize)]
+        struct Validation {
+            checked: u64,
+            corrupt: u64,
+            wrong_key: u64,
+            stale: u64,
         }
 
         #[derive(Serialize)]
-        struct JsonSnapshot {
+        struct Host {
+            interfaces: BTreeMap<String, Interface>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            tcp_retransmits: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            conntrack: Option<u64>,
+        }
+
+        #[derive(Serialize)]
+        struct JsonSnapshot<'a> {
             window: u64,
+            #[serde(skip_serializing_if = "std::ops::Not::not")]
+            blackout: bool,
             interval: f64,
             connections: Connections,
             request_count: u64,
             request_errors: u64,
             response_count: u64,
             response_errors: u64,
+            response_throttled: u64,
             connect_count: u64,
             connect_errors: u64,
             get_count: u64,
             hit_count: u64,
+            request_rate: f64,
+            response_rate: f64,
+            accepted_rate: f64,
+            requests_per_write: f64,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            cutover: Option<CutoverShare>,
+            collection_write_count: u64,
+            collection_element_count: u64,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            validation: Option<Validation>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            host: Option<Host>,
+
+            connect_latency: BTreeMap<&'a str, u64>,
+            request_latency: BTreeMap<&'a str, u64>,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            collection_write_bytes: BTreeMap<&'static str, u64>,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            collection_write_elements: BTreeMap<&'static str, u64>,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            hgetall_fields: BTreeMap<&'static str, u64>,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            corrected_latency: BTreeMap<&'static str, u64>,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            queue_delay: BTreeMap<&'static str, u64>,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            calibration_latency: BTreeMap<&'static str, u64>,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            calibration_wakeup_delay: BTreeMap<&'static str, u64>,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            session_lifetime: BTreeMap<&'static str, u64>,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            command_latency: BTreeMap<&'static str, BTreeMap<&'static str, u64>>,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            endpoint_latency: BTreeMap<&'a str, BTreeMap<&'static str, u64>>,
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            endpoints: BTreeMap<&'a str, EndpointCounts>,
 
             connect: Vec<Bucket>,
             request: Vec<Bucket>,
+
+            #[serde(skip_serializing_if = "HashMap::is_empty")]
+            commands: HashMap<&'static str, u64>,
+
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            sla: Vec<SlaVerdict>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            search: Option<&'a SearchStep>,
         }
 
-        fn heatmap_to_buckets(heatmap: &Heatmap) -> Vec<Bucket> {
+        fn heatmap_to_buckets(heatmap: &Latency) -> Vec<Bucket> {
             heatmap
-                .summary()
+                .buckets()
+                .into_iter()
+                .map(|(value, count)| Bucket { value, count })
+                .collect()
+        }
+
+        fn percentiles(heatmap: &Latency) -> BTreeMap<&'static str, u64> {
+            [("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("p999", 99.9)]
                 .into_iter()
-                // Only include buckets that actually contain values
-                .filter(|bucket| bucket.count() != 0)
-                .map(|bucket| Bucket {
-                    value: bucket.high(),
-                    count: bucket.count(),
+                .map(|(label, percentile)| {
+                    (
+                        label,
+                        heatmap
+                            .percentile(percentile)
+                            .map(|b| b.high())
+                            .unwrap_or(0),
+                    )
                 })
                 .collect()
         }
 
+        let mut commands = HashMap::new();
+        if self.verbosity == Verbosity::Verbose {
+            for verb in Verb::iter() {
+                let count = snapshot.delta_count(previous, &Snapshot::request_command_name(verb));
+                if count > 0 {
+                    commands.insert(verb.into(), count);
+                }
+            }
+        }
+
         let json = JsonSnapshot {
             connections: Connections {
-                attempts: snapshot.delta_count(&self.snapshot, CONNECT.name()),
-                opened: snapshot.delta_count(&self.snapshot, SESSION.name()),
-                errors: snapshot.delta_count(&self.snapshot, CONNECT_EX.name()),
-                timeouts: snapshot.delta_count(&self.snapshot, CONNECT_TIMEOUT.name()),
+                attempts: snapshot.delta_count(previous, CONNECT.name()),
+                opened: snapshot.delta_count(previous, SESSION.name()),
+                errors: snapshot.delta_count(previous, CONNECT_EX.name()),
+                timeouts: snapshot.delta_count(previous, CONNECT_TIMEOUT.name()),
                 open: OPEN.value(),
+                expired: snapshot.delta_count(previous, SESSION_EXPIRED.name()),
+                reconnect_abandoned: snapshot.delta_count(previous, RECONNECT_ABANDONED.name()),
+                reconnect_drain_timeouts: snapshot
+                    .delta_count(previous, RECONNECT_DRAIN_TIMEOUT.name()),
+                slow_start: snapshot.delta_count(previous, SLOW_START.name()),
+                slow_start_warming: SLOW_START_WARMING.value(),
+                slow_start_deferred: snapshot.delta_count(previous, SLOW_START_DEFERRED.name()),
             },
             window,
-            interval: (snapshot.timestamp - self.snapshot.timestamp).as_secs_f64(),
-            request_count: snapshot.delta_count(&self.snapshot, REQUEST.name()),
-            request_errors: snapshot.delta_count(&self.snapshot, REQUEST_EX.name()),
-            response_count: snapshot.delta_count(&self.snapshot, RESPONSE.name()),
-            response_errors: snapshot.delta_count(&self.snapshot, RESPONSE_EX.name()),
-            connect_count: snapshot.delta_count(&self.snapshot, CONNECT.name()),
-            connect_errors: snapshot.delta_count(&self.snapshot, CONNECT_EX.name()),
-            get_count: snapshot.delta_count(&self.snapshot, REQUEST_GET.name()),
-            hit_count: snapshot.delta_count(&self.snapshot, REQUEST_GET.name()),
+            blackout: self.blackout,
+            interval: (snapshot.timestamp - previous.timestamp).as_secs_f64(),
+            request_count: snapshot.delta_count(previous, REQUEST.name()),
+            request_errors: snapshot.delta_count(previous, REQUEST_EX.name()),
+            response_count: snapshot.delta_count(previous, RESPONSE.name()),
+            response_errors: snapshot.delta_count(previous, RESPONSE_EX.name()),
+            response_throttled: snapshot.delta_count(previous, RESPONSE_THROTTLED.name()),
+            connect_count: snapshot.delta_count(previous, CONNECT.name()),
+            connect_errors: snapshot.delta_count(previous, CONNECT_EX.name()),
+            get_count: snapshot.delta_count(previous, REQUEST_GET.name()),
+            hit_count: snapshot.delta_count(previous, RESPONSE_HIT.name()),
+            request_rate: snapshot.rate(previous, REQUEST.name()),
+            response_rate: snapshot.rate(previous, RESPONSE.name()),
+            accepted_rate: (snapshot.rate(previous, RESPONSE.name())
+                - snapshot.rate(previous, RESPONSE_THROTTLED.name())
+                - snapshot.rate(previous, RESPONSE_EX.name()))
+            .max(0.0),
+            requests_per_write: snapshot.requests_per_write(previous),
+            cutover: self.cutover.as_ref().map(|cutover| CutoverShare {
+                planned: cutover.share() * 100.0,
+                actual: snapshot.cutover_share(previous, cutover.first()),
+            }),
+            collection_write_count: snapshot.delta_count(previous, COLLECTION_WRITE.name()),
+            collection_element_count: snapshot
+                .delta_count(previous, COLLECTION_WRITE_ELEMENT.name()),
+            validation: self.validate().then(|| Validation {
+                checked: snapshot.delta_count(previous, RESPONSE_VALIDATED.name()),
+                corrupt: snapshot.delta_count(previous, RESPONSE_CORRUPT.name()),
+                wrong_key: snapshot.delta_count(previous, RESPONSE_WRONG_KEY.name()),
+                stale: snapshot.delta_count(previous, RESPONSE_STALE.name()),
+            }),
+            host: snapshot.host.as_ref().zip(previous.host.as_ref()).map(
+                |(host, previous_host)| Host {
+                    interfaces: host.interface_deltas(previous_host),
+                    tcp_retransmits: host.tcp_retransmit_delta(previous_host),
+                    conntrack: host.conntrack,
+                },
+            ),
+
+            connect_latency: snapshot
+                .connect_percentiles
+                .iter()
+                .map(|(label, value)| (label.as_str(), *value))
+                .collect(),
+            request_latency: snapshot
+                .request_percentiles
+                .iter()
+                .map(|(label, value)| (label.as_str(), *value))
+                .collect(),
+            collection_write_bytes: self
+                .write_size_heatmap
+                .as_deref()
+                .map(percentiles)
+                .unwrap_or_default(),
+            collection_write_elements: self
+                .write_element_heatmap
+                .as_deref()
+                .map(percentiles)
+                .unwrap_or_default(),
+            hgetall_fields: self
+                .response_field_heatmap
+                .as_deref()
+                .map(percentiles)
+                .unwrap_or_default(),
+            corrected_latency: self
+                .corrected_heatmap
+                .as_deref()
+                .map(percentiles)
+                .unwrap_or_default(),
+            queue_delay: self
+                .queue_delay_heatmap
+                .as_deref()
+                .map(percentiles)
+                .unwrap_or_default(),
+            calibration_latency: self
+                .calibration_heatmaps
+                .as_ref()
+                .map(|(loopback, _)| percentiles(loopback))
+                .unwrap_or_default(),
+            calibration_wakeup_delay: self
+                .calibration_heatmaps
+                .as_ref()
+                .map(|(_, wakeup)| percentiles(wakeup))
+                .unwrap_or_default(),
+            session_lifetime: self
+                .session_lifetime_heatmap
+                .as_deref()
+                .map(percentiles)
+                .unwrap_or_default(),
+            command_latency: self
+                .command_heatmaps
+                .iter()
+                .map(|(verb, heatmap)| (<&str>::from(*verb), percentiles(heatmap)))
+                .collect(),
+            endpoint_latency: self
+                .endpoint_heatmaps
+                .iter()
+                .map(|(endpoint, heatmap)| (endpoint.as_str(), percentiles(heatmap)))
+                .collect(),
+            endpoints: snapshot.endpoint_deltas(previous).into_iter().collect(),
 
             connect: self
                 .connect_heatmap
                 .as_deref()
-                .map(|heatmap| heatmap_to_buckets(heatmap))
+                .map(heatmap_to_buckets)
                 .unwrap_or_default(),
             request: self
                 .request_heatmap
                 .as_deref()
-                .map(|heatmap| heatmap_to_buckets(heatmap))
+                .map(heatmap_to_buckets)
                 .unwrap_or_default(),
+            commands,
+            sla: self.sla.clone(),
+            search: self.search_step.as_ref(),
         };
 
-        println!(
-            "{}",
-            serde_json::to_string(&json).expect("Failed to output to stdout")
-        );
+        serde_json::to_string(&json).expect("Failed to serialize snapshot")
     }
 }
 
 #[derive(Clone)]
 pub struct Snapshot {
-    counters: HashMap<&'static str, SnapshotEntry<u64>>,
-    gauges: HashMap<&'static str, SnapshotEntry<i64>>,
+    counters: HashMap<String, SnapshotEntry<u64>>,
+    gauges: HashMap<String, SnapshotEntry<i64>>,
     timestamp: Instant,
+    time: DateTime<Utc>,
     connect_percentiles: Vec<(String, u64)>,
     request_percentiles: Vec<(String, u64)>,
+    host: Option<HostStats>,
+    endpoints: Vec<(String, EndpointCounts)>,
 }
 
 #[derive(Clone)]
@@ -402,7 +2513,7 @@ pub struct SnapshotEntry<T> {
 }
 
 impl Snapshot {
-    fn new(connect_heatmap: Option<&Arc<Heatmap>>, request_heatmap: Option<&Arc<Heatmap>>) -> Self {
+    fn new(connect_heatmap: Option<&Arc<Latency>>, request_heatmap: Option<&Arc<Latency>>) -> Self {
         let mut counters = HashMap::new();
         let mut gauges = HashMap::new();
         for metric in metriken::metrics().static_metrics() {
@@ -416,29 +2527,37 @@ impl Snapshot {
                     description: metric.description(),
                     value: counter.value(),
                 };
-                counters.insert(metric.name(), entry);
+                counters.insert(metric.name().to_owned(), entry);
             } else if let Some(gauge) = any.downcast_ref::<Gauge>() {
                 let entry = SnapshotEntry {
                     description: metric.description(),
                     value: gauge.value(),
                 };
-                gauges.insert(metric.name(), entry);
+                gauges.insert(metric.name().to_owned(), entry);
             }
         }
 
-        let percentiles = vec![
-            ("p25", 25.0),
-            ("p50", 50.0),
-            ("p75", 75.0),
-            ("p90", 90.0),
-            ("p99", 99.0),
-            ("p999", 99.9),
-            ("p9999", 99.99),
-        ];
+        for verb in Verb::iter() {
+            for (kind, counter) in [
+                ("request", &REQUEST_COMMAND[verb as usize]),
+                ("request_bytes", &REQUEST_COMMAND_BYTE[verb as usize]),
+                ("response", &RESPONSE_COMMAND[verb as usize]),
+                ("response_bytes", &RESPONSE_COMMAND_BYTE[verb as usize]),
+                ("response_ex", &RESPONSE_COMMAND_EX[verb as usize]),
+                ("response_hit", &RESPONSE_COMMAND_HIT[verb as usize]),
+                ("response_timeout", &RESPONSE_COMMAND_TIMEOUT[verb as usize]),
+            ] {
+                let entry = SnapshotEntry {
+                    description: None,
+                    value: counter.value(),
+                };
+                counters.insert(Self::command_name(kind, verb), entry);
+            }
+        }
 
         let mut connect_percentiles = Vec::new();
         if let Some(heatmap) = connect_heatmap {
-            for (label, value) in &percentiles {
+            for (label, value) in &PERCENTILES {
                 connect_percentiles.push((
                     label.to_string(),
                     heatmap.percentile(*value).map(|b| b.high()).unwrap_or(0),
@@ -448,7 +2567,7 @@ impl Snapshot {
 
         let mut request_percentiles = Vec::new();
         if let Some(heatmap) = request_heatmap {
-            for (label, value) in &percentiles {
+            for (label, value) in &PERCENTILES {
                 request_percentiles.push((
                     label.to_string(),
                     heatmap.percentile(*value).map(|b| b.high()).unwrap_or(0),
@@ -460,24 +2579,74 @@ impl Snapshot {
             counters,
             gauges,
             timestamp: Instant::now(),
+            time: Utc::now(),
             connect_percentiles,
             request_percentiles,
+            host: None,
+            endpoints: Vec::new(),
         }
     }
 
-    fn delta_count(&self, other: &Self, counter: &'static str) -> u64 {
-        let this = self.counters.get(&counter).map(|v| v.value).unwrap_or(0);
-        let other = other.counters.get(&counter).map(|v| v.value).unwrap_or(0);
+    /// Name used to expose the per-command request counter for the verb.
+    fn request_command_name(verb: Verb) -> String {
+        Self::command_name("request", verb)
+    }
+
+    /// Name used to expose the failures of an http assertion.
+    fn assertion_name(name: &str) -> String {
+        format!("{}/{}", RESPONSE_ASSERTION_FAILED.name(), name)
+    }
+
+    /// Name used to expose a per-command counter of the kind for the verb.
+    fn command_name(kind: &str, verb: Verb) -> String {
+        format!("{}/{}", kind, <&'static str>::from(verb))
+    }
+
+    fn delta_count(&self, other: &Self, counter: &str) -> u64 {
+        let this = self.counters.get(counter).map(|v| v.value).unwrap_or(0);
+        let other = other.counters.get(counter).map(|v| v.value).unwrap_or(0);
         this - other
     }
 
-    fn rate(&self, other: &Self, counter: &'static str) -> f64 {
+    /// The change in the counters of each endpoint since the other snapshot.
+    /// The counters start at zero, so they may be missing from the other.
+    /// The percentage of the requests in the window which were sent to the
+    /// cutover endpoints
+    fn cutover_share(&self, other: &Self, first: usize) -> f64 {
+        let requests: Vec<u64> = self
+            .endpoint_deltas(other)
+            .iter()
+            .map(|(_, delta)| delta.request)
+            .collect();
+        let total: u64 = requests.iter().sum();
+        if total == 0 {
+            return 0.0;
+        }
+        100.0 * requests[first.min(requests.len())..].iter().sum::<u64>() as f64 / total as f64
+    }
+
+    fn endpoint_deltas(&self, other: &Self) -> Vec<(&str, EndpointCounts)> {
+        self.endpoints
+            .iter()
+            .enumerate()
+            .map(|(index, (endpoint, counts))| {
+                let other = other
+                    .endpoints
+                    .get(index)
+                    .map(|(_, other)| *other)
+                    .unwrap_or_default();
+                (endpoint.as_str(), counts.delta(&other))
+            })
+            .collect()
+    }
+
+    fn rate(&self, other: &Self, counter: &str) -> f64 {
         let delta = self.delta_count(other, counter) as f64;
         let time = (self.timestamp - other.timestamp).as_secs_f64();
         delta / time
     }
 
-    fn success_rate(&self, other: &Self, total: &'static str, error: &'static str) -> f64 {
+    fn success_rate(&self, other: &Self, total: &str, error: &str) -> f64 {
         let total = self.rate(other, total);
         let error = self.rate(other, error);
         if total > 0.0 {
@@ -487,7 +2656,7 @@ impl Snapshot {
         }
     }
 
-    fn hitrate(&self, other: &Self, total: &'static str, hit: &'static str) -> f64 {
+    fn hitrate(&self, other: &Self, total: &str, hit: &str) -> f64 {
         let total = self.rate(other, total);
         let hit = self.rate(other, hit);
         if total > 0.0 {
@@ -497,6 +2666,16 @@ impl Snapshot {
         }
     }
 
+    /// The average number of requests carried by each write to a session
+    fn requests_per_write(&self, other: &Self) -> f64 {
+        let writes = self.delta_count(other, SESSION_SEND.name());
+        if writes > 0 {
+            self.delta_count(other, REQUEST.name()) as f64 / writes as f64
+        } else {
+            0.0
+        }
+    }
+
     pub fn human(&self) -> String {
         let mut data = Vec::new();
         for (counter, entry) in &self.counters {
@@ -541,6 +2720,91 @@ impl Snapshot {
         content
     }
 
+    /// Ops and bytes per command, both for the interval since the previous
+    /// snapshot and in total, so that pollers don't need to keep the previous
+    /// values to work out the deltas themselves.
+    pub fn throughput(&self, previous: &Self) -> String {
+        #[derive(Serialize, Default)]
+        struct Counts {
+            requests: u64,
+            responses: u64,
+            request_bytes: u64,
+            response_bytes: u64,
+        }
+
+        impl Counts {
+            fn add(&mut self, other: &Self) {
+                self.requests += other.requests;
+                self.responses += other.responses;
+                self.request_bytes += other.request_bytes;
+                self.response_bytes += other.response_bytes;
+            }
+        }
+
+        #[derive(Serialize, Default)]
+        struct Throughput {
+            interval: Counts,
+            cumulative: Counts,
+        }
+
+        #[derive(Serialize)]
+        struct JsonThroughput {
+            start: String,
+            end: String,
+            interval: f64,
+            total: Throughput,
+            commands: BTreeMap<&'static str, Throughput>,
+        }
+
+        let value = |snapshot: &Self, kind: &str, verb: Verb| {
+            snapshot
+                .counters
+                .get(&Self::command_name(kind, verb))
+                .map(|v| v.value)
+                .unwrap_or(0)
+        };
+        let counts = |snapshot: &Self, verb: Verb| Counts {
+            requests: value(snapshot, "request", verb),
+            responses: value(snapshot, "response", verb),
+            request_bytes: value(snapshot, "request_bytes", verb),
+            response_bytes: value(snapshot, "response_bytes", verb),
+        };
+
+        let mut total = Throughput::default();
+        let mut commands = BTreeMap::new();
+        for verb in Verb::iter() {
+            let cumulative = counts(self, verb);
+            if cumulative.requests == 0 && cumulative.responses == 0 {
+                continue;
+            }
+            let before = counts(previous, verb);
+            let interval = Counts {
+                requests: cumulative.requests - before.requests,
+                responses: cumulative.responses - before.responses,
+                request_bytes: cumulative.request_bytes - before.request_bytes,
+                response_bytes: cumulative.response_bytes - before.response_bytes,
+            };
+            total.interval.add(&interval);
+            total.cumulative.add(&cumulative);
+            commands.insert(
+                verb.into(),
+                Throughput {
+                    interval,
+                    cumulative,
+                },
+            );
+        }
+
+        let throughput = JsonThroughput {
+            start: previous.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            end: self.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            interval: (self.timestamp - previous.timestamp).as_secs_f64(),
+            total,
+            commands,
+        };
+        serde_json::to_string(&throughput).unwrap()
+    }
+
     pub fn prometheus(&self) -> String {
         let mut data = Vec::new();
         for (counter, entry) in &self.counters {
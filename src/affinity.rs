@@ -0,0 +1,42 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Tracks the requests sent to each shard when keys have connection affinity
+/// so that the skew of the key distribution across connections is visible.
+pub struct Affinity {
+    requests: Vec<AtomicU64>,
+}
+
+impl Affinity {
+    pub fn new(shards: usize) -> Self {
+        Self {
+            requests: (0..shards).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn shards(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn increment(&self, shard: usize) {
+        self.requests[shard].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The requests sent to the busiest shard as a percentage of the mean. An
+    /// even distribution across all shards results in a value of 100.
+    pub fn skew(&self) -> u64 {
+        let requests: Vec<u64> = self
+            .requests
+            .iter()
+            .map(|r| r.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = requests.iter().sum();
+        let max = requests.iter().max().copied().unwrap_or(0);
+        (max * 100 * requests.len() as u64)
+            .checked_div(total)
+            .unwrap_or(0)
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Checks made on every http response, so that a server which answers
+//! quickly but wrongly shows up during a load test instead of only in a
+//! separate functional suite. Each assertion counts its own failures.
+
+use crate::config_file::HttpAssertion;
+use regex::bytes::Regex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The assertions made on every response, with their failure counts
+#[derive(Default)]
+pub struct Assertions {
+    assertions: Vec<Assertion>,
+}
+
+struct Assertion {
+    name: String,
+    check: Check,
+    failures: AtomicU64,
+}
+
+enum Check {
+    /// the status is one of these
+    Status(Vec<u16>),
+    /// a header with this name is present
+    Header(String),
+    BodyContains(Vec<u8>),
+    BodyRegex(Regex),
+    /// the json body has this value at the pointer
+    JsonPointer(String, serde_json::Value),
+}
+
+impl Check {
+    fn new(assertion: &HttpAssertion) -> Result<Self, String> {
+        let mut checks = Vec::new();
+        if let Some(status) = assertion.status() {
+            checks.push(Self::Status(status.to_vec()));
+        }
+        if let Some(header) = assertion.header() {
+            checks.push(Self::Header(header.to_string()));
+        }
+        if let Some(contains) = assertion.body_contains() {
+            checks.push(Self::BodyContains(contains.as_bytes().to_vec()));
+        }
+        if let Some(regex) = assertion.body_regex() {
+            let regex = Regex::new(regex).map_err(|e| format!("bad body_regex: {e}"))?;
+            checks.push(Self::BodyRegex(regex));
+        }
+        if let Some(pointer) = assertion.json_pointer() {
+            if !pointer.is_empty() && !pointer.starts_with('/') {
+                return Err(format!("json pointer must start with '/': {pointer}"));
+            }
+            let equals = assertion
+                .equals()
+                .ok_or("a json pointer needs a value it equals")?;
+            let equals = serde_json::to_value(equals).map_err(|e| e.to_string())?;
+            checks.push(Self::JsonPointer(pointer.to_string(), equals));
+        } else if assertion.equals().is_some() {
+            return Err("equals is only used with a json pointer".to_string());
+        }
+        if checks.len() != 1 {
+            return Err("each assertion needs exactly one check".to_string());
+        }
+        Ok(checks.remove(0))
+    }
+
+    fn passes(&self, status: u16, headers: &[(&str, &str)], body: &[u8]) -> bool {
+        match self {
+            Self::Status(statuses) => statuses.contains(&status),
+            Self::Header(header) => headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case(header)),
+            Self::BodyContains(contains) => {
+                contains.is_empty() || body.windows(contains.len()).any(|w| w == contains)
+            }
+            Self::BodyRegex(regex) => regex.is_match(body),
+            Self::JsonPointer(pointer, equals) => {
+                serde_json::from_slice::<serde_json::Value>(body)
+                    .ok()
+                    .and_then(|json| json.pointer(pointer).cloned())
+                    .as_ref()
+                    == Some(equals)
+            }
+        }
+    }
+}
+
+impl Assertions {
+    pub fn new(assertions: &[HttpAssertion]) -> Result<Self, String> {
+        let mut names: Vec<&str> = Vec::new();
+        let mut checked = Vec::new();
+        for assertion in assertions {
+            let name = assertion.name();
+            if name.is_empty() || names.contains(&name) {
+                return Err(format!("each assertion needs a unique name: {name:?}"));
+            }
+            names.push(name);
+            let check = Check::new(assertion).map_err(|e| format!("assertion {name}: {e}"))?;
+            checked.push(Assertion {
+                name: name.to_string(),
+                check,
+                failures: AtomicU64::new(0),
+            });
+        }
+        Ok(Self {
+            assertions: checked,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.assertions.is_empty()
+    }
+
+    /// Returns true if any assertion looks at the body, so that a chunked body
+    /// has to be reassembled
+    pub fn needs_body(&self) -> bool {
+        self.assertions
+            .iter()
+            .any(|assertion| !matches!(assertion.check, Check::Status(_) | Check::Header(_)))
+    }
+
+    /// Make every assertion on the response, counting those which fail.
+    /// Returns false if any failed.
+    pub fn check(&self, status: u16, headers: &[(&str, &str)], body: &[u8]) -> bool {
+        let mut passed = true;
+        for assertion in &self.assertions {
+            if !assertion.check.passes(status, headers, body) {
+                assertion.failures.fetch_add(1, Ordering::Relaxed);
+                passed = false;
+            }
+        }
+        passed
+    }
+
+    /// The name of each assertion with the number of responses which failed it
+    pub fn failures(&self) -> Vec<(String, u64)> {
+        self.assertions
+            .iter()
+            .map(|assertion| {
+                (
+                    assertion.name.clone(),
+                    assertion.failures.load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assertions(toml: &str) -> Result<Assertions, String> {
+        #[derive(serde_derive::Deserialize)]
+        struct File {
+            assertions: Vec<HttpAssertion>,
+        }
+        let file: File = toml::from_str(toml).unwrap();
+        Assertions::new(&file.assertions)
+    }
+
+    #[test]
+    fn check() {
+        let assertions = assertions(
+            r#"
+            assertions = [
+                { name = "ok", status = [200, 404] },
+                { name = "typed", header = "content-type" },
+                { name = "contains", body_contains = "ready" },
+                { name = "regex", body_regex = "id\":\\s*\\d+" },
+                { name = "pointer", json_pointer = "/state/ready", equals = true },
+            ]
+            "#,
+        )
+        .unwrap();
+        let headers = [("Content-Type", "application/json")];
+        let body = br#"{"id": 7, "state": {"ready": true}}"#;
+        assert!(assertions.check(200, &headers, body));
+
+        assert!(!assertions.check(500, &[], br#"{"id": "x", "state": {"done": 1}}"#));
+        let failures = assertions.failures();
+        assert_eq!(failures.len(), 5);
+        assert!(failures.iter().all(|(_, count)| *count == 1));
+        assert_eq!(failures[0].0, "ok");
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(assertions(r#"assertions = [{ name = "none" }]"#).is_err());
+        assert!(
+            assertions(r#"assertions = [{ name = "two", status = [200], header = "a" }]"#).is_err()
+        );
+        assert!(assertions(r#"assertions = [{ name = "p", json_pointer = "/a" }]"#).is_err());
+        assert!(assertions(r#"assertions = [{ name = "e", header = "a", equals = 1 }]"#).is_err());
+        assert!(assertions(r#"assertions = [{ name = "r", body_regex = "(" }]"#).is_err());
+        assert!(assertions(
+            r#"assertions = [{ name = "a", header = "a" }, { name = "a", header = "b" }]"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Compares the command summary of a run against the summary of an earlier,
+//! known good run, so that a single run can check for regressions. The
+//! summary is the line of JSON written at the end of a run with the json
+//! output format or a json output file.
+
+use crate::config_file;
+use serde_derive::Deserialize;
+
+/// A command's results from the summary of a run
+#[derive(Deserialize)]
+struct Command {
+    command: String,
+    rate: f64,
+    success: f64,
+    p50: u64,
+    p99: u64,
+    p999: u64,
+}
+
+#[derive(Deserialize)]
+struct Summary {
+    commands: Vec<Command>,
+}
+
+#[derive(Deserialize)]
+struct Artifact {
+    summary: Summary,
+}
+
+/// The commands of the last summary in the content, which may have a line of
+/// JSON for each window before it
+fn parse(content: &str) -> Option<Vec<Command>> {
+    content
+        .lines()
+        .rev()
+        .find_map(|line| serde_json::from_str::<Artifact>(line).ok())
+        .map(|artifact| artifact.summary.commands)
+}
+
+/// The outcome of comparing one metric of a command against the baseline
+pub struct Check {
+    pub command: String,
+    pub metric: &'static str,
+    pub baseline: f64,
+    /// The value from this run, none if the command wasn't in its summary
+    pub current: Option<f64>,
+    pub pass: bool,
+}
+
+impl Check {
+    /// The change from the baseline as a percentage of it
+    pub fn change(&self) -> Option<f64> {
+        self.current
+            .filter(|_| self.baseline != 0.0)
+            .map(|current| 100.0 * (current - self.baseline) / self.baseline)
+    }
+}
+
+pub struct Baseline {
+    file: String,
+    commands: Vec<Command>,
+    tolerance: config_file::Baseline,
+}
+
+impl Baseline {
+    /// Load the last command summary from the file
+    pub fn load(file: &str, tolerance: config_file::Baseline) -> Result<Self, String> {
+        let content = std::fs::read_to_string(file).map_err(|e| format!("{file}: {e}"))?;
+        let commands = parse(&content).ok_or_else(|| format!("{file}: no run summary found"))?;
+        if commands.is_empty() {
+            return Err(format!("{file}: the run summary has no commands"));
+        }
+        Ok(Self {
+            file: file.to_string(),
+            commands,
+            tolerance,
+        })
+    }
+
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    /// Check each command of the baseline against the summary of this run.
+    /// Latencies may be higher and the rate lower by their tolerance, as a
+    /// percentage, and the success rate lower by its tolerance in percentage
+    /// points. A command which is missing from this run fails, while a
+    /// summary which can't be read is an error.
+    pub fn compare(&self, summary: &str) -> Result<Vec<Check>, String> {
+        let current = parse(summary).ok_or("the run summary can't be read")?;
+        let latency = 1.0 + self.tolerance.latency() / 100.0;
+        let throughput = 1.0 - self.tolerance.throughput() / 100.0;
+
+        let mut checks = Vec::new();
+        for baseline in &self.commands {
+            let command = current.iter().find(|c| c.command == baseline.command);
+            let metrics: [(&'static str, f64, Option<f64>, f64); 5] = [
+                (
+                    "p50",
+                    baseline.p50 as f64,
+                    command.map(|c| c.p50 as f64),
+                    baseline.p50 as f64 * latency,
+                ),
+                (
+                    "p99",
+                    baseline.p99 as f64,
+                    command.map(|c| c.p99 as f64),
+                    baseline.p99 as f64 * latency,
+                ),
+                (
+                    "p999",
+                    baseline.p999 as f64,
+                    command.map(|c| c.p999 as f64),
+                    baseline.p999 as f64 * latency,
+                ),
+                (
+                    "rate",
+                    baseline.rate,
+                    command.map(|c| c.rate),
+                    baseline.rate * throughput,
+                ),
+                (
+                    "success",
+                    baseline.success,
+                    command.map(|c| c.success),
+                    baseline.success - self.tolerance.success(),
+                ),
+            ];
+            for (metric, value, current, limit) in metrics {
+                // latencies are limited from above, the rates from below
+                let pass = match current {
+                    Some(current) if metric.starts_with('p') => current <= limit,
+                    Some(current) => current >= limit,
+                    None => false,
+                };
+                checks.push(Check {
+                    command: baseline.command.clone(),
+                    metric,
+                    baseline: value,
+                    current,
+                    pass,
+                });
+            }
+        }
+        Ok(checks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(commands: &str) -> String {
+        format!(
+            "{{\"window\": 0}}\n{{\"summary\": {{\"windows\": 5, \"duration\": 300.0, \"commands\": [{commands}]}}}}\n"
+        )
+    }
+
+    fn baseline(commands: &str) -> Baseline {
+        let tolerance: config_file::Baseline = toml::from_str("latency = 10.0").unwrap();
+        Baseline {
+            file: "baseline.json".to_string(),
+            commands: parse(&summary(commands)).unwrap(),
+            tolerance,
+        }
+    }
+
+    const GET: &str = r#"{"command": "get", "count": 1000, "rate": 1000.0, "success": 100.0, "hitrate": 90.0, "p50": 100, "p99": 1000, "p999": 2000, "max": 5000}"#;
+
+    #[test]
+    fn pass() {
+        let baseline = baseline(GET);
+        let checks = baseline
+            .compare(&summary(
+                r#"{"command": "get", "count": 950, "rate": 950.0, "success": 99.5, "hitrate": 90.0, "p50": 105, "p99": 1100, "p999": 1500, "max": 9000}"#,
+            ))
+            .unwrap();
+        assert_eq!(checks.len(), 5);
+        assert!(checks.iter().all(|check| check.pass));
+        assert_eq!(checks[1].change(), Some(10.0));
+    }
+
+    #[test]
+    fn regression() {
+        let baseline = baseline(GET);
+        let checks = baseline
+            .compare(&summary(
+                r#"{"command": "get", "count": 800, "rate": 800.0, "success": 98.0, "hitrate": 90.0, "p50": 100, "p99": 1200, "p999": 2000, "max": 5000}"#,
+            ))
+            .unwrap();
+        let failed: Vec<&str> = checks
+            .iter()
+            .filter(|check| !check.pass)
+            .map(|check| check.metric)
+            .collect();
+        assert_eq!(failed, ["p99", "rate", "success"]);
+    }
+
+    #[test]
+    fn missing() {
+        let baseline = baseline(GET);
+        let checks = baseline.compare(&summary("")).unwrap();
+        assert!(checks
+            .iter()
+            .all(|check| !check.pass && check.current.is_none()));
+        assert!(parse("{\"window\": 0}\n").is_none());
+        assert!(baseline.compare("{\"window\": 0}\n").is_err());
+    }
+}
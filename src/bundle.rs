@@ -0,0 +1,91 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Collects the outputs of a run into a single gzip compressed tarball so that
+//! results can easily be gathered from many load generator hosts.
+
+use crate::Config;
+use chrono::{DateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Writes the bundle for a run which started at the provided time into the
+/// directory. The resulting archive is named
+/// `rpc-perf-<run id>-<timestamp>.tar.gz` and contains the config file, the
+/// JSON summary of the run, and the waterfall, per-window JSON output, and
+/// debug log if configured. If the run was stopped early, the reason is
+/// written to `abort.txt`.
+pub fn write(
+    config: &Config,
+    directory: &Path,
+    started: DateTime<Utc>,
+    summary: &str,
+    abort: Option<&str>,
+) -> Result<PathBuf, std::io::Error> {
+    let run_id = config.run_id();
+    let name = format!(
+        "{}-{}-{}",
+        crate::config::NAME,
+        run_id,
+        started.format("%Y%m%dT%H%M%SZ")
+    );
+    let path = directory.join(format!("{name}.tar.gz"));
+
+    let file = File::create(&path)?;
+    let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+    let prefix = Path::new(&name);
+
+    archive.append_path_with_name(config.file(), prefix.join(file_name(config.file())))?;
+
+    append_data(&mut archive, &prefix.join("summary.json"), summary)?;
+
+    if let Some(reason) = abort {
+        append_data(
+            &mut archive,
+            &prefix.join("abort.txt"),
+            &format!("{reason}\n"),
+        )?;
+    }
+
+    let optional = [
+        config.waterfall().file(),
+        config.general().json_output(),
+        config.debug().log_file(),
+    ];
+    let commands = config
+        .verbs()
+        .into_iter()
+        .filter_map(|verb| config.waterfall().command_file(verb));
+    for file in optional.into_iter().flatten().chain(commands) {
+        if Path::new(&file).exists() {
+            archive.append_path_with_name(&file, prefix.join(file_name(&file)))?;
+        }
+    }
+
+    archive.into_inner()?.finish()?;
+
+    Ok(path)
+}
+
+fn append_data<W: std::io::Write>(
+    archive: &mut tar::Builder<W>,
+    path: &Path,
+    data: &str,
+) -> Result<(), std::io::Error> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    header.set_cksum();
+    archive.append_data(&mut header, path, data.as_bytes())
+}
+
+fn file_name(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path)
+}
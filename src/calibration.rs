@@ -0,0 +1,124 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Measures the latency of a reference server built into rpc-perf, which
+//! answers over loopback without doing any work. Its latency is the floor the
+//! load generator's host can measure, so pauses on the host, such as CPU
+//! throttling or a noisy neighbor, show up here as well as in the latency of
+//! the target. The time each probe wakes up past when it was due is recorded
+//! too, which shows the host pausing the load generator. Probes are timed with
+//! the standard library's clock, the heatmaps are stamped with the crate's.
+
+use crate::metrics::*;
+use crate::*;
+use std::io::{Error, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+/// The size of each probe, which the server echoes back
+const PROBE_LEN: usize = 8;
+
+pub struct Calibration {
+    interval: Duration,
+    listener: TcpListener,
+    loopback: Arc<Latency>,
+    wakeup: Arc<Latency>,
+}
+
+impl Calibration {
+    /// Bind the reference server to an ephemeral port on loopback
+    pub fn new(rate: u64, loopback: Arc<Latency>, wakeup: Arc<Latency>) -> Result<Self, Error> {
+        Ok(Self {
+            interval: Duration::from_nanos(1_000_000_000 / rate),
+            listener: TcpListener::bind("127.0.0.1:0")?,
+            loopback,
+            wakeup,
+        })
+    }
+
+    /// Serve the reference server on its own thread and send probes to it at
+    /// the configured rate. A failed probe reconnects.
+    pub fn run(self) {
+        let addr = match self.listener.local_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("calibration server has no address: {}", e);
+                return;
+            }
+        };
+        let listener = self.listener;
+        std::thread::spawn(move || serve(listener));
+
+        let mut stream = None;
+        let mut next = Instant::now();
+        loop {
+            next += self.interval;
+            let now = Instant::now();
+            if next > now {
+                std::thread::sleep(next - now);
+            }
+            let woke = Instant::now();
+            let delay = woke.saturating_duration_since(next);
+            self.wakeup
+                .increment(crate::Instant::now(), delay.as_nanos() as u64 / 1_000, 1);
+            // probes which fell behind are skipped rather than sent in a burst
+            if delay > self.interval {
+                next = woke;
+            }
+
+            if stream.is_none() {
+                stream = TcpStream::connect(addr)
+                    .and_then(|stream| stream.set_nodelay(true).map(|_| stream))
+                    .ok();
+            }
+            let result = match stream.as_mut() {
+                Some(stream) => probe(stream),
+                None => Err(Error::from(std::io::ErrorKind::NotConnected)),
+            };
+            match result {
+                Ok(latency) => {
+                    CALIBRATION_PROBE.increment();
+                    self.loopback.increment(
+                        crate::Instant::now(),
+                        latency.as_nanos() as u64 / 1_000,
+                        1,
+                    );
+                }
+                Err(e) => {
+                    CALIBRATION_PROBE_EX.increment();
+                    debug!("calibration probe failed: {}", e);
+                    stream = None;
+                }
+            }
+        }
+    }
+}
+
+/// Send a probe and wait for it to be echoed
+fn probe(stream: &mut TcpStream) -> Result<Duration, Error> {
+    let mut buf = [0; PROBE_LEN];
+    let start = Instant::now();
+    stream.write_all(&buf)?;
+    stream.read_exact(&mut buf)?;
+    Ok(start.elapsed())
+}
+
+/// Echo every probe, with a thread for each connection
+fn serve(listener: TcpListener) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let _ = stream.set_nodelay(true);
+        std::thread::spawn(move || {
+            let mut buf = [0; PROBE_LEN];
+            while stream.read_exact(&mut buf).is_ok() {
+                if stream.write_all(&buf).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
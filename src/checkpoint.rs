@@ -0,0 +1,92 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Saves the position of the workload so that a restarted run can resume
+//! where it left off rather than starting over. A checkpoint holds the number
+//! of windows which completed, how far the prefill got, and the versions
+//! written to each key when values are mutated. Statistics are not saved, so
+//! the results of a resumed run only cover the windows after it resumed.
+
+use crate::prefill::Prefill;
+use crate::Config;
+use serde_derive::{Deserialize, Serialize};
+use std::path::Path;
+
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// the windows which completed before the checkpoint
+    windows: u64,
+    /// the keys the prefill has stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prefill: Option<usize>,
+    /// the versions of each key in the keyspaces which mutate values
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    versions: Vec<KeyspaceVersions>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyspaceVersions {
+    keyspace: usize,
+    written: Vec<u32>,
+    acknowledged: Vec<u32>,
+}
+
+impl Checkpoint {
+    /// The state of the workload once the windows have completed
+    pub fn capture(config: &Config, windows: u64, prefill: Option<&Prefill>) -> Self {
+        let versions = config
+            .keyspaces()
+            .iter()
+            .filter_map(|keyspace| {
+                keyspace
+                    .versions()
+                    .map(|(written, acknowledged)| KeyspaceVersions {
+                        keyspace: keyspace.index(),
+                        written,
+                        acknowledged,
+                    })
+            })
+            .collect();
+        Self {
+            windows,
+            prefill: prefill.map(|prefill| prefill.stored()),
+            versions,
+        }
+    }
+
+    /// The windows which completed before the checkpoint
+    pub fn windows(&self) -> u64 {
+        self.windows
+    }
+
+    /// Write the checkpoint to a temporary file which is renamed over the
+    /// previous one, so a crash while writing leaves the previous intact
+    pub fn save(&self, file: &str) -> Result<(), std::io::Error> {
+        let temporary = format!("{file}.tmp");
+        std::fs::write(&temporary, serde_json::to_vec(self)?)?;
+        std::fs::rename(&temporary, file)
+    }
+
+    pub fn load(file: &str) -> Result<Self, String> {
+        let data = std::fs::read(Path::new(file))
+            .map_err(|e| format!("failed to read checkpoint: {file}: {e}"))?;
+        serde_json::from_slice(&data).map_err(|e| format!("bad checkpoint: {file}: {e}"))
+    }
+
+    /// Continue the workload from the checkpoint. The config must have the
+    /// same keyspaces as the run which wrote it.
+    pub fn restore(&self, config: &Config, prefill: Option<&Prefill>) -> Result<(), String> {
+        for versions in &self.versions {
+            let keyspace = config
+                .keyspaces()
+                .get(versions.keyspace)
+                .ok_or_else(|| format!("checkpoint has unknown keyspace {}", versions.keyspace))?;
+            keyspace.restore_versions(&versions.written, &versions.acknowledged)?;
+        }
+        if let (Some(prefill), Some(stored)) = (prefill, self.prefill) {
+            prefill.resume(stored);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,585 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+use crate::codec::*;
+use crate::config::*;
+use crate::config_file::Verb;
+use crate::*;
+
+use boring::hash::{hash, MessageDigest};
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+
+use std::io::{BufRead, Write};
+
+const VERSION: u8 = 0x04;
+const RESPONSE: u8 = 0x80;
+const HEADER_LEN: usize = 9;
+
+const OPCODE_ERROR: u8 = 0x00;
+const OPCODE_STARTUP: u8 = 0x01;
+const OPCODE_READY: u8 = 0x02;
+const OPCODE_AUTHENTICATE: u8 = 0x03;
+const OPCODE_RESULT: u8 = 0x08;
+const OPCODE_PREPARE: u8 = 0x09;
+const OPCODE_EXECUTE: u8 = 0x0A;
+
+const RESULT_ROWS: i32 = 0x0002;
+const RESULT_PREPARED: i32 = 0x0004;
+
+const ERROR_UNAVAILABLE: i32 = 0x1000;
+const ERROR_OVERLOADED: i32 = 0x1001;
+const ERROR_UNPREPARED: i32 = 0x2500;
+
+const FLAG_VALUES: u8 = 0x01;
+const FLAG_SKIP_METADATA: u8 = 0x02;
+
+const ROWS_GLOBAL_TABLES_SPEC: i32 = 0x0001;
+const ROWS_HAS_MORE_PAGES: i32 = 0x0002;
+const ROWS_NO_METADATA: i32 = 0x0004;
+
+/// Requests use one stream, and each frame which sets up the connection uses
+/// one of its own, so that their responses can be told apart. The statements
+/// are prepared on consecutive streams from the first prepare stream.
+const STREAM_REQUEST: i16 = 0;
+const STREAM_STARTUP: i16 = 1;
+const STREAM_PREPARE: i16 = 2;
+
+/// Maps get, set, and delete onto prepared statements against a table of
+/// blob keys and values, using version 4 of the native protocol. Each
+/// connection is set up with a startup frame, and once the server is ready,
+/// a prepare frame for each statement. Requests execute the prepared
+/// statements once they have all been prepared.
+pub struct Cql {
+    config: Arc<Config>,
+    rng: SmallRng,
+    consistency: u16,
+    select: Statement,
+    insert: Statement,
+    delete: Statement,
+}
+
+struct Statement {
+    query: String,
+    /// Servers identify a statement prepared outside of any keyspace by the
+    /// MD5 digest of its text, so the id is known before it is prepared
+    id: Vec<u8>,
+}
+
+impl Statement {
+    fn new(query: String) -> Self {
+        let id = hash(MessageDigest::md5(), query.as_bytes())
+            .expect("md5 failure")
+            .to_vec();
+        Self { query, id }
+    }
+}
+
+impl Cql {
+    pub fn new(config: Arc<Config>) -> Self {
+        let cql = config.protocol().cql();
+        let (table, key, value) = (cql.table(), cql.key_column(), cql.value_column());
+        Self {
+            config,
+            rng: SmallRng::from_entropy(),
+            consistency: cql.consistency().code(),
+            select: Statement::new(format!("SELECT {value} FROM {table} WHERE {key} = ?")),
+            insert: Statement::new(format!(
+                "INSERT INTO {table} ({key}, {value}) VALUES (?, ?) USING TTL ?"
+            )),
+            delete: Statement::new(format!("DELETE FROM {table} WHERE {key} = ?")),
+        }
+    }
+
+    fn statements(&self) -> [&Statement; 3] {
+        [&self.select, &self.insert, &self.delete]
+    }
+
+    /// Send the prepared statement with the bound values
+    fn request(&self, buf: &mut Session, statement: &Statement, values: &[&[u8]]) {
+        let mut body = Vec::new();
+        body.extend_from_slice(&(statement.id.len() as u16).to_be_bytes());
+        body.extend_from_slice(&statement.id);
+        body.extend_from_slice(&self.consistency.to_be_bytes());
+        body.push(FLAG_VALUES | FLAG_SKIP_METADATA);
+        body.extend_from_slice(&(values.len() as u16).to_be_bytes());
+        for value in values {
+            body.extend_from_slice(&(value.len() as i32).to_be_bytes());
+            body.extend_from_slice(value);
+        }
+        let _ = buf.write_all(&frame(STREAM_REQUEST, OPCODE_EXECUTE, &body));
+    }
+
+    /// Handle the responses which set up the connection. Once the server is
+    /// ready, each statement is prepared. The setup is complete once every
+    /// statement was prepared with the id it was expected to have.
+    fn decode_setup(&self, buffer: &mut Session) -> Result<(), ParseError> {
+        let buf: &[u8] = (*buffer).buffer();
+        let (stream, opcode, body, end) = next_frame(buf)?;
+        if stream == STREAM_STARTUP {
+            match opcode {
+                OPCODE_READY => {}
+                OPCODE_AUTHENTICATE => {
+                    debug!("cql server requires authentication, which is not supported");
+                    return Err(ParseError::Error);
+                }
+                _ => {
+                    debug!("cql connection startup failed: {}", error_message(body));
+                    return Err(ParseError::Error);
+                }
+            }
+            buffer.consume(end);
+            for (statement, stream) in self.statements().iter().zip(STREAM_PREPARE..) {
+                let mut body = Vec::new();
+                write_long_string(&mut body, &statement.query);
+                let _ = buffer.write_all(&frame(stream, OPCODE_PREPARE, &body));
+            }
+            return Ok(());
+        }
+
+        // the statements may be prepared in any order, the responses are
+        // consumed together once they have all arrived
+        let mut position = 0;
+        for _ in self.statements() {
+            let (stream, opcode, body, len) = next_frame(&buf[position..])?;
+            position += len;
+            let statement = match self
+                .statements()
+                .into_iter()
+                .zip(STREAM_PREPARE..)
+                .find(|(_, prepare)| *prepare == stream)
+            {
+                Some((statement, _)) => statement,
+                None => return Err(ParseError::Unknown),
+            };
+            if opcode != OPCODE_RESULT {
+                debug!("cql prepare failed: {}", error_message(body));
+                return Err(ParseError::Error);
+            }
+            let mut reader = Reader::new(body);
+            if reader.int()? != RESULT_PREPARED {
+                return Err(ParseError::Unknown);
+            }
+            let len = reader.short()? as usize;
+            if reader.bytes(len)? != statement.id {
+                debug!("cql statement was prepared with an unexpected id");
+                return Err(ParseError::Error);
+            }
+        }
+        buffer.consume(position);
+        Ok(())
+    }
+}
+
+impl Codec for Cql {
+    fn setup(&mut self, buf: &mut Session) -> bool {
+        let mut body = Vec::new();
+        body.extend_from_slice(&1u16.to_be_bytes());
+        write_string(&mut body, "CQL_VERSION");
+        write_string(&mut body, "3.0.0");
+        let _ = buf.write_all(&frame(STREAM_STARTUP, OPCODE_STARTUP, &body));
+        true
+    }
+
+    fn encode(&mut self, buf: &mut Session) -> (Verb, Outcome) {
+        let mut outcome = Outcome::default();
+        let keyspace = self.config.choose_keyspace(&mut self.rng);
+        let verb = keyspace.choose_command(&mut self.rng, buf).verb();
+        let key = keyspace.generate_key(&mut self.rng, buf.shard(), &mut outcome);
+        match verb {
+            Verb::Get => {
+                metrics::REQUEST_GET.increment();
+                self.request(buf, &self.select, &[&key]);
+            }
+            Verb::Set => {
+                let value = keyspace.generate_value(&mut self.rng).unwrap_or_default();
+                let ttl = (keyspace.ttl() as i32).to_be_bytes();
+                self.request(buf, &self.insert, &[&key, &value, &ttl]);
+            }
+            Verb::Delete => {
+                self.request(buf, &self.delete, &[&key]);
+            }
+            _ => {
+                unimplemented!()
+            }
+        }
+        (verb, outcome)
+    }
+
+    fn decode(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        if buffer.is_setting_up() {
+            return self.decode_setup(buffer).map(|_| Outcome::default());
+        }
+
+        let mut outcome = Outcome::default();
+        loop {
+            // no-copy borrow as a slice
+            let buf: &[u8] = (*buffer).buffer();
+            let (stream, opcode, body, end) = next_frame(buf)?;
+            if stream != STREAM_REQUEST {
+                // events are only sent to connections which register for them
+                buffer.consume(end);
+                continue;
+            }
+
+            match opcode {
+                OPCODE_RESULT => {
+                    if buffer.command() == Some(Verb::Get) && rows(body)? > 0 {
+                        outcome.hit();
+                    }
+                }
+                OPCODE_ERROR => match Reader::new(body).int()? {
+                    // the coordinator is overloaded or too few replicas are up
+                    ERROR_OVERLOADED | ERROR_UNAVAILABLE => {
+                        metrics::RESPONSE_THROTTLED.increment();
+                    }
+                    // the server lost the prepared statements, such as when
+                    // it restarted, so the session is closed and prepares
+                    // them again when it reconnects
+                    ERROR_UNPREPARED => {
+                        debug!("cql statement is not prepared on the server");
+                        metrics::RESPONSE_EX.increment();
+                        return Err(ParseError::Error);
+                    }
+                    _ => {
+                        outcome.error();
+                    }
+                },
+                _ => return Err(ParseError::Unknown),
+            }
+            buffer.consume(end);
+            return Ok(outcome);
+        }
+    }
+}
+
+/// The stream, opcode, and body of the next frame, with the length of the
+/// whole frame
+fn next_frame(buf: &[u8]) -> Result<(i16, u8, &[u8], usize), ParseError> {
+    if buf.len() < HEADER_LEN {
+        return Err(ParseError::Incomplete);
+    }
+    if buf[0] != RESPONSE | VERSION {
+        return Err(ParseError::Unknown);
+    }
+    let stream = i16::from_be_bytes([buf[2], buf[3]]);
+    let opcode = buf[4];
+    let len = i32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]);
+    if len < 0 {
+        return Err(ParseError::Unknown);
+    }
+    let end = HEADER_LEN + len as usize;
+    if buf.len() < end {
+        return Err(ParseError::Incomplete);
+    }
+    Ok((stream, opcode, &buf[HEADER_LEN..end], end))
+}
+
+fn frame(stream: i16, opcode: u8, body: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+    frame.push(VERSION);
+    frame.push(0);
+    frame.extend_from_slice(&stream.to_be_bytes());
+    frame.push(opcode);
+    frame.extend_from_slice(&(body.len() as i32).to_be_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+fn write_string(buf: &mut Vec<u8>, value: &str) {
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn write_long_string(buf: &mut Vec<u8>, value: &str) {
+    buf.extend_from_slice(&(value.len() as i32).to_be_bytes());
+    buf.extend_from_slice(value.as_bytes());
+}
+
+/// The number of rows in a result, which is zero for results other than rows
+fn rows(body: &[u8]) -> Result<i32, ParseError> {
+    let mut reader = Reader::new(body);
+    if reader.int()? != RESULT_ROWS {
+        return Ok(0);
+    }
+    let flags = reader.int()?;
+    let columns = reader.int()?;
+    if flags & ROWS_HAS_MORE_PAGES != 0 {
+        let len = reader.int()?;
+        reader.bytes(len.max(0) as usize)?;
+    }
+    if flags & ROWS_NO_METADATA == 0 {
+        let global = flags & ROWS_GLOBAL_TABLES_SPEC != 0;
+        if global {
+            reader.string()?;
+            reader.string()?;
+        }
+        for _ in 0..columns {
+            if !global {
+                reader.string()?;
+                reader.string()?;
+            }
+            reader.string()?;
+            reader.option()?;
+        }
+    }
+    reader.int()
+}
+
+/// The message of an error response
+fn error_message(body: &[u8]) -> String {
+    let mut reader = Reader::new(body);
+    match (reader.int(), reader.string()) {
+        (Ok(code), Ok(message)) => format!("{code:#06x}: {}", String::from_utf8_lossy(message)),
+        _ => "bad error response".to_string(),
+    }
+}
+
+/// Reads the notation of the native protocol from a frame body
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ParseError> {
+        if self.buf.len() < len {
+            return Err(ParseError::Unknown);
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn short(&mut self) -> Result<u16, ParseError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn int(&mut self) -> Result<i32, ParseError> {
+        let bytes = self.bytes(4)?;
+        Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], ParseError> {
+        let len = self.short()? as usize;
+        self.bytes(len)
+    }
+
+    /// Skip over the type of a column
+    fn option(&mut self) -> Result<(), ParseError> {
+        match self.short()? {
+            // custom
+            0x0000 => {
+                self.string()?;
+            }
+            // list and set
+            0x0020 | 0x0022 => self.option()?,
+            // map
+            0x0021 => {
+                self.option()?;
+                self.option()?;
+            }
+            // user defined type
+            0x0030 => {
+                self.string()?;
+                self.string()?;
+                for _ in 0..self.short()? {
+                    self.string()?;
+                    self.option()?;
+                }
+            }
+            // tuple
+            0x0031 => {
+                for _ in 0..self.short()? {
+                    self.option()?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::tests::{config, receive, session};
+
+    fn response(stream: i16, opcode: u8, body: &[u8]) -> Vec<u8> {
+        let mut frame = frame(stream, opcode, body);
+        frame[0] = RESPONSE | VERSION;
+        frame
+    }
+
+    fn prepared(id: &[u8]) -> Vec<u8> {
+        let mut body = RESULT_PREPARED.to_be_bytes().to_vec();
+        body.extend_from_slice(&(id.len() as u16).to_be_bytes());
+        body.extend_from_slice(id);
+        body
+    }
+
+    fn error(code: i32) -> Vec<u8> {
+        let mut body = code.to_be_bytes().to_vec();
+        write_string(&mut body, "error");
+        body
+    }
+
+    #[test]
+    fn frames() {
+        let frame = response(STREAM_REQUEST, OPCODE_READY, b"body");
+        assert_eq!(
+            next_frame(&frame),
+            Ok((STREAM_REQUEST, OPCODE_READY, &b"body"[..], frame.len()))
+        );
+        assert_eq!(
+            next_frame(&frame[..HEADER_LEN - 1]),
+            Err(ParseError::Incomplete)
+        );
+        assert_eq!(
+            next_frame(&frame[..frame.len() - 1]),
+            Err(ParseError::Incomplete)
+        );
+
+        // a request frame, or a frame from another version of the protocol
+        let mut version = frame.clone();
+        version[0] = VERSION;
+        assert_eq!(next_frame(&version), Err(ParseError::Unknown));
+
+        let mut negative = frame;
+        negative[5..9].copy_from_slice(&(-1i32).to_be_bytes());
+        assert_eq!(next_frame(&negative), Err(ParseError::Unknown));
+    }
+
+    #[test]
+    fn rows_result() {
+        let void = 0x0001i32.to_be_bytes();
+        assert_eq!(rows(&void), Ok(0));
+
+        // the metadata is skipped when it was not requested
+        let mut body = RESULT_ROWS.to_be_bytes().to_vec();
+        body.extend_from_slice(&ROWS_NO_METADATA.to_be_bytes());
+        body.extend_from_slice(&1i32.to_be_bytes());
+        body.extend_from_slice(&1i32.to_be_bytes());
+        assert_eq!(rows(&body), Ok(1));
+
+        // global table spec, with a blob and a list of blobs
+        let mut body = RESULT_ROWS.to_be_bytes().to_vec();
+        body.extend_from_slice(&ROWS_GLOBAL_TABLES_SPEC.to_be_bytes());
+        body.extend_from_slice(&2i32.to_be_bytes());
+        write_string(&mut body, "keyspace");
+        write_string(&mut body, "table");
+        write_string(&mut body, "value");
+        body.extend_from_slice(&0x0003u16.to_be_bytes());
+        write_string(&mut body, "values");
+        body.extend_from_slice(&0x0020u16.to_be_bytes());
+        body.extend_from_slice(&0x0003u16.to_be_bytes());
+        body.extend_from_slice(&3i32.to_be_bytes());
+        assert_eq!(rows(&body), Ok(3));
+
+        // truncated
+        assert_eq!(rows(&body[..body.len() - 1]), Err(ParseError::Unknown));
+    }
+
+    #[test]
+    fn setup() {
+        let cql = Cql::new(config("cql.toml"));
+
+        // authentication is not supported
+        let mut authenticate = session();
+        authenticate.set_setting_up(true);
+        receive(
+            &mut authenticate,
+            &response(STREAM_STARTUP, OPCODE_AUTHENTICATE, &[]),
+        );
+        assert_eq!(cql.decode(&mut authenticate), Err(ParseError::Error));
+
+        let mut session = session();
+        session.set_setting_up(true);
+        receive(&mut session, &response(STREAM_STARTUP, OPCODE_READY, &[]));
+        assert_eq!(cql.decode(&mut session), Ok(Outcome::default()));
+        // each statement is prepared on its own stream
+        let mut unsent = session.unsent();
+        for (statement, stream) in cql.statements().iter().zip(STREAM_PREPARE..) {
+            let mut body = Vec::new();
+            write_long_string(&mut body, &statement.query);
+            let expected = frame(stream, OPCODE_PREPARE, &body);
+            assert!(unsent.starts_with(&expected));
+            unsent = &unsent[expected.len()..];
+        }
+        assert!(unsent.is_empty());
+        session.mark_sent(session.write_pending());
+
+        // the responses may arrive in any order
+        let ids: Vec<Vec<u8>> = cql.statements().iter().map(|s| s.id.clone()).collect();
+        receive(
+            &mut session,
+            &response(STREAM_PREPARE + 2, OPCODE_RESULT, &prepared(&ids[2])),
+        );
+        receive(
+            &mut session,
+            &response(STREAM_PREPARE, OPCODE_RESULT, &prepared(&ids[0])),
+        );
+        assert_eq!(cql.decode(&mut session), Err(ParseError::Incomplete));
+        receive(
+            &mut session,
+            &response(STREAM_PREPARE + 1, OPCODE_RESULT, &prepared(&ids[1])),
+        );
+        assert_eq!(cql.decode(&mut session), Ok(Outcome::default()));
+        assert_eq!(session.read_pending(), 0);
+        assert_eq!(session.write_pending(), 0);
+
+        // a statement prepared with another id
+        for (stream, id) in (STREAM_PREPARE..).zip([&ids[0], &ids[1], &ids[0]]) {
+            receive(
+                &mut session,
+                &response(stream, OPCODE_RESULT, &prepared(id)),
+            );
+        }
+        assert_eq!(cql.decode(&mut session), Err(ParseError::Error));
+    }
+
+    #[test]
+    fn decode() {
+        let cql = Cql::new(config("cql.toml"));
+        let mut session = session();
+
+        session.push_command(Verb::Get, false);
+        let mut body = RESULT_ROWS.to_be_bytes().to_vec();
+        body.extend_from_slice(&ROWS_NO_METADATA.to_be_bytes());
+        body.extend_from_slice(&1i32.to_be_bytes());
+        body.extend_from_slice(&1i32.to_be_bytes());
+        receive(
+            &mut session,
+            &response(STREAM_REQUEST, OPCODE_RESULT, &body),
+        );
+        assert_eq!(cql.decode(&mut session).map(|outcome| outcome.hits), Ok(1));
+        let _ = session.pop_command();
+
+        session.push_command(Verb::Set, false);
+        receive(
+            &mut session,
+            &response(STREAM_REQUEST, OPCODE_ERROR, &error(ERROR_OVERLOADED)),
+        );
+        assert_eq!(cql.decode(&mut session), Ok(Outcome::default()));
+        receive(
+            &mut session,
+            &response(STREAM_REQUEST, OPCODE_ERROR, &error(0x1100)),
+        );
+        assert_eq!(
+            cql.decode(&mut session).map(|outcome| outcome.errors),
+            Ok(1)
+        );
+
+        // the session is closed once the statements are not prepared
+        receive(
+            &mut session,
+            &response(STREAM_REQUEST, OPCODE_ERROR, &error(ERROR_UNPREPARED)),
+        );
+        assert_eq!(cql.decode(&mut session), Err(ParseError::Error));
+    }
+}
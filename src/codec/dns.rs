@@ -0,0 +1,241 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+use crate::codec::*;
+use crate::config::*;
+use crate::config_file::Verb;
+use crate::*;
+
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+use std::io::{BufRead, Write};
+
+const KEY: &[u8] = b"${key}";
+const HEADER_LEN: usize = 12;
+const MAX_LABEL: usize = 63;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+
+const RCODE_NOERROR: u16 = 0;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+
+const CLASS_IN: u16 = 1;
+const TYPE_OPT: u16 = 41;
+/// The udp payload size advertised with EDNS, which avoids fragmentation
+const EDNS_PAYLOAD: u16 = 1232;
+
+/// Queries the templated name for one of the configured record types. Each
+/// message is prefixed with its length as with DNS over TCP, which the udp
+/// transport strips from each datagram it sends and adds to each it receives.
+/// Responses are not matched to queries by their id, so only one query is
+/// outstanding on each socket at a time. A lost datagram closes the socket
+/// so its response can not be mistaken for a later one.
+pub struct Dns {
+    config: Arc<Config>,
+    rng: SmallRng,
+    id: u16,
+    name: Vec<u8>,
+    types: Vec<u16>,
+    flags: u16,
+}
+
+impl Dns {
+    pub fn new(config: Arc<Config>) -> Self {
+        let dns = config.protocol().dns();
+        let flags = if dns.recursion_desired() {
+            FLAG_RECURSION_DESIRED
+        } else {
+            0
+        };
+        Self {
+            rng: SmallRng::from_entropy(),
+            id: 0,
+            name: dns.name().into_bytes(),
+            types: dns.types().expect("bad dns record types"),
+            flags,
+            config,
+        }
+    }
+
+    fn query(&mut self, buf: &mut Session, keyspace: &Keyspace, outcome: &mut Outcome) {
+        let key = keyspace.generate_key(&mut self.rng, buf.shard(), outcome);
+        let kind = self.types[self.rng.gen_range(0..self.types.len())];
+        self.id = self.id.wrapping_add(1);
+
+        let mut message = Vec::with_capacity(HEADER_LEN + self.name.len() + key.len() + 16);
+        message.extend_from_slice(&self.id.to_be_bytes());
+        message.extend_from_slice(&self.flags.to_be_bytes());
+        // one question, and the opt record in the additional section
+        for count in [1u16, 0, 0, 1] {
+            message.extend_from_slice(&count.to_be_bytes());
+        }
+
+        // labels which are too long are truncated rather than rejected, as
+        // keys may be longer than a label allows
+        for label in replace(&self.name, &key).split(|b| *b == b'.') {
+            if label.is_empty() {
+                continue;
+            }
+            let label = &label[..label.len().min(MAX_LABEL)];
+            message.push(label.len() as u8);
+            message.extend_from_slice(label);
+        }
+        message.push(0);
+        message.extend_from_slice(&kind.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+        // an opt record for the root name, advertising the payload size
+        message.push(0);
+        message.extend_from_slice(&TYPE_OPT.to_be_bytes());
+        message.extend_from_slice(&EDNS_PAYLOAD.to_be_bytes());
+        message.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+        let _ = buf.write_all(&(message.len() as u16).to_be_bytes());
+        let _ = buf.write_all(&message);
+    }
+}
+
+/// Replace each occurrence of the key template in the name
+fn replace(name: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(name.len() + key.len());
+    let mut rest = name;
+    while let Some(start) = rest.windows(KEY.len()).position(|w| w == KEY) {
+        out.extend_from_slice(&rest[..start]);
+        out.extend_from_slice(key);
+        rest = &rest[(start + KEY.len())..];
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+impl Codec for Dns {
+    fn encode(&mut self, buf: &mut Session) -> (Verb, Outcome) {
+        let mut outcome = Outcome::default();
+        let config = self.config.clone();
+        let keyspace = config.choose_keyspace(&mut self.rng);
+        let verb = keyspace.choose_command(&mut self.rng, buf).verb();
+        match verb {
+            Verb::Get => {
+                metrics::REQUEST_GET.increment();
+                self.query(buf, keyspace, &mut outcome);
+            }
+            _ => {
+                unimplemented!()
+            }
+        }
+        (verb, outcome)
+    }
+
+    fn decode(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        // no-copy borrow as a slice
+        let buf: &[u8] = (*buffer).buffer();
+        if buf.len() < 2 {
+            return Err(ParseError::Incomplete);
+        }
+        let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+        if buf.len() < 2 + len {
+            return Err(ParseError::Incomplete);
+        }
+        if len < HEADER_LEN {
+            return Err(ParseError::Unknown);
+        }
+
+        let flags = u16::from_be_bytes([buf[4], buf[5]]);
+        if flags & FLAG_RESPONSE == 0 {
+            return Err(ParseError::Unknown);
+        }
+        let mut outcome = Outcome::default();
+        let answers = u16::from_be_bytes([buf[8], buf[9]]);
+        if flags & FLAG_TRUNCATED != 0 {
+            metrics::RESPONSE_TRUNCATED.increment();
+        }
+        match flags & 0x000F {
+            RCODE_NOERROR => {
+                metrics::RESPONSE_NOERROR.increment();
+                if answers > 0 {
+                    outcome.hit();
+                }
+            }
+            RCODE_NXDOMAIN => {
+                metrics::RESPONSE_NXDOMAIN.increment();
+            }
+            RCODE_SERVFAIL => {
+                metrics::RESPONSE_SERVFAIL.increment();
+                outcome.error();
+            }
+            _ => {
+                outcome.error();
+            }
+        }
+
+        buffer.consume(2 + len);
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::tests::{config, receive, session};
+
+    /// A response with the flags and number of answers, prefixed with its
+    /// length
+    fn response(flags: u16, answers: u16) -> Vec<u8> {
+        let mut message = vec![0, 1];
+        message.extend_from_slice(&(FLAG_RESPONSE | flags).to_be_bytes());
+        for count in [1u16, answers, 0, 0] {
+            message.extend_from_slice(&count.to_be_bytes());
+        }
+        let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(&message);
+        framed
+    }
+
+    #[test]
+    fn decode() {
+        let dns = Dns::new(config("dns.toml"));
+        let mut session = session();
+
+        let noerror = response(RCODE_NOERROR, 1);
+        receive(&mut session, &noerror[..noerror.len() - 1]);
+        assert_eq!(dns.decode(&mut session), Err(ParseError::Incomplete));
+        receive(&mut session, &noerror[noerror.len() - 1..]);
+        assert_eq!(dns.decode(&mut session).map(|o| o.hits), Ok(1));
+        assert_eq!(session.read_pending(), 0);
+
+        // an empty answer is not a hit
+        receive(&mut session, &response(RCODE_NOERROR, 0));
+        assert_eq!(dns.decode(&mut session), Ok(Outcome::default()));
+
+        let nxdomain = RESPONSE_NXDOMAIN.value();
+        receive(&mut session, &response(RCODE_NXDOMAIN, 0));
+        assert_eq!(dns.decode(&mut session), Ok(Outcome::default()));
+        assert!(RESPONSE_NXDOMAIN.value() > nxdomain);
+
+        let servfail = RESPONSE_SERVFAIL.value();
+        receive(&mut session, &response(RCODE_SERVFAIL, 0));
+        assert_eq!(dns.decode(&mut session).map(|o| o.errors), Ok(1));
+        assert!(RESPONSE_SERVFAIL.value() > servfail);
+
+        // refused
+        receive(&mut session, &response(5, 0));
+        assert_eq!(dns.decode(&mut session).map(|o| o.errors), Ok(1));
+
+        // a truncated response is still answered
+        let truncated = RESPONSE_TRUNCATED.value();
+        receive(&mut session, &response(FLAG_TRUNCATED, 1));
+        assert_eq!(dns.decode(&mut session).map(|o| o.hits), Ok(1));
+        assert!(RESPONSE_TRUNCATED.value() > truncated);
+
+        // a query is not a response
+        let mut query = response(0, 0);
+        query[4] &= !((FLAG_RESPONSE >> 8) as u8);
+        receive(&mut session, &query);
+        assert_eq!(dns.decode(&mut session), Err(ParseError::Unknown));
+    }
+}
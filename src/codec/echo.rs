@@ -4,6 +4,7 @@
 
 use crate::codec::*;
 use crate::config::Keyspace;
+use crate::config_file::Verb;
 use crate::*;
 use crc::{Crc, CRC_32_ISO_HDLC};
 use std::io::BufRead;
@@ -43,12 +44,13 @@ impl Echo {
 }
 
 impl Codec for Echo {
-    fn encode(&mut self, buf: &mut Session) {
+    fn encode(&mut self, buf: &mut Session) -> (Verb, Outcome) {
         let keyspace = self.config.choose_keyspace(&mut self.rng);
-        Self::echo(&mut self.rng, keyspace, buf)
+        Self::echo(&mut self.rng, keyspace, buf);
+        (Verb::Echo, Outcome::default())
     }
 
-    fn decode(&self, buffer: &mut Session) -> Result<(), ParseError> {
+    fn decode(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
         // no-copy borrow as a slice
         let buf: &[u8] = (*buffer).buffer();
 
@@ -63,18 +65,18 @@ impl Codec for Echo {
                 let mut digest = CRC.digest();
                 digest.update(message);
                 let crc_calculated = digest.finalize();
-                let crc_calculated: [u8; 4] =
-                    unsafe { std::mem::transmute(crc_calculated.to_be()) };
+                let crc_calculated: [u8; 4] = crc_calculated.to_be_bytes();
                 if crc_calculated != crc_received[..] {
                     debug!(
                         "Response has bad CRC: {:?} != {:?}",
                         crc_received, crc_calculated
                     );
+                    // the session is closed, so the error is only counted
                     metrics::RESPONSE_EX.increment();
                     Err(ParseError::Error)
                 } else {
-                    let _ = buffer.consume(response_end + 2);
-                    Ok(())
+                    buffer.consume(response_end + 2);
+                    Ok(Outcome::default())
                 }
             }
         } else {
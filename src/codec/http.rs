@@ -0,0 +1,626 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+use crate::codec::*;
+use crate::config::*;
+use crate::config_file::{HttpStickiness, StickinessLabel, Verb};
+use crate::*;
+
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+
+use std::io::{BufRead, Write};
+use std::str;
+
+/// Replaced with the generated key in the path and header values
+const KEY: &str = "${key}";
+/// Replaced with the value of an environment variable in header values
+const ENV: &str = "${env:";
+/// Replaced with the contents of a file in header values, such as a bearer
+/// token which is rotated during the run
+const FILE: &str = "${file:";
+
+/// Sends HTTP/1.1 requests built from the request template, reusing each
+/// connection for subsequent requests.
+pub struct Http {
+    config: Arc<Config>,
+    rng: SmallRng,
+    method: Option<String>,
+    path: String,
+    host: String,
+    headers: Vec<(String, Vec<Segment>)>,
+    // files used in header values, with their current contents
+    files: Vec<(String, String)>,
+    header_refresh: std::time::Duration,
+    files_read: std::time::Instant,
+    body: Option<Vec<u8>>,
+    cookies: bool,
+    login: Option<Login>,
+    stickiness: Option<HttpStickiness>,
+}
+
+/// The login request sent on each new connection
+struct Login {
+    method: String,
+    path: String,
+    headers: Vec<(String, Vec<Segment>)>,
+    body: Vec<Segment>,
+}
+
+/// A part of a header value
+enum Segment {
+    Text(String),
+    Key,
+    /// the index of the file in the codec's files
+    File(usize),
+}
+
+impl Http {
+    pub fn new(config: Arc<Config>) -> Self {
+        let http = config.protocol().http();
+        let body = match http.body_file() {
+            Some(file) => match std::fs::read(&file) {
+                Ok(body) => Some(body),
+                Err(e) => {
+                    fatal!("failed to read http body file: {}: {}", file, e);
+                }
+            },
+            None => None,
+        };
+        let mut files = Vec::new();
+        let headers = http
+            .headers()
+            .into_iter()
+            .map(|(name, value)| (name, parse_header(&value, &mut files)))
+            .collect();
+        let login = http.login().map(|login| Login {
+            method: login.method(),
+            path: login.path(),
+            headers: login
+                .headers()
+                .into_iter()
+                .map(|(name, value)| (name, parse_header(&value, &mut files)))
+                .collect(),
+            body: parse_header(&login.body(), &mut files),
+        });
+        let files = files
+            .into_iter()
+            .map(|path| match read_header_file(&path) {
+                Ok(contents) => (path, contents),
+                Err(e) => {
+                    fatal!("failed to read http header file: {}: {}", path, e);
+                }
+            })
+            .collect();
+        let stickiness = config.stickiness().cloned();
+        Self {
+            config,
+            rng: SmallRng::from_entropy(),
+            method: http.method(),
+            path: http.path(),
+            host: http.host(),
+            headers,
+            files,
+            header_refresh: http.header_refresh(),
+            files_read: std::time::Instant::now(),
+            body,
+            cookies: http.cookies(),
+            login,
+            stickiness,
+        }
+    }
+
+    /// The value of a header with its templates filled in
+    fn header_value(&self, segments: &[Segment], key: &str) -> String {
+        let mut value = String::new();
+        for segment in segments {
+            match segment {
+                Segment::Text(text) => value.push_str(text),
+                Segment::Key => value.push_str(key),
+                Segment::File(index) => value.push_str(&self.files[*index].1),
+            }
+        }
+        value
+    }
+
+    /// Send the current oauth access token, when oauth is configured
+    fn write_authorization(&self, buf: &mut Session) {
+        if let Some(token) = self.config.oauth_token().and_then(|token| token.bearer()) {
+            let _ = buf.write_all(format!("Authorization: Bearer {token}\r\n").as_bytes());
+        }
+    }
+
+    /// Send the connection's label, unless it is only sent once and already
+    /// has been
+    fn write_label(&self, buf: &mut Session) {
+        let stickiness = match self.stickiness {
+            Some(ref stickiness) => stickiness,
+            None => return,
+        };
+        let send = match stickiness.label() {
+            StickinessLabel::Header => true,
+            StickinessLabel::FirstRequest => !buf.is_label_sent(),
+            StickinessLabel::Sni => false,
+        };
+        if let Some(label) = buf.label().filter(|_| send).map(str::to_string) {
+            let _ = buf.write_all(format!("{}: {}\r\n", stickiness.header(), label).as_bytes());
+            buf.set_label_sent();
+        }
+    }
+
+    /// Read the files used in header values again once the refresh interval
+    /// has passed. The previous contents are kept if a file can't be read.
+    fn refresh_files(&mut self) {
+        if self.files.is_empty() || self.files_read.elapsed() < self.header_refresh {
+            return;
+        }
+        self.files_read = std::time::Instant::now();
+        for (path, contents) in self.files.iter_mut() {
+            match read_header_file(path) {
+                Ok(current) => *contents = current,
+                Err(e) => {
+                    warn!("failed to refresh http header file: {}: {}", path, e);
+                }
+            }
+        }
+    }
+}
+
+/// Split a header value or login body into its text and templates. Environment variables
+/// are substituted here, files are added to the list of files to read.
+fn parse_header(value: &str, files: &mut Vec<String>) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        if start > 0 {
+            segments.push(Segment::Text(rest[..start].to_string()));
+        }
+        let template = &rest[start..];
+        let end = match template.find('}') {
+            Some(end) => end,
+            None => {
+                fatal!("unterminated template in http header: {}", value);
+            }
+        };
+        let inner = &template[..end];
+        if &template[..=end] == KEY {
+            segments.push(Segment::Key);
+        } else if let Some(name) = inner.strip_prefix(ENV) {
+            match std::env::var(name) {
+                Ok(value) => segments.push(Segment::Text(value)),
+                Err(_) => {
+                    fatal!("environment variable for http header is not set: {}", name);
+                }
+            }
+        } else if let Some(path) = inner.strip_prefix(FILE) {
+            let index = match files.iter().position(|file| file == path) {
+                Some(index) => index,
+                None => {
+                    files.push(path.to_string());
+                    files.len() - 1
+                }
+            };
+            segments.push(Segment::File(index));
+        } else {
+            fatal!("unknown template in http header: {}", &template[..=end]);
+        }
+        rest = &template[end + 1..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Text(rest.to_string()));
+    }
+    segments
+}
+
+/// Files usually end with a newline, which is not part of the value
+fn read_header_file(path: &str) -> Result<String, std::io::Error> {
+    Ok(std::fs::read_to_string(path)?.trim().to_string())
+}
+
+impl Codec for Http {
+    fn encode(&mut self, buf: &mut Session) -> (Verb, Outcome) {
+        let mut outcome = Outcome::default();
+        self.refresh_files();
+        let keyspace = self.config.choose_keyspace(&mut self.rng);
+        let verb = keyspace.choose_command(&mut self.rng, buf).verb();
+        let method = match (&self.method, verb) {
+            (Some(method), _) => method.as_str(),
+            (None, Verb::Get) => "GET",
+            (None, Verb::Set) => "PUT",
+            (None, Verb::Delete) => "DELETE",
+            _ => {
+                unimplemented!()
+            }
+        };
+        if verb == Verb::Get {
+            metrics::REQUEST_GET.increment();
+        }
+
+        let key = keyspace.generate_key(&mut self.rng, buf.shard(), &mut outcome);
+        let key = String::from_utf8_lossy(&key);
+        let vhost = match self.config.vhosts() {
+            Some(vhosts) if vhosts.per_request() => Some(vhosts.choose(&mut self.rng)),
+            _ => buf.vhost(),
+        };
+
+        let _ = buf.write_all(
+            format!(
+                "{} {} HTTP/1.1\r\nHost: {}\r\n",
+                method,
+                self.path.replace(KEY, &key),
+                vhost_name(&self.host, vhost)
+            )
+            .as_bytes(),
+        );
+        for (name, segments) in &self.headers {
+            let value = self.header_value(segments, &key);
+            let _ = buf.write_all(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        self.write_authorization(buf);
+        self.write_label(buf);
+        if self.cookies && !buf.cookies().is_empty() {
+            let cookies: Vec<String> = buf
+                .cookies()
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect();
+            let _ = buf.write_all(format!("Cookie: {}\r\n", cookies.join("; ")).as_bytes());
+        }
+
+        let value;
+        let body = match self.body {
+            Some(ref body) => Some(body.as_slice()),
+            None if verb == Verb::Set => {
+                value = keyspace
+                    .generate_value(&mut self.rng)
+                    .unwrap_or_else(|| b"".to_vec());
+                Some(value.as_slice())
+            }
+            None => None,
+        };
+        if let Some(body) = body {
+            let _ = buf.write_all(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes());
+            let _ = buf.write_all(body);
+        } else {
+            let _ = buf.write_all(b"\r\n");
+        }
+
+        (verb, outcome)
+    }
+
+    fn setup(&mut self, buf: &mut Session) -> bool {
+        self.refresh_files();
+        let login = match self.login {
+            Some(ref login) => login,
+            None => return false,
+        };
+        let _ = buf.write_all(
+            format!(
+                "{} {} HTTP/1.1\r\nHost: {}\r\n",
+                login.method,
+                login.path,
+                vhost_name(&self.host, buf.vhost())
+            )
+            .as_bytes(),
+        );
+        for (name, segments) in &login.headers {
+            let value = self.header_value(segments, "");
+            let _ = buf.write_all(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        self.write_authorization(buf);
+        self.write_label(buf);
+        let body = self.header_value(&login.body, "");
+        let _ = buf.write_all(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes());
+        let _ = buf.write_all(body.as_bytes());
+        true
+    }
+
+    fn decode(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        // no-copy borrow as a slice
+        let buf: &[u8] = (*buffer).buffer();
+
+        let mut windows = buf.windows(4);
+        let header_end = windows
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or(ParseError::Incomplete)?;
+        let header = str::from_utf8(&buf[0..header_end]).map_err(|_| ParseError::Unknown)?;
+
+        let mut lines = header.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or(ParseError::Unknown)?;
+
+        let assertions = self.config.assertions();
+        let mut headers = Vec::new();
+        let mut content_length = 0;
+        let mut chunked = false;
+        let mut retry_after = None;
+        let mut cookies = Vec::new();
+        let mut backend = None;
+        let identity = self
+            .stickiness
+            .as_ref()
+            .and_then(|stickiness| stickiness.identity());
+        for line in lines {
+            if let Some((name, value)) = line.split_once(':') {
+                if !assertions.is_empty() {
+                    headers.push((name, value.trim()));
+                }
+                if self.cookies && name.eq_ignore_ascii_case("set-cookie") {
+                    cookies.extend(parse_set_cookie(value));
+                } else if identity.is_some_and(|identity| name.eq_ignore_ascii_case(identity)) {
+                    backend = Some(value.trim().to_string());
+                } else if name.eq_ignore_ascii_case("retry-after") {
+                    // only the delay in seconds is supported, not a date
+                    retry_after = value.trim().parse::<u64>().ok();
+                } else if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value
+                        .trim()
+                        .parse::<usize>()
+                        .map_err(|_| ParseError::Unknown)?;
+                } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                    chunked = value.to_ascii_lowercase().contains("chunked");
+                }
+            }
+        }
+
+        let body_start = header_end + 4;
+        let response_end = if chunked {
+            body_start + chunked_len(&buf[body_start..])?
+        } else {
+            body_start + content_length
+        };
+        if response_end > buf.len() {
+            return Err(ParseError::Incomplete);
+        }
+
+        let passed = buffer.is_setting_up() || assertions.is_empty() || {
+            let body = &buf[body_start..response_end];
+            if chunked && assertions.needs_body() {
+                assertions.check(status, &headers, &dechunk(body))
+            } else {
+                assertions.check(status, &headers, body)
+            }
+        };
+
+        for (name, value) in cookies {
+            buffer.set_cookie(&name, value.as_deref());
+        }
+
+        if let Some(backend) = backend {
+            metrics::RESPONSE_STICKY.increment();
+            if !buffer.check_backend(&backend) {
+                metrics::RESPONSE_STICKY_VIOLATION.increment();
+            }
+        }
+
+        if buffer.is_setting_up() {
+            // redirects are a common answer to a successful form login
+            if status == 401 {
+                metrics::RESPONSE_UNAUTHORIZED.increment();
+                if let Some(token) = self.config.oauth_token() {
+                    token.set_stale();
+                }
+            }
+            if !(200..400).contains(&status) {
+                debug!("http login failed with status: {}", status);
+                return Err(ParseError::Error);
+            }
+            buffer.consume(response_end);
+            return Ok(Outcome::default());
+        }
+
+        let mut outcome = Outcome::default();
+        let error = match status {
+            200..=299 => {
+                if passed && buffer.command() == Some(Verb::Get) {
+                    outcome.hit();
+                }
+                false
+            }
+            // the token may have expired or been revoked early
+            401 => {
+                metrics::RESPONSE_UNAUTHORIZED.increment();
+                if let Some(token) = self.config.oauth_token() {
+                    token.set_stale();
+                }
+                true
+            }
+            // a miss for gets
+            404 => false,
+            // the server is rate limiting or overloaded
+            429 | 503 => {
+                metrics::RESPONSE_THROTTLED.increment();
+                if let Some(seconds) = retry_after {
+                    buffer.set_retry_after(Some(Instant::now() + Duration::from_secs(seconds)));
+                }
+                false
+            }
+            _ => true,
+        };
+
+        // a response which fails an assertion is an error, even when its
+        // status is not
+        if !passed {
+            metrics::RESPONSE_ASSERTION_FAILED.increment();
+        }
+        if error || !passed {
+            outcome.error();
+        }
+
+        buffer.consume(response_end);
+        Ok(outcome)
+    }
+}
+
+/// The name and value of the cookie in a Set-Cookie header. The value is
+/// `None` when the server removes the cookie, which it does by expiring it.
+fn parse_set_cookie(header: &str) -> Option<(String, Option<String>)> {
+    let mut attributes = header.split(';');
+    let (name, value) = attributes.next()?.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    let expired = attributes.any(|attribute| {
+        attribute
+            .split_once('=')
+            .map(|(name, value)| {
+                name.trim().eq_ignore_ascii_case("max-age") && value.trim().starts_with(['0', '-'])
+            })
+            .unwrap_or(false)
+    });
+    let value = value.trim().trim_matches('"');
+    Some((
+        name.to_string(),
+        (!expired && !value.is_empty()).then(|| value.to_string()),
+    ))
+}
+
+/// The data of a complete chunked body, without the chunk sizes
+fn dechunk(buf: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    let mut position = 0;
+    while let Some(line_end) = buf[position..].windows(2).position(|w| w == b"\r\n") {
+        let size = str::from_utf8(&buf[position..(position + line_end)])
+            .ok()
+            .and_then(|line| line.split(';').next())
+            .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+            .unwrap_or(0);
+        let start = position + line_end + 2;
+        if size == 0 || start + size > buf.len() {
+            break;
+        }
+        body.extend_from_slice(&buf[start..(start + size)]);
+        position = start + size + 2;
+        if position > buf.len() {
+            break;
+        }
+    }
+    body
+}
+
+/// The length of a chunked body, including the final chunk and any trailers
+fn chunked_len(buf: &[u8]) -> Result<usize, ParseError> {
+    let mut position = 0;
+    loop {
+        let line_end = buf[position..]
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or(ParseError::Incomplete)?
+            + position;
+        let size = str::from_utf8(&buf[position..line_end])
+            .ok()
+            .and_then(|line| line.split(';').next())
+            .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+            .ok_or(ParseError::Unknown)?;
+        if size == 0 {
+            // the body ends with an empty line after any trailers
+            let end = buf[position..]
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .ok_or(ParseError::Incomplete)?;
+            return Ok(position + end + 4);
+        }
+        position = line_end + 2 + size + 2;
+        if position > buf.len() {
+            return Err(ParseError::Incomplete);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::tests::{config, receive, session};
+
+    #[test]
+    fn decode() {
+        let http = Http::new(config("http.toml"));
+        let mut session = session();
+
+        // a get which is answered is a hit, once all of its body is received
+        session.push_command(Verb::Get, false);
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+        receive(&mut session, &ok[..20]);
+        assert_eq!(http.decode(&mut session), Err(ParseError::Incomplete));
+        receive(&mut session, &ok[20..ok.len() - 1]);
+        assert_eq!(http.decode(&mut session), Err(ParseError::Incomplete));
+        receive(&mut session, &ok[ok.len() - 1..]);
+        assert_eq!(http.decode(&mut session).map(|o| o.hits), Ok(1));
+        assert_eq!(session.read_pending(), 0);
+        session.pop_command();
+
+        // a set is not a hit
+        session.push_command(Verb::Set, false);
+        receive(&mut session, b"HTTP/1.1 204 No Content\r\n\r\n");
+        assert_eq!(http.decode(&mut session), Ok(Outcome::default()));
+        session.pop_command();
+
+        // a miss
+        session.push_command(Verb::Get, false);
+        receive(
+            &mut session,
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert_eq!(http.decode(&mut session), Ok(Outcome::default()));
+        session.pop_command();
+
+        // a chunked body ends with its last chunk
+        session.push_command(Verb::Get, false);
+        let chunked =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+        receive(&mut session, &chunked[..chunked.len() - 2]);
+        assert_eq!(http.decode(&mut session), Err(ParseError::Incomplete));
+        receive(&mut session, &chunked[chunked.len() - 2..]);
+        assert_eq!(http.decode(&mut session).map(|o| o.hits), Ok(1));
+        assert_eq!(session.read_pending(), 0);
+        session.pop_command();
+
+        // throttling is not an error, and the delay is kept for the session
+        session.push_command(Verb::Get, false);
+        receive(
+            &mut session,
+            b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 5\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert_eq!(http.decode(&mut session), Ok(Outcome::default()));
+        assert!(session.retry_after().is_some());
+        session.pop_command();
+
+        session.push_command(Verb::Get, false);
+        receive(
+            &mut session,
+            b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert_eq!(http.decode(&mut session).map(|o| o.errors), Ok(1));
+        assert_eq!(session.read_pending(), 0);
+        session.pop_command();
+
+        receive(&mut session, b"HTTP/1.1 OK\r\n\r\n");
+        assert_eq!(http.decode(&mut session), Err(ParseError::Unknown));
+    }
+
+    #[test]
+    fn login() {
+        let http = Http::new(config("http.toml"));
+        let mut session = session();
+        session.set_setting_up(true);
+
+        // a redirect is a successful login, and is not counted as a response
+        receive(
+            &mut session,
+            b"HTTP/1.1 302 Found\r\nLocation: /\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert_eq!(http.decode(&mut session), Ok(Outcome::default()));
+        assert_eq!(session.read_pending(), 0);
+
+        receive(
+            &mut session,
+            b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert_eq!(http.decode(&mut session), Err(ParseError::Error));
+    }
+}
@@ -8,6 +8,7 @@ use crate::config_file::Verb;
 use crate::*;
 use std::io::BufRead;
 use std::io::Write;
+use std::str;
 
 use rand::rngs::SmallRng;
 use rand::SeedableRng;
@@ -15,33 +16,77 @@ use rand::SeedableRng;
 pub struct Memcache {
     config: Arc<Config>,
     rng: SmallRng,
+    validate: bool,
+    // stores are acknowledged when any keyspace mutates its values
+    mutation: bool,
 }
 
 impl Memcache {
     pub fn new(config: Arc<Config>) -> Self {
+        let validate = config.general().validate();
+        let mutation = config.keyspaces().iter().any(|k| k.mutates_values());
         Self {
             config,
             rng: SmallRng::from_entropy(),
+            validate,
+            mutation,
         }
     }
 
-    fn get(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
+    fn get(
+        rng: &mut SmallRng,
+        validate: bool,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
         let _ = buf.write_all(b"get ");
+        metrics::GET_KEY.add(keyspace.batch_size() as u64);
 
+        let mut keys = Vec::new();
         for i in 0..keyspace.batch_size() {
-            let key = keyspace.generate_key(rng);
+            let (key, version) = keyspace.generate_read_key(rng, buf.shard(), outcome);
             let _ = buf.write_all(&key);
             if i + 1 < keyspace.batch_size() {
                 let _ = buf.write_all(b" ");
             }
+            if validate {
+                keys.push((key, version));
+            }
         }
 
         let _ = buf.write_all(b"\r\n");
+
+        if validate {
+            buf.push_keys(keys);
+        }
+    }
+
+    fn set(
+        rng: &mut SmallRng,
+        validate: bool,
+        mutation: bool,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let write = keyspace.generate_write(rng, buf.shard(), outcome);
+        Self::store(validate, mutation, keyspace, write, buf)
     }
 
-    fn set(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
-        let value = keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec());
+    fn store(
+        validate: bool,
+        mutation: bool,
+        keyspace: &Keyspace,
+        (key, mut value, write): (Vec<u8>, Vec<u8>, Option<VersionedWrite>),
+        buf: &mut Session,
+    ) {
+        if validate {
+            validate::seal(&key, &mut value);
+        }
+        if mutation {
+            buf.push_write(write);
+        }
         let ttl = keyspace.ttl();
         let _ = buf.write_all(b"set ");
         let _ = buf.write_all(&key);
@@ -50,32 +95,269 @@ impl Memcache {
         let _ = buf.write_all(b"\r\n");
     }
 
-    fn delete(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
+    fn delete(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session, outcome: &mut Outcome) {
+        let key = keyspace.generate_key(rng, buf.shard(), outcome);
         let _ = buf.write_all(b"delete ");
         let _ = buf.write_all(&key);
         let _ = buf.write_all(b"\r\n");
     }
+
+    /// The first step of a compare-and-swap reads the key with its cas
+    /// unique. The value it is swapped for is sent once the read is answered.
+    fn cas(
+        rng: &mut SmallRng,
+        validate: bool,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let (key, mut value, _) = keyspace.generate_write(rng, buf.shard(), outcome);
+        if validate {
+            validate::seal(&key, &mut value);
+        }
+        let _ = buf.write_all(b"gets ");
+        let _ = buf.write_all(&key);
+        let _ = buf.write_all(b"\r\n");
+        buf.set_next_step(key, value, keyspace.ttl());
+    }
+
+    /// Handle the replies to a compare-and-swap. A read which finds the key
+    /// is followed by the swap, which is answered with STORED, or with EXISTS
+    /// or NOT_FOUND when the key changed after it was read. A miss ends the
+    /// command without a swap.
+    fn decode_cas(buffer: &mut Session) -> Result<Outcome, ParseError> {
+        let buf: &[u8] = (*buffer).buffer();
+        if !buffer.has_next_step() {
+            let line_end = buf
+                .windows(2)
+                .position(|w| w == b"\r\n")
+                .ok_or(ParseError::Incomplete)?;
+            match &buf[0..line_end] {
+                b"STORED" => {
+                    metrics::CAS_SUCCESS.increment();
+                }
+                b"EXISTS" | b"NOT_FOUND" => {
+                    metrics::CAS_CONFLICT.increment();
+                }
+                _ => return Err(ParseError::Unknown),
+            }
+            buffer.consume(line_end + 2);
+            return Ok(Outcome::default());
+        }
+
+        let response_end = buf
+            .windows(5)
+            .position(|w| w == b"END\r\n")
+            .ok_or(ParseError::Incomplete)?;
+        // VALUE <key> <flags> <bytes> <cas unique>
+        let unique = buf
+            .split(|b| *b == b'\r')
+            .next()
+            .filter(|line| line.starts_with(b"VALUE "))
+            .and_then(|line| line.split(|b| *b == b' ').nth(4))
+            .map(|unique| unique.to_vec());
+        buffer.consume(response_end + 5);
+        let (key, value, ttl) = buffer.take_next_step().unwrap_or_default();
+        let mut outcome = Outcome::default();
+        if let Some(unique) = unique {
+            outcome.hit();
+            let _ = buffer.write_all(b"cas ");
+            let _ = buffer.write_all(&key);
+            let _ = buffer.write_all(format!(" 0 {} {} ", ttl, value.len()).as_bytes());
+            let _ = buffer.write_all(&unique);
+            let _ = buffer.write_all(b"\r\n");
+            let _ = buffer.write_all(&value);
+            let _ = buffer.write_all(b"\r\n");
+        }
+        Ok(outcome)
+    }
+
+    /// The first step of a read-modify-write reads the key. The value written
+    /// back is derived from the value which was read once it arrives.
+    fn read_modify_write(
+        rng: &mut SmallRng,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let (key, value, _) = keyspace.generate_write(rng, buf.shard(), outcome);
+        let _ = buf.write_all(b"get ");
+        let _ = buf.write_all(&key);
+        let _ = buf.write_all(b"\r\n");
+        buf.set_next_step(key, value, keyspace.ttl());
+    }
+
+    /// Handle the replies to a read-modify-write. The read is followed by a
+    /// set of the derived value, or of a new value if the key was missing.
+    fn decode_read_modify_write(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        let buf: &[u8] = (*buffer).buffer();
+        if !buffer.has_next_step() {
+            let line_end = buf
+                .windows(2)
+                .position(|w| w == b"\r\n")
+                .ok_or(ParseError::Incomplete)?;
+            match &buf[0..line_end] {
+                b"STORED" | b"NOT_STORED" => {}
+                _ => return Err(ParseError::Unknown),
+            }
+            buffer.consume(line_end + 2);
+            return Ok(Outcome::default());
+        }
+
+        let response_end = buf
+            .windows(5)
+            .position(|w| w == b"END\r\n")
+            .ok_or(ParseError::Incomplete)?;
+        let read = Self::first_value(&buf[0..(response_end + 5)]).map(|value| value.to_vec());
+        buffer.consume(response_end + 5);
+        let (key, generated, ttl) = buffer.take_next_step().unwrap_or_default();
+        let mut outcome = Outcome::default();
+        if let Some(ref read) = read {
+            outcome.hit();
+            if self.validate {
+                validate::record(&key, read, 0);
+            }
+        }
+        let mut value = modify(read.as_deref(), generated);
+        if self.validate {
+            validate::seal(&key, &mut value);
+        }
+        let _ = buffer.write_all(b"set ");
+        let _ = buffer.write_all(&key);
+        let _ = buffer.write_all(format!(" 0 {} {}\r\n", ttl, value.len()).as_bytes());
+        let _ = buffer.write_all(&value);
+        let _ = buffer.write_all(b"\r\n");
+        Ok(outcome)
+    }
+
+    fn counter(
+        rng: &mut SmallRng,
+        command: &[u8],
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let key = keyspace.generate_key(rng, buf.shard(), outcome);
+        let _ = buf.write_all(command);
+        let _ = buf.write_all(&key);
+        let _ = buf.write_all(format!(" {}\r\n", keyspace.choose_delta(rng)).as_bytes());
+    }
+
+    /// The data of the first value in a get response, if any key was found
+    fn first_value(response: &[u8]) -> Option<&[u8]> {
+        if !response.starts_with(b"VALUE ") {
+            return None;
+        }
+        let line_end = response.windows(2).position(|w| w == b"\r\n")?;
+        // VALUE <key> <flags> <bytes> [<cas unique>]
+        let len = response[0..line_end]
+            .split(|b| *b == b' ')
+            .nth(3)
+            .and_then(|len| str::from_utf8(len).ok())
+            .and_then(|len| len.parse::<usize>().ok())?;
+        response.get((line_end + 2)..(line_end + 2 + len))
+    }
+
+    /// The number of values in a get response, one for each key found
+    fn values(response: &[u8]) -> usize {
+        let mut count = 0;
+        let mut remaining = response;
+        while remaining.starts_with(b"VALUE ") {
+            let line_end = match remaining.windows(2).position(|w| w == b"\r\n") {
+                Some(line_end) => line_end,
+                None => break,
+            };
+            // VALUE <key> <flags> <bytes> [<cas unique>]
+            let len = remaining[0..line_end]
+                .split(|b| *b == b' ')
+                .nth(3)
+                .and_then(|len| str::from_utf8(len).ok())
+                .and_then(|len| len.parse::<usize>().ok());
+            let len = match len {
+                Some(len) => len,
+                None => break,
+            };
+            count += 1;
+            remaining = &remaining[(line_end + len + 4).min(remaining.len())..];
+        }
+        count
+    }
+
+    /// Check each value in a get response against the requested keys
+    fn validate(response: &[u8], keys: &[(Vec<u8>, u32)]) {
+        let mut remaining = response;
+        while let Some(line_end) = remaining.windows(2).position(|w| w == b"\r\n") {
+            let mut fields = remaining[0..line_end].split(|b| *b == b' ');
+            if fields.next() != Some(b"VALUE") {
+                break;
+            }
+            let key = fields.next().unwrap_or_default();
+            let start = line_end + 2;
+            let end = fields
+                .nth(1)
+                .and_then(|len| str::from_utf8(len).ok())
+                .and_then(|len| len.parse::<usize>().ok())
+                .map(|len| start + len)
+                .filter(|end| *end <= remaining.len());
+            let end = match end {
+                Some(end) => end,
+                None => {
+                    metrics::RESPONSE_VALIDATED.increment();
+                    metrics::RESPONSE_CORRUPT.increment();
+                    break;
+                }
+            };
+            if let Some((_, version)) = keys.iter().find(|(k, _)| k == key) {
+                validate::record(key, &remaining[start..end], *version);
+            } else {
+                metrics::RESPONSE_VALIDATED.increment();
+                metrics::RESPONSE_WRONG_KEY.increment();
+            }
+            remaining = &remaining[(end + 2).min(remaining.len())..];
+        }
+    }
 }
 
 impl Codec for Memcache {
-    fn encode(&mut self, buf: &mut Session) {
-        let keyspace = self.config.choose_keyspace(&mut self.rng);
-        let command = keyspace.choose_command(&mut self.rng);
-        match command.verb() {
+    fn encode(&mut self, buf: &mut Session) -> (Verb, Outcome) {
+        let mut outcome = Outcome::default();
+        let rng = &mut self.rng;
+        let keyspace = self.config.choose_keyspace(rng);
+        let verb = keyspace.choose_command(rng, buf).verb();
+        let out = &mut outcome;
+        match verb {
             Verb::Get => {
                 metrics::REQUEST_GET.increment();
-                Self::get(&mut self.rng, keyspace, buf)
+                Self::get(rng, self.validate, keyspace, buf, out)
             }
-            Verb::Set => Self::set(&mut self.rng, keyspace, buf),
-            Verb::Delete => Self::delete(&mut self.rng, keyspace, buf),
+            Verb::Set => Self::set(rng, self.validate, self.mutation, keyspace, buf, out),
+            Verb::Delete => Self::delete(rng, keyspace, buf, out),
+            Verb::Incr => Self::counter(rng, b"incr ", keyspace, buf, out),
+            Verb::Decr => Self::counter(rng, b"decr ", keyspace, buf, out),
+            Verb::Cas => Self::cas(rng, self.validate, keyspace, buf, out),
+            Verb::ReadModifyWrite => Self::read_modify_write(rng, keyspace, buf, out),
             _ => {
                 unimplemented!()
             }
         }
+        (verb, outcome)
+    }
+
+    fn prefill(&mut self, buf: &mut Session, keyspace: usize, index: usize) {
+        let keyspace = self.config.keyspace(keyspace);
+        let write = keyspace.generate_write_for(&mut self.rng, index);
+        Self::store(self.validate, self.mutation, keyspace, write, buf)
     }
 
-    fn decode(&self, buffer: &mut Session) -> Result<(), ParseError> {
+    fn decode(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        if buffer.command() == Some(Verb::Cas) {
+            return Self::decode_cas(buffer);
+        }
+
+        if buffer.command() == Some(Verb::ReadModifyWrite) {
+            return self.decode_read_modify_write(buffer);
+        }
+
         // no-copy borrow as a slice
         let buf: &[u8] = (*buffer).buffer();
 
@@ -89,24 +371,60 @@ impl Codec for Memcache {
         ] {
             let bytes = response.as_bytes();
             if buf.len() >= bytes.len() && &buf[0..bytes.len()] == bytes {
-                let _ = buffer.consume(bytes.len());
-                return Ok(());
+                buffer.consume(bytes.len());
+                // each store has a write, which is acknowledged once stored
+                if self.mutation
+                    && matches!(*response, "STORED\r\n" | "NOT_STORED\r\n" | "EXISTS\r\n")
+                {
+                    if let Some(write) = buffer.pop_write() {
+                        if *response == "STORED\r\n" {
+                            self.config.acknowledge(write);
+                        } else {
+                            self.config.reject(write);
+                        }
+                    }
+                }
+                return Ok(Outcome::default());
             }
         }
 
+        // incr and decr reply with the new value of a counter which was found
+        if matches!(buffer.command(), Some(Verb::Incr | Verb::Decr)) {
+            return match buf.windows(2).position(|w| w == b"\r\n") {
+                Some(line_end)
+                    if line_end > 0 && buf[0..line_end].iter().all(u8::is_ascii_digit) =>
+                {
+                    let mut outcome = Outcome::default();
+                    outcome.hit();
+                    buffer.consume(line_end + 2);
+                    Ok(outcome)
+                }
+                Some(_) => Err(ParseError::Unknown),
+                None => Err(ParseError::Incomplete),
+            };
+        }
+
         let mut windows = buf.windows(5);
         if let Some(response_end) = windows.position(|w| w == b"END\r\n") {
             let response = &buf[0..(response_end + 5)];
-            let mut start = 0;
-            let mut lines = response.windows(2);
-            while let Some(line_end) = lines.position(|w| w == b"\r\n") {
-                if response.len() >= 5 && &response[start..(start + 5)] == b"VALUE" {
-                    metrics::RESPONSE_HIT.increment();
-                }
-                start = line_end + 2;
+            // a multi-key get is a hit when any of its keys are found
+            let hits = Self::values(response);
+            let mut outcome = Outcome::default();
+            if hits > 0 {
+                outcome.hit();
+            }
+            if buffer.command() == Some(Verb::Get) {
+                metrics::GET_KEY_HIT.add(hits as u64);
+            }
+            let validate = self.validate && buffer.command() == Some(Verb::Get);
+            if validate {
+                Self::validate(response, buffer.keys());
             }
             buffer.consume(response_end + 5);
-            return Ok(());
+            if validate {
+                let _ = buffer.pop_keys();
+            }
+            return Ok(outcome);
         }
 
         Err(ParseError::Incomplete)
@@ -2,19 +2,51 @@
 // Licensed under the Apache License, Version 2.0
 // http://www.apache.org/licenses/LICENSE-2.0
 
+mod cql;
+mod dns;
 mod echo;
+mod http;
 mod memcache;
+mod mongodb;
 mod ping;
+mod postgres;
 mod redis;
 mod thrift;
 mod thrift_cache;
+mod thrift_rpc;
+mod websocket;
 
-use crate::Session;
+use crate::config_file::Verb;
+use crate::{metrics, Session};
+pub use cql::Cql;
+pub use dns::Dns;
 pub use echo::Echo;
+pub use http::Http;
 pub use memcache::Memcache;
+pub use mongodb::Mongodb;
 pub use ping::Ping;
+pub use postgres::Postgres;
 pub use redis::Redis;
 pub use thrift_cache::ThriftCache;
+pub use thrift_rpc::ThriftRpc;
+pub use websocket::Websocket;
+
+pub(crate) use websocket::base64;
+
+/// The value written back by a read-modify-write. The value which was read
+/// keeps its length and has its start replaced by the generated value, while
+/// a missing key is written with the generated value.
+pub(crate) fn modify(read: Option<&[u8]>, generated: Vec<u8>) -> Vec<u8> {
+    match read {
+        Some(read) => {
+            let mut value = read.to_vec();
+            let len = value.len().min(generated.len());
+            value[0..len].copy_from_slice(&generated[0..len]);
+            value
+        }
+        None => generated,
+    }
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum ParseError {
@@ -23,7 +55,101 @@ pub enum ParseError {
     Unknown,
 }
 
+/// What a codec counted while encoding a request or decoding a response,
+/// which the worker attributes to the request's command. Recording a hit,
+/// an error, fields, or elements also counts them in the global metrics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Outcome {
+    /// responses which indicated a cache hit
+    pub hits: u64,
+    /// responses which indicated an error
+    pub errors: u64,
+    /// fields returned by a hash get all response
+    pub fields: u64,
+    /// elements written by a collection write request
+    pub elements: u64,
+    /// the request used an edge-case key
+    pub edge_case: bool,
+}
+
+impl Outcome {
+    /// Count a response that indicated a cache hit
+    pub fn hit(&mut self) {
+        metrics::RESPONSE_HIT.increment();
+        self.hits += 1;
+    }
+
+    /// Count a response that indicated an error
+    pub fn error(&mut self) {
+        metrics::RESPONSE_EX.increment();
+        self.errors += 1;
+    }
+
+    /// Count the fields returned by a hash get all response
+    pub fn add_fields(&mut self, fields: u64) {
+        metrics::RESPONSE_FIELD.add(fields);
+        self.fields += fields;
+    }
+
+    /// Count the elements written by a collection write request
+    pub fn add_elements(&mut self, elements: u64) {
+        metrics::COLLECTION_WRITE_ELEMENT.add(elements);
+        self.elements += elements;
+    }
+}
+
 pub trait Codec: Send {
-    fn decode(&self, buf: &mut Session) -> Result<(), ParseError>;
-    fn encode(&mut self, buf: &mut Session);
+    /// Decode a response from the session buffer, returning what it counted
+    fn decode(&self, buf: &mut Session) -> Result<Outcome, ParseError>;
+    /// Encode a request into the session buffer, returning the command that
+    /// was used for the request and what it counted. A command sent in
+    /// several steps writes each later step while decoding the response to
+    /// the one before, and is complete once a response is decoded without
+    /// writing anything.
+    fn encode(&mut self, buf: &mut Session) -> (Verb, Outcome);
+    /// Encode a request which stores the key with the provided index in the
+    /// keyspace. Used to prefill the keyspaces before the run starts.
+    fn prefill(&mut self, _buf: &mut Session, _keyspace: usize, _index: usize) {
+        unimplemented!()
+    }
+    /// Encode a request which sets up a new connection before any other
+    /// requests are sent on it, such as a login. Returns false when the codec
+    /// has nothing to send. Its response is decoded like any other, but is
+    /// not counted. A setup with several steps writes the next request while
+    /// decoding the response to the previous one, and is complete once a
+    /// response is decoded without writing anything.
+    fn setup(&mut self, _buf: &mut Session) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::config::Config;
+    use crate::{Session, TcpStream};
+    use std::sync::Arc;
+
+    /// Load one of the example configs
+    pub fn config(name: &str) -> Arc<Config> {
+        let path = format!("{}/configs/{}", env!("CARGO_MANIFEST_DIR"), name);
+        Arc::new(Config::new(Some(&path)))
+    }
+
+    /// A session which is never read from or written to, with its responses
+    /// provided by `receive` instead
+    pub fn session() -> Session {
+        let stream = TcpStream::connect_udp("127.0.0.1:9".parse().unwrap()).unwrap();
+        Session::plain_with_capacity(stream, 1024, 1024 * 1024)
+    }
+
+    /// Append bytes to the session's read buffer as if they were received
+    pub fn receive(session: &mut Session, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let space = session.read_space().unwrap();
+            let len = space.len().min(bytes.len());
+            space[..len].copy_from_slice(&bytes[..len]);
+            session.mark_received(len);
+            bytes = &bytes[len..];
+        }
+    }
 }
@@ -0,0 +1,513 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+use crate::codec::*;
+use crate::config::*;
+use crate::config_file::Verb;
+use crate::*;
+
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+
+use std::io::{BufRead, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const OP_MSG: i32 = 2013;
+const HEADER_LEN: usize = 16;
+const FLAG_CHECKSUM_PRESENT: u32 = 1;
+const SECTION_BODY: u8 = 0;
+
+const BSON_DOUBLE: u8 = 0x01;
+const BSON_STRING: u8 = 0x02;
+const BSON_DOCUMENT: u8 = 0x03;
+const BSON_ARRAY: u8 = 0x04;
+const BSON_BINARY: u8 = 0x05;
+const BSON_BOOLEAN: u8 = 0x08;
+const BSON_DATETIME: u8 = 0x09;
+const BSON_INT32: u8 = 0x10;
+const BSON_INT64: u8 = 0x12;
+
+/// Servers reply with these codes when a command is throttled
+const ERROR_RATE_LIMITED: [i32; 2] = [462, 16500];
+
+/// Maps get, set, and delete onto the find, update, and delete commands
+/// against a collection of documents keyed by `_id`, sent as OP_MSG frames.
+/// Gets with a batch size above one find all of their keys with `$in`, and
+/// sets replace the whole document, upserting it when it does not exist.
+/// Keys and values are stored as binary.
+pub struct Mongodb {
+    config: Arc<Config>,
+    rng: SmallRng,
+    request_id: i32,
+    database: String,
+    collection: String,
+    write_concern: Option<String>,
+}
+
+impl Mongodb {
+    pub fn new(config: Arc<Config>) -> Self {
+        let mongodb = config.protocol().mongodb();
+        Self {
+            rng: SmallRng::from_entropy(),
+            request_id: 0,
+            database: mongodb.database(),
+            collection: mongodb.collection(),
+            write_concern: mongodb.write_concern(),
+            config,
+        }
+    }
+
+    /// Add the configured write concern to a command which writes
+    fn write_concern(&self, command: &mut Document) {
+        if let Some(w) = &self.write_concern {
+            let mut write_concern = Document::new();
+            match w.parse::<i32>() {
+                Ok(w) => write_concern.int32("w", w),
+                Err(_) => write_concern.string("w", w),
+            }
+            command.document(BSON_DOCUMENT, "writeConcern", write_concern);
+        }
+    }
+
+    /// Send the command, which is finished with the database it runs against
+    fn command(&mut self, buf: &mut Session, mut command: Document) {
+        command.string("$db", &self.database);
+        let body = command.finish();
+
+        self.request_id = self.request_id.wrapping_add(1);
+        let len = HEADER_LEN + 4 + 1 + body.len();
+        let mut frame = Vec::with_capacity(len);
+        frame.extend_from_slice(&(len as i32).to_le_bytes());
+        frame.extend_from_slice(&self.request_id.to_le_bytes());
+        frame.extend_from_slice(&0i32.to_le_bytes());
+        frame.extend_from_slice(&OP_MSG.to_le_bytes());
+        frame.extend_from_slice(&0u32.to_le_bytes());
+        frame.push(SECTION_BODY);
+        frame.extend_from_slice(&body);
+        let _ = buf.write_all(&frame);
+    }
+
+    fn find(&mut self, buf: &mut Session, keyspace: &Keyspace, outcome: &mut Outcome) {
+        let mut filter = Document::new();
+        let batch_size = keyspace.batch_size();
+        if batch_size == 1 {
+            filter.binary(
+                "_id",
+                &keyspace.generate_key(&mut self.rng, buf.shard(), outcome),
+            );
+        } else {
+            let mut keys = Document::new();
+            for i in 0..batch_size {
+                keys.binary(
+                    &i.to_string(),
+                    &keyspace.generate_key(&mut self.rng, buf.shard(), outcome),
+                );
+            }
+            let mut any = Document::new();
+            any.document(BSON_ARRAY, "$in", keys);
+            filter.document(BSON_DOCUMENT, "_id", any);
+        }
+
+        let mut command = Document::new();
+        command.string("find", &self.collection);
+        command.document(BSON_DOCUMENT, "filter", filter);
+        command.int32("limit", batch_size as i32);
+        command.boolean("singleBatch", true);
+        self.command(buf, command);
+    }
+
+    fn replace(&mut self, buf: &mut Session, keyspace: &Keyspace, outcome: &mut Outcome) {
+        let key = keyspace.generate_key(&mut self.rng, buf.shard(), outcome);
+        let value = keyspace.generate_value(&mut self.rng).unwrap_or_default();
+
+        let mut query = Document::new();
+        query.binary("_id", &key);
+        let mut replacement = Document::new();
+        replacement.binary("_id", &key);
+        replacement.binary("value", &value);
+        if keyspace.ttl() > 0 {
+            // documents expire with a TTL index on this field
+            let expires = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_millis() as i64)
+                .unwrap_or(0)
+                + keyspace.ttl() as i64 * 1000;
+            replacement.datetime("expires", expires);
+        }
+        let mut update = Document::new();
+        update.document(BSON_DOCUMENT, "q", query);
+        update.document(BSON_DOCUMENT, "u", replacement);
+        update.boolean("upsert", true);
+        let mut updates = Document::new();
+        updates.document(BSON_DOCUMENT, "0", update);
+
+        let mut command = Document::new();
+        command.string("update", &self.collection);
+        command.document(BSON_ARRAY, "updates", updates);
+        self.write_concern(&mut command);
+        self.command(buf, command);
+    }
+
+    fn delete(&mut self, buf: &mut Session, keyspace: &Keyspace, outcome: &mut Outcome) {
+        let mut query = Document::new();
+        query.binary(
+            "_id",
+            &keyspace.generate_key(&mut self.rng, buf.shard(), outcome),
+        );
+        let mut delete = Document::new();
+        delete.document(BSON_DOCUMENT, "q", query);
+        delete.int32("limit", 1);
+        let mut deletes = Document::new();
+        deletes.document(BSON_DOCUMENT, "0", delete);
+
+        let mut command = Document::new();
+        command.string("delete", &self.collection);
+        command.document(BSON_ARRAY, "deletes", deletes);
+        self.write_concern(&mut command);
+        self.command(buf, command);
+    }
+}
+
+impl Codec for Mongodb {
+    fn encode(&mut self, buf: &mut Session) -> (Verb, Outcome) {
+        let mut outcome = Outcome::default();
+        let config = self.config.clone();
+        let keyspace = config.choose_keyspace(&mut self.rng);
+        let verb = keyspace.choose_command(&mut self.rng, buf).verb();
+        match verb {
+            Verb::Get => {
+                metrics::REQUEST_GET.increment();
+                self.find(buf, keyspace, &mut outcome);
+            }
+            Verb::Set => {
+                self.replace(buf, keyspace, &mut outcome);
+            }
+            Verb::Delete => {
+                self.delete(buf, keyspace, &mut outcome);
+            }
+            _ => {
+                unimplemented!()
+            }
+        }
+        (verb, outcome)
+    }
+
+    fn decode(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        // no-copy borrow as a slice
+        let buf: &[u8] = (*buffer).buffer();
+        if buf.len() < HEADER_LEN {
+            return Err(ParseError::Incomplete);
+        }
+        let len = i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        let opcode = i32::from_le_bytes([buf[12], buf[13], buf[14], buf[15]]);
+        if opcode != OP_MSG || len < HEADER_LEN + 5 {
+            return Err(ParseError::Unknown);
+        }
+        if buf.len() < len {
+            return Err(ParseError::Incomplete);
+        }
+
+        let flags = u32::from_le_bytes([buf[16], buf[17], buf[18], buf[19]]);
+        let end = if flags & FLAG_CHECKSUM_PRESENT != 0 {
+            len - 4
+        } else {
+            len
+        };
+        if end <= HEADER_LEN + 5 || buf[20] != SECTION_BODY {
+            return Err(ParseError::Unknown);
+        }
+        let reply = document(&buf[21..end])?;
+
+        let mut ok = false;
+        let mut code = None;
+        let mut hits = 0;
+        let mut write_errors = false;
+        let mut write_concern_error = false;
+        for element in Elements::new(reply) {
+            let (kind, name, value) = element?;
+            match name {
+                b"ok" => ok = number(kind, value) == Some(1),
+                b"code" => code = number(kind, value),
+                b"cursor" if kind == BSON_DOCUMENT => {
+                    for element in Elements::new(document(value)?) {
+                        let (kind, name, value) = element?;
+                        if name == b"firstBatch" && kind == BSON_ARRAY {
+                            for document in Elements::new(document(value)?) {
+                                document?;
+                                hits += 1;
+                            }
+                        }
+                    }
+                }
+                b"writeErrors" => write_errors = true,
+                b"writeConcernError" => write_concern_error = true,
+                _ => {}
+            }
+        }
+
+        let mut outcome = Outcome::default();
+        if !ok {
+            match code {
+                Some(code) if ERROR_RATE_LIMITED.contains(&(code as i32)) => {
+                    metrics::RESPONSE_THROTTLED.increment();
+                }
+                _ => {
+                    outcome.error();
+                }
+            }
+        } else if write_errors {
+            outcome.error();
+        } else if write_concern_error {
+            metrics::RESPONSE_WRITE_CONCERN_EX.increment();
+        } else {
+            for _ in 0..hits {
+                outcome.hit();
+            }
+        }
+
+        buffer.consume(len);
+        Ok(outcome)
+    }
+}
+
+/// Builds a BSON document
+struct Document {
+    buf: Vec<u8>,
+}
+
+impl Document {
+    fn new() -> Self {
+        Self {
+            buf: vec![0, 0, 0, 0],
+        }
+    }
+
+    fn key(&mut self, kind: u8, key: &str) {
+        self.buf.push(kind);
+        self.buf.extend_from_slice(key.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn string(&mut self, key: &str, value: &str) {
+        self.key(BSON_STRING, key);
+        self.buf
+            .extend_from_slice(&(value.len() as i32 + 1).to_le_bytes());
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn binary(&mut self, key: &str, value: &[u8]) {
+        self.key(BSON_BINARY, key);
+        self.buf
+            .extend_from_slice(&(value.len() as i32).to_le_bytes());
+        // the generic binary subtype
+        self.buf.push(0);
+        self.buf.extend_from_slice(value);
+    }
+
+    fn int32(&mut self, key: &str, value: i32) {
+        self.key(BSON_INT32, key);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn boolean(&mut self, key: &str, value: bool) {
+        self.key(BSON_BOOLEAN, key);
+        self.buf.push(value as u8);
+    }
+
+    /// A UTC datetime, in milliseconds since the epoch
+    fn datetime(&mut self, key: &str, value: i64) {
+        self.key(BSON_DATETIME, key);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Add a document or an array, which is a document with the indexes as
+    /// its keys
+    fn document(&mut self, kind: u8, key: &str, value: Document) {
+        self.key(kind, key);
+        self.buf.extend_from_slice(&value.finish());
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.buf.push(0);
+        let len = (self.buf.len() as i32).to_le_bytes();
+        self.buf[0..4].copy_from_slice(&len);
+        self.buf
+    }
+}
+
+/// The elements of a BSON document, without its length and terminator
+fn document(buf: &[u8]) -> Result<&[u8], ParseError> {
+    if buf.len() < 5 {
+        return Err(ParseError::Unknown);
+    }
+    let len = i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    if len < 5 || len > buf.len() || buf[len - 1] != 0 {
+        return Err(ParseError::Unknown);
+    }
+    Ok(&buf[4..(len - 1)])
+}
+
+/// The value of a numeric element, as returned for fields like `ok` which
+/// servers send as either doubles or integers
+fn number(kind: u8, value: &[u8]) -> Option<i64> {
+    match kind {
+        BSON_DOUBLE => value.try_into().ok().map(|v| f64::from_le_bytes(v) as i64),
+        BSON_INT32 => value.try_into().ok().map(|v| i32::from_le_bytes(v) as i64),
+        BSON_INT64 => value.try_into().ok().map(i64::from_le_bytes),
+        BSON_BOOLEAN => value.first().map(|v| *v as i64),
+        _ => None,
+    }
+}
+
+/// Iterates over the type, name, and value of each element in a document
+struct Elements<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Elements<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    /// The length of a value which starts with its length as an int32, plus
+    /// any bytes which follow without being counted. A negative length is
+    /// rejected.
+    fn prefixed(&self, start: usize, extra: usize) -> Option<usize> {
+        let len = self.buf.get(start..start.checked_add(4)?)?;
+        usize::try_from(i32::from_le_bytes(len.try_into().ok()?))
+            .ok()?
+            .checked_add(extra)
+    }
+
+    /// The length of a cstring, including its terminator
+    fn cstring(&self, start: usize) -> Option<usize> {
+        self.buf
+            .get(start..)?
+            .iter()
+            .position(|b| *b == 0)
+            .map(|end| end + 1)
+    }
+
+    fn value_len(&self, kind: u8, start: usize) -> Option<usize> {
+        match kind {
+            // double, datetime, timestamp, and int64
+            0x01 | 0x09 | 0x11 | 0x12 => Some(8),
+            // string, javascript, and symbol
+            0x02 | 0x0D | 0x0E => self.prefixed(start, 4),
+            // document, array, and javascript with scope
+            0x03 | 0x04 | 0x0F => self.prefixed(start, 0),
+            // binary, with its subtype
+            0x05 => self.prefixed(start, 5),
+            // undefined, null, min key, and max key
+            0x06 | 0x0A | 0x7F | 0xFF => Some(0),
+            0x07 => Some(12),
+            0x08 => Some(1),
+            // regular expression, as a pattern and options
+            0x0B => {
+                let pattern = self.cstring(start)?;
+                Some(pattern + self.cstring(start + pattern)?)
+            }
+            // db pointer
+            0x0C => self.prefixed(start, 16),
+            0x10 => Some(4),
+            0x13 => Some(16),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Iterator for Elements<'a> {
+    type Item = Result<(u8, &'a [u8], &'a [u8]), ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let kind = *self.buf.first()?;
+        let element = self.cstring(1).and_then(|name| {
+            let start = 1 + name;
+            let end = start.checked_add(self.value_len(kind, start)?)?;
+            let value = self.buf.get(start..end)?;
+            Some((&self.buf[1..name], value, end))
+        });
+        match element {
+            Some((name, value, end)) => {
+                self.buf = &self.buf[end..];
+                Some(Ok((kind, name, value)))
+            }
+            None => {
+                self.buf = &[];
+                Some(Err(ParseError::Unknown))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn elements(buf: &[u8]) -> Vec<<Elements<'_> as Iterator>::Item> {
+        Elements::new(buf).collect()
+    }
+
+    #[test]
+    fn bson() {
+        let mut nested = Document::new();
+        nested.int32("n", 1);
+        let mut doc = Document::new();
+        doc.string("s", "value");
+        doc.binary("b", b"key");
+        doc.document(BSON_DOCUMENT, "d", nested);
+        let doc = doc.finish();
+
+        let body = document(&doc).unwrap();
+        let parsed = elements(body);
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(
+            parsed[0],
+            Ok((BSON_STRING, &b"s"[..], &b"\x06\0\0\0value\0"[..]))
+        );
+        assert_eq!(
+            parsed[1],
+            Ok((BSON_BINARY, &b"b"[..], &b"\x03\0\0\0\0key"[..]))
+        );
+        assert!(matches!(parsed[2], Ok((BSON_DOCUMENT, b"d", _))));
+
+        // the document's length runs past the buffer
+        assert_eq!(document(&doc[..doc.len() - 1]), Err(ParseError::Unknown));
+        assert_eq!(document(&doc[..4]), Err(ParseError::Unknown));
+    }
+
+    #[test]
+    fn bson_lengths() {
+        let element = |len: i32| {
+            let mut buf = vec![BSON_STRING, b's', 0];
+            buf.extend_from_slice(&len.to_le_bytes());
+            buf.extend_from_slice(b"value\0");
+            buf
+        };
+        assert!(elements(&element(6))[0].is_ok());
+
+        // a value which runs past the end of the document
+        assert_eq!(elements(&element(7)), vec![Err(ParseError::Unknown)]);
+        assert_eq!(elements(&element(i32::MAX)), vec![Err(ParseError::Unknown)]);
+        // negative lengths
+        assert_eq!(elements(&element(-1)), vec![Err(ParseError::Unknown)]);
+        assert_eq!(elements(&element(-4)), vec![Err(ParseError::Unknown)]);
+        assert_eq!(elements(&element(i32::MIN)), vec![Err(ParseError::Unknown)]);
+
+        // a length which is cut short
+        let truncated = element(6);
+        assert_eq!(elements(&truncated[..5]), vec![Err(ParseError::Unknown)]);
+        // a name without its terminator
+        assert_eq!(
+            elements(&[BSON_INT32, b'n']),
+            vec![Err(ParseError::Unknown)]
+        );
+        // an unknown type
+        assert_eq!(
+            elements(&[0x42, b'n', 0, 0]),
+            vec![Err(ParseError::Unknown)]
+        );
+    }
+}
@@ -3,28 +3,67 @@
 // http://www.apache.org/licenses/LICENSE-2.0
 
 use crate::codec::*;
+use crate::config_file::{Transport, Verb};
 use crate::Session;
 use crate::*;
 use std::io::{BufRead, Write};
 
-pub struct Ping;
+const PING: &[u8] = b"PING\r\n";
+
+/// Over udp, each ping is prefixed with its length, which the transport
+/// strips from each datagram it sends and adds to each it receives. Echo
+/// servers are supported by taking a ping which comes back as its response.
+pub struct Ping {
+    datagrams: bool,
+}
 
 impl Ping {
-    pub fn new(_config: Arc<Config>) -> Self {
-        Self
+    pub fn new(config: Arc<Config>) -> Self {
+        Self {
+            datagrams: config.protocol().ping().transport() == Transport::Udp,
+        }
     }
 
-    fn ping(buf: &mut Session) {
-        let _ = buf.write_all(b"PING\r\n");
+    fn ping(&self, buf: &mut Session) {
+        if self.datagrams {
+            let _ = buf.write_all(&(PING.len() as u16).to_be_bytes());
+        }
+        let _ = buf.write_all(PING);
+    }
+
+    fn decode_datagram(buffer: &mut Session) -> Result<Outcome, ParseError> {
+        let buf: &[u8] = (*buffer).buffer();
+        if buf.len() < 2 {
+            return Err(ParseError::Incomplete);
+        }
+        let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+        if buf.len() < 2 + len {
+            return Err(ParseError::Incomplete);
+        }
+        // the line ending is optional, as each datagram is a whole response
+        let response = &buf[2..(2 + len)];
+        let response = response.strip_suffix(b"\r\n").unwrap_or(response);
+        match response {
+            b"pong" | b"PONG" | b"ping" | b"PING" => {
+                buffer.consume(2 + len);
+                Ok(Outcome::default())
+            }
+            _ => Err(ParseError::Unknown),
+        }
     }
 }
 
 impl Codec for Ping {
-    fn encode(&mut self, buf: &mut Session) {
-        Self::ping(buf)
+    fn encode(&mut self, buf: &mut Session) -> (Verb, Outcome) {
+        self.ping(buf);
+        (Verb::Ping, Outcome::default())
     }
 
-    fn decode(&self, buffer: &mut Session) -> Result<(), ParseError> {
+    fn decode(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        if self.datagrams {
+            return Self::decode_datagram(buffer);
+        }
+
         // no-copy borrow as a slice
         let buf: &[u8] = (*buffer).buffer();
 
@@ -33,8 +72,8 @@ impl Codec for Ping {
         if let Some(response_end) = double_byte_windows.position(|w| w == b"\r\n") {
             match &buf[0..response_end] {
                 b"pong" | b"PONG" => {
-                    let _ = buffer.consume(response_end + 2);
-                    Ok(())
+                    buffer.consume(response_end + 2);
+                    Ok(Outcome::default())
                 }
                 _ => Err(ParseError::Unknown),
             }
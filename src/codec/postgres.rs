@@ -0,0 +1,577 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+use crate::codec::*;
+use crate::config::*;
+use crate::config_file::Verb;
+use crate::*;
+
+use boring::base64::{decode_block, encode_block};
+use boring::hash::{hash, MessageDigest};
+use boring::memcmp;
+use boring::pkcs5::pbkdf2_hmac;
+use boring::sha::sha256;
+use rand::distributions::Alphanumeric;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+use std::io::{BufRead, Write};
+
+const PROTOCOL_VERSION: i32 = 196608;
+
+const AUTH_OK: i32 = 0;
+const AUTH_CLEARTEXT: i32 = 3;
+const AUTH_MD5: i32 = 5;
+const AUTH_SASL: i32 = 10;
+const AUTH_SASL_CONTINUE: i32 = 11;
+const AUTH_SASL_FINAL: i32 = 12;
+
+const SCRAM: &str = "SCRAM-SHA-256";
+/// The length of the client's SCRAM nonce, which the server echoes at the
+/// start of its own
+const SCRAM_NONCE_LEN: usize = 24;
+
+/// Errors in this class mean the server is out of connections, memory, or
+/// disk, and are counted as throttling
+const SQLSTATE_INSUFFICIENT_RESOURCES: &[u8] = b"53";
+
+/// Maps get, set, and delete onto prepared statements against a table of
+/// bytea keys and values, using the extended query protocol. Each connection
+/// starts up, authenticates, and prepares the statements before it is used
+/// for requests. Each request binds and executes one statement and is
+/// followed by a sync, so requests can be pipelined.
+pub struct Postgres {
+    config: Arc<Config>,
+    rng: SmallRng,
+    user: String,
+    database: String,
+    password: Option<String>,
+    statements: [(&'static str, String); 3],
+}
+
+impl Postgres {
+    pub fn new(config: Arc<Config>) -> Self {
+        let postgres = config.protocol().postgres();
+        let password = postgres
+            .password()
+            .map(|password| match crate::oauth::resolve(&password) {
+                Ok(password) => password,
+                Err(e) => {
+                    fatal!("failed to get postgres password: {}", e);
+                }
+            });
+        let (table, key, value) = (
+            postgres.table(),
+            postgres.key_column(),
+            postgres.value_column(),
+        );
+        Self {
+            config,
+            rng: SmallRng::from_entropy(),
+            user: postgres.user(),
+            database: postgres.database(),
+            password,
+            statements: [
+                ("get", format!("SELECT {value} FROM {table} WHERE {key} = $1")),
+                (
+                    "set",
+                    format!(
+                        "INSERT INTO {table} ({key}, {value}) VALUES ($1, $2) ON CONFLICT ({key}) DO UPDATE SET {value} = EXCLUDED.{value}"
+                    ),
+                ),
+                ("delete", format!("DELETE FROM {table} WHERE {key} = $1")),
+            ],
+        }
+    }
+
+    /// Bind the parameters to a prepared statement and execute it. Parameters
+    /// and results are in the binary format.
+    fn execute(buf: &mut Session, statement: &str, params: &[&[u8]]) {
+        let mut body = Vec::new();
+        // the unnamed portal
+        body.push(0);
+        write_cstring(&mut body, statement);
+        body.extend_from_slice(&1i16.to_be_bytes());
+        body.extend_from_slice(&1i16.to_be_bytes());
+        body.extend_from_slice(&(params.len() as i16).to_be_bytes());
+        for param in params {
+            body.extend_from_slice(&(param.len() as i32).to_be_bytes());
+            body.extend_from_slice(param);
+        }
+        body.extend_from_slice(&1i16.to_be_bytes());
+        body.extend_from_slice(&1i16.to_be_bytes());
+        let _ = buf.write_all(&message(b'B', &body));
+
+        // the unnamed portal, with no limit on the rows returned
+        let _ = buf.write_all(&message(b'E', &[0, 0, 0, 0, 0]));
+        let _ = buf.write_all(&message(b'S', &[]));
+    }
+
+    /// Answer an authentication request from the server during startup
+    fn authenticate(&self, buf: &mut Session, code: i32, data: &[u8]) -> Result<(), ParseError> {
+        let password = match self.password {
+            Some(ref password) => password,
+            None => {
+                debug!("postgres server requires a password, which is not configured");
+                return Err(ParseError::Error);
+            }
+        };
+        match code {
+            AUTH_CLEARTEXT => {
+                let mut body = Vec::new();
+                write_cstring(&mut body, password);
+                let _ = buf.write_all(&message(b'p', &body));
+            }
+            AUTH_MD5 => {
+                let inner = md5_hex(&[password.as_bytes(), self.user.as_bytes()].concat());
+                let outer = md5_hex(&[inner.as_bytes(), data].concat());
+                let mut body = Vec::new();
+                write_cstring(&mut body, &format!("md5{outer}"));
+                let _ = buf.write_all(&message(b'p', &body));
+            }
+            AUTH_SASL => {
+                if !data.split(|b| *b == 0).any(|m| m == SCRAM.as_bytes()) {
+                    debug!("postgres server does not offer {}", SCRAM);
+                    return Err(ParseError::Error);
+                }
+                let nonce: String = rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(SCRAM_NONCE_LEN)
+                    .map(char::from)
+                    .collect();
+                let first = format!("n,,n=,r={nonce}");
+                let mut body = Vec::new();
+                write_cstring(&mut body, SCRAM);
+                body.extend_from_slice(&(first.len() as i32).to_be_bytes());
+                body.extend_from_slice(first.as_bytes());
+                let _ = buf.write_all(&message(b'p', &body));
+                buf.set_setup_state(Some(nonce.into_bytes()));
+            }
+            AUTH_SASL_CONTINUE => {
+                let nonce = buf.setup_state().unwrap_or_default();
+                // the user is taken from the startup message instead
+                let (last, signature) =
+                    scram_final("", password, nonce, data).ok_or_else(|| {
+                        debug!("bad scram challenge from postgres server");
+                        ParseError::Error
+                    })?;
+                let _ = buf.write_all(&message(b'p', last.as_bytes()));
+                // the server proves that it knows the password too
+                buf.set_setup_state(Some(signature.to_vec()));
+            }
+            _ => {
+                debug!("postgres authentication method is not supported: {}", code);
+                return Err(ParseError::Error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle the messages which set up the connection. Authentication
+    /// requests are answered as they arrive, and the statements are prepared
+    /// once the server is ready for queries.
+    fn decode_setup(&self, buffer: &mut Session) -> Result<(), ParseError> {
+        let buf: &[u8] = (*buffer).buffer();
+        let mut position = 0;
+        let mut prepared = false;
+        loop {
+            let (kind, body, len) = next_message(&buf[position..])?;
+            position += len;
+            match kind {
+                b'R' => {
+                    let code = body
+                        .get(..4)
+                        .map(|code| i32::from_be_bytes([code[0], code[1], code[2], code[3]]))
+                        .ok_or(ParseError::Unknown)?;
+                    if code == AUTH_SASL_FINAL {
+                        let verified = body[4..]
+                            .strip_prefix(b"v=")
+                            .and_then(|signature| {
+                                decode_block(&String::from_utf8_lossy(signature)).ok()
+                            })
+                            .zip(buffer.setup_state())
+                            .is_some_and(|(signature, expected)| {
+                                signature.len() == expected.len()
+                                    && memcmp::eq(&signature, expected)
+                            });
+                        if !verified {
+                            debug!("postgres server signature did not verify");
+                            return Err(ParseError::Error);
+                        }
+                    } else if code != AUTH_OK {
+                        let data = body[4..].to_vec();
+                        buffer.consume(position);
+                        return self.authenticate(buffer, code, &data);
+                    }
+                }
+                b'E' => {
+                    debug!("postgres connection setup failed: {}", error_message(body));
+                    return Err(ParseError::Error);
+                }
+                // the statements are prepared
+                b'1' => prepared = true,
+                b'Z' => break,
+                // parameter status, backend key data, and notices
+                _ => {}
+            }
+        }
+        buffer.consume(position);
+        if !prepared {
+            for (name, query) in &self.statements {
+                let mut body = Vec::new();
+                write_cstring(&mut body, name);
+                write_cstring(&mut body, query);
+                // the types of the parameters are taken from the table
+                body.extend_from_slice(&0i16.to_be_bytes());
+                let _ = buffer.write_all(&message(b'P', &body));
+            }
+            let _ = buffer.write_all(&message(b'S', &[]));
+        }
+        Ok(())
+    }
+}
+
+impl Codec for Postgres {
+    fn encode(&mut self, buf: &mut Session) -> (Verb, Outcome) {
+        let mut outcome = Outcome::default();
+        let keyspace = self.config.choose_keyspace(&mut self.rng);
+        let verb = keyspace.choose_command(&mut self.rng, buf).verb();
+        let key = keyspace.generate_key(&mut self.rng, buf.shard(), &mut outcome);
+        match verb {
+            Verb::Get => {
+                metrics::REQUEST_GET.increment();
+                Self::execute(buf, "get", &[&key]);
+            }
+            Verb::Set => {
+                let value = keyspace.generate_value(&mut self.rng).unwrap_or_default();
+                Self::execute(buf, "set", &[&key, &value]);
+            }
+            Verb::Delete => {
+                Self::execute(buf, "delete", &[&key]);
+            }
+            _ => {
+                unimplemented!()
+            }
+        }
+        (verb, outcome)
+    }
+
+    fn setup(&mut self, buf: &mut Session) -> bool {
+        let mut body = Vec::new();
+        body.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        write_cstring(&mut body, "user");
+        write_cstring(&mut body, &self.user);
+        write_cstring(&mut body, "database");
+        write_cstring(&mut body, &self.database);
+        body.push(0);
+        // the startup message has no type
+        let _ = buf.write_all(&((body.len() + 4) as i32).to_be_bytes());
+        let _ = buf.write_all(&body);
+        true
+    }
+
+    fn decode(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        if buffer.is_setting_up() {
+            return self.decode_setup(buffer).map(|_| Outcome::default());
+        }
+
+        // no-copy borrow as a slice
+        let buf: &[u8] = (*buffer).buffer();
+        let mut position = 0;
+        let mut rows = 0;
+        let mut error = None;
+        loop {
+            let (kind, body, len) = next_message(&buf[position..])?;
+            position += len;
+            match kind {
+                b'D' => rows += 1,
+                b'E' => error = Some(sqlstate(body)),
+                // the sync which follows each request
+                b'Z' => break,
+                // bind and command complete, and notices
+                _ => {}
+            }
+        }
+
+        let mut outcome = Outcome::default();
+        match error {
+            Some(Some(code)) if code.starts_with(SQLSTATE_INSUFFICIENT_RESOURCES) => {
+                metrics::RESPONSE_THROTTLED.increment();
+            }
+            Some(_) => {
+                outcome.error();
+            }
+            None => {
+                if buffer.command() == Some(Verb::Get) && rows > 0 {
+                    outcome.hit();
+                }
+            }
+        }
+
+        buffer.consume(position);
+        Ok(outcome)
+    }
+}
+
+/// A message with its type and length
+fn message(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(5 + body.len());
+    message.push(kind);
+    message.extend_from_slice(&((body.len() + 4) as i32).to_be_bytes());
+    message.extend_from_slice(body);
+    message
+}
+
+fn write_cstring(buf: &mut Vec<u8>, value: &str) {
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(0);
+}
+
+/// The type and body of the next message, with its total length
+fn next_message(buf: &[u8]) -> Result<(u8, &[u8], usize), ParseError> {
+    if buf.len() < 5 {
+        return Err(ParseError::Incomplete);
+    }
+    let len = i32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+    if len < 4 {
+        return Err(ParseError::Unknown);
+    }
+    let end = 1 + len as usize;
+    if buf.len() < end {
+        return Err(ParseError::Incomplete);
+    }
+    Ok((buf[0], &buf[5..end], end))
+}
+
+/// The fields of an error response, by their type
+fn error_fields(body: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    body.split(|b| *b == 0)
+        .filter(|field| !field.is_empty())
+        .map(|field| (field[0], &field[1..]))
+}
+
+/// The SQLSTATE code of an error response
+fn sqlstate(body: &[u8]) -> Option<&[u8]> {
+    error_fields(body)
+        .find(|(kind, _)| *kind == b'C')
+        .map(|(_, code)| code)
+}
+
+/// The message of an error response
+fn error_message(body: &[u8]) -> String {
+    let field = |kind| {
+        error_fields(body)
+            .find(|(k, _)| *k == kind)
+            .map(|(_, value)| String::from_utf8_lossy(value).to_string())
+            .unwrap_or_default()
+    };
+    format!("{}: {}", field(b'C'), field(b'M'))
+}
+
+fn md5_hex(data: &[u8]) -> String {
+    hash(MessageDigest::md5(), data)
+        .expect("md5 failure")
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = [0; 32];
+    let mut len = 0;
+    // the output has room for a sha256 digest, which is all HMAC writes
+    let out = unsafe {
+        boring_sys::HMAC(
+            boring_sys::EVP_sha256(),
+            key.as_ptr() as *const _,
+            key.len(),
+            data.as_ptr(),
+            data.len(),
+            mac.as_mut_ptr(),
+            &mut len,
+        )
+    };
+    assert!(!out.is_null(), "hmac failure");
+    mac
+}
+
+/// The client's final SCRAM message, with its proof, and the signature the
+/// server proves itself with, from the server's first message. The server's
+/// nonce must extend the client's.
+fn scram_final(
+    user: &str,
+    password: &str,
+    client_nonce: &[u8],
+    server_first: &[u8],
+) -> Option<(String, [u8; 32])> {
+    let server_first = std::str::from_utf8(server_first).ok()?;
+    let attribute = |name: &str| {
+        server_first
+            .split(',')
+            .find_map(|attribute| attribute.strip_prefix(name))
+    };
+    let nonce = attribute("r=")?;
+    if client_nonce.is_empty()
+        || nonce.len() <= client_nonce.len()
+        || !nonce.as_bytes().starts_with(client_nonce)
+    {
+        return None;
+    }
+    let salt = decode_block(attribute("s=")?).ok()?;
+    let iterations: usize = attribute("i=")?.parse().ok()?;
+    let client_first = format!("n={user},r={}", std::str::from_utf8(client_nonce).ok()?);
+
+    let mut salted = [0; 32];
+    pbkdf2_hmac(
+        password.as_bytes(),
+        &salt,
+        iterations,
+        MessageDigest::sha256(),
+        &mut salted,
+    )
+    .ok()?;
+    let client_key = hmac_sha256(&salted, b"Client Key");
+    let stored_key = sha256(&client_key);
+    // the gs2 header of `n,,` is `biws` when encoded
+    let without_proof = format!("c=biws,r={nonce}");
+    let auth_message = format!("{client_first},{server_first},{without_proof}");
+    let signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+    let proof: Vec<u8> = client_key
+        .iter()
+        .zip(signature.iter())
+        .map(|(key, signature)| key ^ signature)
+        .collect();
+    let server_key = hmac_sha256(&salted, b"Server Key");
+    let server_signature = hmac_sha256(&server_key, auth_message.as_bytes());
+    Some((
+        format!("{without_proof},p={}", encode_block(&proof)),
+        server_signature,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::tests::{config, receive, session};
+
+    // the example exchange from RFC 7677, for the user `user` and the
+    // password `pencil`
+    const CLIENT_NONCE: &[u8] = b"rOprNGfwEbeRWgbNEkqO";
+    const SERVER_FIRST: &[u8] =
+        b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+
+    fn auth(code: i32, data: &[u8]) -> Vec<u8> {
+        message(b'R', &[&code.to_be_bytes(), data].concat())
+    }
+
+    /// The types of the messages written to the session
+    fn sent(session: &Session) -> Vec<u8> {
+        let mut buf = session.unsent();
+        let mut kinds = Vec::new();
+        while !buf.is_empty() {
+            let (kind, _, len) = next_message(buf).unwrap();
+            kinds.push(kind);
+            buf = &buf[len..];
+        }
+        kinds
+    }
+
+    #[test]
+    fn scram() {
+        let (last, signature) = scram_final("user", "pencil", CLIENT_NONCE, SERVER_FIRST).unwrap();
+        assert_eq!(
+            last,
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+        assert_eq!(
+            encode_block(&signature),
+            "6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="
+        );
+    }
+
+    #[test]
+    fn scram_nonce() {
+        // the server's nonce must extend the client's
+        assert!(scram_final("user", "pencil", b"wPmjdQVZrMvFmAzhNcjO", SERVER_FIRST).is_none());
+        assert!(scram_final(
+            "user",
+            "pencil",
+            CLIENT_NONCE,
+            b"r=rOprNGfwEbeRWgbNEkqO,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+        )
+        .is_none());
+        assert!(scram_final("user", "pencil", b"", SERVER_FIRST).is_none());
+    }
+
+    #[test]
+    fn server_signature() {
+        let postgres = Postgres::new(config("postgres.toml"));
+        let (_, signature) = scram_final("user", "pencil", CLIENT_NONCE, SERVER_FIRST).unwrap();
+        // a session waiting for the server's final message
+        let waiting = || {
+            let mut session = session();
+            session.set_setting_up(true);
+            session.set_setup_state(Some(signature.to_vec()));
+            session
+        };
+        let fin = |signature: &[u8]| {
+            let v = format!("v={}", encode_block(signature));
+            auth(AUTH_SASL_FINAL, v.as_bytes())
+        };
+
+        // a server which does not know the password is rejected
+        let mut forged = signature;
+        forged[0] ^= 1;
+        let mut rejected = waiting();
+        receive(&mut rejected, &fin(&forged));
+        assert_eq!(postgres.decode(&mut rejected), Err(ParseError::Error));
+
+        let mut verified = waiting();
+        receive(&mut verified, &fin(&signature));
+        receive(&mut verified, &auth(AUTH_OK, &[]));
+        receive(&mut verified, &message(b'Z', b"I"));
+        assert_eq!(postgres.decode(&mut verified), Ok(Outcome::default()));
+        assert_eq!(sent(&verified), b"PPPS");
+    }
+
+    #[test]
+    fn setup() {
+        let postgres = Postgres::new(config("postgres.toml"));
+        let mut session = session();
+        session.set_setting_up(true);
+
+        let mut startup = auth(AUTH_OK, &[]);
+        startup.extend(message(b'S', b"server_version\x0016.2\x00"));
+        startup.extend(message(b'K', &[0; 8]));
+        startup.extend(message(b'Z', b"I"));
+        let (head, tail) = startup.split_at(startup.len() - 1);
+        receive(&mut session, head);
+        assert_eq!(postgres.decode(&mut session), Err(ParseError::Incomplete));
+        assert_eq!(session.read_pending(), head.len());
+        receive(&mut session, tail);
+        assert_eq!(postgres.decode(&mut session), Ok(Outcome::default()));
+        assert_eq!(session.read_pending(), 0);
+        // each statement is prepared, followed by a sync
+        assert_eq!(sent(&session), b"PPPS");
+
+        // the statements are not prepared again once they are answered
+        session.mark_sent(session.write_pending());
+        for _ in 0..3 {
+            receive(&mut session, &message(b'1', &[]));
+        }
+        receive(&mut session, &message(b'Z', b"I"));
+        assert_eq!(postgres.decode(&mut session), Ok(Outcome::default()));
+        assert_eq!(session.read_pending(), 0);
+        assert_eq!(session.write_pending(), 0);
+
+        // an error closes the connection
+        let mut error = Vec::new();
+        for field in ["SFATAL", "C28P01", "Mpassword authentication failed"] {
+            write_cstring(&mut error, field);
+        }
+        error.push(0);
+        receive(&mut session, &message(b'E', &error));
+        assert_eq!(postgres.decode(&mut session), Err(ParseError::Error));
+    }
+}
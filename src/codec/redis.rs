@@ -4,15 +4,22 @@
 
 use crate::codec::*;
 use crate::config::*;
-use crate::config_file::{Protocol, Verb};
+use crate::config_file::{Protocol, TransactionMode, Verb};
 use crate::*;
 
 use rand::rngs::SmallRng;
-use rand::SeedableRng;
+use rand::{Rng, SeedableRng};
 
 use std::io::{BufRead, Write};
+use std::ops::Range;
 use std::str;
 
+/// The commands sent to name a new connection, which are pipelined
+const SETUP_COMMANDS: usize = 3;
+
+/// The command, key, and other arguments of a command in a transaction
+type Operation = (&'static str, Vec<u8>, Vec<Vec<u8>>);
+
 pub enum Mode {
     Inline,
     Resp,
@@ -22,6 +29,15 @@ pub struct Redis {
     config: Arc<Config>,
     mode: Mode,
     rng: SmallRng,
+    validate: bool,
+    // stores are acknowledged when any keyspace mutates its values
+    mutation: bool,
+    // whether any keyspace sends its transactions with MULTI and EXEC
+    multi: bool,
+    // connections are named with this prefix and their index
+    client_name: Option<String>,
+    // the scripts of every keyspace, which are loaded on each connection
+    scripts: Vec<LuaScript>,
 }
 
 impl Redis {
@@ -33,10 +49,126 @@ impl Redis {
                 fatal!("protocol: {:?} is not a redis protocol", unknown);
             }
         };
+        let validate = config.general().validate();
+        let mutation = config.keyspaces().iter().any(|k| k.mutates_values());
+        let multi = config.keyspaces().iter().any(|k| {
+            k.transaction()
+                .is_some_and(|transaction| transaction.mode() == TransactionMode::Multi)
+        });
+        // client names can not contain spaces
+        let client_name = config.protocol().redis().client_name().then(|| {
+            let run_id: String = config
+                .run_id()
+                .chars()
+                .map(|c| if c.is_whitespace() { '-' } else { c })
+                .collect();
+            format!("{}-{}", NAME, run_id)
+        });
+        let scripts = config
+            .keyspaces()
+            .iter()
+            .flat_map(|keyspace| keyspace.scripts().iter().cloned())
+            .collect();
         Self {
             config,
             mode,
             rng: SmallRng::from_entropy(),
+            validate,
+            mutation,
+            multi,
+            client_name,
+            scripts,
+        }
+    }
+
+    /// Handle the responses to the commands which set up the connection.
+    /// Only naming the connection needs to succeed, as `CLIENT SETINFO` is not
+    /// supported before redis 7.2. Each script load is answered with the
+    /// script's digest.
+    fn decode_setup(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        let buf: &[u8] = (*buffer).buffer();
+        let naming = if self.client_name.is_some() {
+            SETUP_COMMANDS
+        } else {
+            0
+        };
+        let mut position = 0;
+        for step in 0..(naming + self.scripts.len()) {
+            let reply = &buf[position..];
+            let len = value(reply)?;
+            let line_end = reply.windows(2).position(|w| w == b"\r\n").unwrap_or(0);
+            let line = &reply[0..line_end];
+            if step == 0 && naming > 0 && line != b"+OK" {
+                debug!(
+                    "redis client setname failed: {}",
+                    String::from_utf8_lossy(line)
+                );
+                return Err(ParseError::Error);
+            }
+            if step < naming && !line.starts_with(b"+") && !line.starts_with(b"-") {
+                return Err(ParseError::Unknown);
+            }
+            if step >= naming && !line.starts_with(b"$") {
+                debug!(
+                    "redis script load failed: {}",
+                    String::from_utf8_lossy(line)
+                );
+                return Err(ParseError::Error);
+            }
+            position += len;
+        }
+        buffer.consume(position);
+        Ok(Outcome::default())
+    }
+
+    /// Consume the messages the server pushed ahead of the next response.
+    /// RESP3 pushes, such as pub/sub messages and client side caching
+    /// invalidations, do not answer any request. Over RESP2, pub/sub messages
+    /// are arrays of the message kind, the channel, and the message, with the
+    /// pattern ahead of the channel for a pattern subscription.
+    fn skip_pushes(buffer: &mut Session) -> Result<(), ParseError> {
+        loop {
+            let buf: &[u8] = (*buffer).buffer();
+            let resp2 = if buf.starts_with(b"*3\r\n") || buf.starts_with(b"*4\r\n") {
+                true
+            } else if buf.starts_with(b">") {
+                false
+            } else {
+                return Ok(());
+            };
+            let len = value(buf)?;
+            // the first element of a push names its kind
+            let line_end = buf.windows(2).position(|w| w == b"\r\n").unwrap_or(0);
+            let kind = match element(&buf[(line_end + 2)..len]) {
+                Ok((Some(range), _)) => {
+                    &buf[(range.start + line_end + 2)..(range.end + line_end + 2)]
+                }
+                _ => b"".as_slice(),
+            };
+            if resp2 {
+                // any other array is the response to a request
+                let pubsub = match kind {
+                    b"message" | b"smessage" => buf.starts_with(b"*3"),
+                    b"pmessage" => buf.starts_with(b"*4"),
+                    _ => false,
+                };
+                if !pubsub {
+                    return Ok(());
+                }
+            }
+            match kind {
+                b"message" | b"pmessage" | b"smessage" | b"subscribe" | b"psubscribe"
+                | b"ssubscribe" | b"unsubscribe" | b"punsubscribe" | b"sunsubscribe" => {
+                    metrics::PUSH_PUBSUB.increment();
+                }
+                b"invalidate" => {
+                    metrics::PUSH_INVALIDATE.increment();
+                }
+                _ => {
+                    metrics::PUSH_OTHER.increment();
+                }
+            }
+            buffer.consume(len);
         }
     }
 
@@ -63,25 +195,73 @@ impl Redis {
         }
     }
 
-    fn get(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
-        let args = vec![keyspace.generate_key(rng)];
-        Redis::command(buf, mode, "get", args);
+    fn get(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        validate: bool,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let (key, version) = keyspace.generate_read_key(rng, buf.shard(), outcome);
+        if validate {
+            buf.push_keys(vec![(key.clone(), version)]);
+        }
+        metrics::GET_KEY.increment();
+        Redis::command(buf, mode, "get", vec![key]);
     }
 
-    fn mget(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
+    fn mget(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        validate: bool,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
         let mut args = Vec::new();
+        let mut keys = Vec::new();
         for _ in 0..keyspace.batch_size() {
-            args.push(keyspace.generate_key(rng));
+            let (key, version) = keyspace.generate_read_key(rng, buf.shard(), outcome);
+            args.push(key.clone());
+            keys.push((key, version));
+        }
+        if validate {
+            buf.push_keys(keys);
         }
+        metrics::GET_KEY.add(args.len() as u64);
         Redis::command(buf, mode, "mget", args);
     }
 
-    fn set(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
+    fn set(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        validate: bool,
+        mutation: bool,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let write = keyspace.generate_write(rng, buf.shard(), outcome);
+        Self::store(mode, validate, mutation, keyspace, write, buf)
+    }
+
+    fn store(
+        mode: &Mode,
+        validate: bool,
+        mutation: bool,
+        keyspace: &Keyspace,
+        (key, mut value, write): (Vec<u8>, Vec<u8>, Option<VersionedWrite>),
+        buf: &mut Session,
+    ) {
         let command = "set";
-        let mut args = vec![
-            keyspace.generate_key(rng),
-            keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec()),
-        ];
+        if validate {
+            validate::seal(&key, &mut value);
+        }
+        if mutation {
+            buf.push_write(write);
+        }
+        let mut args = vec![key, value];
         let ttl = keyspace.ttl();
         if ttl != 0 {
             args.push(b"EX".to_vec());
@@ -90,15 +270,27 @@ impl Redis {
         Redis::command(buf, mode, command, args);
     }
 
-    fn del(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
-        let args = vec![keyspace.generate_key(rng)];
+    fn del(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let args = vec![keyspace.generate_key(rng, buf.shard(), outcome)];
         Redis::command(buf, mode, "del", args);
     }
 
-    fn hget(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
+    fn hget(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
         let command = "hget";
         let args = vec![
-            keyspace.generate_key(rng),
+            keyspace.generate_key(rng, buf.shard(), outcome),
             keyspace
                 .generate_inner_key(rng)
                 .unwrap_or_else(|| b"".to_vec()),
@@ -106,10 +298,50 @@ impl Redis {
         Redis::command(buf, mode, command, args);
     }
 
-    fn hset(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
+    fn hgetall(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let args = vec![keyspace.generate_key(rng, buf.shard(), outcome)];
+        Redis::command(buf, mode, "hgetall", args);
+    }
+
+    fn hset(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let fields = keyspace.choose_field_count(rng).unwrap_or(1);
+        outcome.add_elements(fields as _);
         let command = "hset";
+        let mut args = vec![keyspace.generate_key(rng, buf.shard(), outcome)];
+        for _ in 0..fields {
+            args.push(
+                keyspace
+                    .generate_inner_key(rng)
+                    .unwrap_or_else(|| b"".to_vec()),
+            );
+            args.push(keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec()));
+        }
+        Redis::command(buf, mode, command, args);
+    }
+
+    fn hsetnx(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        outcome.add_elements(1);
+        let command = "hsetnx";
         let args = vec![
-            keyspace.generate_key(rng),
+            keyspace.generate_key(rng, buf.shard(), outcome),
             keyspace
                 .generate_inner_key(rng)
                 .unwrap_or_else(|| b"".to_vec()),
@@ -118,66 +350,710 @@ impl Redis {
         Redis::command(buf, mode, command, args);
     }
 
-    fn hsetnx(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
-        let command = "hsetnx";
+    /// Send the keyspace's transaction, either queued between MULTI and EXEC
+    /// or run by a script
+    fn transaction(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        validate: bool,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        // the config requires a transaction for the transaction command
+        let transaction = match keyspace.transaction() {
+            Some(transaction) => transaction,
+            None => return,
+        };
+        let operations: Vec<Operation> = transaction
+            .commands()
+            .iter()
+            .map(|verb| Self::operation(rng, validate, keyspace, *verb, buf.shard(), outcome))
+            .collect();
+        match transaction.mode() {
+            TransactionMode::Multi => {
+                Redis::command(buf, mode, "multi", Vec::new());
+                for (command, key, mut args) in operations {
+                    args.insert(0, key);
+                    Redis::command(buf, mode, command, args);
+                }
+                Redis::command(buf, mode, "exec", Vec::new());
+            }
+            TransactionMode::Eval => {
+                // the keys are passed in KEYS and the other arguments in ARGV,
+                // so the script is the same for each request to the keyspace
+                let mut script = String::new();
+                let mut keys = Vec::new();
+                let mut argv = Vec::new();
+                for (command, key, args) in operations {
+                    keys.push(key);
+                    script.push_str(&format!("redis.call('{}', KEYS[{}]", command, keys.len()));
+                    for arg in args {
+                        argv.push(arg);
+                        script.push_str(&format!(", ARGV[{}]", argv.len()));
+                    }
+                    script.push_str(")\n");
+                }
+                script.push_str(&format!("return {}", keys.len()));
+                let mut args = vec![script.into_bytes(), keys.len().to_string().into_bytes()];
+                args.extend(keys);
+                args.extend(argv);
+                Redis::command(buf, mode, "eval", args);
+            }
+        }
+    }
+
+    /// A command in a transaction. Hash sets write a single field.
+    fn operation(
+        rng: &mut SmallRng,
+        validate: bool,
+        keyspace: &Keyspace,
+        verb: Verb,
+        shard: Option<(usize, usize)>,
+        outcome: &mut Outcome,
+    ) -> Operation {
+        let field = |rng: &mut SmallRng| {
+            keyspace
+                .generate_inner_key(rng)
+                .unwrap_or_else(|| b"".to_vec())
+        };
+        match verb {
+            Verb::Get => (
+                "get",
+                keyspace.generate_key(rng, shard, outcome),
+                Vec::new(),
+            ),
+            Verb::Set => {
+                let (key, mut value, _) = keyspace.generate_write(rng, shard, outcome);
+                if validate {
+                    validate::seal(&key, &mut value);
+                }
+                let mut args = vec![value];
+                let ttl = keyspace.ttl();
+                if ttl != 0 {
+                    args.push(b"EX".to_vec());
+                    args.push(format!("{}", ttl).as_bytes().to_vec());
+                }
+                ("set", key, args)
+            }
+            Verb::Delete => (
+                "del",
+                keyspace.generate_key(rng, shard, outcome),
+                Vec::new(),
+            ),
+            Verb::Hget => {
+                let key = keyspace.generate_key(rng, shard, outcome);
+                ("hget", key, vec![field(rng)])
+            }
+            Verb::Hset => {
+                outcome.add_elements(1);
+                let key = keyspace.generate_key(rng, shard, outcome);
+                let field = field(rng);
+                let value = keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec());
+                ("hset", key, vec![field, value])
+            }
+            Verb::Hdel => {
+                let key = keyspace.generate_key(rng, shard, outcome);
+                ("hdel", key, vec![field(rng)])
+            }
+            _ => {
+                unimplemented!()
+            }
+        }
+    }
+
+    /// Handle the replies to a transaction. MULTI is answered with OK and each
+    /// queued command with QUEUED, or an error if it was rejected. EXEC is
+    /// then answered with the results, or with nil or EXECABORT when the
+    /// transaction was aborted. A script sent with EVAL is answered once.
+    fn decode_transaction(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        let buf: &[u8] = (*buffer).buffer();
+        let (len, aborted) = if buf.starts_with(b"+OK\r\n") {
+            let mut position = 5;
+            loop {
+                let reply = &buf[position..];
+                position += value(reply)?;
+                let queued = reply.starts_with(b"+QUEUED")
+                    || (reply.starts_with(b"-") && !reply.starts_with(b"-EXECABORT"));
+                if !queued {
+                    let aborted = reply.starts_with(b"-")
+                        || reply.starts_with(b"*-1")
+                        || reply.starts_with(b"_");
+                    break (position, aborted);
+                }
+            }
+        } else if self.multi && buf.starts_with(b"-") {
+            // MULTI itself failed, so the commands after it ran on their own
+            // and their replies would be taken for those of later requests.
+            // The session is closed instead. A script error can't be told
+            // apart while some keyspace uses MULTI, so it closes it too.
+            value(buf)?;
+            metrics::TRANSACTION_ABORT.increment();
+            metrics::RESPONSE_EX.increment();
+            return Err(ParseError::Error);
+        } else {
+            (value(buf)?, buf.starts_with(b"-"))
+        };
+        let mut outcome = Outcome::default();
+        if aborted {
+            metrics::TRANSACTION_ABORT.increment();
+            outcome.error();
+        }
+        buffer.consume(len);
+        Ok(outcome)
+    }
+
+    /// Run one of the keyspace's scripts by its digest, or by its source if
+    /// the server has answered NOSCRIPT on this connection since the script
+    /// was last sent
+    fn evalsha(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        // the config requires scripts for the evalsha command
+        let script = match keyspace.choose_script(rng) {
+            Some(script) => script,
+            None => return,
+        };
+        let args = keyspace.generate_script_args(rng, script, buf.shard(), outcome);
+        if buf.take_stale_script(script.sha()) {
+            metrics::SCRIPT_FALLBACK.increment();
+            let mut eval = vec![script.source().as_bytes().to_vec()];
+            eval.extend(args);
+            Redis::command(buf, mode, "eval", eval);
+        } else {
+            let mut evalsha = vec![script.sha().as_bytes().to_vec()];
+            evalsha.extend(args);
+            Redis::command(buf, mode, "evalsha", evalsha);
+        }
+    }
+
+    /// Handle the reply to a script, which is an error if the script failed.
+    /// A server which restarted or flushed its scripts answers NOSCRIPT.
+    /// Which script it was is not known, so each script is sent with EVAL the
+    /// next time on this connection, which loads it again.
+    fn decode_script(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        let buf: &[u8] = (*buffer).buffer();
+        let len = value(buf)?;
+        let mut outcome = Outcome::default();
+        if buf.starts_with(b"-") {
+            outcome.error();
+        }
+        if buf.starts_with(b"-NOSCRIPT") {
+            metrics::RESPONSE_NOSCRIPT.increment();
+            buffer.set_scripts_stale(self.scripts.iter().map(|script| script.sha()));
+        }
+        buffer.consume(len);
+        Ok(outcome)
+    }
+
+    fn hdel(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let command = "hdel";
         let args = vec![
-            keyspace.generate_key(rng),
+            keyspace.generate_key(rng, buf.shard(), outcome),
             keyspace
                 .generate_inner_key(rng)
                 .unwrap_or_else(|| b"".to_vec()),
-            keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec()),
         ];
         Redis::command(buf, mode, command, args);
     }
 
-    fn hdel(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
-        let command = "hdel";
+    /// Push as many values as the batch size onto the list
+    fn push(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        command: &str,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let mut args = vec![keyspace.generate_key(rng, buf.shard(), outcome)];
+        for _ in 0..keyspace.batch_size() {
+            args.push(keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec()));
+        }
+        outcome.add_elements(args.len() as u64 - 1);
+        Redis::command(buf, mode, command, args);
+    }
+
+    /// Return as many elements as the batch size from the head of the list
+    fn lrange(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let args = vec![
+            keyspace.generate_key(rng, buf.shard(), outcome),
+            b"0".to_vec(),
+            format!("{}", keyspace.batch_size().max(1) - 1).into_bytes(),
+        ];
+        Redis::command(buf, mode, "lrange", args);
+    }
+
+    /// Truncate the list to as many elements as the batch size, keeping the
+    /// newest elements pushed onto its tail
+    fn ltrim(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let args = vec![
+            keyspace.generate_key(rng, buf.shard(), outcome),
+            format!("-{}", keyspace.batch_size().max(1)).into_bytes(),
+            b"-1".to_vec(),
+        ];
+        Redis::command(buf, mode, "ltrim", args);
+    }
+
+    /// Remove every element of the list which equals a generated value
+    fn lrem(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
         let args = vec![
-            keyspace.generate_key(rng),
+            keyspace.generate_key(rng, buf.shard(), outcome),
+            b"0".to_vec(),
+            keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec()),
+        ];
+        Redis::command(buf, mode, "lrem", args);
+    }
+
+    /// The key and a member of the set or sorted set, where the members are
+    /// the keyspace's inner keys
+    fn member(
+        rng: &mut SmallRng,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) -> Vec<Vec<u8>> {
+        vec![
+            keyspace.generate_key(rng, buf.shard(), outcome),
             keyspace
                 .generate_inner_key(rng)
                 .unwrap_or_else(|| b"".to_vec()),
+        ]
+    }
+
+    fn sadd(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let members = keyspace.choose_field_count(rng).unwrap_or(1);
+        outcome.add_elements(members as _);
+        let mut args = vec![keyspace.generate_key(rng, buf.shard(), outcome)];
+        for _ in 0..members {
+            args.push(
+                keyspace
+                    .generate_inner_key(rng)
+                    .unwrap_or_else(|| b"".to_vec()),
+            );
+        }
+        Redis::command(buf, mode, "sadd", args);
+    }
+
+    fn zadd(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let members = keyspace.choose_field_count(rng).unwrap_or(1);
+        outcome.add_elements(members as _);
+        let mut args = vec![keyspace.generate_key(rng, buf.shard(), outcome)];
+        for _ in 0..members {
+            args.push(format!("{}", rng.gen::<f64>()).into_bytes());
+            args.push(
+                keyspace
+                    .generate_inner_key(rng)
+                    .unwrap_or_else(|| b"".to_vec()),
+            );
+        }
+        Redis::command(buf, mode, "zadd", args);
+    }
+
+    fn zincrby(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let mut args = Self::member(rng, keyspace, buf, outcome);
+        args.insert(1, b"1".to_vec());
+        Redis::command(buf, mode, "zincrby", args);
+    }
+
+    /// Range over as many members as the batch size, starting from the lowest
+    /// rank
+    fn zrange(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let args = vec![
+            keyspace.generate_key(rng, buf.shard(), outcome),
+            b"0".to_vec(),
+            format!("{}", keyspace.batch_size().max(1) - 1).into_bytes(),
+        ];
+        Redis::command(buf, mode, "zrange", args);
+    }
+
+    /// The first step of a compare-and-swap watches the key and reads it. The
+    /// value it is swapped for is written in a transaction once the read is
+    /// answered.
+    fn cas(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        validate: bool,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let (key, mut value, _) = keyspace.generate_write(rng, buf.shard(), outcome);
+        if validate {
+            validate::seal(&key, &mut value);
+        }
+        Redis::command(buf, mode, "watch", vec![key.clone()]);
+        Redis::command(buf, mode, "get", vec![key.clone()]);
+        buf.set_next_step(key, value, keyspace.ttl());
+    }
+
+    /// Handle the replies to a compare-and-swap. WATCH is answered with OK
+    /// and GET with the value, which is followed by the transaction. MULTI is
+    /// answered with OK, SET with QUEUED, and EXEC with the results, or with
+    /// nil when the key changed after it was watched. An error from WATCH or
+    /// GET closes the session.
+    fn decode_cas(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        let buf: &[u8] = (*buffer).buffer();
+        if buffer.has_next_step() {
+            let watch = value(buf)?;
+            let len = watch + value(&buf[watch..])?;
+            let error = buf.starts_with(b"-") || buf[watch..].starts_with(b"-");
+            let hit =
+                !error && !buf[watch..].starts_with(b"$-1") && !buf[watch..].starts_with(b"_");
+            buffer.consume(len);
+            let step = buffer.take_next_step();
+            if error {
+                // the key stays watched, and would make a later swap on this
+                // connection conflict, so the session is closed instead
+                metrics::RESPONSE_EX.increment();
+                return Err(ParseError::Error);
+            }
+            let mut outcome = Outcome::default();
+            if hit {
+                outcome.hit();
+            }
+            // a missing key is still swapped, which succeeds unless the key
+            // was set after it was watched
+            if let Some((key, value, ttl)) = step {
+                let mut args = vec![key, value];
+                if ttl != 0 {
+                    args.push(b"EX".to_vec());
+                    args.push(format!("{}", ttl).into_bytes());
+                }
+                Self::command(buffer, &self.mode, "multi", Vec::new());
+                Self::command(buffer, &self.mode, "set", args);
+                Self::command(buffer, &self.mode, "exec", Vec::new());
+            }
+            return Ok(outcome);
+        }
+
+        let mut len = 0;
+        for _ in 0..2 {
+            len += value(&buf[len..])?;
+        }
+        let exec = &buf[len..];
+        len += value(exec)?;
+        let mut outcome = Outcome::default();
+        if exec.starts_with(b"*-1") || exec.starts_with(b"_") {
+            metrics::CAS_CONFLICT.increment();
+        } else if exec.starts_with(b"*") {
+            metrics::CAS_SUCCESS.increment();
+        } else {
+            outcome.error();
+        }
+        buffer.consume(len);
+        Ok(outcome)
+    }
+
+    /// The first step of a read-modify-write reads the key. The value written
+    /// back is derived from the value which was read once it arrives.
+    fn read_modify_write(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let (key, value, _) = keyspace.generate_write(rng, buf.shard(), outcome);
+        Redis::command(buf, mode, "get", vec![key.clone()]);
+        buf.set_next_step(key, value, keyspace.ttl());
+    }
+
+    /// Handle the replies to a read-modify-write. GET is answered with the
+    /// value, which is followed by a SET of the derived value, or of a new
+    /// value if the key was missing, which is answered with OK.
+    fn decode_read_modify_write(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        let buf: &[u8] = (*buffer).buffer();
+        let len = value(buf)?;
+        let mut outcome = Outcome::default();
+        if buf.starts_with(b"-") {
+            buffer.consume(len);
+            let _ = buffer.take_next_step();
+            outcome.error();
+            return Ok(outcome);
+        }
+        if !buffer.has_next_step() {
+            buffer.consume(len);
+            return Ok(outcome);
+        }
+
+        let read = match element(buf) {
+            Ok((Some(range), _)) => Some(buf[range].to_vec()),
+            _ => None,
+        };
+        buffer.consume(len);
+        let (key, generated, ttl) = buffer.take_next_step().unwrap_or_default();
+        if let Some(ref read) = read {
+            outcome.hit();
+            if self.validate {
+                validate::record(&key, read, 0);
+            }
+        }
+        let mut value = modify(read.as_deref(), generated);
+        if self.validate {
+            validate::seal(&key, &mut value);
+        }
+        let mut args = vec![key, value];
+        if ttl != 0 {
+            args.push(b"EX".to_vec());
+            args.push(format!("{}", ttl).into_bytes());
+        }
+        Self::command(buffer, &self.mode, "set", args);
+        Ok(outcome)
+    }
+
+    fn counter(
+        rng: &mut SmallRng,
+        mode: &Mode,
+        command: &str,
+        keyspace: &Keyspace,
+        buf: &mut Session,
+        outcome: &mut Outcome,
+    ) {
+        let args = vec![
+            keyspace.generate_key(rng, buf.shard(), outcome),
+            format!("{}", keyspace.choose_delta(rng)).into_bytes(),
         ];
         Redis::command(buf, mode, command, args);
     }
 }
 
 impl Codec for Redis {
-    fn encode(&mut self, buf: &mut Session) {
+    fn encode(&mut self, buf: &mut Session) -> (Verb, Outcome) {
+        let mut outcome = Outcome::default();
+        let out = &mut outcome;
         let keyspace = self.config.choose_keyspace(&mut self.rng);
-        let command = keyspace.choose_command(&mut self.rng);
-        match command.verb() {
+        let verb = keyspace.choose_command(&mut self.rng, buf).verb();
+        match verb {
             Verb::Get => {
                 metrics::REQUEST_GET.increment();
                 if keyspace.batch_size() == 1 {
-                    Self::get(&mut self.rng, &self.mode, keyspace, buf)
+                    Self::get(&mut self.rng, &self.mode, self.validate, keyspace, buf, out)
                 } else {
-                    Self::mget(&mut self.rng, &self.mode, keyspace, buf)
+                    Self::mget(&mut self.rng, &self.mode, self.validate, keyspace, buf, out)
                 }
             }
-            Verb::Set => Self::set(&mut self.rng, &self.mode, keyspace, buf),
-            Verb::Delete => Self::del(&mut self.rng, &self.mode, keyspace, buf),
+            Verb::Set => Self::set(
+                &mut self.rng,
+                &self.mode,
+                self.validate,
+                self.mutation,
+                keyspace,
+                buf,
+                out,
+            ),
+            Verb::Delete => Self::del(&mut self.rng, &self.mode, keyspace, buf, out),
             Verb::Hget => {
                 metrics::REQUEST_GET.increment();
-                Self::hget(&mut self.rng, &self.mode, keyspace, buf)
+                Self::hget(&mut self.rng, &self.mode, keyspace, buf, out)
+            }
+            Verb::Hgetall => {
+                metrics::REQUEST_GET.increment();
+                Self::hgetall(&mut self.rng, &self.mode, keyspace, buf, out)
+            }
+            Verb::Hset => Self::hset(&mut self.rng, &self.mode, keyspace, buf, out),
+            Verb::Hsetnx => Self::hsetnx(&mut self.rng, &self.mode, keyspace, buf, out),
+            Verb::Hdel => Self::hdel(&mut self.rng, &self.mode, keyspace, buf, out),
+            Verb::Transaction => {
+                Self::transaction(&mut self.rng, &self.mode, self.validate, keyspace, buf, out)
+            }
+            Verb::Evalsha => Self::evalsha(&mut self.rng, &self.mode, keyspace, buf, out),
+            Verb::Lpush => Self::push(&mut self.rng, &self.mode, "lpush", keyspace, buf, out),
+            Verb::Rpush => Self::push(&mut self.rng, &self.mode, "rpush", keyspace, buf, out),
+            Verb::Rpushx => Self::push(&mut self.rng, &self.mode, "rpushx", keyspace, buf, out),
+            Verb::Lpop | Verb::Rpop => {
+                metrics::REQUEST_GET.increment();
+                let command = if verb == Verb::Lpop { "lpop" } else { "rpop" };
+                let args = vec![keyspace.generate_key(&mut self.rng, buf.shard(), out)];
+                Self::command(buf, &self.mode, command, args)
+            }
+            Verb::Lrange => {
+                metrics::REQUEST_GET.increment();
+                Self::lrange(&mut self.rng, &self.mode, keyspace, buf, out)
+            }
+            Verb::Count => {
+                let args = vec![keyspace.generate_key(&mut self.rng, buf.shard(), out)];
+                Self::command(buf, &self.mode, "llen", args)
+            }
+            Verb::Lrem => Self::lrem(&mut self.rng, &self.mode, keyspace, buf, out),
+            Verb::Ltrim => Self::ltrim(&mut self.rng, &self.mode, keyspace, buf, out),
+            Verb::Sadd => Self::sadd(&mut self.rng, &self.mode, keyspace, buf, out),
+            Verb::Smembers => {
+                metrics::REQUEST_GET.increment();
+                let args = vec![keyspace.generate_key(&mut self.rng, buf.shard(), out)];
+                Self::command(buf, &self.mode, "smembers", args)
+            }
+            Verb::Srem => {
+                let args = Self::member(&mut self.rng, keyspace, buf, out);
+                Self::command(buf, &self.mode, "srem", args)
+            }
+            Verb::Zadd => Self::zadd(&mut self.rng, &self.mode, keyspace, buf, out),
+            Verb::Zrank => {
+                metrics::REQUEST_GET.increment();
+                let args = Self::member(&mut self.rng, keyspace, buf, out);
+                Self::command(buf, &self.mode, "zrank", args)
+            }
+            Verb::Zscore => {
+                metrics::REQUEST_GET.increment();
+                let args = Self::member(&mut self.rng, keyspace, buf, out);
+                Self::command(buf, &self.mode, "zscore", args)
+            }
+            Verb::Zrem => {
+                let args = Self::member(&mut self.rng, keyspace, buf, out);
+                Self::command(buf, &self.mode, "zrem", args)
+            }
+            Verb::Zincrby => Self::zincrby(&mut self.rng, &self.mode, keyspace, buf, out),
+            Verb::Zrange => {
+                metrics::REQUEST_GET.increment();
+                Self::zrange(&mut self.rng, &self.mode, keyspace, buf, out)
+            }
+            Verb::Incr => Self::counter(&mut self.rng, &self.mode, "incrby", keyspace, buf, out),
+            Verb::Decr => Self::counter(&mut self.rng, &self.mode, "decrby", keyspace, buf, out),
+            Verb::Cas => Self::cas(&mut self.rng, &self.mode, self.validate, keyspace, buf, out),
+            Verb::ReadModifyWrite => {
+                Self::read_modify_write(&mut self.rng, &self.mode, keyspace, buf, out)
             }
-            Verb::Hset => Self::hset(&mut self.rng, &self.mode, keyspace, buf),
-            Verb::Hsetnx => Self::hsetnx(&mut self.rng, &self.mode, keyspace, buf),
-            Verb::Hdel => Self::hdel(&mut self.rng, &self.mode, keyspace, buf),
             _ => {
                 unimplemented!()
             }
         }
+        (verb, outcome)
     }
 
-    fn decode(&self, buffer: &mut Session) -> Result<(), ParseError> {
+    fn prefill(&mut self, buf: &mut Session, keyspace: usize, index: usize) {
+        let keyspace = self.config.keyspace(keyspace);
+        let write = keyspace.generate_write_for(&mut self.rng, index);
+        Self::store(
+            &self.mode,
+            self.validate,
+            self.mutation,
+            keyspace,
+            write,
+            buf,
+        )
+    }
+
+    fn setup(&mut self, buf: &mut Session) -> bool {
+        if self.client_name.is_none() && self.scripts.is_empty() {
+            return false;
+        }
+        if let Some(ref name) = self.client_name {
+            let name = format!("{}-{}", name, buf.connection());
+            let client = |args: &[&str]| args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+            Self::command(buf, &self.mode, "CLIENT", client(&["SETNAME", &name]));
+            Self::command(
+                buf,
+                &self.mode,
+                "CLIENT",
+                client(&["SETINFO", "LIB-NAME", NAME]),
+            );
+            Self::command(
+                buf,
+                &self.mode,
+                "CLIENT",
+                client(&["SETINFO", "LIB-VER", VERSION]),
+            );
+        }
+        // scripts are loaded on every connection, as each may be to a
+        // different server
+        for script in &self.scripts {
+            Self::command(
+                buf,
+                &self.mode,
+                "SCRIPT",
+                vec![b"LOAD".to_vec(), script.source().as_bytes().to_vec()],
+            );
+        }
+        true
+    }
+
+    fn decode(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        if buffer.is_setting_up() {
+            return self.decode_setup(buffer);
+        }
+
+        Self::skip_pushes(buffer)?;
+
+        if buffer.command() == Some(Verb::Transaction) {
+            return self.decode_transaction(buffer);
+        }
+
+        if buffer.command() == Some(Verb::Evalsha) {
+            return self.decode_script(buffer);
+        }
+
+        if buffer.command() == Some(Verb::Cas) {
+            return self.decode_cas(buffer);
+        }
+
+        if buffer.command() == Some(Verb::ReadModifyWrite) {
+            return self.decode_read_modify_write(buffer);
+        }
+
         // no-copy borrow as a slice
         let buf: &[u8] = (*buffer).buffer();
 
-        if buf.len() < 1 {
+        if buf.is_empty() {
             return Err(ParseError::Incomplete);
         }
 
+        // only get responses are checked, other commands return values which
+        // were not sealed
+        let validate = self.validate && buffer.command() == Some(Verb::Get);
+        let mut outcome = Outcome::default();
+
         let first_char = &buf[0..1];
         match str::from_utf8(first_char) {
             Ok("+") => {
@@ -187,8 +1063,13 @@ impl Codec for Redis {
                     let msg = &buf[1..line_end];
                     match str::from_utf8(msg) {
                         Ok("OK") | Ok("PONG") => {
-                            let _ = buffer.consume(line_end + 2);
-                            Ok(())
+                            buffer.consume(line_end + 2);
+                            if self.mutation && buffer.command() == Some(Verb::Set) {
+                                if let Some(write) = buffer.pop_write() {
+                                    self.config.acknowledge(write);
+                                }
+                            }
+                            Ok(outcome)
                         }
                         _ => Err(ParseError::Unknown),
                     }
@@ -197,8 +1078,43 @@ impl Codec for Redis {
                 }
             }
             Ok("-") => {
-                // error response
-                Err(ParseError::Error)
+                // a busy server rejects the request, and a cluster redirects
+                // it while slots move between nodes, but the connection can
+                // still be used
+                let busy = buf.starts_with(b"-BUSY");
+                let moved = buf.starts_with(b"-MOVED ");
+                let ask = buf.starts_with(b"-ASK ");
+                let recoverable = busy || moved || ask;
+                let line_end = buf.windows(2).position(|w| w == b"\r\n");
+                if recoverable && line_end.is_none() {
+                    return Err(ParseError::Incomplete);
+                }
+                // error response, the write was not stored
+                if self.mutation && buffer.command() == Some(Verb::Set) {
+                    if let Some(write) = buffer.pop_write() {
+                        self.config.reject(write);
+                    }
+                }
+                match line_end {
+                    Some(line_end) if recoverable => {
+                        if busy {
+                            metrics::RESPONSE_THROTTLED.increment();
+                        } else {
+                            if moved {
+                                metrics::RESPONSE_MOVED.increment();
+                            } else {
+                                metrics::RESPONSE_ASK.increment();
+                            }
+                            outcome.error();
+                        }
+                        buffer.consume(line_end + 2);
+                        if validate {
+                            let _ = buffer.pop_keys();
+                        }
+                        Ok(outcome)
+                    }
+                    _ => Err(ParseError::Error),
+                }
             }
             Ok(":") => {
                 // numeric response
@@ -208,8 +1124,13 @@ impl Codec for Redis {
                     match str::from_utf8(msg) {
                         Ok(msg) => match msg.parse::<i64>() {
                             Ok(_) => {
-                                let _ = buffer.consume(line_end + 2);
-                                Ok(())
+                                // a rank is only returned for a member which
+                                // was found
+                                if buffer.command() == Some(Verb::Zrank) {
+                                    outcome.hit();
+                                }
+                                buffer.consume(line_end + 2);
+                                Ok(outcome)
                             }
                             Err(_) => Err(ParseError::Unknown),
                         },
@@ -226,16 +1147,37 @@ impl Codec for Redis {
                     let msg = &buf[1..line_end];
                     match str::from_utf8(msg) {
                         Ok("-1") => {
-                            let _ = buffer.consume(line_end + 2);
-                            Ok(())
+                            buffer.consume(line_end + 2);
+                            if validate {
+                                let _ = buffer.pop_keys();
+                            }
+                            Ok(outcome)
                         }
                         Ok(n) => {
                             let len = n.parse::<usize>().map_err(|_| ParseError::Unknown)?;
                             let response_end = len + line_end + 4;
                             if response_end <= buf.len() {
-                                metrics::RESPONSE_HIT.increment();
-                                let _ = buffer.consume(response_end);
-                                Ok(())
+                                // the new score is not a lookup
+                                if buffer.command() != Some(Verb::Zincrby) {
+                                    outcome.hit();
+                                }
+                                if buffer.command() == Some(Verb::Get) {
+                                    metrics::GET_KEY_HIT.increment();
+                                }
+                                if validate {
+                                    if let Some((key, version)) = buffer.keys().first() {
+                                        validate::record(
+                                            key,
+                                            &buf[(line_end + 2)..(len + line_end + 2)],
+                                            *version,
+                                        );
+                                    }
+                                }
+                                buffer.consume(response_end);
+                                if validate {
+                                    let _ = buffer.pop_keys();
+                                }
+                                Ok(outcome)
                             } else {
                                 Err(ParseError::Incomplete)
                             }
@@ -248,21 +1190,137 @@ impl Codec for Redis {
             }
             Ok("*") => {
                 // arrays
-                let msg = &buf[1..buf.len() - 2];
-                match str::from_utf8(msg) {
-                    Ok("-1") => {
-                        let response_end = buf.len();
-                        let _ = buffer.consume(response_end);
-                        Ok(())
+                let mut lines = buf.windows(2);
+                let line_end = lines
+                    .position(|w| w == b"\r\n")
+                    .ok_or(ParseError::Incomplete)?;
+                let count = str::from_utf8(&buf[1..line_end])
+                    .ok()
+                    .and_then(|count| count.parse::<i64>().ok())
+                    .ok_or(ParseError::Unknown)?;
+                let mut response_end = line_end + 2;
+                let mut elements = Vec::new();
+                for _ in 0..count {
+                    let (element, len) = element(&buf[response_end..])?;
+                    elements.push(
+                        element
+                            .map(|range| (range.start + response_end)..(range.end + response_end)),
+                    );
+                    response_end += len;
+                }
+                if buffer.command() == Some(Verb::Hgetall) {
+                    // the fields and values are interleaved
+                    outcome.add_fields(elements.len() as u64 / 2);
+                    if !elements.is_empty() {
+                        outcome.hit();
+                    }
+                } else if buffer.command() == Some(Verb::Lrange) {
+                    metrics::RESPONSE_ELEMENT.add(elements.len() as u64);
+                    if !elements.is_empty() {
+                        outcome.hit();
+                    }
+                } else if matches!(buffer.command(), Some(Verb::Smembers | Verb::Zrange)) {
+                    metrics::RESPONSE_MEMBER.add(elements.len() as u64);
+                    if !elements.is_empty() {
+                        outcome.hit();
                     }
-                    Ok(_) => {
-                        // TODO: implement array parsing
-                        Err(ParseError::Unknown)
+                } else if buffer.command() == Some(Verb::Get) {
+                    // an mget is a hit when any of its keys are found
+                    let hits = elements.iter().filter(|element| element.is_some()).count();
+                    if hits > 0 {
+                        outcome.hit();
                     }
-                    Err(_) => Err(ParseError::Unknown),
+                    metrics::GET_KEY_HIT.add(hits as u64);
                 }
+                if validate {
+                    for ((key, version), element) in buffer.keys().iter().zip(elements) {
+                        if let Some(range) = element {
+                            validate::record(key, &buf[range], *version);
+                        }
+                    }
+                }
+                buffer.consume(response_end);
+                if validate {
+                    let _ = buffer.pop_keys();
+                }
+                Ok(outcome)
             }
             _ => Err(ParseError::Unknown),
         }
     }
 }
+
+/// Parse a single element of an array response, returning the range of the
+/// element's data if it is a non-nil bulk string and the length of the
+/// element.
+fn element(buf: &[u8]) -> Result<(Option<Range<usize>>, usize), ParseError> {
+    let mut lines = buf.windows(2);
+    let line_end = lines
+        .position(|w| w == b"\r\n")
+        .ok_or(ParseError::Incomplete)?;
+    match &buf[0..1] {
+        b"$" => {
+            let len = str::from_utf8(&buf[1..line_end])
+                .ok()
+                .and_then(|len| len.parse::<i64>().ok())
+                .ok_or(ParseError::Unknown)?;
+            if len < 0 {
+                return Ok((None, line_end + 2));
+            }
+            let start = line_end + 2;
+            let end = start + len as usize;
+            if end + 2 <= buf.len() {
+                Ok((Some(start..end), end + 2))
+            } else {
+                Err(ParseError::Incomplete)
+            }
+        }
+        b":" | b"+" => Ok((None, line_end + 2)),
+        _ => Err(ParseError::Unknown),
+    }
+}
+
+/// The length of the complete value at the start of the buffer, including any
+/// values nested in it. Covers the types added by RESP3 as well as RESP2.
+fn value(buf: &[u8]) -> Result<usize, ParseError> {
+    let line_end = buf
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .ok_or(ParseError::Incomplete)?;
+    let len = || {
+        str::from_utf8(&buf[1..line_end])
+            .ok()
+            .and_then(|len| len.parse::<i64>().ok())
+            .ok_or(ParseError::Unknown)
+    };
+    match buf[0] {
+        b'+' | b'-' | b':' | b'_' | b'#' | b',' | b'(' => Ok(line_end + 2),
+        // bulk strings, bulk errors and verbatim strings
+        b'$' | b'!' | b'=' => {
+            let len = len()?;
+            if len < 0 {
+                return Ok(line_end + 2);
+            }
+            let end = line_end + 2 + len as usize + 2;
+            if end <= buf.len() {
+                Ok(end)
+            } else {
+                Err(ParseError::Incomplete)
+            }
+        }
+        // arrays, sets and pushes, and maps and attributes which hold a key
+        // and a value for each entry
+        b'*' | b'~' | b'>' | b'%' | b'|' => {
+            let mut elements = len()?;
+            if matches!(buf[0], b'%' | b'|') {
+                elements *= 2;
+            }
+            let mut end = line_end + 2;
+            for _ in 0..elements.max(0) {
+                end += value(&buf[end..])?;
+            }
+            Ok(end)
+        }
+        _ => Err(ParseError::Unknown),
+    }
+}
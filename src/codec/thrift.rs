@@ -27,8 +27,7 @@ pub struct ThriftBuffer {
 
 impl Default for ThriftBuffer {
     fn default() -> Self {
-        let mut buffer = Vec::<u8>::new();
-        buffer.resize(4, 0);
+        let buffer = vec![0; 4];
 
         Self { buffer }
     }
@@ -68,7 +67,7 @@ impl ThriftBuffer {
     /// add sequence id to buffer
     #[inline]
     pub fn sequence_id(&mut self, id: i32) -> &Self {
-        self.write_i32(id as i32)
+        self.write_i32(id)
     }
 
     /// add stop sequence to buffer
@@ -119,6 +118,14 @@ impl ThriftBuffer {
         self
     }
 
+    // write length prefixed binary to the buffer
+    #[inline]
+    pub fn write_binary(&mut self, bytes: &[u8]) -> &Self {
+        self.write_i32(bytes.len() as i32);
+        self.buffer.extend_from_slice(bytes);
+        self
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         &self.buffer
     }
@@ -28,12 +28,13 @@ impl ThriftCache {
         }
     }
 
-    fn append(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
+    fn append(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session, outcome: &mut Outcome) {
+        let key = keyspace.generate_key(rng, buf.shard(), outcome);
         let mut values = Vec::new();
         for _ in 0..keyspace.batch_size() {
             values.push(keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec()));
         }
+        outcome.add_elements(values.len() as _);
 
         let mut buffer = thrift::ThriftBuffer::new();
         buffer.protocol_header();
@@ -74,12 +75,13 @@ impl ThriftCache {
         let _ = buf.write(buffer.as_bytes());
     }
 
-    fn appendx(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
+    fn appendx(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session, outcome: &mut Outcome) {
+        let key = keyspace.generate_key(rng, buf.shard(), outcome);
         let mut values = Vec::new();
         for _ in 0..keyspace.batch_size() {
             values.push(keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec()));
         }
+        outcome.add_elements(values.len() as _);
 
         let mut buffer = thrift::ThriftBuffer::new();
         buffer.protocol_header();
@@ -120,8 +122,8 @@ impl ThriftCache {
         let _ = buf.write(buffer.as_bytes());
     }
 
-    fn count(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
+    fn count(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session, outcome: &mut Outcome) {
+        let key = keyspace.generate_key(rng, buf.shard(), outcome);
         let timeout = None;
 
         let mut buffer = thrift::ThriftBuffer::new();
@@ -159,8 +161,8 @@ impl ThriftCache {
         let _ = buf.write(buffer.as_bytes());
     }
 
-    fn get(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
+    fn get(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session, outcome: &mut Outcome) {
+        let key = keyspace.generate_key(rng, buf.shard(), outcome);
         let mut fields = Vec::new();
         for _ in 0..keyspace.batch_size() {
             fields.push(
@@ -216,10 +218,13 @@ impl ThriftCache {
         let _ = buf.write(buffer.as_bytes());
     }
 
-    fn put(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
+    fn put(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session, outcome: &mut Outcome) {
+        let key = keyspace.generate_key(rng, buf.shard(), outcome);
+        let count = keyspace
+            .choose_field_count(rng)
+            .unwrap_or_else(|| keyspace.batch_size());
         let mut fields = Vec::new();
-        for _ in 0..keyspace.batch_size() {
+        for _ in 0..count {
             fields.push(
                 keyspace
                     .generate_inner_key(rng)
@@ -227,9 +232,10 @@ impl ThriftCache {
             );
         }
         let mut values = Vec::new();
-        for _ in 0..keyspace.batch_size() {
+        for _ in 0..count {
             values.push(keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec()));
         }
+        outcome.add_elements(values.len() as _);
         let timeout = None;
         let timestamp = None;
         let ttl = keyspace.ttl();
@@ -301,8 +307,8 @@ impl ThriftCache {
         let _ = buf.write(buffer.as_bytes());
     }
 
-    fn remove(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
+    fn remove(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session, outcome: &mut Outcome) {
+        let key = keyspace.generate_key(rng, buf.shard(), outcome);
         let mut fields = Vec::new();
         for _ in 0..keyspace.batch_size() {
             fields.push(
@@ -372,8 +378,8 @@ impl ThriftCache {
         let _ = buf.write(buffer.as_bytes());
     }
 
-    fn range(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
+    fn range(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session, outcome: &mut Outcome) {
+        let key = keyspace.generate_key(rng, buf.shard(), outcome);
         let mut fields = Vec::new();
         for _ in 0..keyspace.batch_size() {
             fields.push(
@@ -431,8 +437,8 @@ impl ThriftCache {
     }
 
     #[allow(dead_code)]
-    fn scan(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
+    fn scan(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session, outcome: &mut Outcome) {
+        let key = keyspace.generate_key(rng, buf.shard(), outcome);
         let start_field = None;
         let end_field = None;
         let ascending = None;
@@ -498,8 +504,8 @@ impl ThriftCache {
         let _ = buf.write(buffer.as_bytes());
     }
 
-    fn trim(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
+    fn trim(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session, outcome: &mut Outcome) {
+        let key = keyspace.generate_key(rng, buf.shard(), outcome);
         let target_size = 1;
         let trim_from_smallest = true;
         let timeout = None;
@@ -549,25 +555,27 @@ impl ThriftCache {
 }
 
 impl Codec for ThriftCache {
-    fn encode(&mut self, buf: &mut Session) {
+    fn encode(&mut self, buf: &mut Session) -> (Verb, Outcome) {
+        let mut outcome = Outcome::default();
         let keyspace = self.config.choose_keyspace(&mut self.rng);
-        let command = keyspace.choose_command(&mut self.rng);
-        match command.verb() {
-            Verb::Rpush => Self::append(&mut self.rng, keyspace, buf),
-            Verb::Rpushx => Self::appendx(&mut self.rng, keyspace, buf),
-            Verb::Count => Self::count(&mut self.rng, keyspace, buf),
-            Verb::Hget => Self::get(&mut self.rng, keyspace, buf),
-            Verb::Hset => Self::put(&mut self.rng, keyspace, buf),
-            Verb::Hdel => Self::remove(&mut self.rng, keyspace, buf),
-            Verb::Lrange => Self::range(&mut self.rng, keyspace, buf),
-            Verb::Ltrim => Self::trim(&mut self.rng, keyspace, buf),
+        let verb = keyspace.choose_command(&mut self.rng, buf).verb();
+        match verb {
+            Verb::Rpush => Self::append(&mut self.rng, keyspace, buf, &mut outcome),
+            Verb::Rpushx => Self::appendx(&mut self.rng, keyspace, buf, &mut outcome),
+            Verb::Count => Self::count(&mut self.rng, keyspace, buf, &mut outcome),
+            Verb::Hget => Self::get(&mut self.rng, keyspace, buf, &mut outcome),
+            Verb::Hset => Self::put(&mut self.rng, keyspace, buf, &mut outcome),
+            Verb::Hdel => Self::remove(&mut self.rng, keyspace, buf, &mut outcome),
+            Verb::Lrange => Self::range(&mut self.rng, keyspace, buf, &mut outcome),
+            Verb::Ltrim => Self::trim(&mut self.rng, keyspace, buf, &mut outcome),
             _ => {
                 unimplemented!()
             }
         }
+        (verb, outcome)
     }
 
-    fn decode(&self, buffer: &mut Session) -> Result<(), ParseError> {
+    fn decode(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
         // no-copy borrow as a slice
         let buf: &[u8] = (*buffer).buffer();
 
@@ -579,7 +587,7 @@ impl Codec for ThriftCache {
                 Some(b) => {
                     if b == bytes {
                         buffer.consume(b as usize);
-                        Ok(())
+                        Ok(Outcome::default())
                     } else {
                         Err(ParseError::Incomplete)
                     }
@@ -0,0 +1,240 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+use super::thrift;
+use std::io::BufRead;
+
+use crate::codec::*;
+use crate::config::*;
+use crate::config_file::{ThriftArg, ThriftField, Verb};
+use crate::*;
+
+use std::io::Write;
+
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+use strum::EnumCount;
+
+const MESSAGE_REPLY: u8 = 2;
+const MESSAGE_EXCEPTION: u8 = 3;
+
+/// Calls methods on a thrift service using the framed binary protocol. Each
+/// command maps to a method whose arguments are built from the keyspace.
+pub struct ThriftRpc {
+    config: Arc<Config>,
+    rng: SmallRng,
+    // the method name and arguments, indexed by verb
+    methods: Vec<Option<(String, Vec<ThriftArg>)>>,
+    sequence_id: i32,
+}
+
+impl ThriftRpc {
+    pub fn new(config: Arc<Config>) -> Self {
+        let thrift = config.protocol().thrift();
+        let mut methods = vec![None; Verb::COUNT];
+        for method in thrift.methods() {
+            let name = match thrift.service() {
+                Some(ref service) => format!("{}:{}", service, method.name()),
+                None => method.name(),
+            };
+            methods[method.verb() as usize] = Some((name, method.args()));
+        }
+        Self {
+            config,
+            rng: SmallRng::from_entropy(),
+            methods,
+            sequence_id: 0,
+        }
+    }
+}
+
+impl Codec for ThriftRpc {
+    fn encode(&mut self, buf: &mut Session) -> (Verb, Outcome) {
+        let mut outcome = Outcome::default();
+        let keyspace = self.config.choose_keyspace(&mut self.rng);
+        let verb = keyspace.choose_command(&mut self.rng, buf).verb();
+        let (name, args) = match self.methods[verb as usize] {
+            Some(ref method) => method,
+            None => {
+                unimplemented!()
+            }
+        };
+        if verb == Verb::Get {
+            metrics::REQUEST_GET.increment();
+        }
+
+        self.sequence_id = self.sequence_id.wrapping_add(1);
+
+        let mut buffer = thrift::ThriftBuffer::new();
+        buffer.protocol_header();
+        buffer.method_name(name);
+        buffer.sequence_id(self.sequence_id);
+
+        for arg in args {
+            match arg.field() {
+                ThriftField::Key => {
+                    let key = keyspace.generate_key(&mut self.rng, buf.shard(), &mut outcome);
+                    buffer.write_bytes(&[thrift::STRING]);
+                    buffer.write_i16(arg.id());
+                    buffer.write_binary(&key);
+                }
+                ThriftField::Keys => {
+                    buffer.write_bytes(&[thrift::LIST]);
+                    buffer.write_i16(arg.id());
+                    buffer.write_bytes(&[thrift::STRING]);
+                    buffer.write_i32(keyspace.batch_size() as i32);
+                    for _ in 0..keyspace.batch_size() {
+                        let key = keyspace.generate_key(&mut self.rng, buf.shard(), &mut outcome);
+                        buffer.write_binary(&key);
+                    }
+                }
+                ThriftField::InnerKey => {
+                    let inner_key = keyspace
+                        .generate_inner_key(&mut self.rng)
+                        .unwrap_or_else(|| b"".to_vec());
+                    buffer.write_bytes(&[thrift::STRING]);
+                    buffer.write_i16(arg.id());
+                    buffer.write_binary(&inner_key);
+                }
+                ThriftField::Value => {
+                    let value = keyspace
+                        .generate_value(&mut self.rng)
+                        .unwrap_or_else(|| b"".to_vec());
+                    buffer.write_bytes(&[thrift::STRING]);
+                    buffer.write_i16(arg.id());
+                    buffer.write_binary(&value);
+                }
+                ThriftField::Ttl => {
+                    buffer.write_bytes(&[thrift::I64]);
+                    buffer.write_i16(arg.id());
+                    buffer.write_i64(keyspace.ttl() as i64);
+                }
+            }
+        }
+
+        // stop args struct
+        buffer.stop();
+        buffer.frame();
+
+        let _ = buf.write(buffer.as_bytes());
+
+        (verb, outcome)
+    }
+
+    fn decode(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        // no-copy borrow as a slice
+        let buf: &[u8] = (*buffer).buffer();
+
+        if buf.len() < 4 {
+            return Err(ParseError::Incomplete);
+        }
+        let length = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        let end = length.checked_add(4).ok_or(ParseError::Unknown)?;
+        if buf.len() < end {
+            return Err(ParseError::Incomplete);
+        }
+        let message = &buf[4..end];
+
+        // strict message header: version, type, name, and sequence id
+        if message.len() < 12 || message[0..2] != [128, 1] {
+            return Err(ParseError::Unknown);
+        }
+        let message_type = message[3];
+        let name_len = i32::from_be_bytes([message[4], message[5], message[6], message[7]]);
+        let body = usize::try_from(name_len)
+            .ok()
+            .and_then(|name_len| message.get(8 + name_len + 4..))
+            .ok_or(ParseError::Unknown)?;
+
+        let mut outcome = Outcome::default();
+        match message_type {
+            MESSAGE_REPLY => {
+                // the result struct holds the return value as field 0, or a
+                // declared exception as one of the other fields. The result
+                // of a method without a return value may be empty.
+                let field = body.len() >= 3 && body[0] != thrift::STOP;
+                if field && body[1..3] != [0, 0] {
+                    outcome.error();
+                } else if field && buffer.command() == Some(Verb::Get) {
+                    outcome.hit();
+                }
+            }
+            MESSAGE_EXCEPTION => {
+                outcome.error();
+            }
+            _ => {
+                return Err(ParseError::Unknown);
+            }
+        }
+
+        buffer.consume(end);
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::tests::{config, receive, session};
+
+    /// A framed message of the type, for the `get` method, with the body
+    fn message(message_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut message = vec![128, 1, 0, message_type];
+        message.extend_from_slice(&3i32.to_be_bytes());
+        message.extend_from_slice(b"get");
+        message.extend_from_slice(&1i32.to_be_bytes());
+        message.extend_from_slice(body);
+        let mut framed = (message.len() as u32).to_be_bytes().to_vec();
+        framed.extend_from_slice(&message);
+        framed
+    }
+
+    #[test]
+    fn decode() {
+        let thrift = ThriftRpc::new(config("thrift.toml"));
+        let mut session = session();
+        session.push_command(Verb::Get, false);
+
+        // a return value in field 0
+        let value = message(MESSAGE_REPLY, b"\x0b\x00\x00\x00\x00\x00\x01a\x00");
+        receive(&mut session, &value[..3]);
+        assert_eq!(thrift.decode(&mut session), Err(ParseError::Incomplete));
+        receive(&mut session, &value[3..value.len() - 1]);
+        assert_eq!(thrift.decode(&mut session), Err(ParseError::Incomplete));
+        receive(&mut session, &value[value.len() - 1..]);
+        assert_eq!(thrift.decode(&mut session).map(|o| o.hits), Ok(1));
+        assert_eq!(session.read_pending(), 0);
+
+        // an empty result is not a hit
+        receive(&mut session, &message(MESSAGE_REPLY, b"\x00"));
+        assert_eq!(thrift.decode(&mut session), Ok(Outcome::default()));
+
+        // a declared exception in field 1
+        receive(
+            &mut session,
+            &message(MESSAGE_REPLY, b"\x0c\x00\x01\x00\x00"),
+        );
+        assert_eq!(thrift.decode(&mut session).map(|o| o.errors), Ok(1));
+
+        receive(&mut session, &message(MESSAGE_EXCEPTION, b"\x00"));
+        assert_eq!(thrift.decode(&mut session).map(|o| o.errors), Ok(1));
+        assert_eq!(session.read_pending(), 0);
+
+        // a call is not a response
+        receive(&mut session, &message(1, b"\x00"));
+        assert_eq!(thrift.decode(&mut session), Err(ParseError::Unknown));
+    }
+
+    #[test]
+    fn name_length() {
+        let thrift = ThriftRpc::new(config("thrift.toml"));
+        for name_len in [-1i32, 1024] {
+            let mut session = session();
+            let mut framed = message(MESSAGE_REPLY, b"\x00");
+            framed[8..12].copy_from_slice(&name_len.to_be_bytes());
+            receive(&mut session, &framed);
+            assert_eq!(thrift.decode(&mut session), Err(ParseError::Unknown));
+        }
+    }
+}
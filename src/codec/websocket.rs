@@ -0,0 +1,281 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+use crate::codec::*;
+use crate::config::*;
+use crate::config_file::{FrameType, Verb};
+use crate::*;
+
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::Alphanumeric;
+
+use std::io::{BufRead, Write};
+use std::str;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+
+/// Sends messages to a websocket echo server and measures the round trip of
+/// each message. The upgrade request is sent ahead of the first message on
+/// each connection.
+pub struct Websocket {
+    config: Arc<Config>,
+    rng: SmallRng,
+    path: String,
+    host: String,
+    frame: FrameType,
+}
+
+impl Websocket {
+    pub fn new(config: Arc<Config>) -> Self {
+        let websocket = config.protocol().websocket();
+        Self {
+            config,
+            rng: SmallRng::from_entropy(),
+            path: websocket.path(),
+            host: websocket.host(),
+            frame: websocket.frame(),
+        }
+    }
+
+    fn upgrade(&mut self, buf: &mut Session) {
+        let nonce: [u8; 16] = self.rng.gen();
+        let _ = buf.write_all(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+                self.path,
+                vhost_name(&self.host, buf.vhost()),
+                base64(&nonce)
+            )
+            .as_bytes(),
+        );
+        if let Some(token) = self.config.oauth_token().and_then(|token| token.bearer()) {
+            let _ = buf.write_all(format!("Authorization: Bearer {token}\r\n").as_bytes());
+        }
+        let _ = buf.write_all(b"\r\n");
+        buf.set_upgraded();
+    }
+}
+
+impl Codec for Websocket {
+    fn encode(&mut self, buf: &mut Session) -> (Verb, Outcome) {
+        if !buf.is_upgraded() {
+            self.upgrade(buf);
+        }
+
+        let keyspace = self.config.choose_keyspace(&mut self.rng);
+        let (opcode, payload) = match self.frame {
+            FrameType::Binary => (
+                OPCODE_BINARY,
+                keyspace.generate_value(&mut self.rng).unwrap_or_default(),
+            ),
+            FrameType::Text => {
+                let length = keyspace.choose_value_length(&mut self.rng).unwrap_or(0);
+                let payload = (&mut self.rng)
+                    .sample_iter(&Alphanumeric)
+                    .take(length)
+                    .collect::<Vec<u8>>();
+                (OPCODE_TEXT, payload)
+            }
+        };
+
+        // frames sent by a client must be masked
+        let mask: [u8; 4] = self.rng.gen();
+        let mut header = vec![0x80 | opcode];
+        match payload.len() {
+            len if len < 126 => header.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                header.push(0x80 | 126);
+                header.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                header.push(0x80 | 127);
+                header.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        header.extend_from_slice(&mask);
+        let masked: Vec<u8> = payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+        let _ = buf.write_all(&header);
+        let _ = buf.write_all(&masked);
+
+        (Verb::Echo, Outcome::default())
+    }
+
+    fn decode(&self, buffer: &mut Session) -> Result<Outcome, ParseError> {
+        // the upgrade response precedes the first message
+        if buffer.buffer().starts_with(b"HTTP/") {
+            let buf: &[u8] = (*buffer).buffer();
+            let header_end = buf
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .ok_or(ParseError::Incomplete)?;
+            let status = str::from_utf8(&buf[0..header_end])
+                .ok()
+                .and_then(|header| header.split(' ').nth(1))
+                .ok_or(ParseError::Unknown)?;
+            if status == "401" {
+                metrics::RESPONSE_UNAUTHORIZED.increment();
+                if let Some(token) = self.config.oauth_token() {
+                    token.set_stale();
+                }
+            }
+            if status != "101" {
+                debug!("websocket upgrade rejected with status: {}", status);
+                return Err(ParseError::Error);
+            }
+            buffer.consume(header_end + 4);
+        }
+
+        // no-copy borrow as a slice
+        let buf: &[u8] = (*buffer).buffer();
+
+        // a message may be fragmented across several frames, control frames
+        // may be interleaved between them
+        let mut position = 0;
+        loop {
+            let (fin, opcode, len) = frame(&buf[position..])?;
+            position += len;
+            match opcode {
+                OPCODE_CLOSE => {
+                    return Err(ParseError::Error);
+                }
+                OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY if fin => {
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        buffer.consume(position);
+        Ok(Outcome::default())
+    }
+}
+
+/// Parse the frame at the start of the buffer, returning the fin bit, the
+/// opcode, and the total length of the frame
+fn frame(buf: &[u8]) -> Result<(bool, u8, usize), ParseError> {
+    if buf.len() < 2 {
+        return Err(ParseError::Incomplete);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0F;
+    let masked = buf[1] & 0x80 != 0;
+    let (header_len, payload_len) = match buf[1] & 0x7F {
+        126 => {
+            if buf.len() < 4 {
+                return Err(ParseError::Incomplete);
+            }
+            (4, u16::from_be_bytes([buf[2], buf[3]]) as usize)
+        }
+        127 => {
+            if buf.len() < 10 {
+                return Err(ParseError::Incomplete);
+            }
+            let mut len = [0; 8];
+            len.copy_from_slice(&buf[2..10]);
+            (10, u64::from_be_bytes(len) as usize)
+        }
+        len => (2, len as usize),
+    };
+    let len = header_len + if masked { 4 } else { 0 } + payload_len;
+    if buf.len() < len {
+        return Err(ParseError::Incomplete);
+    }
+    Ok((fin, opcode, len))
+}
+
+/// Encode bytes as base64 with padding
+pub(crate) fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::tests::{config, receive, session};
+
+    #[test]
+    fn decode() {
+        let websocket = Websocket::new(config("websocket.toml"));
+        let mut session = session();
+
+        // the upgrade response and the first message may arrive together
+        receive(
+            &mut session,
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n",
+        );
+        assert_eq!(websocket.decode(&mut session), Err(ParseError::Incomplete));
+        receive(&mut session, b"\r\n\x82\x03ab");
+        assert_eq!(websocket.decode(&mut session), Err(ParseError::Incomplete));
+        receive(&mut session, b"c");
+        assert_eq!(websocket.decode(&mut session), Ok(Outcome::default()));
+        assert_eq!(session.read_pending(), 0);
+
+        // a fragmented message, with a ping between its frames
+        receive(&mut session, b"\x02\x02ab\x89\x00\x80\x01c");
+        assert_eq!(websocket.decode(&mut session), Ok(Outcome::default()));
+        assert_eq!(session.read_pending(), 0);
+
+        // an extended payload length
+        let mut message = vec![0x82, 126, 0x01, 0x00];
+        message.extend_from_slice(&[0; 256]);
+        receive(&mut session, &message[..3]);
+        assert_eq!(websocket.decode(&mut session), Err(ParseError::Incomplete));
+        receive(&mut session, &message[3..]);
+        assert_eq!(websocket.decode(&mut session), Ok(Outcome::default()));
+        assert_eq!(session.read_pending(), 0);
+
+        receive(&mut session, b"\x88\x00");
+        assert_eq!(websocket.decode(&mut session), Err(ParseError::Error));
+    }
+
+    #[test]
+    fn rejected() {
+        let websocket = Websocket::new(config("websocket.toml"));
+        let mut session = session();
+        receive(&mut session, b"HTTP/1.1 403 Forbidden\r\n\r\n");
+        assert_eq!(websocket.decode(&mut session), Err(ParseError::Error));
+    }
+
+    #[test]
+    fn base64() {
+        // test vectors from RFC 4648
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (input, output) in vectors {
+            assert_eq!(super::base64(input.as_bytes()), output);
+        }
+    }
+}
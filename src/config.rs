@@ -2,26 +2,61 @@
 // Licensed under the Apache License, Version 2.0
 // http://www.apache.org/licenses/LICENSE-2.0
 
+use crate::assertion::Assertions;
+use crate::codec::Outcome;
 use crate::config_file::*;
+use crate::hot_set::HotKeys;
+use crate::oauth::{self, Token};
+use crate::placement;
+use crate::session::Endpoint;
+use crate::validate;
+use crate::Session;
 use rand::rngs::SmallRng;
 use rand::Rng;
 use rand_distr::Alphanumeric;
 use rand_distr::Uniform;
-use rand_distr::{Distribution, WeightedAliasIndex};
-use std::net::SocketAddr;
+use rand_distr::{Distribution, LogNormal, Normal, Pareto, WeightedAliasIndex};
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
 use zipf::ZipfDistribution;
 
 pub const NAME: &str = env!("CARGO_PKG_NAME");
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Key pools up to this many keys are generated up front, larger pools format
+/// each key on demand.
+const KEY_POOL_MAX: usize = 1 << 20;
+
 pub struct Config {
+    file: String,
     general: General,
     debug: Debug,
     waterfall: Waterfall,
+    bundle: Bundle,
+    abort: Abort,
+    baseline: Baseline,
+    checkpoint: Checkpoint,
+    metrics: Metrics,
+    sla: Vec<Sla>,
+    blackout: Vec<Blackout>,
+    search: Option<Search>,
+    calibration: Option<Calibration>,
+    distributed: Distributed,
     connection: Connection,
     request: Request,
+    protocol: ProtocolOptions,
     tls: Option<Tls>,
-    endpoints: Vec<SocketAddr>,
+    vhosts: Option<Vhosts>,
+    stickiness: Option<HttpStickiness>,
+    assertions: Assertions,
+    endpoints: Vec<Endpoint>,
+    // the index of the first cutover endpoint
+    cutover: Option<usize>,
+    // the current access token, when an oauth client is configured
+    oauth_token: Option<Arc<Token>>,
+    target: Target,
     keyspaces: Vec<Keyspace>,
     keyspace_dist: WeightedAliasIndex<usize>,
 }
@@ -29,78 +64,1033 @@ pub struct Config {
 #[derive(Clone)]
 pub enum KeyDistribution {
     Uniform(Uniform<usize>),
-    Zipf(ZipfDistribution),
+    /// with the exponent, to estimate the hot set
+    Zipf(ZipfDistribution, f64),
 }
 
 impl KeyDistribution {
-    pub fn sample(&self, rng: &mut SmallRng) -> usize {
+    /// The distribution of indices for a field with the given cardinality
+    fn new(config: Option<&crate::config_file::KeyDistribution>, cardinality: u64) -> Self {
+        match config {
+            None => Self::Uniform(Uniform::new(0, cardinality as usize)),
+            Some(kd) => match kd.model {
+                KeyDistributionModel::Uniform => {
+                    Self::Uniform(Uniform::new(0, cardinality as usize))
+                }
+                KeyDistributionModel::Zipf => {
+                    let exponent = match kd
+                        .parameters
+                        .get("exponent")
+                        .map(|exponent| exponent.parse::<f64>())
+                        .unwrap_or(Ok(1.0))
+                    {
+                        Ok(exponent) if exponent.is_finite() && exponent > 0.0 => exponent,
+                        _ => {
+                            fatal!("the zipf exponent must be a number greater than 0");
+                        }
+                    };
+                    // almost every request goes to the first few keys
+                    if exponent > 5.0 {
+                        warn!(
+                            "zipf exponent {} sends nearly every request to the first key",
+                            exponent
+                        );
+                    }
+                    let distribution = match ZipfDistribution::new(cardinality as usize, exponent) {
+                        Ok(distribution) => distribution,
+                        Err(_) => {
+                            fatal!("bad zipf distribution over {} keys", cardinality);
+                        }
+                    };
+                    Self::Zipf(distribution, exponent)
+                }
+            },
+        }
+    }
+
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> usize {
         match self {
             Self::Uniform(d) => d.sample(rng),
-            Self::Zipf(d) => d.sample(rng),
+            Self::Zipf(d, _) => d.sample(rng),
         }
     }
 }
 
+/// Replaced with the chosen virtual host in the tls server name and http host
+pub const VHOST: &str = "${vhost}";
+
+/// Replaced with the connection's label in the tls server name, when load
+/// balancer stickiness is tested by sni
+pub const LABEL: &str = "${label}";
+
+/// Chooses the virtual host for each connection, or for each request
+#[derive(Clone)]
+pub struct Vhosts {
+    distribution: KeyDistribution,
+    per_request: bool,
+}
+
+impl Vhosts {
+    /// A host numbered from zero. Zipf indices start from one, so the most
+    /// popular host is always zero.
+    pub fn choose<R: Rng>(&self, rng: &mut R) -> usize {
+        match self.distribution {
+            KeyDistribution::Uniform(_) => self.distribution.sample(rng),
+            KeyDistribution::Zipf(..) => self.distribution.sample(rng) - 1,
+        }
+    }
+
+    pub fn per_request(&self) -> bool {
+        self.per_request
+    }
+}
+
+/// The name with the virtual host substituted for its template
+pub fn vhost_name(name: &str, vhost: Option<usize>) -> String {
+    match vhost {
+        Some(vhost) => name.replace(VHOST, &vhost.to_string()),
+        None => name.to_string(),
+    }
+}
+
 #[derive(Clone)]
 pub struct Keyspace {
+    index: usize,
     length: usize,
     weight: usize,
-    cardinality: u32,
+    cardinality: u64,
     commands: Vec<Command>,
     command_dist: WeightedAliasIndex<usize>,
+    transition_dist: Vec<WeightedAliasIndex<usize>>,
     inner_keys: Vec<InnerKey>,
     inner_key_dist: Option<WeightedAliasIndex<usize>>,
+    // how each inner key is chosen from its cardinality
+    inner_key_distributions: Vec<KeyDistribution>,
     values: Vec<Value>,
     value_dist: Option<WeightedAliasIndex<usize>>,
+    // the distribution of each value's length, unless it is fixed
+    value_sizes: Vec<Option<ValueLength>>,
+    // what each value is filled with
+    value_fills: Vec<ValueFill>,
+    field_counts: Vec<usize>,
+    field_count_dist: Option<WeightedAliasIndex<usize>>,
+    deltas: Vec<u64>,
+    delta_dist: Option<WeightedAliasIndex<usize>>,
     ttl: usize,
     key_type: FieldType,
     batch_size: usize,
     key_distribution: KeyDistribution,
+    cache_size: Option<u64>,
+    key_pool: Option<KeyPool>,
+    key_template: Option<KeyTemplate>,
+    // the agent index and agent count in a distributed run, each agent only
+    // sends requests for its share of the keys
+    agent_shard: Option<(usize, usize)>,
+    // the version of each key's value, when values are mutated
+    versions: Option<Arc<Versions>>,
+    // counts the requests for the most popular keys, when reported
+    hot_keys: Option<Arc<HotKeys>>,
+    edge_cases: Option<EdgeCaseKeys>,
+    // the namespace version which prefixes the keys, when it is bumped
+    namespace: Option<Arc<Namespace>>,
+    transaction: Option<Transaction>,
+    scripts: Vec<LuaScript>,
+    script_dist: Option<WeightedAliasIndex<usize>>,
+}
+
+/// The kinds of unusual keys mixed into a keyspace's requests
+#[derive(Clone, Copy)]
+enum EdgeCase {
+    MaxLength,
+    SingleByte,
+    Space,
+    Control,
+    NonUtf8,
+}
+
+/// Control characters which protocols commonly use as delimiters
+const CONTROL: &[u8] = b"\0\t\r\n\x1b\x7f";
+
+#[derive(Clone)]
+struct EdgeCaseKeys {
+    share: f64,
+    max_length: usize,
+    kinds: Vec<EdgeCase>,
+}
+
+impl EdgeCaseKeys {
+    /// An edge-case key of one of the kinds. Keys which contain unusual bytes
+    /// are otherwise alphanumeric and have the keyspace's length.
+    fn generate(&self, rng: &mut SmallRng, length: usize) -> Vec<u8> {
+        let alphanumeric =
+            |rng: &mut SmallRng, length| rng.sample_iter(&Alphanumeric).take(length).collect();
+        let mut key: Vec<u8> = alphanumeric(rng, length.max(2));
+        let position = rng.gen_range(0..key.len());
+        match self.kinds[rng.gen_range(0..self.kinds.len())] {
+            EdgeCase::MaxLength => return alphanumeric(rng, self.max_length),
+            EdgeCase::SingleByte => return alphanumeric(rng, 1),
+            EdgeCase::Space => key[position] = b' ',
+            EdgeCase::Control => key[position] = CONTROL[rng.gen_range(0..CONTROL.len())],
+            EdgeCase::NonUtf8 => key[position] = rng.gen_range(0x80..=0xff),
+        }
+        key
+    }
+}
+
+/// Draws the lengths of values whose size follows a distribution
+#[derive(Clone)]
+struct ValueLength {
+    distribution: ValueLengthDistribution,
+    min: usize,
+    max: usize,
+}
+
+#[derive(Clone)]
+enum ValueLengthDistribution {
+    Normal(Normal<f64>),
+    Lognormal(LogNormal<f64>),
+    Pareto(Pareto<f64>),
+}
+
+impl ValueLength {
+    fn new(size: ValueSize) -> Result<Self, String> {
+        let (distribution, min, max) = match size {
+            ValueSize::Normal {
+                mean,
+                std_dev,
+                min,
+                max,
+            } => (
+                Normal::new(mean, std_dev)
+                    .map(ValueLengthDistribution::Normal)
+                    .map_err(|e| format!("bad normal value size: {}", e))?,
+                min,
+                max,
+            ),
+            ValueSize::Lognormal {
+                median,
+                sigma,
+                min,
+                max,
+            } => {
+                if median.is_nan() || median <= 0.0 {
+                    return Err("the lognormal value size needs a median above 0".to_string());
+                }
+                (
+                    LogNormal::new(median.ln(), sigma)
+                        .map(ValueLengthDistribution::Lognormal)
+                        .map_err(|e| format!("bad lognormal value size: {}", e))?,
+                    min,
+                    max,
+                )
+            }
+            ValueSize::Pareto {
+                scale,
+                shape,
+                min,
+                max,
+            } => (
+                Pareto::new(scale, shape)
+                    .map(ValueLengthDistribution::Pareto)
+                    .map_err(|e| format!("bad pareto value size: {}", e))?,
+                min,
+                max,
+            ),
+        };
+        if min > max {
+            return Err(format!(
+                "value size min of {} is greater than the max of {}",
+                min, max
+            ));
+        }
+        Ok(Self {
+            distribution,
+            min,
+            max,
+        })
+    }
+
+    fn sample(&self, rng: &mut SmallRng) -> usize {
+        let length = match self.distribution {
+            ValueLengthDistribution::Normal(ref d) => d.sample(rng),
+            ValueLengthDistribution::Lognormal(ref d) => d.sample(rng),
+            ValueLengthDistribution::Pareto(ref d) => d.sample(rng),
+        };
+        // negative lengths saturate to zero
+        (length.round() as usize).clamp(self.min, self.max)
+    }
+}
+
+/// What a value is filled with, from the value's kind
+#[derive(Clone)]
+enum ValueFill {
+    Random,
+    Zeros,
+    Pattern(Vec<u8>),
+    /// the share of each block which is random
+    Compressible(f64),
+    Json,
+}
+
+/// Values of the compressible kind are built from blocks which start with
+/// random bytes and end with zeros, small enough that compressors see the
+/// pattern
+const COMPRESSIBLE_BLOCK: usize = 256;
+
+/// Room kept at the end of a JSON value for the field which pads it to length
+const JSON_TAIL: usize = 48;
+
+impl ValueFill {
+    fn fill(&self, rng: &mut SmallRng, length: usize) -> Vec<u8> {
+        match self {
+            Self::Random => rng.sample_iter(&Alphanumeric).take(length).collect(),
+            Self::Zeros => vec![0; length],
+            Self::Pattern(pattern) => pattern.iter().copied().cycle().take(length).collect(),
+            Self::Compressible(share) => {
+                let random = (COMPRESSIBLE_BLOCK as f64 * share).ceil() as usize;
+                let mut value = vec![0; length];
+                for block in value.chunks_mut(COMPRESSIBLE_BLOCK) {
+                    let end = random.min(block.len());
+                    rng.fill(&mut block[..end]);
+                }
+                value
+            }
+            Self::Json => json_value(rng, length),
+        }
+    }
+}
+
+/// A flat JSON object of about the length, which cycles through string,
+/// number, boolean and array fields and ends with a string which pads it
+fn json_value(rng: &mut SmallRng, length: usize) -> Vec<u8> {
+    let mut value = b"{".to_vec();
+    let mut field = 0;
+    while value.len() + JSON_TAIL < length {
+        value.extend_from_slice(format!("\"f{}\":", field).as_bytes());
+        match field % 4 {
+            0 => {
+                value.push(b'"');
+                value.extend(rng.sample_iter(&Alphanumeric).take(16));
+                value.push(b'"');
+            }
+            1 => value.extend_from_slice(rng.gen::<u32>().to_string().as_bytes()),
+            2 => value.extend_from_slice(if rng.gen() { b"true" } else { b"false" }),
+            _ => value.extend_from_slice(
+                format!(
+                    "[{},{},{}]",
+                    rng.gen::<u16>(),
+                    rng.gen::<u16>(),
+                    rng.gen::<u16>()
+                )
+                .as_bytes(),
+            ),
+        }
+        value.push(b',');
+        field += 1;
+    }
+    // `"pad":"` and `"}` surround the padding
+    let padding = length.saturating_sub(value.len() + 9);
+    value.extend_from_slice(b"\"pad\":\"");
+    value.extend(rng.sample_iter(&Alphanumeric).take(padding));
+    value.extend_from_slice(b"\"}");
+    value
+}
+
+/// A deterministic set of alphanumeric keys where each key is derived from the
+/// keyspace index and the key index.
+#[derive(Clone)]
+pub enum KeyPool {
+    Generated(Arc<[Vec<u8>]>),
+    OnDemand,
+}
+
+/// Generates the alphanumeric key for a key index. The same keyspace and key
+/// index always produce the same key.
+fn pool_key(keyspace: usize, index: usize, length: usize) -> Vec<u8> {
+    let seed = ((keyspace as u64) << 32) | index as u64;
+    Xoshiro256PlusPlus::seed_from_u64(seed)
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .collect()
+}
+
+/// Generates the alphanumeric inner key or value with the provided index, for
+/// fields with a bounded cardinality. Each field in the keyspace has its own
+/// sequence, so the same index gives different bytes in different fields.
+fn pool_field(keyspace: usize, field: usize, index: u64, length: usize) -> Vec<u8> {
+    let seed = splitmix64(((keyspace as u64) << 32) | field as u64).wrapping_add(index);
+    Xoshiro256PlusPlus::seed_from_u64(seed)
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .collect()
+}
+
+/// Pooled alphanumeric segments of key templates are numbered from here, so
+/// that they never share a sequence with the inner keys or values.
+const TEMPLATE_FIELD_BASE: usize = 1 << 16;
+
+/// The field used for the body of mutated values, which is the same for every
+/// version of a key's value.
+const MUTATION_FIELD: usize = TEMPLATE_FIELD_BASE - 1;
+
+/// The versions of each key's value when values are mutated. Each write of a
+/// key takes the next version, and the lowest version a read may return is
+/// kept so that reads of older values can be detected. Writes of a key race
+/// each other, and an older version may be stored after a newer one, so a
+/// stored write only raises the lowest version to just past the versions
+/// whose writes had all completed when it was made.
+pub struct Versions {
+    written: Vec<AtomicU32>,
+    acknowledged: Vec<AtomicU32>,
+    // the version which every write up to has completed, in the high bits,
+    // and a mask of the completed versions after it, in the low bits
+    completed: Vec<AtomicU64>,
+}
+
+impl Versions {
+    fn new(cardinality: u64) -> Self {
+        // zipf key indices start from one, so the last index is the cardinality
+        let keys = cardinality as usize + 1;
+        Self {
+            written: (0..keys).map(|_| AtomicU32::new(0)).collect(),
+            acknowledged: (0..keys).map(|_| AtomicU32::new(0)).collect(),
+            completed: (0..keys).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// The version which every write of the key up to has completed
+    fn settled(&self, index: usize) -> u32 {
+        (self.completed[index].load(Ordering::Relaxed) >> 32) as u32
+    }
+
+    /// Record that the write of the version completed, whether or not it was
+    /// stored. A write which falls more than 32 versions behind is taken as
+    /// lost, so that it doesn't hold back the versions after it for good.
+    fn complete(&self, index: usize, version: u32) {
+        let _ =
+            self.completed[index].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |completed| {
+                let mut settled = (completed >> 32) as u32;
+                let mut mask = completed as u32;
+                if version <= settled {
+                    return None;
+                }
+                if version - settled > 32 {
+                    let lost = version - settled - 32;
+                    mask = mask.checked_shr(lost).unwrap_or(0);
+                    settled += lost;
+                }
+                mask |= 1 << (version - settled - 1);
+                let done = mask.trailing_ones();
+                let mask = mask.checked_shr(done).unwrap_or(0);
+                Some((((settled + done) as u64) << 32) | mask as u64)
+            });
+    }
+}
+
+/// The version of the namespace which prefixes a keyspace's keys. The admin
+/// bumps it each interval, and requests use whichever version is current.
+struct Namespace {
+    prefix: String,
+    interval: std::time::Duration,
+    version: AtomicU64,
+}
+
+/// A Lua script run by the evalsha command, with the SHA1 digest redis knows
+/// it by
+#[derive(Clone)]
+pub struct LuaScript {
+    source: String,
+    sha: String,
+    keys: usize,
+    args: Vec<ScriptArg>,
+}
+
+#[derive(Clone)]
+enum ScriptArg {
+    Literal(Vec<u8>),
+    Value,
+    InnerKey,
+}
+
+impl LuaScript {
+    fn new(script: &Script) -> Self {
+        let sha = boring::sha::sha1(script.source().as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let args = script
+            .args()
+            .iter()
+            .map(|arg| match arg.as_str() {
+                "{value}" => ScriptArg::Value,
+                "{inner_key}" => ScriptArg::InnerKey,
+                arg => ScriptArg::Literal(arg.as_bytes().to_vec()),
+            })
+            .collect();
+        Self {
+            source: script.source().to_string(),
+            sha,
+            keys: script.keys(),
+            args,
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The hex SHA1 digest of the source
+    pub fn sha(&self) -> &str {
+        &self.sha
+    }
+}
+
+/// A write of a versioned value, acknowledged once the server has stored it
+#[derive(Clone, Copy, Debug)]
+pub struct VersionedWrite {
+    keyspace: usize,
+    index: usize,
+    version: u32,
+    // every write of the key up to this version had completed when this write
+    // was made
+    settled: u32,
+}
+
+/// A key built from a template such as `user:{u32:1e6}:session:{alnum:8}`,
+/// where each segment in braces is generated independently.
+#[derive(Clone)]
+pub struct KeyTemplate {
+    keyspace: usize,
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone)]
+enum Segment {
+    Literal(Vec<u8>),
+    Field {
+        field_type: FieldType,
+        length: usize,
+        // unbounded alphanumeric segments are random
+        distribution: Option<KeyDistribution>,
+    },
+}
+
+impl KeyTemplate {
+    /// Parse a template. Fields are written as `{type:parameters}`:
+    ///
+    /// * `{u32:CARDINALITY}`, `{u64:CARDINALITY}`, `{uuid:CARDINALITY}`
+    /// * `{alnum:LENGTH}` for random strings, or `{alnum:LENGTH:CARDINALITY}`
+    ///
+    /// Any field with a cardinality may end with `:zipf` or `:zipf=EXPONENT`
+    /// to skew the distribution, which is uniform by default.
+    pub fn parse(keyspace: usize, template: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while !rest.is_empty() {
+            match rest.find('{') {
+                Some(0) => {
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| format!("unclosed field in key template: {}", rest))?;
+                    segments.push(Self::field(&rest[1..end])?);
+                    rest = &rest[(end + 1)..];
+                }
+                Some(start) => {
+                    segments.push(Segment::Literal(rest.as_bytes()[0..start].to_vec()));
+                    rest = &rest[start..];
+                }
+                None => {
+                    segments.push(Segment::Literal(rest.as_bytes().to_vec()));
+                    rest = "";
+                }
+            }
+        }
+        if segments
+            .iter()
+            .any(|s| matches!(s, Segment::Literal(l) if l.contains(&b'}')))
+        {
+            return Err(format!("unopened field in key template: {}", template));
+        }
+        Ok(Self { keyspace, segments })
+    }
+
+    fn field(spec: &str) -> Result<Segment, String> {
+        let mut parts = spec.split(':');
+        let field_type = match parts.next() {
+            Some("u32") => FieldType::U32,
+            Some("u64") => FieldType::U64,
+            Some("uuid") => FieldType::Uuid,
+            Some("alnum") => FieldType::Alphanumeric,
+            _ => return Err(format!("unknown field type in key template: {{{}}}", spec)),
+        };
+        let mut length = 0;
+        if field_type == FieldType::Alphanumeric {
+            length = parts
+                .next()
+                .and_then(|l| l.parse().ok())
+                .ok_or_else(|| format!("key template field needs a length: {{{}}}", spec))?;
+        }
+        let mut cardinality = None;
+        let mut exponent = None;
+        for part in parts {
+            if let Some(c) = parse_count(part) {
+                cardinality = Some(c);
+            } else if part == "zipf" {
+                exponent = Some(1.0);
+            } else if let Some(e) = part.strip_prefix("zipf=").and_then(|e| e.parse().ok()) {
+                exponent = Some(e);
+            } else {
+                return Err(format!("bad key template field: {{{}}}", spec));
+            }
+        }
+        let cardinality = match (field_type, cardinality) {
+            (FieldType::Alphanumeric, None) if exponent.is_none() => {
+                return Ok(Segment::Field {
+                    field_type,
+                    length,
+                    distribution: None,
+                });
+            }
+            (FieldType::U32, Some(c)) if c > u32::MAX as u64 + 1 => {
+                return Err(format!(
+                    "u32 fields support a cardinality of at most 2^32: {{{}}}",
+                    spec
+                ));
+            }
+            (_, Some(c)) if c > 0 => c as usize,
+            _ => {
+                return Err(format!(
+                    "key template field needs a cardinality: {{{}}}",
+                    spec
+                ))
+            }
+        };
+        let distribution = match exponent {
+            None => KeyDistribution::Uniform(Uniform::new(0, cardinality)),
+            Some(exponent) => KeyDistribution::Zipf(
+                ZipfDistribution::new(cardinality, exponent)
+                    .map_err(|_| format!("bad zipf exponent in key template: {{{}}}", spec))?,
+                exponent,
+            ),
+        };
+        Ok(Segment::Field {
+            field_type,
+            length,
+            distribution: Some(distribution),
+        })
+    }
+
+    pub fn generate(&self, rng: &mut SmallRng) -> Vec<u8> {
+        let mut key = Vec::new();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Literal(literal) => key.extend_from_slice(literal),
+                Segment::Field {
+                    field_type: FieldType::Alphanumeric,
+                    length,
+                    distribution: None,
+                } => key.extend(rng.sample_iter(&Alphanumeric).take(*length)),
+                Segment::Field {
+                    field_type: FieldType::Alphanumeric,
+                    length,
+                    distribution: Some(distribution),
+                } => key.extend(pool_field(
+                    self.keyspace,
+                    TEMPLATE_FIELD_BASE + i,
+                    distribution.sample(rng) as u64,
+                    *length,
+                )),
+                Segment::Field {
+                    field_type,
+                    distribution: Some(distribution),
+                    ..
+                } => key.extend(format_field(
+                    *field_type,
+                    distribution.sample(rng) as u64,
+                    0,
+                )),
+                Segment::Field { .. } => unreachable!("formatted fields have a cardinality"),
+            }
+        }
+        key
+    }
+}
+
+/// Parse a count written as an integer or in scientific notation, eg: `1e6`
+fn parse_count(count: &str) -> Option<u64> {
+    let count = count.replace('_', "");
+    count.parse().ok().or_else(|| {
+        count
+            .parse::<f64>()
+            .ok()
+            .filter(|c| c.fract() == 0.0 && *c >= 0.0 && *c <= u64::MAX as f64)
+            .map(|c| c as u64)
+    })
+}
+
+/// Formats the field with the provided index for the numeric and UUID field
+/// types. Numeric fields are zero padded to the length.
+fn format_field(field_type: FieldType, index: u64, length: usize) -> Vec<u8> {
+    match field_type {
+        FieldType::U32 | FieldType::U64 => format!("{:0>len$}", index, len = length).into_bytes(),
+        FieldType::Uuid => uuid(index),
+        FieldType::Alphanumeric => unreachable!("alphanumeric fields are not formatted"),
+    }
+}
+
+/// Generates a random looking version 4 UUID which is unique for each index.
+fn uuid(index: u64) -> Vec<u8> {
+    // the mixed index is unique per index, its low 62 bits and high 2 bits are
+    // kept intact around the fixed version and variant bits
+    let mixed = splitmix64(index);
+    let low = (mixed & ((1 << 62) - 1)) | (0b10 << 62);
+    let high = splitmix64(!mixed);
+    let high = (high & !0xf003) | 0x4000 | (mixed >> 62);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+    .into_bytes()
+}
+
+/// A bijective 64bit mix function, see: https://prng.di.unimi.it/splitmix64.c
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
 }
 
 impl Keyspace {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
     pub fn length(&self) -> usize {
         self.length
     }
 
-    pub fn cardinality(&self) -> u32 {
+    pub fn cardinality(&self) -> u64 {
         self.cardinality
     }
 
-    // TODO(aetimmes): implement cardinality for Alphanumeric fields
-    pub fn generate_key(&self, rng: &mut SmallRng) -> Vec<u8> {
+    /// The weight of the keyspace when choosing one for each request
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    pub fn key_type(&self) -> FieldType {
+        self.key_type
+    }
+
+    /// Returns true if keys are built from a template
+    pub fn has_key_template(&self) -> bool {
+        self.key_template.is_some()
+    }
+
+    /// The exponent of the zipf distribution keys are drawn from, unless
+    /// they are drawn uniformly or built from a template
+    pub fn zipf_exponent(&self) -> Option<f64> {
+        match self.key_distribution {
+            KeyDistribution::Zipf(_, exponent) if self.key_template.is_none() => Some(exponent),
+            _ => None,
+        }
+    }
+
+    /// The size of the cache, in keys, whose expected hit rate is reported
+    pub fn cache_size(&self) -> Option<u64> {
+        self.cache_size
+    }
+
+    /// The requests counted for the most popular keys, when they are reported
+    pub fn hot_keys(&self) -> Option<&HotKeys> {
+        self.hot_keys.as_deref()
+    }
+
+    /// Generate a key. If a shard is provided as the shard index and the shard
+    /// count, only keys which belong to that shard are generated. An edge-case
+    /// key is marked in the outcome of the request.
+    pub fn generate_key(
+        &self,
+        rng: &mut SmallRng,
+        shard: Option<(usize, usize)>,
+        outcome: &mut Outcome,
+    ) -> Vec<u8> {
+        if let Some(ref edge_cases) = self.edge_cases {
+            if rng.gen_bool(edge_cases.share) {
+                outcome.edge_case = true;
+                return edge_cases.generate(rng, self.length);
+            }
+        }
+        if let Some(ref template) = self.key_template {
+            return self.namespaced(template.generate(rng));
+        }
         match self.key_type {
-            FieldType::Alphanumeric => rng
-                .sample_iter(&Alphanumeric)
-                .take(self.length())
-                .collect::<Vec<u8>>(),
-            FieldType::U32 => format!(
-                "{:0>len$}",
-                self.key_distribution.sample(rng) as u32,
-                len = self.length()
-            )
-            .as_bytes()
-            .to_vec(),
+            FieldType::Alphanumeric if self.key_pool.is_some() => {
+                self.key(self.sample_key_index(rng, shard))
+            }
+            FieldType::Alphanumeric => self.namespaced(
+                rng.sample_iter(&Alphanumeric)
+                    .take(self.length())
+                    .collect::<Vec<u8>>(),
+            ),
+            FieldType::U32 | FieldType::U64 | FieldType::Uuid => {
+                self.key(self.sample_key_index(rng, shard))
+            }
+        }
+    }
+
+    /// The key with the provided index, for keyspaces with a fixed
+    /// cardinality.
+    pub fn key(&self, index: usize) -> Vec<u8> {
+        let key = match (&self.key_type, &self.key_pool) {
+            (FieldType::Alphanumeric, Some(KeyPool::Generated(keys))) => keys[index].clone(),
+            (FieldType::Alphanumeric, _) => pool_key(self.index, index, self.length),
+            (field_type, _) => format_field(*field_type, index as u64, self.length),
+        };
+        self.namespaced(key)
+    }
+
+    /// Prefix the key with the current namespace version, if there is one
+    fn namespaced(&self, key: Vec<u8>) -> Vec<u8> {
+        match self.namespace {
+            Some(ref namespace) => {
+                let version = namespace.version.load(Ordering::Relaxed);
+                let mut namespaced = format!("{}{}:", namespace.prefix, version).into_bytes();
+                namespaced.extend(key);
+                namespaced
+            }
+            None => key,
+        }
+    }
+
+    /// The commands sent by the transaction command
+    pub fn transaction(&self) -> Option<&Transaction> {
+        self.transaction.as_ref()
+    }
+
+    pub fn scripts(&self) -> &[LuaScript] {
+        &self.scripts
+    }
+
+    /// Choose one of the Lua scripts by weight, if there are any
+    pub fn choose_script(&self, rng: &mut SmallRng) -> Option<&LuaScript> {
+        self.script_dist
+            .as_ref()
+            .map(|dist| &self.scripts[dist.sample(rng)])
+    }
+
+    /// The number of keys followed by the keys and the arguments to run the
+    /// script with
+    pub fn generate_script_args(
+        &self,
+        rng: &mut SmallRng,
+        script: &LuaScript,
+        shard: Option<(usize, usize)>,
+        outcome: &mut Outcome,
+    ) -> Vec<Vec<u8>> {
+        let mut args = vec![script.keys.to_string().into_bytes()];
+        for _ in 0..script.keys {
+            args.push(self.generate_key(rng, shard, outcome));
+        }
+        for arg in &script.args {
+            args.push(match arg {
+                ScriptArg::Literal(arg) => arg.clone(),
+                ScriptArg::Value => self.generate_value(rng).unwrap_or_default(),
+                ScriptArg::InnerKey => self.generate_inner_key(rng).unwrap_or_default(),
+            });
+        }
+        args
+    }
+
+    /// Returns true if the keys are prefixed with a namespace version
+    pub fn has_namespace(&self) -> bool {
+        self.namespace.is_some()
+    }
+
+    /// Bump the namespace to the version which is due once the time has
+    /// elapsed, returning the new version if it changed
+    pub fn advance_namespace(&self, elapsed: std::time::Duration) -> Option<u64> {
+        let namespace = self.namespace.as_ref()?;
+        let version = (elapsed.as_nanos() / namespace.interval.as_nanos()) as u64;
+        (namespace.version.swap(version, Ordering::Relaxed) != version).then_some(version)
+    }
+
+    /// Returns true if the keyspace has a fixed set of keys, either because
+    /// they are formatted from the key index or drawn from a key pool.
+    pub fn has_fixed_keys(&self) -> bool {
+        self.key_template.is_none()
+            && (self.key_type != FieldType::Alphanumeric || self.key_pool.is_some())
+    }
+
+    /// Sample a key index, moving it to the nearest index within the shard so
+    /// that the key distribution is approximately preserved.
+    fn sample_key_index(&self, rng: &mut SmallRng, shard: Option<(usize, usize)>) -> usize {
+        let index = self.key_distribution.sample(rng);
+        // each agent's connections divide the agent's share of the keys
+        let shard = match (self.agent_shard, shard) {
+            (Some((agent, agents)), Some((shard, count))) => {
+                Some((agent * count + shard, agents * count))
+            }
+            (Some(agent_shard), None) => Some(agent_shard),
+            (None, shard) => shard,
+        };
+        let index = match shard {
+            Some((shard, count)) if count > 1 => {
+                let cardinality = self.cardinality as usize;
+                let index = index - index % count + shard;
+                if index < cardinality {
+                    index
+                } else if index >= count {
+                    index - count
+                } else {
+                    // there are fewer keys than shards
+                    shard % cardinality
+                }
+            }
+            _ => index,
+        };
+        if let Some(ref hot_keys) = self.hot_keys {
+            hot_keys.record(index);
+        }
+        index
+    }
+
+    /// Returns true if each write of a key stores the next version of its value
+    pub fn mutates_values(&self) -> bool {
+        self.versions.is_some()
+    }
+
+    /// The written and acknowledged version of each key's value, when values
+    /// are mutated
+    pub fn versions(&self) -> Option<(Vec<u32>, Vec<u32>)> {
+        let versions = self.versions.as_ref()?;
+        let load = |versions: &[AtomicU32]| {
+            versions
+                .iter()
+                .map(|version| version.load(Ordering::Relaxed))
+                .collect()
+        };
+        Some((load(&versions.written), load(&versions.acknowledged)))
+    }
+
+    /// Continue from the written and acknowledged versions of a previous run
+    pub fn restore_versions(&self, written: &[u32], acknowledged: &[u32]) -> Result<(), String> {
+        let versions = match self.versions {
+            Some(ref versions) => versions,
+            None => return Err(format!("keyspace {} does not mutate values", self.index)),
+        };
+        if written.len() != versions.written.len() || acknowledged.len() != written.len() {
+            return Err(format!(
+                "keyspace {} has a different cardinality",
+                self.index
+            ));
+        }
+        for (version, value) in versions.written.iter().zip(written) {
+            version.store(*value, Ordering::Relaxed);
+        }
+        for (version, value) in versions.acknowledged.iter().zip(acknowledged) {
+            version.store(*value, Ordering::Relaxed);
+        }
+        // the writes of the previous run have all completed
+        for (completed, value) in versions.completed.iter().zip(written) {
+            completed.store((*value as u64) << 32, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Generate a key for a read, with the lowest version of its value which
+    /// the read may return. The version is zero unless values are mutated.
+    pub fn generate_read_key(
+        &self,
+        rng: &mut SmallRng,
+        shard: Option<(usize, usize)>,
+        outcome: &mut Outcome,
+    ) -> (Vec<u8>, u32) {
+        match self.versions {
+            Some(ref versions) => {
+                let index = self.sample_key_index(rng, shard);
+                let version = versions.acknowledged[index].load(Ordering::Relaxed);
+                (self.key(index), version)
+            }
+            None => (self.generate_key(rng, shard, outcome), 0),
+        }
+    }
+
+    /// Generate the key and value for a write. When values are mutated, the
+    /// value is the next version of the key's value.
+    pub fn generate_write(
+        &self,
+        rng: &mut SmallRng,
+        shard: Option<(usize, usize)>,
+        outcome: &mut Outcome,
+    ) -> (Vec<u8>, Vec<u8>, Option<VersionedWrite>) {
+        if self.versions.is_some() {
+            let index = self.sample_key_index(rng, shard);
+            self.generate_write_for(rng, index)
+        } else {
+            let key = self.generate_key(rng, shard, outcome);
+            (key, self.generate_value(rng).unwrap_or_default(), None)
+        }
+    }
+
+    /// Generate the key and value for a write of the key with the provided
+    /// index. A mutated value starts with its version, and the rest of the
+    /// value is the same for every version of the key.
+    pub fn generate_write_for(
+        &self,
+        rng: &mut SmallRng,
+        index: usize,
+    ) -> (Vec<u8>, Vec<u8>, Option<VersionedWrite>) {
+        let key = self.key(index);
+        match self.versions {
+            Some(ref versions) => {
+                let settled = versions.settled(index);
+                let version = versions.written[index]
+                    .fetch_add(1, Ordering::Relaxed)
+                    .wrapping_add(1);
+                let length = self.choose_value_length(rng).unwrap_or(0);
+                let mut value = format!("{:08x}", version).into_bytes();
+                value.extend(pool_field(
+                    self.index,
+                    MUTATION_FIELD,
+                    index as u64,
+                    length.saturating_sub(validate::VERSION_LEN),
+                ));
+                let write = VersionedWrite {
+                    keyspace: self.index,
+                    index,
+                    version,
+                    settled,
+                };
+                (key, value, Some(write))
+            }
+            None => (key, self.generate_value(rng).unwrap_or_default(), None),
         }
     }
 
-    //#TODO(atimmes): implement cardinality for Alphanumeric fields
     pub fn generate_inner_key(&self, rng: &mut SmallRng) -> Option<Vec<u8>> {
         if let Some(ref dist) = self.inner_key_dist {
             let idx = dist.sample(rng);
             let conf = &self.inner_keys[idx];
+            let index = self.inner_key_distributions[idx].sample(rng) as u64;
             let inner_key = match conf.field_type() {
+                FieldType::Alphanumeric if conf.is_bounded() => {
+                    pool_field(self.index, idx, index, conf.length())
+                }
                 FieldType::Alphanumeric => rng
                     .sample_iter(&Alphanumeric)
                     .take(conf.length())
                     .collect::<Vec<u8>>(),
-                FieldType::U32 => format!(
-                    "{:0>len$}",
-                    &rng.gen_range(0u32..conf.cardinality()),
-                    len = conf.length()
-                )
-                .as_bytes()
-                .to_vec(),
+                field_type => format_field(field_type, index, conf.length()),
             };
             Some(inner_key)
         } else {
@@ -108,23 +1098,30 @@ impl Keyspace {
         }
     }
 
-    //#TODO(atimmes): implement cardinality for Alphanumeric fields
     pub fn generate_value(&self, rng: &mut SmallRng) -> Option<Vec<u8>> {
         if let Some(ref value_dist) = self.value_dist {
             let value_idx = value_dist.sample(rng);
             let value_conf = &self.values[value_idx];
             let value = match value_conf.field_type() {
-                FieldType::Alphanumeric => rng
-                    .sample_iter(&Alphanumeric)
-                    .take(value_conf.length())
-                    .collect::<Vec<u8>>(),
-                FieldType::U32 => format!(
-                    "{:0>len$}",
-                    &rng.gen_range(0u32..value_conf.cardinality()),
-                    len = value_conf.length()
-                )
-                .as_bytes()
-                .to_vec(),
+                // values are numbered after the inner keys
+                FieldType::Alphanumeric if value_conf.is_bounded() => pool_field(
+                    self.index,
+                    self.inner_keys.len() + value_idx,
+                    rng.gen_range(0..value_conf.cardinality()),
+                    value_conf.length(),
+                ),
+                FieldType::Alphanumeric => {
+                    let length = match self.value_sizes[value_idx] {
+                        Some(ref size) => size.sample(rng),
+                        None => value_conf.length(),
+                    };
+                    self.value_fills[value_idx].fill(rng, length)
+                }
+                field_type => format_field(
+                    field_type,
+                    rng.gen_range(0..value_conf.cardinality()),
+                    value_conf.length(),
+                ),
             };
             Some(value)
         } else {
@@ -132,18 +1129,49 @@ impl Keyspace {
         }
     }
 
-    pub fn choose_command(&self, rng: &mut SmallRng) -> &Command {
-        &self.commands[self.command_dist.sample(rng)]
+    /// Choose the next command to send on the session. If the keyspace has a
+    /// transition matrix and the previous command on the session was from
+    /// this keyspace, the choice depends on the previous command.
+    pub fn choose_command(&self, rng: &mut SmallRng, session: &mut Session) -> &Command {
+        let idx = match session.previous_command() {
+            Some((keyspace, previous))
+                if keyspace == self.index && !self.transition_dist.is_empty() =>
+            {
+                self.transition_dist[previous].sample(rng)
+            }
+            _ => self.command_dist.sample(rng),
+        };
+        session.set_previous_command(self.index, idx);
+        &self.commands[idx]
     }
 
-    pub fn choose_value(&self, rng: &mut SmallRng) -> Option<&Value> {
-        if self.value_dist.is_some() {
-            Some(&self.values[self.value_dist.as_ref().unwrap().sample(rng)])
-        } else {
-            None
+    /// The length of a value, drawn from its size distribution when it has
+    /// one
+    pub fn choose_value_length(&self, rng: &mut SmallRng) -> Option<usize> {
+        let index = self.value_dist.as_ref()?.sample(rng);
+        match self.value_sizes[index] {
+            Some(ref size) => Some(size.sample(rng)),
+            None => Some(self.values[index].length()),
         }
     }
 
+    /// Choose the number of fields to write with a hash set, if the keyspace
+    /// has a distribution of field counts
+    pub fn choose_field_count(&self, rng: &mut SmallRng) -> Option<usize> {
+        self.field_count_dist
+            .as_ref()
+            .map(|dist| self.field_counts[dist.sample(rng)])
+    }
+
+    /// Choose the amount to increment or decrement a counter by, which is one
+    /// unless the keyspace has deltas
+    pub fn choose_delta(&self, rng: &mut SmallRng) -> u64 {
+        self.delta_dist
+            .as_ref()
+            .map(|dist| self.deltas[dist.sample(rng)])
+            .unwrap_or(1)
+    }
+
     pub fn ttl(&self) -> usize {
         self.ttl
     }
@@ -155,14 +1183,55 @@ impl Keyspace {
 
 impl Config {
     pub fn new(file: Option<&str>) -> Self {
-        let config_file = if let Some(file) = file {
-            ConfigFile::load_from_file(file)
+        let (file, config_file) = if let Some(file) = file {
+            (file.to_owned(), ConfigFile::load_from_file(file))
         } else {
             fatal!("need a config file");
         };
 
         let mut keyspaces = Vec::new();
-        for k in config_file.keyspaces() {
+        for (index, k) in config_file.keyspaces().iter().enumerate() {
+            if k.cardinality() == 0 {
+                fatal!("keyspace {} needs a cardinality of at least one", index);
+            }
+            if k.commands().iter().all(|c| c.weight() == 0) {
+                fatal!("keyspace {} needs a command with a non-zero weight", index);
+            }
+            if !k.values().is_empty() && k.values().iter().all(|v| v.weight() == 0) {
+                fatal!("keyspace {} needs a value with a non-zero weight", index);
+            }
+            if !k.inner_keys().is_empty() && k.inner_keys().iter().all(|f| f.weight() == 0) {
+                fatal!(
+                    "keyspace {} needs an inner key with a non-zero weight",
+                    index
+                );
+            }
+            // numeric keys grow past the length when they need more digits,
+            // and pooled alphanumeric keys repeat if they are too short
+            let distinct =
+                |alphabet: f64| alphabet.powi(k.length() as i32) >= k.cardinality() as f64;
+            let pooled = k.key_pool() || k.is_bounded();
+            if k.key_template().is_none() {
+                match k.key_type() {
+                    FieldType::Alphanumeric if pooled && !distinct(62.0) => {
+                        fatal!(
+                            "keyspace {} can not have {} distinct alphanumeric keys of length {}",
+                            index,
+                            k.cardinality(),
+                            k.length()
+                        );
+                    }
+                    FieldType::U32 | FieldType::U64 if !distinct(10.0) => {
+                        warn!(
+                            "keyspace {} has {} keys, some are longer than the length of {}",
+                            index,
+                            k.cardinality(),
+                            k.length()
+                        );
+                    }
+                    _ => {}
+                }
+            }
             let inner_keys = k.inner_keys();
             let inner_key_weights: Vec<usize> = if inner_keys.is_empty() {
                 Vec::new()
@@ -178,6 +1247,28 @@ impl Config {
             let command_weights: Vec<usize> = k.commands().iter().map(|v| v.weight()).collect();
             let command_dist = WeightedAliasIndex::new(command_weights).unwrap();
 
+            let transitions = k.transitions();
+            if !transitions.is_empty()
+                && (transitions.len() != k.commands().len()
+                    || transitions
+                        .iter()
+                        .any(|row| row.len() != k.commands().len()))
+            {
+                fatal!("keyspace transitions must be a square matrix with one row and column per command");
+            }
+            let transition_dist = transitions
+                .into_iter()
+                .map(|row| match WeightedAliasIndex::new(row) {
+                    Ok(dist) => dist,
+                    Err(_) => {
+                        fatal!(
+                            "keyspace {} transitions need a non-zero weight in each row",
+                            index
+                        );
+                    }
+                })
+                .collect();
+
             let values = k.values();
             let value_weights: Vec<usize> = if values.is_empty() {
                 Vec::new()
@@ -189,66 +1280,982 @@ impl Config {
             } else {
                 Some(WeightedAliasIndex::new(value_weights).unwrap())
             };
+            let value_sizes = values
+                .iter()
+                .map(|value| match (value.has_length(), value.size()) {
+                    (true, None) => None,
+                    (false, Some(size)) => {
+                        if value.field_type() != FieldType::Alphanumeric || value.is_bounded() {
+                            fatal!("keyspace {} value sizes need alphanumeric values without a cardinality", index);
+                        }
+                        match ValueLength::new(size) {
+                            Ok(size) => Some(size),
+                            Err(e) => {
+                                fatal!("keyspace {} {}", index, e);
+                            }
+                        }
+                    }
+                    _ => {
+                        fatal!(
+                            "keyspace {} values need either a length or a size",
+                            index
+                        );
+                    }
+                })
+                .collect();
+            let value_fills = values
+                .iter()
+                .map(|value| {
+                    let kind = value.kind();
+                    if kind == ValueKind::Random {
+                        return ValueFill::Random;
+                    }
+                    if value.field_type() != FieldType::Alphanumeric || value.is_bounded() {
+                        fatal!(
+                            "keyspace {} value kinds need alphanumeric values without a cardinality",
+                            index
+                        );
+                    }
+                    if k.value_mutation() {
+                        fatal!("keyspace {} can not mix value kinds with value mutation", index);
+                    }
+                    let binary = matches!(kind, ValueKind::Zeros | ValueKind::Compressible);
+                    if binary
+                        && matches!(
+                            config_file.general().protocol(),
+                            Protocol::Redis | Protocol::RedisInline
+                        )
+                    {
+                        fatal!("binary values need the redis_resp protocol, inline commands can not carry them");
+                    }
+                    match (kind, value.pattern(), value.compression_ratio()) {
+                        (ValueKind::Pattern, Some(pattern), _) if !pattern.is_empty() => {
+                            ValueFill::Pattern(pattern.into_bytes())
+                        }
+                        (ValueKind::Pattern, _, _) => {
+                            fatal!("keyspace {} pattern values need a pattern", index);
+                        }
+                        (ValueKind::Compressible, _, Some(ratio)) if ratio >= 1.0 => {
+                            ValueFill::Compressible(1.0 / ratio)
+                        }
+                        (ValueKind::Compressible, _, _) => {
+                            fatal!(
+                                "keyspace {} compressible values need a compression_ratio of at least 1",
+                                index
+                            );
+                        }
+                        (ValueKind::Zeros, _, _) => ValueFill::Zeros,
+                        (ValueKind::Json, _, _) => ValueFill::Json,
+                        (ValueKind::Random, _, _) => ValueFill::Random,
+                    }
+                })
+                .collect();
+
+            let field_counts = k.fields();
+            if field_counts.iter().any(|f| f.count() == 0) {
+                fatal!("keyspace field counts must be at least one");
+            }
+            let field_count_dist = if field_counts.is_empty() {
+                None
+            } else {
+                match WeightedAliasIndex::new(field_counts.iter().map(|f| f.weight()).collect()) {
+                    Ok(dist) => Some(dist),
+                    Err(_) => {
+                        fatal!(
+                            "keyspace {} needs a field count with a non-zero weight",
+                            index
+                        );
+                    }
+                }
+            };
+
+            let deltas = k.deltas();
+            if deltas.iter().any(|d| d.delta() == 0) {
+                fatal!("keyspace {} deltas must be at least one", index);
+            }
+            let delta_dist = if deltas.is_empty() {
+                None
+            } else {
+                match WeightedAliasIndex::new(deltas.iter().map(|d| d.weight()).collect()) {
+                    Ok(dist) => Some(dist),
+                    Err(_) => {
+                        fatal!("keyspace {} needs a delta with a non-zero weight", index);
+                    }
+                }
+            };
+
+            let key_distribution =
+                KeyDistribution::new(k.key_distribution.as_ref(), k.cardinality());
+            // the expected hit rate of a cache this size is reported at startup
+            let cache_size = k
+                .key_distribution
+                .as_ref()
+                .and_then(|kd| kd.parameters.get("cache_size"))
+                .map(|size| match size.parse::<u64>() {
+                    Ok(size) => size,
+                    Err(_) => {
+                        fatal!("bad cache_size for key distribution: {}", size);
+                    }
+                });
+            // the most requested keys are reported at the end of the run
+            let hot_keys = k
+                .key_distribution
+                .as_ref()
+                .and_then(|kd| kd.parameters.get("hot_keys"))
+                .map(|report| match report.parse::<usize>() {
+                    Ok(report) if report > 0 => report,
+                    _ => {
+                        fatal!("bad hot_keys for key distribution: {}", report);
+                    }
+                });
+            let hot_keys = match hot_keys {
+                Some(_) if !matches!(key_distribution, KeyDistribution::Zipf(..)) => {
+                    fatal!("keyspace {} can only report hot keys for zipf keys", index);
+                }
+                Some(_) if k.key_template().is_some() => {
+                    fatal!(
+                        "keyspace {} can not report hot keys for templated keys",
+                        index
+                    );
+                }
+                Some(_) if k.key_type() == FieldType::Alphanumeric && !pooled => {
+                    fatal!(
+                        "keyspace {} can only report hot keys for alphanumeric keys with a cardinality",
+                        index
+                    );
+                }
+                Some(report) => Some(Arc::new(HotKeys::new(report, k.cardinality()))),
+                None => None,
+            };
+            let inner_key_distributions = inner_keys
+                .iter()
+                .map(|f| KeyDistribution::new(f.distribution(), f.cardinality()))
+                .collect();
+
+            let mut fields = vec![(k.key_type(), k.cardinality())];
+            fields.extend(
+                k.inner_keys()
+                    .iter()
+                    .map(|f| (f.field_type(), f.cardinality())),
+            );
+            fields.extend(k.values().iter().map(|f| (f.field_type(), f.cardinality())));
+            for (field_type, cardinality) in fields {
+                if field_type == FieldType::U32 && cardinality > u32::MAX as u64 + 1 {
+                    fatal!("u32 fields support a cardinality of at most 2^32, use u64 instead");
+                }
+            }
+
+            // alphanumeric keys with a set cardinality are drawn from a pool
+            let key_pool = if pooled && k.key_type() == FieldType::Alphanumeric {
+                let cardinality = k.cardinality() as usize;
+                if cardinality <= KEY_POOL_MAX {
+                    let keys: Vec<Vec<u8>> = (0..cardinality)
+                        .map(|i| pool_key(index, i, k.length()))
+                        .collect();
+                    Some(KeyPool::Generated(keys.into()))
+                } else {
+                    Some(KeyPool::OnDemand)
+                }
+            } else {
+                None
+            };
 
-            let key_distribution = match k.key_distribution {
-                None => KeyDistribution::Uniform(Uniform::new(0, k.cardinality() as usize)),
-                Some(ref kd) => match kd.model {
-                    KeyDistributionModel::Uniform => {
-                        KeyDistribution::Uniform(Uniform::new(0, k.cardinality() as usize))
+            let key_template = k.key_template().map(|template| {
+                KeyTemplate::parse(index, &template).unwrap_or_else(|e| {
+                    fatal!("{}", e);
+                })
+            });
+
+            let edge_cases = k.edge_cases().map(|edge_cases| {
+                if !(0.0..=1.0).contains(&edge_cases.share()) {
+                    fatal!("keyspace {} edge case share must be from 0 to 1", index);
+                }
+                if edge_cases.max_length() == 0 {
+                    fatal!("keyspace {} edge case max_length must be at least 1", index);
+                }
+                if k.value_mutation() {
+                    fatal!(
+                        "keyspace {} can not mix edge case keys with value mutation",
+                        index
+                    );
+                }
+                let mut kinds = vec![EdgeCase::MaxLength, EdgeCase::SingleByte];
+                // text protocols delimit keys with spaces and newlines
+                if matches!(
+                    config_file.general().protocol(),
+                    Protocol::RedisResp | Protocol::ThriftCache | Protocol::Mongodb
+                ) {
+                    kinds.extend([EdgeCase::Space, EdgeCase::Control, EdgeCase::NonUtf8]);
+                }
+                EdgeCaseKeys {
+                    share: edge_cases.share(),
+                    max_length: edge_cases.max_length(),
+                    kinds,
+                }
+            });
+
+            let namespace = k.namespace().map(|namespace| {
+                if namespace.interval().is_zero() {
+                    fatal!("keyspace {} namespace interval must be at least 1s", index);
+                }
+                Arc::new(Namespace {
+                    prefix: namespace.prefix().to_string(),
+                    interval: namespace.interval(),
+                    version: AtomicU64::new(0),
+                })
+            });
+
+            let transaction = k.transaction();
+            match transaction {
+                Some(ref transaction) => {
+                    let protocol = config_file.general().protocol();
+                    if !matches!(
+                        protocol,
+                        Protocol::Redis | Protocol::RedisInline | Protocol::RedisResp
+                    ) {
+                        fatal!("transactions are only supported for the redis protocols");
+                    }
+                    if transaction.commands().is_empty() {
+                        fatal!("keyspace {} transaction needs at least one command", index);
                     }
-                    KeyDistributionModel::Zipf => {
-                        let exponent = kd
-                            .parameters
-                            .get("exponent")
-                            .unwrap_or(&"1.0".to_owned())
-                            .parse::<f64>()
-                            .expect("bad exponent for zipf distribution");
-                        KeyDistribution::Zipf(
-                            ZipfDistribution::new(k.cardinality() as usize, exponent)
-                                .expect("bad zipf config"),
+                    if !transaction.commands().iter().all(|verb| {
+                        matches!(
+                            verb,
+                            Verb::Get
+                                | Verb::Set
+                                | Verb::Delete
+                                | Verb::Hget
+                                | Verb::Hset
+                                | Verb::Hdel
                         )
+                    }) {
+                        fatal!(
+                            "keyspace {} transaction only supports the get, set, delete, hget, hset, and hdel commands",
+                            index
+                        );
+                    }
+                    // inline commands are split on spaces, which a script has
+                    if transaction.mode() == TransactionMode::Eval
+                        && protocol != Protocol::RedisResp
+                    {
+                        fatal!("eval transactions need the redis_resp protocol");
+                    }
+                }
+                None => {
+                    if k.commands()
+                        .iter()
+                        .any(|command| command.verb() == Verb::Transaction)
+                    {
+                        fatal!(
+                            "keyspace {} needs a transaction for the transaction command",
+                            index
+                        );
+                    }
+                }
+            }
+
+            let scripts: Vec<LuaScript> = k.scripts().iter().map(LuaScript::new).collect();
+            let script_dist = if scripts.is_empty() {
+                if k.commands()
+                    .iter()
+                    .any(|command| command.verb() == Verb::Evalsha)
+                {
+                    fatal!("keyspace {} needs scripts for the evalsha command", index);
+                }
+                None
+            } else {
+                // inline commands are split on spaces, which a script has
+                if config_file.general().protocol() != Protocol::RedisResp {
+                    fatal!("lua scripts need the redis_resp protocol");
+                }
+                match WeightedAliasIndex::new(k.scripts().iter().map(|s| s.weight()).collect()) {
+                    Ok(dist) => Some(dist),
+                    Err(_) => {
+                        fatal!("keyspace {} needs a script with a non-zero weight", index);
                     }
-                },
+                }
             };
 
             let keyspace = Keyspace {
+                index,
                 length: k.length(),
                 weight: k.weight(),
                 cardinality: k.cardinality(),
                 commands: k.commands(),
                 command_dist,
+                transition_dist,
                 inner_keys: k.inner_keys(),
                 inner_key_dist,
+                inner_key_distributions,
                 values: k.values(),
                 value_dist,
+                value_sizes,
+                value_fills,
+                field_counts: field_counts.iter().map(|f| f.count()).collect(),
+                field_count_dist,
+                deltas: deltas.iter().map(|d| d.delta()).collect(),
+                delta_dist,
                 ttl: k.ttl(),
                 key_type: k.key_type(),
                 batch_size: k.batch_size(),
                 key_distribution,
+                cache_size,
+                key_pool,
+                key_template,
+                agent_shard: None,
+                versions: k
+                    .value_mutation()
+                    .then(|| Arc::new(Versions::new(k.cardinality()))),
+                hot_keys,
+                edge_cases,
+                namespace,
+                transaction,
+                scripts,
+                script_dist,
             };
             keyspaces.push(keyspace);
         }
 
         let weights: Vec<usize> = keyspaces.iter().map(|k| k.weight).collect();
-        let keyspace_dist = WeightedAliasIndex::new(weights).unwrap();
+        let keyspace_dist = match WeightedAliasIndex::new(weights) {
+            Ok(keyspace_dist) => keyspace_dist,
+            Err(_) => {
+                fatal!("need a keyspace with a non-zero weight");
+            }
+        };
 
-        if config_file.target().endpoints().is_empty() {
+        let cache_protocol = matches!(
+            config_file.general().protocol(),
+            Protocol::Memcache | Protocol::Redis | Protocol::RedisInline | Protocol::RedisResp
+        );
+        if config_file.general().prefill() && !cache_protocol {
+            fatal!("prefill is only supported for memcache and redis protocols");
+        }
+        if config_file.general().validate() && !cache_protocol {
+            fatal!("validation is only supported for memcache and redis protocols");
+        }
+        if keyspaces.iter().any(|k| k.mutates_values()) {
+            if !cache_protocol {
+                fatal!("value mutation is only supported for memcache and redis protocols");
+            }
+            if keyspaces
+                .iter()
+                .any(|k| k.mutates_values() && !k.has_fixed_keys())
+            {
+                fatal!(
+                    "value mutation needs numeric keys, or alphanumeric keys with a cardinality"
+                );
+            }
+        }
+
+        let verbs: Vec<Verb> = keyspaces
+            .iter()
+            .flat_map(|k| k.commands.iter().map(|c| c.verb()))
+            .collect();
+        for sla in config_file.sla() {
+            if !verbs.contains(&sla.verb()) {
+                fatal!(
+                    "sla for {} which is not used by any keyspace",
+                    <&str>::from(sla.verb())
+                );
+            }
+            if !(sla.percentile() > 0.0 && sla.percentile() <= 100.0) {
+                fatal!("sla percentile must be greater than 0 and at most 100");
+            }
+            if sla.latency().is_none() && sla.error_rate().is_none() && sla.rate().is_none() {
+                fatal!(
+                    "sla for {} needs a latency, error_rate, or rate",
+                    <&str>::from(sla.verb())
+                );
+            }
+            if sla
+                .error_rate()
+                .is_some_and(|error_rate| !(0.0..=100.0).contains(&error_rate))
+            {
+                fatal!("sla error_rate must be from 0 to 100");
+            }
+            if sla.rate().is_some_and(|rate| rate.is_nan() || rate < 0.0) {
+                fatal!("sla rate must not be negative");
+            }
+        }
+
+        if config_file.abort().windows() == 0 {
+            fatal!("abort windows must be at least 1");
+        }
+        if config_file
+            .abort()
+            .error_rate()
+            .is_some_and(|error_rate| !(0.0..=100.0).contains(&error_rate))
+        {
+            fatal!("abort error_rate must be from 0 to 100");
+        }
+
+        for verb in config_file.waterfall().commands() {
+            if !verbs.contains(&verb) {
+                fatal!(
+                    "waterfall for {} which is not used by any keyspace",
+                    <&str>::from(verb)
+                );
+            }
+        }
+
+        if let Some(calibration) = config_file.calibration() {
+            if calibration.rate() == 0 || calibration.rate() > 1_000_000 {
+                fatal!("calibration rate must be from 1 to 1000000 probes per second");
+            }
+        }
+
+        if config_file
+            .blackout()
+            .iter()
+            .any(|blackout| blackout.duration().is_zero())
+        {
+            fatal!("blackout duration must be greater than 0");
+        }
+
+        // a closed-loop connection waits for a full pipeline of requests, so
+        // it would never send any
+        if config_file.connection().pipeline() == 0 {
+            fatal!("pipeline must be at least one");
+        }
+
+        if config_file.checkpoint().windows() == 0 {
+            fatal!("checkpoint windows must be greater than 0");
+        }
+
+        let baseline = config_file.baseline();
+        if [
+            baseline.latency(),
+            baseline.throughput(),
+            baseline.success(),
+        ]
+        .iter()
+        .any(|tolerance| tolerance.is_nan() || *tolerance < 0.0)
+        {
+            fatal!("baseline tolerances can not be negative");
+        }
+
+        // only heatmaps keep the history a waterfall is rendered from
+        if config_file.metrics().backend() != LatencyBackend::Heatmap
+            && config_file.waterfall().file().is_some()
+        {
+            fatal!("a waterfall requires the heatmap metrics backend");
+        }
+
+        if let Err(error) = config_file
+            .protocol()
+            .check(config_file.general().protocol())
+        {
+            fatal!("{}", error);
+        }
+
+        if config_file.general().protocol() == Protocol::Http
+            && config_file.protocol().http().method().is_none()
+            && !verbs
+                .iter()
+                .all(|verb| matches!(verb, Verb::Get | Verb::Set | Verb::Delete))
+        {
+            fatal!("http requests need a method for commands other than get, set, and delete");
+        }
+
+        if config_file.general().protocol() == Protocol::Cql {
+            if !verbs
+                .iter()
+                .all(|verb| matches!(verb, Verb::Get | Verb::Set | Verb::Delete))
+            {
+                fatal!("cql only supports the get, set, and delete commands");
+            }
+            // responses may return in any order, but are matched to requests
+            // in the order they were sent
+            if config_file.connection().pipeline() > 1
+                || (config_file.request().mode() == LoadMode::Open
+                    && config_file.request().max_inflight() > 1)
+            {
+                fatal!("cql needs a pipeline and max_inflight of 1, use more connections instead");
+            }
+        }
+
+        // the next step of a command is matched to the reply which follows
+        // the reply to its last step, so nothing may be sent in between
+        if let Some(verb) = verbs.iter().find(|verb| verb.is_multi_step()) {
+            if config_file.connection().pipeline() > 1
+                || (config_file.request().mode() == LoadMode::Open
+                    && config_file.request().max_inflight() > 1)
+            {
+                fatal!(
+                    "the {} command needs a pipeline and max_inflight of 1, use more connections instead",
+                    <&str>::from(*verb)
+                );
+            }
+        }
+
+        if let Some(oauth) = config_file.request().oauth() {
+            let protocol = config_file.general().protocol();
+            if !matches!(protocol, Protocol::Http | Protocol::Websocket) {
+                fatal!("oauth is only supported for the http and websocket protocols");
+            }
+            if let Err(e) = oauth::parse_url(oauth.token_url()) {
+                fatal!("{}", e);
+            }
+            if protocol == Protocol::Http
+                && config_file
+                    .protocol()
+                    .http()
+                    .headers()
+                    .iter()
+                    .any(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+            {
+                fatal!("http requests can not have an authorization header with oauth");
+            }
+        }
+
+        if matches!(
+            config_file.general().protocol(),
+            Protocol::Mongodb | Protocol::Postgres
+        ) && !verbs
+            .iter()
+            .all(|verb| matches!(verb, Verb::Get | Verb::Set | Verb::Delete))
+        {
+            fatal!("mongodb and postgres only support the get, set, and delete commands");
+        }
+
+        if config_file.general().protocol() == Protocol::Dns {
+            match config_file.protocol().dns().types() {
+                Ok(types) if types.is_empty() => {
+                    fatal!("dns queries need at least one record type");
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    fatal!("{}", e);
+                }
+            }
+            if !verbs.iter().all(|verb| *verb == Verb::Get) {
+                fatal!("dns only supports the get command");
+            }
+            // responses are matched to queries in the order they arrive, not
+            // by their id, and datagrams may arrive in any order
+            if config_file.connection().pipeline() > 1
+                || (config_file.request().mode() == LoadMode::Open
+                    && config_file.request().max_inflight() > 1)
+            {
+                fatal!("dns needs a pipeline and max_inflight of 1, use more connections instead");
+            }
+        }
+
+        if config_file.general().protocol() == Protocol::Thrift {
+            let methods = config_file.protocol().thrift().methods();
+            for verb in &verbs {
+                if !methods.iter().any(|method| method.verb() == *verb) {
+                    fatal!(
+                        "thrift requests need a method for the {} command",
+                        <&str>::from(*verb)
+                    );
+                }
+            }
+        }
+
+        if let Some(search) = config_file.search() {
+            if !(search.percentile() > 0.0 && search.percentile() <= 100.0) {
+                fatal!("search percentile must be greater than 0 and at most 100");
+            }
+            if search.min_rate() == 0 || search.min_rate() >= search.max_rate() {
+                fatal!("search min_rate must be at least 1 and less than max_rate");
+            }
+            if config_file.request().ratelimit().is_none() {
+                fatal!("the rate search needs a request ratelimit, which is the first rate tried");
+            }
+            if config_file.request().ratelimit_final().is_some()
+                || config_file.request().ratelimit_ramp() == RampModel::Sine
+            {
+                fatal!("the rate search can not be combined with a ratelimit ramp");
+            }
+            // each rate is judged by the latency of its own window
+            if config_file.general().accumulation() == Accumulation::Run {
+                fatal!("the rate search needs latencies accumulated by window, not by run");
+            }
+        }
+
+        // the latencies span every window of the run
+        if config_file.general().accumulation() == Accumulation::Run
+            && config_file.general().windows().is_none()
+        {
+            fatal!("run accumulation needs a fixed number of windows");
+        }
+
+        // the quiet summary is printed once the last window ends
+        if config_file.general().verbosity() == Verbosity::Quiet
+            && config_file.general().windows().is_none()
+        {
+            fatal!("quiet output needs a fixed number of windows");
+        }
+
+        if config_file.request().ratelimit_ramp() == RampModel::Sine {
+            let request = config_file.request();
+            match (request.ratelimit(), request.ratelimit_max()) {
+                (Some(min), Some(max)) if min <= max => {}
+                (Some(_), Some(_)) => {
+                    fatal!("ratelimit_max must be at least the request ratelimit");
+                }
+                _ => {
+                    fatal!("the sine ramp needs a request ratelimit and a ratelimit_max");
+                }
+            }
+            if request.ratelimit_period().unwrap_or_default().is_zero() {
+                fatal!("the sine ramp needs a ratelimit_period of at least one second");
+            }
+            if request.ratelimit_final().is_some() {
+                fatal!("ratelimit_final can not be used with the sine ramp");
+            }
+        }
+
+        if config_file.request().corrected_latency() && config_file.request().ratelimit().is_none()
+        {
+            fatal!("corrected latency needs a request ratelimit to know when requests were due");
+        }
+
+        if config_file.request().timeouts().any(|timeout| timeout == 0) {
+            fatal!("request timeouts must be at least one millisecond");
+        }
+
+        if config_file.request().mode() == LoadMode::Open {
+            if config_file.request().ratelimit().is_none() {
+                fatal!("open-loop mode needs a request ratelimit to schedule requests");
+            }
+            if config_file.request().max_inflight() == 0 {
+                fatal!("max_inflight must be at least one");
+            }
+        } else if config_file.general().generator_threads() > 0 {
+            fatal!("generator threads schedule requests for open-loop mode only");
+        }
+
+        if config_file.general().backend() == Backend::IoUring {
+            if !cfg!(target_os = "linux") {
+                fatal!("the io_uring backend is only available on linux");
+            }
+            if config_file.tls().is_some() {
+                fatal!("the io_uring backend only supports plaintext connections");
+            }
+            if config_file.request().mode() == LoadMode::Open {
+                fatal!("the io_uring backend only supports closed-loop mode");
+            }
+        }
+
+        let general = config_file.general();
+        if general
+            .windows()
+            .is_some_and(|windows| general.warmup() > windows)
+        {
+            fatal!("warmup can not be longer than the run");
+        }
+        if general.cpus().is_some() || general.numa_nodes().is_some() {
+            if !cfg!(target_os = "linux") {
+                fatal!("cpu and numa placement is only available on linux");
+            }
+            if general.cpus().is_some() && general.numa_nodes().is_some() {
+                fatal!("workers can be placed by cpus or by numa_nodes, but not both");
+            }
+            if general.cpus().is_some_and(|cpus| cpus.is_empty())
+                || general.numa_nodes().is_some_and(|nodes| nodes.is_empty())
+            {
+                fatal!("cpus and numa_nodes must not be empty");
+            }
+            if general
+                .cpus()
+                .is_some_and(|cpus| cpus.iter().any(|cpu| *cpu >= placement::MAX_CPU))
+            {
+                fatal!("cpus must be below {}", placement::MAX_CPU);
+            }
+            // workers can only be pinned to the cpus this process may run
+            // on, which may be limited by a cgroup or by taskset
+            let allowed = match placement::allowed() {
+                Ok(allowed) => allowed,
+                Err(e) => {
+                    fatal!("failed to get the cpus available for the workers: {}", e);
+                }
+            };
+            if let Some(cpu) = general
+                .cpus()
+                .and_then(|cpus| cpus.iter().find(|cpu| !allowed.contains(cpu)))
+            {
+                fatal!("cpu {} is not available for the workers", cpu);
+            }
+            for node in general.numa_nodes().unwrap_or_default() {
+                match placement::node_cpus(*node) {
+                    Ok(cpus) if cpus.iter().any(|cpu| *cpu >= placement::MAX_CPU) => {
+                        fatal!("numa node {} has cpus above {}", node, placement::MAX_CPU);
+                    }
+                    Ok(cpus) if !cpus.iter().any(|cpu| allowed.contains(cpu)) => {
+                        fatal!(
+                            "none of the cpus of numa node {} are available for the workers",
+                            node
+                        );
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        fatal!("{}", e);
+                    }
+                }
+            }
+        }
+
+        match config_file.distributed().role() {
+            Some(Role::Controller) => {
+                if config_file.general().admin().is_none() {
+                    fatal!("the controller serves agents on the admin address, which must be set");
+                }
+                if config_file.distributed().agents() == 0 {
+                    fatal!("the controller needs at least one agent");
+                }
+                if config_file.general().windows().is_none() {
+                    fatal!("distributed runs need a fixed number of windows");
+                }
+            }
+            Some(Role::Agent) if config_file.distributed().controller().is_none() => {
+                fatal!("agents need the address of the controller");
+            }
+            // agents divide the keys between them by index
+            Some(Role::Agent) if keyspaces.iter().any(|k: &Keyspace| !k.has_fixed_keys()) => {
+                fatal!("agents need keyspaces with a fixed set of keys");
+            }
+            _ => {}
+        }
+
+        let target = config_file.target();
+        let mut endpoints = target.endpoints();
+        if endpoints.is_empty() {
             fatal!("no target endpoints configured");
         }
+        // the cutover endpoints follow the target endpoints
+        let cutover = target.cutover().map(|cutover| {
+            let first = endpoints.len();
+            let cutover = cutover.endpoints();
+            if cutover.is_empty() {
+                fatal!("no cutover endpoints configured");
+            }
+            endpoints.extend(cutover);
+            first
+        });
+        if cutover.is_some() {
+            if matches!(target.balance(), Balance::Weighted | Balance::KeyHash) {
+                fatal!("a cutover needs the ready, round_robin, or random balance");
+            }
+            if config_file.connection().affinity() {
+                fatal!("a cutover can't be used with affinity, which pins keys to connections");
+            }
+        }
+        // dns queries and pings over udp are sent as datagrams to the same
+        // addresses
+        let options = config_file.protocol();
+        let datagrams = match config_file.general().protocol() {
+            Protocol::Dns if options.dns().transport() == Transport::Udp => Some("dns"),
+            Protocol::Ping if options.ping().transport() == Transport::Udp => Some("ping"),
+            _ => None,
+        };
+        if let Some(protocol) = datagrams {
+            if config_file.tls().is_some() {
+                fatal!(
+                    "{} over udp can not use tls, use the tcp transport",
+                    protocol
+                );
+            }
+            if config_file.connection().proxy().is_some() {
+                fatal!("{} over udp can not be sent through a proxy", protocol);
+            }
+            if config_file.general().backend() == Backend::IoUring {
+                fatal!(
+                    "the io_uring backend does not support {} over udp",
+                    protocol
+                );
+            }
+            endpoints = endpoints
+                .into_iter()
+                .map(|endpoint| match endpoint {
+                    Endpoint::Tcp(addr) | Endpoint::Udp(addr) => Endpoint::Udp(addr),
+                    Endpoint::Unix(_) => {
+                        fatal!("{} over udp needs ip endpoints", protocol);
+                    }
+                })
+                .collect();
+        }
+        match target.balance() {
+            Balance::Weighted => {
+                if target.weights().len() != endpoints.len() {
+                    fatal!("the weighted balance needs one weight for each endpoint");
+                }
+                if target.weights().iter().all(|weight| *weight == 0) {
+                    fatal!("the weighted balance needs a non-zero weight");
+                }
+            }
+            Balance::KeyHash => {
+                if config_file.connection().affinity() {
+                    fatal!("affinity already sends each key to a single endpoint, use it without the key_hash balance");
+                }
+                if keyspaces.iter().any(|k: &Keyspace| !k.has_fixed_keys()) {
+                    fatal!("the key_hash balance needs keyspaces with a fixed set of keys");
+                }
+            }
+            _ => {}
+        }
+        // affinity shards the key index, so every key has to come from it
+        if config_file.connection().affinity() {
+            if keyspaces.iter().any(|k: &Keyspace| !k.has_fixed_keys()) {
+                fatal!("affinity needs keyspaces with a fixed set of keys");
+            }
+            if keyspaces.iter().any(|k| k.edge_cases.is_some()) {
+                fatal!(
+                    "edge case keys can't be used with affinity, which pins keys to connections"
+                );
+            }
+        }
+
+        let connection = config_file.connection();
+        if connection.read_buffer() == 0 || connection.write_buffer() == 0 {
+            fatal!("the session buffers need to be at least one byte");
+        }
+        if connection.max_read_buffer() < connection.read_buffer() {
+            fatal!("max_read_buffer must be at least the size of the read_buffer");
+        }
+        match connection.lifetime() {
+            Some(Lifetime::Fixed { lifetime: 0 }) | Some(Lifetime::Exponential { mean: 0 }) => {
+                fatal!("connection lifetime must be greater than zero");
+            }
+            Some(Lifetime::Uniform { min, max }) if min > max || max == 0 => {
+                fatal!("connection lifetime needs a min no greater than a non-zero max");
+            }
+            _ => {}
+        }
+
+        let share = config_file.connection().slow_start_share();
+        if !(share > 0.0 && share <= 1.0) {
+            fatal!("slow_start_share must be greater than 0 and at most 1");
+        }
+
+        if let Some(proxy) = config_file.connection().proxy() {
+            if proxy.has_partial_credentials() {
+                fatal!("proxy authentication needs both a username and a password");
+            }
+            if let Some((username, password)) = proxy.credentials() {
+                if proxy.protocol() == ProxyProtocol::Socks5
+                    && (username.is_empty()
+                        || username.len() > 255
+                        || password.is_empty()
+                        || password.len() > 255)
+                {
+                    fatal!("socks5 usernames and passwords must be 1 to 255 bytes");
+                }
+            }
+            if std::net::ToSocketAddrs::to_socket_addrs(proxy.address()).is_err() {
+                fatal!("could not resolve proxy address: {}", proxy.address());
+            }
+            if config_file
+                .target()
+                .endpoints()
+                .iter()
+                .any(|endpoint| matches!(endpoint, Endpoint::Unix(_)))
+            {
+                fatal!("unix domain socket endpoints can not be reached through a proxy");
+            }
+        }
+
+        let templated = config_file
+            .tls()
+            .is_some_and(|tls| tls.server_name().contains(VHOST))
+            || match config_file.general().protocol() {
+                Protocol::Http => config_file.protocol().http().host().contains(VHOST),
+                Protocol::Websocket => config_file.protocol().websocket().host().contains(VHOST),
+                _ => false,
+            };
+        let vhosts = match config_file.vhost() {
+            Some(vhost) => {
+                if vhost.count() == 0 {
+                    fatal!("vhost count must be greater than 0");
+                }
+                if !templated {
+                    warn!("vhosts are configured but neither the tls server name nor the http host uses {}", VHOST);
+                }
+                Some(Vhosts {
+                    distribution: KeyDistribution::new(vhost.distribution(), vhost.count()),
+                    per_request: vhost.per_request(),
+                })
+            }
+            None => {
+                if templated {
+                    fatal!("{} is used but there is no vhost section", VHOST);
+                }
+                None
+            }
+        };
+
+        let stickiness = match config_file.general().protocol() {
+            Protocol::Http => config_file.protocol().http().stickiness().cloned(),
+            _ => None,
+        };
+        let labeled_sni = config_file
+            .tls()
+            .is_some_and(|tls| tls.server_name().contains(LABEL));
+        match stickiness.as_ref().map(|stickiness| stickiness.label()) {
+            Some(StickinessLabel::Sni) if !labeled_sni => {
+                fatal!(
+                    "connections labeled by sni need a tls server name which uses {}",
+                    LABEL
+                );
+            }
+            Some(StickinessLabel::Sni) => {}
+            _ if labeled_sni => {
+                fatal!(
+                    "{} is used in the tls server name but connections are not labeled by sni",
+                    LABEL
+                );
+            }
+            _ => {}
+        }
+
+        let assertions = match config_file.general().protocol() {
+            Protocol::Http => match Assertions::new(config_file.protocol().http().assertions()) {
+                Ok(assertions) => assertions,
+                Err(e) => {
+                    fatal!("{}", e);
+                }
+            },
+            _ => Assertions::default(),
+        };
 
         Self {
+            file,
             general: config_file.general(),
             debug: config_file.debug(),
             waterfall: config_file.waterfall(),
+            bundle: config_file.bundle(),
+            abort: config_file.abort(),
+            baseline: config_file.baseline(),
+            checkpoint: config_file.checkpoint(),
+            metrics: config_file.metrics(),
+            sla: config_file.sla(),
+            blackout: config_file.blackout(),
+            search: config_file.search(),
+            calibration: config_file.calibration(),
+            distributed: config_file.distributed(),
             tls: config_file.tls(),
+            vhosts,
+            stickiness,
+            assertions,
             connection: config_file.connection(),
             request: config_file.request(),
-            endpoints: config_file.target().endpoints(),
+            protocol: config_file.protocol(),
+            endpoints,
+            cutover,
+            oauth_token: None,
+            target,
             keyspaces,
             keyspace_dist,
         }
     }
 
+    /// The path of the file this config was loaded from
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
     pub fn general(&self) -> &General {
         &self.general
     }
@@ -261,10 +2268,86 @@ impl Config {
         &self.waterfall
     }
 
+    pub fn bundle(&self) -> &Bundle {
+        &self.bundle
+    }
+
+    /// The identifier of the run, which is the hostname unless one is set in
+    /// the bundle section
+    pub fn run_id(&self) -> String {
+        self.bundle.run_id().unwrap_or_else(|| {
+            std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .map(|hostname| hostname.trim().to_owned())
+                .unwrap_or_else(|_| std::process::id().to_string())
+        })
+    }
+
+    pub fn abort(&self) -> &Abort {
+        &self.abort
+    }
+
+    /// The tolerances used when comparing the run against a baseline
+    pub fn baseline(&self) -> &Baseline {
+        &self.baseline
+    }
+
+    pub fn checkpoint(&self) -> &Checkpoint {
+        &self.checkpoint
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.metrics
+    }
+
+    /// The latency targets for each command
+    pub fn sla(&self) -> &[Sla] {
+        &self.sla
+    }
+
+    /// Whether the window overlaps any blackout, which excludes it from the
+    /// latency targets and the error rate abort
+    pub fn is_blackout(&self, window: u64) -> bool {
+        let interval = self.general.interval();
+        let start = interval * window as u32;
+        self.blackout
+            .iter()
+            .any(|blackout| blackout.overlaps(start, start + interval))
+    }
+
+    /// The latency target for the rate search, when searching for the highest
+    /// sustainable rate
+    pub fn search(&self) -> Option<&Search> {
+        self.search.as_ref()
+    }
+
+    /// The probes of the built-in loopback server, when calibrating
+    pub fn calibration(&self) -> Option<&Calibration> {
+        self.calibration.as_ref()
+    }
+
+    pub fn distributed(&self) -> &Distributed {
+        &self.distributed
+    }
+
     pub fn tls(&self) -> Option<&Tls> {
         self.tls.as_ref()
     }
 
+    /// The virtual hosts, when the server name or http host is templated
+    pub fn vhosts(&self) -> Option<&Vhosts> {
+        self.vhosts.as_ref()
+    }
+
+    /// How http connections are labeled for load balancer stickiness
+    pub fn stickiness(&self) -> Option<&HttpStickiness> {
+        self.stickiness.as_ref()
+    }
+
+    /// The checks made on every http response
+    pub fn assertions(&self) -> &Assertions {
+        &self.assertions
+    }
+
     pub fn connection(&self) -> &Connection {
         &self.connection
     }
@@ -273,11 +2356,245 @@ impl Config {
         &self.request
     }
 
-    pub fn endpoints(&self) -> Vec<SocketAddr> {
+    /// The options of the protocol in use, from its `[protocol.<name>]`
+    /// section
+    pub fn protocol(&self) -> &ProtocolOptions {
+        &self.protocol
+    }
+
+    /// The target settings. The endpoints are resolved once and should be
+    /// taken from `endpoints` instead.
+    pub fn target(&self) -> &Target {
+        &self.target
+    }
+
+    pub fn endpoints(&self) -> Vec<Endpoint> {
         self.endpoints.clone()
     }
 
+    /// The index of the first endpoint which requests are moved to by the
+    /// cutover, the endpoints before it are the original target
+    pub fn cutover_endpoint(&self) -> Option<usize> {
+        self.cutover
+    }
+
+    /// Whether any keyspace mixes in edge-case keys
+    /// Whether any gets request several keys at once
+    pub fn has_multi_get(&self) -> bool {
+        self.keyspaces.iter().any(|keyspace| {
+            keyspace.batch_size() > 1
+                && keyspace
+                    .commands()
+                    .iter()
+                    .any(|command| command.verb() == Verb::Get)
+        })
+    }
+
+    pub fn has_edge_cases(&self) -> bool {
+        self.keyspaces.iter().any(|k| k.edge_cases.is_some())
+    }
+
+    /// The distinct commands used across all keyspaces
+    pub fn verbs(&self) -> Vec<Verb> {
+        let mut verbs: Vec<Verb> = self
+            .keyspaces
+            .iter()
+            .flat_map(|keyspace| keyspace.commands.iter().map(|command| command.verb()))
+            .collect();
+        verbs.sort_by_key(|verb| *verb as usize);
+        verbs.dedup();
+        verbs
+    }
+
+    pub fn keyspaces(&self) -> &[Keyspace] {
+        &self.keyspaces
+    }
+
+    pub fn keyspace(&self, index: usize) -> &Keyspace {
+        &self.keyspaces[index]
+    }
+
     pub fn choose_keyspace(&self, rng: &mut SmallRng) -> &Keyspace {
         &self.keyspaces[self.keyspace_dist.sample(rng)]
     }
+
+    /// Record that the server has stored a versioned value. Older writes which
+    /// were still in flight may be stored after it, so reads only need to
+    /// return the version after those which had all completed.
+    pub fn acknowledge(&self, write: VersionedWrite) {
+        if let Some(ref versions) = self.keyspaces[write.keyspace].versions {
+            versions.complete(write.index, write.version);
+            versions.acknowledged[write.index].fetch_max(write.settled + 1, Ordering::Relaxed);
+        }
+    }
+
+    /// Record that the server did not store a versioned value
+    pub fn reject(&self, write: VersionedWrite) {
+        if let Some(ref versions) = self.keyspaces[write.keyspace].versions {
+            versions.complete(write.index, write.version);
+        }
+    }
+
+    /// How long a request sent as a datagram waits for its response before
+    /// it is counted as lost
+    pub fn datagram_timeout(&self) -> Option<std::time::Duration> {
+        match self.general.protocol() {
+            Protocol::Dns if self.protocol.dns().transport() == Transport::Udp => {
+                Some(self.protocol.dns().timeout())
+            }
+            Protocol::Ping if self.protocol.ping().transport() == Transport::Udp => {
+                Some(self.protocol.ping().timeout())
+            }
+            _ => None,
+        }
+    }
+
+    /// How long a request for the command waits for its response before it
+    /// times out. Requests sent as datagrams use the timeout of their
+    /// protocol unless a request timeout is set.
+    pub fn request_timeout(&self, verb: Verb) -> Option<std::time::Duration> {
+        self.request
+            .timeout(verb)
+            .or_else(|| self.datagram_timeout())
+    }
+
+    /// How long a datagram socket is kept open after a timeout, so that late
+    /// responses can be told apart from requests which were never answered
+    pub fn datagram_grace(&self) -> Option<std::time::Duration> {
+        let grace = match self.general.protocol() {
+            Protocol::Dns => self.protocol.dns().grace(),
+            Protocol::Ping => self.protocol.ping().grace(),
+            _ => return None,
+        };
+        self.datagram_timeout()
+            .and(Some(grace))
+            .filter(|grace| !grace.is_zero())
+    }
+
+    /// The access token sent with requests, when an oauth client is
+    /// configured
+    pub fn oauth_token(&self) -> Option<&Token> {
+        self.oauth_token.as_deref()
+    }
+
+    pub fn set_oauth_token(&mut self, token: Option<Arc<Token>>) {
+        self.oauth_token = token;
+    }
+
+    /// Restrict the keys to the share of an agent in a distributed run
+    pub fn set_agent_shard(&mut self, agent: usize, agents: usize) {
+        for keyspace in &mut self.keyspaces {
+            keyspace.agent_shard = Some((agent, agents));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        let versions = Versions::new(1);
+        // the second write completes before the first
+        versions.complete(1, 2);
+        assert_eq!(versions.settled(1), 0);
+        versions.complete(1, 1);
+        assert_eq!(versions.settled(1), 2);
+        versions.complete(1, 1);
+        assert_eq!(versions.settled(1), 2);
+        // the third write is lost once the writes after it run far ahead
+        for version in 4..=34 {
+            versions.complete(1, version);
+        }
+        assert_eq!(versions.settled(1), 2);
+        versions.complete(1, 35);
+        assert_eq!(versions.settled(1), 35);
+    }
+
+    #[test]
+    fn key_template() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let template = KeyTemplate::parse(0, "user:{u32:1e3}:session:{alnum:8:10}").unwrap();
+        for _ in 0..100 {
+            let key = String::from_utf8(template.generate(&mut rng)).unwrap();
+            let parts: Vec<&str> = key.split(':').collect();
+            assert_eq!(parts.len(), 4);
+            assert_eq!(parts[0], "user");
+            assert!(parts[1].parse::<u32>().unwrap() < 1000);
+            assert_eq!(parts[2], "session");
+            assert_eq!(parts[3].len(), 8);
+        }
+
+        assert!(KeyTemplate::parse(0, "{u32}").is_err());
+        assert!(KeyTemplate::parse(0, "{alnum:8:zipf}").is_err());
+        assert!(KeyTemplate::parse(0, "{u64:10:zipf=0.9}").is_ok());
+        assert!(KeyTemplate::parse(0, "user:{u32:10").is_err());
+        assert!(KeyTemplate::parse(0, "user}").is_err());
+    }
+
+    #[test]
+    fn value_length() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let size = ValueLength::new(ValueSize::Pareto {
+            scale: 100.0,
+            shape: 1.0,
+            min: 0,
+            max: 1000,
+        })
+        .unwrap();
+        let lengths: Vec<usize> = (0..1000).map(|_| size.sample(&mut rng)).collect();
+        assert!(lengths.iter().all(|l| (100..=1000).contains(l)));
+        // about a tenth of the lengths reach the clamp
+        assert!(lengths.iter().filter(|l| **l == 1000).count() > 50);
+
+        // negative lengths are clamped to the min
+        let size = ValueLength::new(ValueSize::Normal {
+            mean: -10.0,
+            std_dev: 1.0,
+            min: 1,
+            max: 10,
+        })
+        .unwrap();
+        assert_eq!(size.sample(&mut rng), 1);
+
+        assert!(ValueLength::new(ValueSize::Lognormal {
+            median: 0.0,
+            sigma: 1.0,
+            min: 0,
+            max: 10,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn value_fill() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        for length in [0, 10, 100, 4096] {
+            let value = ValueFill::Json.fill(&mut rng, length);
+            assert_eq!(value.len(), length.max(10));
+            assert!(serde_json::from_slice::<serde_json::Value>(&value).is_ok());
+        }
+
+        let value = ValueFill::Compressible(0.25).fill(&mut rng, 4096);
+        let zeros = value.iter().filter(|b| **b == 0).count();
+        assert!((zeros as f64 / 4096.0 - 0.75).abs() < 0.01);
+
+        let value = ValueFill::Pattern(b"abc".to_vec()).fill(&mut rng, 7);
+        assert_eq!(value, b"abcabca");
+    }
+
+    #[test]
+    fn request_timeout() {
+        let request: Request =
+            toml::from_str("timeout = 200\ntimeouts = [ { verb = \"get\", timeout = 50 } ]")
+                .unwrap();
+        let ms = std::time::Duration::from_millis;
+        assert_eq!(request.timeout(Verb::Get), Some(ms(50)));
+        assert_eq!(request.timeout(Verb::Set), Some(ms(200)));
+        assert_eq!(request.timeouts().collect::<Vec<u64>>(), [200, 50]);
+
+        let request: Request = toml::from_str("").unwrap();
+        assert_eq!(request.timeout(Verb::Get), None);
+    }
 }
@@ -2,14 +2,15 @@
 // Licensed under the Apache License, Version 2.0
 // http://www.apache.org/licenses/LICENSE-2.0
 
+use crate::session::Endpoint;
 use core::time::Duration;
 use ringlog::Level;
 use serde_derive::*;
 use serde_json::Value as JsonValue;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Read;
-use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
+use strum_macros::{EnumCount, EnumIter, IntoStaticStr};
 use waterfall::{Palette, Scale};
 use zookeeper::*;
 
@@ -21,12 +22,33 @@ pub struct ConfigFile {
     debug: Debug,
     #[serde(default)]
     waterfall: Waterfall,
+    #[serde(default)]
+    bundle: Bundle,
+    #[serde(default)]
+    abort: Abort,
+    #[serde(default)]
+    baseline: Baseline,
+    #[serde(default)]
+    checkpoint: Checkpoint,
+    #[serde(default)]
+    metrics: Metrics,
+    #[serde(default)]
+    sla: Vec<Sla>,
+    #[serde(default)]
+    blackout: Vec<Blackout>,
+    search: Option<Search>,
+    calibration: Option<Calibration>,
+    #[serde(default)]
+    distributed: Distributed,
     target: Target,
     #[serde(default)]
     connection: Connection,
     #[serde(default)]
     request: Request,
+    #[serde(default)]
+    protocol: ProtocolOptions,
     tls: Option<Tls>,
+    vhost: Option<Vhost>,
     keyspace: Vec<Keyspace>,
 }
 
@@ -40,17 +62,25 @@ impl ConfigFile {
     }
 
     pub fn connection(&self) -> Connection {
-        self.connection
+        self.connection.clone()
     }
 
     pub fn request(&self) -> Request {
-        self.request
+        self.request.clone()
+    }
+
+    pub fn protocol(&self) -> ProtocolOptions {
+        self.protocol.clone()
     }
 
     pub fn tls(&self) -> Option<Tls> {
         self.tls.clone()
     }
 
+    pub fn vhost(&self) -> Option<Vhost> {
+        self.vhost.clone()
+    }
+
     pub fn keyspaces(&self) -> Vec<Keyspace> {
         self.keyspace.clone()
     }
@@ -63,6 +93,46 @@ impl ConfigFile {
         self.waterfall.clone()
     }
 
+    pub fn bundle(&self) -> Bundle {
+        self.bundle.clone()
+    }
+
+    pub fn abort(&self) -> Abort {
+        self.abort.clone()
+    }
+
+    pub fn baseline(&self) -> Baseline {
+        self.baseline
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        self.checkpoint.clone()
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.metrics
+    }
+
+    pub fn sla(&self) -> Vec<Sla> {
+        self.sla.clone()
+    }
+
+    pub fn blackout(&self) -> Vec<Blackout> {
+        self.blackout.clone()
+    }
+
+    pub fn search(&self) -> Option<Search> {
+        self.search
+    }
+
+    pub fn calibration(&self) -> Option<Calibration> {
+        self.calibration
+    }
+
+    pub fn distributed(&self) -> Distributed {
+        self.distributed.clone()
+    }
+
     pub fn load_from_file(filename: &str) -> Self {
         let mut file = match std::fs::File::open(filename) {
             Ok(c) => c,
@@ -79,8 +149,22 @@ impl ConfigFile {
                 std::process::exit(1);
             }
         }
-        let toml = toml::from_str(&content);
-        match toml {
+        let mut toml: toml::Value = match toml::from_str(&content) {
+            Ok(toml) => toml,
+            Err(error) => {
+                eprintln!("Failed to parse TOML config: {filename}\n{error}");
+                std::process::exit(1);
+            }
+        };
+        if let Err(error) = crate::preset::expand(&mut toml) {
+            eprintln!("bad workload preset in config: {filename}\n{error}");
+            std::process::exit(1);
+        }
+        if let Err(error) = migrate_protocol_sections(&mut toml) {
+            eprintln!("bad protocol section in config: {filename}\n{error}");
+            std::process::exit(1);
+        }
+        match toml.try_into() {
             Ok(toml) => toml,
             Err(error) => {
                 eprintln!("Failed to parse TOML config: {filename}\n{error}");
@@ -106,8 +190,8 @@ fn one() -> usize {
     1
 }
 
-fn u32_max() -> u32 {
-    u32::MAX
+fn default_cardinality() -> u64 {
+    u32::MAX as u64
 }
 
 fn default_nodelay() -> bool {
@@ -128,6 +212,9 @@ fn empty_map() -> HashMap<String, String> {
 pub enum FieldType {
     Alphanumeric,
     U32,
+    U64,
+    /// A UUID in the canonical hyphenated form, which ignores the length
+    Uuid,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
@@ -141,23 +228,74 @@ pub enum Protocol {
     RedisInline,
     RedisResp,
     ThriftCache,
+    Http,
+    Websocket,
+    Thrift,
+    Cql,
+    Mongodb,
+    Postgres,
+    Dns,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(deny_unknown_fields)]
 pub enum OutputFormat {
     /// Output resulting statistics via log messages. This is the default.
+    #[default]
     Log,
 
     /// Output statistics as JSON messages on stdout.
     Json,
 }
 
-impl Default for OutputFormat {
-    fn default() -> Self {
-        Self::Log
-    }
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum Verbosity {
+    /// Only output a summary of the entire run once it has completed.
+    /// Requires a fixed number of windows.
+    Quiet,
+
+    /// Output a summary for each window. This is the default.
+    #[default]
+    Normal,
+
+    /// Output a summary for each window with a per-command breakdown.
+    Verbose,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum Accumulation {
+    /// Latency percentiles only cover the most recent window. This is the
+    /// default.
+    #[default]
+    Window,
+
+    /// Latency percentiles accumulate across all windows of the run. Requires
+    /// a fixed number of windows.
+    ///
+    /// There is no accumulation by phase, as the windows of a run are only
+    /// split by the warmup, and the command summary already covers the
+    /// windows after it.
+    Run,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum Backend {
+    /// Each worker waits for readiness events and reads and writes each
+    /// session as it becomes ready. This is the default.
+    #[default]
+    Mio,
+
+    /// Each worker submits its sends and receives to an io_uring and reaps
+    /// their completions, which takes far fewer syscalls at high rates. Linux
+    /// only, and limited to plaintext connections in closed-loop mode.
+    IoUring,
 }
 
 #[derive(Deserialize, Clone)]
@@ -171,11 +309,40 @@ pub struct General {
     #[serde(default = "one")]
     threads: usize,
     #[serde(default)]
+    generator_threads: usize,
+    #[serde(default)]
     service: bool,
     admin: Option<String>,
 
     #[serde(default)]
     output_format: OutputFormat,
+    json_output: Option<String>,
+
+    #[serde(default)]
+    verbosity: Verbosity,
+
+    #[serde(default)]
+    accumulation: Accumulation,
+
+    #[serde(default)]
+    warmup: usize,
+
+    #[serde(default)]
+    prefill: bool,
+
+    #[serde(default)]
+    validate: bool,
+
+    #[serde(default)]
+    host_stats: bool,
+
+    preset: Option<String>,
+
+    #[serde(default)]
+    backend: Backend,
+
+    cpus: Option<Vec<usize>>,
+    numa_nodes: Option<Vec<usize>>,
 }
 
 impl General {
@@ -195,10 +362,20 @@ impl General {
         }
     }
 
+    /// The number of worker threads, which drive the connections, each in its
+    /// own event loop. Workers also schedule their requests unless there are
+    /// generator threads.
     pub fn threads(&self) -> usize {
         self.threads
     }
 
+    /// The number of threads which schedule open-loop requests from the
+    /// ratelimit for the workers to send. With none, each worker takes its
+    /// requests from the ratelimit itself.
+    pub fn generator_threads(&self) -> usize {
+        self.generator_threads
+    }
+
     pub fn admin(&self) -> Option<String> {
         self.admin.clone()
     }
@@ -206,6 +383,61 @@ impl General {
     pub fn output_format(&self) -> OutputFormat {
         self.output_format
     }
+
+    /// A file which a JSON summary of each window is appended to
+    pub fn json_output(&self) -> Option<String> {
+        self.json_output.clone()
+    }
+
+    pub fn verbosity(&self) -> Verbosity {
+        self.verbosity
+    }
+
+    pub fn accumulation(&self) -> Accumulation {
+        self.accumulation
+    }
+
+    /// The number of windows at the start of the run which are left out of
+    /// the summary printed once the run completes
+    pub fn warmup(&self) -> usize {
+        self.warmup
+    }
+
+    /// Store every key in each keyspace before the run starts
+    pub fn prefill(&self) -> bool {
+        self.prefill
+    }
+
+    /// Seal stored values with a fingerprint of their key and a checksum, and
+    /// check the values returned by gets against them
+    pub fn validate(&self) -> bool {
+        self.validate
+    }
+
+    /// Sample the host's network counters from `/proc` each window
+    pub fn host_stats(&self) -> bool {
+        self.host_stats
+    }
+
+    /// The named workload which the keyspaces were generated from
+    pub fn preset(&self) -> Option<String> {
+        self.preset.clone()
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// The cores which the workers are pinned to, one core per worker
+    pub fn cpus(&self) -> Option<&[usize]> {
+        self.cpus.as_deref()
+    }
+
+    /// The NUMA nodes which the workers are spread across, each worker being
+    /// pinned to all the cores of its node
+    pub fn numa_nodes(&self) -> Option<&[usize]> {
+        self.numa_nodes.as_deref()
+    }
 }
 
 fn log_level() -> Level {
@@ -294,6 +526,22 @@ impl Default for Debug {
     }
 }
 
+/// How requests are issued relative to the responses for earlier requests
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum LoadMode {
+    /// Each connection sends its next requests once the responses for the
+    /// previous ones have been received. This is the default.
+    #[default]
+    Closed,
+
+    /// Requests are issued on the ratelimit's schedule regardless of
+    /// outstanding responses, up to `max_inflight` on each connection.
+    /// Requests which cannot be sent on time wait in a queue.
+    Open,
+}
+
 #[derive(Deserialize, Copy, Clone)]
 #[serde(deny_unknown_fields)]
 pub enum RatelimitModel {
@@ -302,7 +550,42 @@ pub enum RatelimitModel {
     Normal,
 }
 
-#[derive(Deserialize, Copy, Clone)]
+/// How a connection which is chosen to be reconnected is closed. The
+/// connection is chosen as it sends a batch of requests.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum ReconnectMode {
+    /// Stop sending requests on the connection and close it once the responses
+    /// for the requests in flight have been received.
+    #[default]
+    Drain,
+
+    /// Close the connection immediately, abandoning the requests in flight.
+    Abrupt,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum RampModel {
+    /// The rate changes continuously from the initial to the final rate. This
+    /// is the default.
+    #[default]
+    Linear,
+
+    /// The rate changes once at the start of each window, to the rate of the
+    /// linear ramp at that time, reaching the final rate by the end of the
+    /// run.
+    Step,
+
+    /// The rate rises from the initial rate to the maximum rate and falls back
+    /// again along a sine wave, repeating each period. This simulates diurnal
+    /// traffic and does not need a fixed number of windows.
+    Sine,
+}
+
+#[derive(Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Connection {
     #[serde(default = "one")]
@@ -310,12 +593,133 @@ pub struct Connection {
     ratelimit: Option<usize>,
     ratelimit_model: Option<RatelimitModel>,
     reconnect: Option<usize>,
+    #[serde(default)]
+    reconnect_mode: ReconnectMode,
+    reconnect_drain_timeout: Option<u64>,
     #[serde(default = "default_nodelay")]
     tcp_nodelay: bool,
     #[allow(dead_code)]
     timeout: Option<usize>,
     #[serde(default = "one")]
     pipeline: usize,
+    #[serde(default)]
+    affinity: bool,
+    proxy: Option<Proxy>,
+    slow_start: Option<u64>,
+    #[serde(default = "default_slow_start_share")]
+    slow_start_share: f64,
+    #[serde(default = "default_buffer_size")]
+    read_buffer: usize,
+    #[serde(default = "default_buffer_size")]
+    write_buffer: usize,
+    #[serde(default = "default_max_read_buffer")]
+    max_read_buffer: usize,
+    #[serde(default)]
+    buffer_growth: BufferGrowth,
+    #[serde(default)]
+    buffer_retain: bool,
+    lifetime: Option<Lifetime>,
+}
+
+fn default_slow_start_share() -> f64 {
+    0.1
+}
+
+fn default_buffer_size() -> usize {
+    1024
+}
+
+fn default_max_read_buffer() -> usize {
+    512 * 1024
+}
+
+/// How long each connection is kept open before it is closed to reconnect,
+/// in milliseconds. A new lifetime is drawn each time a connection is made.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "model", rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum Lifetime {
+    /// Every connection lives for the same time
+    Fixed { lifetime: u64 },
+    /// Lifetimes are spread evenly between the bounds
+    Uniform { min: u64, max: u64 },
+    /// Connections close at random with the given mean lifetime, as they do
+    /// behind idle timeouts and NATs which drop flows
+    Exponential { mean: u64 },
+}
+
+/// What values are filled with, for benchmarking servers and proxies which
+/// compress or parse values in the data path
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum ValueKind {
+    /// Random alphanumeric characters. This is the default.
+    #[default]
+    Random,
+    /// Zero bytes, which compress almost entirely
+    Zeros,
+    /// The `pattern` repeated to the length of the value
+    Pattern,
+    /// Random bytes mixed with zeros so that the value compresses by about
+    /// the `compression_ratio`
+    Compressible,
+    /// A flat JSON object of string, number, boolean and array fields. Values
+    /// which are too short for an object are extended to the shortest one.
+    Json,
+}
+
+/// How the length of each value is drawn, in bytes. Lengths are rounded and
+/// clamped to `min` and `max`, which is 1 MiB by default.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "model", rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum ValueSize {
+    /// Lengths are spread evenly around the mean
+    Normal {
+        mean: f64,
+        std_dev: f64,
+        #[serde(default)]
+        min: usize,
+        #[serde(default = "default_max_value_size")]
+        max: usize,
+    },
+    /// Lengths are skewed with a long tail, where half of the lengths are
+    /// below the median and a larger sigma gives a longer tail
+    Lognormal {
+        median: f64,
+        sigma: f64,
+        #[serde(default)]
+        min: usize,
+        #[serde(default = "default_max_value_size")]
+        max: usize,
+    },
+    /// Lengths are at least the scale, with a heavy tail where a smaller shape
+    /// gives a heavier tail
+    Pareto {
+        scale: f64,
+        shape: f64,
+        #[serde(default)]
+        min: usize,
+        #[serde(default = "default_max_value_size")]
+        max: usize,
+    },
+}
+
+fn default_max_value_size() -> usize {
+    1024 * 1024
+}
+
+/// How a session buffer grows when it needs more room
+#[derive(Deserialize, Clone, Copy, Debug, Eq, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum BufferGrowth {
+    /// Grow to the next power of two
+    #[default]
+    Double,
+    /// Grow in steps of the initial size
+    Linear,
 }
 
 impl Default for Connection {
@@ -325,9 +729,21 @@ impl Default for Connection {
             ratelimit: None,
             ratelimit_model: None,
             reconnect: None,
+            reconnect_mode: ReconnectMode::default(),
+            reconnect_drain_timeout: None,
             tcp_nodelay: false,
             timeout: None,
             pipeline: 1,
+            affinity: false,
+            proxy: None,
+            slow_start: None,
+            slow_start_share: default_slow_start_share(),
+            read_buffer: default_buffer_size(),
+            write_buffer: default_buffer_size(),
+            max_read_buffer: default_max_read_buffer(),
+            buffer_growth: BufferGrowth::default(),
+            buffer_retain: false,
+            lifetime: None,
         }
     }
 }
@@ -349,6 +765,18 @@ impl Connection {
         self.reconnect
     }
 
+    pub fn reconnect_mode(&self) -> ReconnectMode {
+        self.reconnect_mode
+    }
+
+    /// The longest a draining connection waits for its responses before it is
+    /// closed, abandoning the requests still in flight. Draining connections
+    /// wait indefinitely when this is not set.
+    pub fn reconnect_drain_timeout(&self) -> Option<std::time::Duration> {
+        self.reconnect_drain_timeout
+            .map(std::time::Duration::from_millis)
+    }
+
     pub fn poolsize(&self) -> usize {
         self.poolsize
     }
@@ -360,6 +788,123 @@ impl Connection {
     pub fn pipeline(&self) -> usize {
         self.pipeline
     }
+
+    /// When enabled, each key is only ever sent on one connection. The key
+    /// index determines which connection in the client-wide pool it uses, so
+    /// the keyspaces need a fixed set of keys and no edge case keys.
+    pub fn affinity(&self) -> bool {
+        self.affinity
+    }
+
+    /// A proxy which connections to the target are made through
+    pub fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+
+    /// How long a connection takes to warm up after it reconnects. While it
+    /// warms up, the connection's share of the requests grows from the
+    /// initial share to a full share.
+    pub fn slow_start(&self) -> Option<std::time::Duration> {
+        self.slow_start.map(std::time::Duration::from_millis)
+    }
+
+    /// The share of a full connection's requests which a connection takes
+    /// as it starts to warm up
+    pub fn slow_start_share(&self) -> f64 {
+        self.slow_start_share
+    }
+
+    /// The initial size of each session's read buffer, in bytes, which is
+    /// also the most read at once until the buffer grows
+    pub fn read_buffer(&self) -> usize {
+        self.read_buffer
+    }
+
+    /// The initial size of each session's write buffer, in bytes
+    pub fn write_buffer(&self) -> usize {
+        self.write_buffer
+    }
+
+    /// The read buffer grows to hold a large response up to this size, in
+    /// bytes, after which the session is closed
+    pub fn max_read_buffer(&self) -> usize {
+        self.max_read_buffer
+    }
+
+    pub fn buffer_growth(&self) -> BufferGrowth {
+        self.buffer_growth
+    }
+
+    /// Keep the room a buffer has grown to rather than shrinking it back to
+    /// the initial size once it has drained, trading memory for fewer
+    /// reallocations
+    pub fn buffer_retain(&self) -> bool {
+        self.buffer_retain
+    }
+
+    /// The distribution of connection lifetimes. Once a connection has lived
+    /// for its lifetime it is reconnected after its next requests are sent,
+    /// as with the reconnect rate.
+    pub fn lifetime(&self) -> Option<Lifetime> {
+        self.lifetime
+    }
+}
+
+/// The protocol spoken by a proxy between the client and the target
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum ProxyProtocol {
+    /// A SOCKS5 proxy, with optional username and password authentication
+    Socks5,
+    /// An HTTP proxy which tunnels connections with the CONNECT method, with
+    /// optional basic authentication
+    Http,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Proxy {
+    protocol: ProxyProtocol,
+    address: String,
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(default = "default_proxy_timeout")]
+    timeout: u64,
+}
+
+fn default_proxy_timeout() -> u64 {
+    5000
+}
+
+impl Proxy {
+    pub fn protocol(&self) -> ProxyProtocol {
+        self.protocol
+    }
+
+    /// The host and port of the proxy
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// The username and password used to authenticate with the proxy, if
+    /// authentication is configured
+    pub fn credentials(&self) -> Option<(&str, &str)> {
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => Some((username, password)),
+            _ => None,
+        }
+    }
+
+    pub fn has_partial_credentials(&self) -> bool {
+        self.username.is_some() != self.password.is_some()
+    }
+
+    /// The longest a connection waits for the proxy to establish the tunnel
+    /// to the target
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.timeout)
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -386,8 +931,7 @@ pub struct Keyspace {
     length: usize,
     #[serde(default = "one")]
     weight: usize,
-    #[serde(default = "u32_max")]
-    cardinality: u32,
+    cardinality: Option<u64>,
     commands: Vec<Command>,
     #[serde(default)]
     inner_keys: Vec<InnerKey>,
@@ -400,6 +944,22 @@ pub struct Keyspace {
     #[serde(default = "one")]
     batch_size: usize,
     pub(crate) key_distribution: Option<KeyDistribution>,
+    #[serde(default)]
+    transitions: Vec<Vec<usize>>,
+    #[serde(default)]
+    key_pool: bool,
+    #[serde(default)]
+    fields: Vec<FieldCount>,
+    #[serde(default)]
+    deltas: Vec<Delta>,
+    key_template: Option<String>,
+    #[serde(default)]
+    value_mutation: bool,
+    edge_cases: Option<EdgeCases>,
+    namespace: Option<Namespace>,
+    transaction: Option<Transaction>,
+    #[serde(default)]
+    scripts: Vec<Script>,
 }
 
 impl Keyspace {
@@ -411,8 +971,14 @@ impl Keyspace {
         self.weight
     }
 
-    pub fn cardinality(&self) -> u32 {
-        self.cardinality
+    pub fn cardinality(&self) -> u64 {
+        self.cardinality.unwrap_or_else(default_cardinality)
+    }
+
+    /// Whether the cardinality was set. Alphanumeric keys are only bounded
+    /// by the cardinality when it is set, otherwise they are random.
+    pub fn is_bounded(&self) -> bool {
+        self.cardinality.is_some()
     }
 
     pub fn inner_keys(&self) -> Vec<InnerKey> {
@@ -438,77 +1004,353 @@ impl Keyspace {
     pub fn batch_size(&self) -> usize {
         self.batch_size
     }
-}
 
-#[derive(Deserialize, Clone, Copy, Eq, PartialEq)]
-#[serde(rename_all = "snake_case")]
-#[serde(deny_unknown_fields)]
-pub enum Verb {
-    /// Sends a simple 'ping' to a pingserver.
-    Ping,
-    /// Sends a payload with a CRC to an echo server and checks for corruption.
-    Echo,
-    /// Simple key-value get which reads the value for one or more keys
-    /// depending on the batch size.
-    Get,
-    /// Simple key-value set which will overwrite the value for a key.
-    Set,
-    /// Remove a key.
-    Delete,
-    /// Hash get, reads the value for one or more fields within the hash stored
-    /// at the key.
-    Hget,
-    /// Hash set, set the value for a field within the hash stored at the key.
-    Hset,
-    /// Hash set non-existing, set the value for a field within the hash stored
-    /// at the key only if the field does not exist.
-    Hsetnx,
-    /// Deletes one or more fields from the hash stored at the key
-    Hdel,
-    /// Insert all the specified values at the tail of the list stored at a key.
-    /// Creates a new key if the key does not exist. Returns an error if the key
-    /// contains a value which is not a list.
-    Rpush,
-    /// Insert all the specified values at the tail of the list stored at a key,
-    /// returns an error if the key does not exist or contains a value which is
-    /// not a list.
-    Rpushx,
-    /// Count the number of items stored at a key
-    Count,
-    /// Returns the elements of the list stored at the key
-    Lrange,
-    /// Trims the elements of the list sotred at the key
-    Ltrim,
-}
+    /// A matrix of weights where each row gives the likelihood of choosing each
+    /// command given that the previous command on the connection was the
+    /// command with the row's index. Commands are chosen independently when
+    /// this is empty.
+    pub fn transitions(&self) -> Vec<Vec<usize>> {
+        self.transitions.clone()
+    }
 
-#[derive(Deserialize, Copy, Clone)]
-#[serde(deny_unknown_fields)]
-pub struct Command {
-    verb: Verb,
-    #[serde(default = "one")]
-    weight: usize,
-}
+    /// Alphanumeric keys are drawn from a deterministic pool of `cardinality`
+    /// keys instead of being random, so that requests for the same key index
+    /// use the same bytes. This is implied when the cardinality is set.
+    pub fn key_pool(&self) -> bool {
+        self.key_pool
+    }
 
-impl Command {
-    pub fn verb(&self) -> Verb {
-        self.verb
+    /// The number of fields written by each hash set, chosen by weight. The
+    /// field names are drawn from the inner keys, so their cardinality is
+    /// independent of the keyspace cardinality.
+    pub fn fields(&self) -> Vec<FieldCount> {
+        self.fields.clone()
     }
 
-    pub fn weight(&self) -> usize {
-        self.weight
+    /// The amounts counters are incremented or decremented by, chosen by
+    /// weight. Each is one when none are set.
+    pub fn deltas(&self) -> Vec<Delta> {
+        self.deltas.clone()
     }
-}
 
-#[derive(Deserialize, Clone)]
+    /// Keys are built from this template instead of the key type, length,
+    /// cardinality, and distribution. See `KeyTemplate::parse` for the format.
+    pub fn key_template(&self) -> Option<String> {
+        self.key_template.clone()
+    }
+
+    /// Each write of a key stores the next version of its value, rather than
+    /// an unrelated value
+    pub fn value_mutation(&self) -> bool {
+        self.value_mutation
+    }
+
+    /// Unusual keys mixed into the keyspace's requests
+    pub fn edge_cases(&self) -> Option<EdgeCases> {
+        self.edge_cases.clone()
+    }
+
+    /// Keys are prefixed with a namespace version which is bumped on an
+    /// interval
+    pub fn namespace(&self) -> Option<Namespace> {
+        self.namespace.clone()
+    }
+
+    /// The commands sent by the transaction command
+    pub fn transaction(&self) -> Option<Transaction> {
+        self.transaction.clone()
+    }
+
+    /// The Lua scripts run by the evalsha command
+    pub fn scripts(&self) -> Vec<Script> {
+        self.scripts.clone()
+    }
+}
+
+/// A Lua script which is loaded into redis as each connection is set up, and
+/// run with EVALSHA. Its keys are drawn from the keyspace, and an argument of
+/// `{value}` or `{inner_key}` is replaced by a value or an inner key from the
+/// keyspace. Other arguments are sent as they are.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Script {
+    source: String,
+    #[serde(default = "one")]
+    keys: usize,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default = "one")]
+    weight: usize,
+}
+
+impl Script {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The number of keys passed to the script
+    pub fn keys(&self) -> usize {
+        self.keys
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+}
+
+/// A sequence of commands which the transaction command sends to redis to be
+/// applied atomically. Each command draws its own key from the keyspace.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Transaction {
+    commands: Vec<Verb>,
+    #[serde(default)]
+    mode: TransactionMode,
+}
+
+impl Transaction {
+    pub fn commands(&self) -> &[Verb] {
+        &self.commands
+    }
+
+    pub fn mode(&self) -> TransactionMode {
+        self.mode
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum TransactionMode {
+    /// The commands are queued between MULTI and EXEC. This is the default.
+    #[default]
+    Multi,
+
+    /// The commands are run by a Lua script sent with EVAL
+    Eval,
+}
+
+/// Prefixes each key with the version of a namespace, such as `ns3:`, and
+/// bumps the version every interval. The keys of the previous version are
+/// no longer requested, as if the whole keyspace had been invalidated, so the
+/// hit-rate collapses and recovers as the cache refills.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Namespace {
+    interval: u64,
+    #[serde(default = "default_namespace_prefix")]
+    prefix: String,
+}
+
+fn default_namespace_prefix() -> String {
+    "ns".to_string()
+}
+
+impl Namespace {
+    /// How long each version of the namespace is used for. Set in seconds.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval)
+    }
+
+    /// Comes before the version in each key
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+}
+
+/// A share of a keyspace's keys which are replaced with unusual keys, to test
+/// how the server copes with them under load. Keys of the maximum length and
+/// single byte keys are sent with every protocol. Keys with spaces, control
+/// characters, and bytes which are not valid UTF-8 are only sent with binary
+/// safe protocols.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct EdgeCases {
+    share: f64,
+    #[serde(default = "default_max_key_length")]
+    max_length: usize,
+}
+
+fn default_max_key_length() -> usize {
+    250
+}
+
+impl EdgeCases {
+    /// The share of keys which are edge cases
+    pub fn share(&self) -> f64 {
+        self.share
+    }
+
+    /// The length of the longest keys, which defaults to the memcache limit
+    /// of 250 bytes
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+}
+
+#[derive(
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    Eq,
+    PartialEq,
+    Hash,
+    EnumCount,
+    EnumIter,
+    IntoStaticStr,
+)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+#[strum(serialize_all = "snake_case")]
+pub enum Verb {
+    /// Sends a simple 'ping' to a pingserver.
+    Ping,
+    /// Sends a payload with a CRC to an echo server and checks for corruption.
+    Echo,
+    /// Simple key-value get which reads the value for one or more keys
+    /// depending on the batch size.
+    Get,
+    /// Simple key-value set which will overwrite the value for a key.
+    Set,
+    /// Remove a key.
+    Delete,
+    /// Hash get, reads the value for one or more fields within the hash stored
+    /// at the key.
+    Hget,
+    /// Hash set, set the value for a field within the hash stored at the key.
+    Hset,
+    /// Hash set non-existing, set the value for a field within the hash stored
+    /// at the key only if the field does not exist.
+    Hsetnx,
+    /// Deletes one or more fields from the hash stored at the key
+    Hdel,
+    /// Hash get all, reads every field and value of the hash stored at the
+    /// key.
+    Hgetall,
+    /// Insert all the specified values at the tail of the list stored at a key.
+    /// Creates a new key if the key does not exist. Returns an error if the key
+    /// contains a value which is not a list.
+    Rpush,
+    /// Insert all the specified values at the tail of the list stored at a key,
+    /// returns an error if the key does not exist or contains a value which is
+    /// not a list.
+    Rpushx,
+    /// Count the number of items stored at a key
+    Count,
+    /// Returns the elements of the list stored at the key
+    Lrange,
+    /// Trims the elements of the list sotred at the key
+    Ltrim,
+    /// Runs the keyspace's transaction, a sequence of commands which are
+    /// applied atomically.
+    Transaction,
+    /// Runs one of the keyspace's Lua scripts, chosen by weight, by the SHA1
+    /// digest it was loaded with.
+    Evalsha,
+    /// Insert all the specified values at the head of the list stored at a
+    /// key. Creates a new key if the key does not exist.
+    Lpush,
+    /// Removes and returns the first element of the list stored at the key
+    Lpop,
+    /// Removes and returns the last element of the list stored at the key
+    Rpop,
+    /// Removes the elements equal to a value from the list stored at the key
+    Lrem,
+    /// Set add, adds members to the set stored at the key.
+    Sadd,
+    /// Set members, returns every member of the set stored at the key.
+    Smembers,
+    /// Set remove, removes a member from the set stored at the key.
+    Srem,
+    /// Sorted set add, adds members with random scores to the sorted set
+    /// stored at the key.
+    Zadd,
+    /// Sorted set rank, returns the rank of a member in the sorted set stored
+    /// at the key.
+    Zrank,
+    /// Sorted set score, returns the score of a member in the sorted set
+    /// stored at the key.
+    Zscore,
+    /// Sorted set remove, removes a member from the sorted set stored at the
+    /// key.
+    Zrem,
+    /// Sorted set increment, adds one to the score of a member in the sorted
+    /// set stored at the key.
+    Zincrby,
+    /// Sorted set range, returns the members with the lowest ranks in the
+    /// sorted set stored at the key.
+    Zrange,
+    /// Increments the counter stored at the key by a delta chosen from the
+    /// keyspace's deltas.
+    Incr,
+    /// Decrements the counter stored at the key by a delta chosen from the
+    /// keyspace's deltas.
+    Decr,
+    /// Compare-and-swap, reads the key and then writes a new value only if
+    /// the key has not changed since it was read.
+    Cas,
+    /// Reads the key and then writes back a value derived from the one which
+    /// was read, on the same connection, like a session store.
+    ReadModifyWrite,
+}
+
+impl Verb {
+    /// Returns true for commands which write elements into a collection, such
+    /// as a hash or a list.
+    pub fn is_collection_write(&self) -> bool {
+        matches!(
+            self,
+            Self::Hset
+                | Self::Hsetnx
+                | Self::Rpush
+                | Self::Rpushx
+                | Self::Lpush
+                | Self::Sadd
+                | Self::Zadd
+        )
+    }
+
+    /// Returns true for commands sent in several steps, each written once
+    /// the reply to the last has arrived.
+    pub fn is_multi_step(&self) -> bool {
+        matches!(self, Self::Cas | Self::ReadModifyWrite)
+    }
+}
+
+#[derive(Deserialize, Copy, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Command {
+    verb: Verb,
+    #[serde(default = "one")]
+    weight: usize,
+}
+
+impl Command {
+    pub fn verb(&self) -> Verb {
+        self.verb
+    }
+
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+}
+
+#[derive(Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct InnerKey {
     length: usize,
     #[serde(default = "one")]
     weight: usize,
-    #[serde(default = "u32_max")]
-    cardinality: u32,
+    cardinality: Option<u64>,
     #[serde(default = "alphanumeric")]
     field_type: FieldType,
+    distribution: Option<KeyDistribution>,
 }
 
 impl InnerKey {
@@ -520,23 +1362,74 @@ impl InnerKey {
         self.length
     }
 
-    pub fn cardinality(&self) -> u32 {
-        self.cardinality
+    pub fn cardinality(&self) -> u64 {
+        self.cardinality.unwrap_or_else(default_cardinality)
+    }
+
+    /// Whether the cardinality was set. Alphanumeric inner keys are only
+    /// bounded by the cardinality when it is set, otherwise they are random.
+    pub fn is_bounded(&self) -> bool {
+        self.cardinality.is_some()
     }
 
     pub fn field_type(&self) -> FieldType {
         self.field_type
     }
+
+    /// How the inner key is chosen from the cardinality, uniform by default
+    pub fn distribution(&self) -> Option<&KeyDistribution> {
+        self.distribution.as_ref()
+    }
+}
+
+#[derive(Deserialize, Copy, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FieldCount {
+    count: usize,
+    #[serde(default = "one")]
+    weight: usize,
+}
+
+impl FieldCount {
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Delta {
+    delta: u64,
+    #[serde(default = "one")]
+    weight: usize,
+}
+
+impl Delta {
+    pub fn delta(&self) -> u64 {
+        self.delta
+    }
+
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
 }
 
 #[derive(Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Value {
-    length: usize,
+    length: Option<usize>,
+    size: Option<ValueSize>,
+    #[serde(default)]
+    kind: ValueKind,
+    pattern: Option<String>,
+    compression_ratio: Option<f64>,
     #[serde(default = "one")]
     weight: usize,
-    #[serde(default = "u32_max")]
-    cardinality: u32,
+    cardinality: Option<u64>,
     #[serde(default = "alphanumeric")]
     field_type: FieldType,
 }
@@ -546,12 +1439,46 @@ impl Value {
         self.weight
     }
 
+    /// The fixed length of the value, see `size` for values whose lengths
+    /// vary
     pub fn length(&self) -> usize {
-        self.length
+        self.length.unwrap_or(0)
+    }
+
+    pub fn has_length(&self) -> bool {
+        self.length.is_some()
+    }
+
+    /// The distribution the length of each value is drawn from, instead of a
+    /// fixed length. Only alphanumeric values without a cardinality vary.
+    pub fn size(&self) -> Option<ValueSize> {
+        self.size
+    }
+
+    /// What alphanumeric values without a cardinality are filled with
+    pub fn kind(&self) -> ValueKind {
+        self.kind
+    }
+
+    /// The bytes repeated to fill values of the pattern kind
+    pub fn pattern(&self) -> Option<String> {
+        self.pattern.clone()
+    }
+
+    /// How many times smaller values of the compressible kind become when
+    /// compressed
+    pub fn compression_ratio(&self) -> Option<f64> {
+        self.compression_ratio
     }
 
-    pub fn cardinality(&self) -> u32 {
-        self.cardinality
+    pub fn cardinality(&self) -> u64 {
+        self.cardinality.unwrap_or_else(default_cardinality)
+    }
+
+    /// Whether the cardinality was set. Alphanumeric values are only bounded
+    /// by the cardinality when it is set, otherwise they are random.
+    pub fn is_bounded(&self) -> bool {
+        self.cardinality.is_some()
     }
 
     pub fn field_type(&self) -> FieldType {
@@ -559,29 +1486,1148 @@ impl Value {
     }
 }
 
-#[derive(Deserialize, Copy, Clone, Default)]
+#[derive(Deserialize, Clone, Default)]
 #[serde(deny_unknown_fields)]
 pub struct Request {
-    #[allow(dead_code)]
-    timeout: Option<usize>,
+    timeout: Option<u64>,
+    #[serde(default)]
+    timeouts: Vec<CommandTimeout>,
     ratelimit: Option<usize>,
     ratelimit_model: Option<RatelimitModel>,
+    ratelimit_final: Option<usize>,
+    #[serde(default)]
+    ratelimit_ramp: RampModel,
+    ratelimit_max: Option<usize>,
+    ratelimit_period: Option<u64>,
+    #[serde(default)]
+    mode: LoadMode,
+    #[serde(default = "default_max_inflight")]
+    max_inflight: usize,
+    #[serde(default)]
+    corrected_latency: bool,
+    #[serde(default)]
+    honor_retry_after: bool,
+    oauth: Option<Oauth>,
+}
+
+impl Request {
+    pub fn ratelimit(&self) -> Option<usize> {
+        self.ratelimit
+    }
+
+    /// The rate the request ratelimit ramps to by the end of the run. No
+    /// ramping is done unless this is set.
+    pub fn ratelimit_final(&self) -> Option<usize> {
+        self.ratelimit_final
+    }
+
+    pub fn ratelimit_ramp(&self) -> RampModel {
+        self.ratelimit_ramp
+    }
+
+    /// The peak rate of the sine ramp
+    pub fn ratelimit_max(&self) -> Option<usize> {
+        self.ratelimit_max
+    }
+
+    /// The time taken for one cycle of the sine ramp, set in seconds
+    pub fn ratelimit_period(&self) -> Option<std::time::Duration> {
+        self.ratelimit_period.map(std::time::Duration::from_secs)
+    }
+
+    pub fn ratelimit_model(&self) -> ratelimit::Refill {
+        match self.ratelimit_model {
+            None | Some(RatelimitModel::Smooth) => ratelimit::Refill::Smooth,
+            Some(RatelimitModel::Uniform) => ratelimit::Refill::Uniform,
+            Some(RatelimitModel::Normal) => ratelimit::Refill::Normal,
+        }
+    }
+
+    pub fn mode(&self) -> LoadMode {
+        self.mode
+    }
+
+    /// The most requests outstanding on each connection in open-loop mode
+    pub fn max_inflight(&self) -> usize {
+        self.max_inflight
+    }
+
+    /// Also record latency from when each request was scheduled to be sent,
+    /// which includes time spent waiting behind slow responses
+    pub fn corrected_latency(&self) -> bool {
+        self.corrected_latency
+    }
+
+    /// Stop sending on a connection until the time given by the Retry-After
+    /// header of a throttled response has passed
+    pub fn honor_retry_after(&self) -> bool {
+        self.honor_retry_after
+    }
+
+    /// The OAuth2 client whose access token is sent with http and websocket
+    /// requests
+    pub fn oauth(&self) -> Option<Oauth> {
+        self.oauth.clone()
+    }
+
+    /// How long a request for the command waits for its response, set in
+    /// milliseconds, before it times out. A timeout set for the command
+    /// overrides the timeout for all commands.
+    pub fn timeout(&self, verb: Verb) -> Option<std::time::Duration> {
+        self.timeouts
+            .iter()
+            .find(|timeout| timeout.verb == verb)
+            .map(|timeout| timeout.timeout)
+            .or(self.timeout)
+            .map(std::time::Duration::from_millis)
+    }
+
+    /// Every timeout which is set, in milliseconds
+    pub fn timeouts(&self) -> impl Iterator<Item = u64> + '_ {
+        self.timeout
+            .into_iter()
+            .chain(self.timeouts.iter().map(|timeout| timeout.timeout))
+    }
+}
+
+/// The timeout for requests of one command, which overrides the timeout set
+/// for all commands
+#[derive(Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct CommandTimeout {
+    verb: Verb,
+    timeout: u64,
+}
+
+/// The options of each protocol, each in its own `[protocol.<name>]` section.
+/// Only the section of the protocol in use may be set.
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ProtocolOptions {
+    http: Option<Http>,
+    websocket: Option<Websocket>,
+    thrift: Option<Thrift>,
+    cql: Option<Cql>,
+    mongodb: Option<Mongodb>,
+    postgres: Option<Postgres>,
+    dns: Option<Dns>,
+    ping: Option<Ping>,
+    redis: Option<Redis>,
+}
+
+impl ProtocolOptions {
+    /// The template for requests sent with the http protocol
+    pub fn http(&self) -> Http {
+        self.http.clone().unwrap_or_default()
+    }
+
+    /// The upgrade request and frame type for the websocket protocol
+    pub fn websocket(&self) -> Websocket {
+        self.websocket.clone().unwrap_or_default()
+    }
+
+    /// The methods called for each command with the thrift protocol
+    pub fn thrift(&self) -> Thrift {
+        self.thrift.clone().unwrap_or_default()
+    }
+
+    /// The table and consistency level used with the cql protocol
+    pub fn cql(&self) -> Cql {
+        self.cql.clone().unwrap_or_default()
+    }
+
+    /// The collection used with the mongodb protocol
+    pub fn mongodb(&self) -> Mongodb {
+        self.mongodb.clone().unwrap_or_default()
+    }
+
+    /// The table and credentials used with the postgres protocol
+    pub fn postgres(&self) -> Postgres {
+        self.postgres.clone().unwrap_or_default()
+    }
+
+    /// The names and record types queried with the dns protocol
+    pub fn dns(&self) -> Dns {
+        self.dns.clone().unwrap_or_default()
+    }
+
+    /// How requests are sent with the ping protocol
+    pub fn ping(&self) -> Ping {
+        self.ping.clone().unwrap_or_default()
+    }
+
+    /// How connections are set up with the redis protocols
+    pub fn redis(&self) -> Redis {
+        self.redis.clone().unwrap_or_default()
+    }
+
+    /// Check that no section is set for a protocol other than the one in use
+    pub fn check(&self, protocol: Protocol) -> Result<(), String> {
+        let sections = [
+            ("http", self.http.is_some(), protocol == Protocol::Http),
+            (
+                "websocket",
+                self.websocket.is_some(),
+                protocol == Protocol::Websocket,
+            ),
+            (
+                "thrift",
+                self.thrift.is_some(),
+                protocol == Protocol::Thrift,
+            ),
+            ("cql", self.cql.is_some(), protocol == Protocol::Cql),
+            (
+                "mongodb",
+                self.mongodb.is_some(),
+                protocol == Protocol::Mongodb,
+            ),
+            (
+                "postgres",
+                self.postgres.is_some(),
+                protocol == Protocol::Postgres,
+            ),
+            ("dns", self.dns.is_some(), protocol == Protocol::Dns),
+            ("ping", self.ping.is_some(), protocol == Protocol::Ping),
+            (
+                "redis",
+                self.redis.is_some(),
+                matches!(
+                    protocol,
+                    Protocol::Redis | Protocol::RedisInline | Protocol::RedisResp
+                ),
+            ),
+        ];
+        for (name, set, applies) in sections {
+            if set && !applies {
+                return Err(format!(
+                    "[protocol.{name}] does not apply to the protocol in use"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The sections which moved from `[request.<name>]` to `[protocol.<name>]`
+const PROTOCOL_SECTIONS: &[&str] = &[
+    "http",
+    "websocket",
+    "thrift",
+    "cql",
+    "mongodb",
+    "postgres",
+    "dns",
+    "ping",
+    "redis",
+];
+
+/// Move protocol sections still written under `[request]` to `[protocol]`,
+/// warning that the old place is deprecated
+fn migrate_protocol_sections(toml: &mut toml::Value) -> Result<(), String> {
+    let request = match toml.get_mut("request").and_then(|r| r.as_table_mut()) {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    let moved: Vec<(String, toml::Value)> = PROTOCOL_SECTIONS
+        .iter()
+        .filter_map(|name| request.remove(*name).map(|v| (name.to_string(), v)))
+        .collect();
+    if moved.is_empty() {
+        return Ok(());
+    }
+    let protocol = toml
+        .as_table_mut()
+        .ok_or("the config must be a table")?
+        .entry("protocol")
+        .or_insert_with(|| toml::Value::Table(Default::default()))
+        .as_table_mut()
+        .ok_or("protocol must be a table")?;
+    for (name, value) in moved {
+        if protocol.contains_key(&name) {
+            return Err(format!(
+                "[request.{name}] and [protocol.{name}] can not both be set"
+            ));
+        }
+        eprintln!("[request.{name}] is deprecated, use [protocol.{name}] instead");
+        protocol.insert(name, value);
+    }
+    Ok(())
+}
+
+fn default_max_inflight() -> usize {
+    64
+}
+
+/// A template for http requests. The path and header values may contain
+/// `${key}`, which is replaced with the generated key for each request.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Http {
+    method: Option<String>,
+    #[serde(default = "default_http_path")]
+    path: String,
+    #[serde(default = "default_http_host")]
+    host: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default = "default_header_refresh")]
+    header_refresh: u64,
+    body_file: Option<String>,
+    #[serde(default)]
+    cookies: bool,
+    login: Option<HttpLogin>,
+    stickiness: Option<HttpStickiness>,
+    #[serde(default)]
+    assertions: Vec<HttpAssertion>,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            method: None,
+            path: default_http_path(),
+            host: default_http_host(),
+            headers: BTreeMap::new(),
+            header_refresh: default_header_refresh(),
+            body_file: None,
+            cookies: false,
+            login: None,
+            stickiness: None,
+            assertions: Vec::new(),
+        }
+    }
+}
+
+fn default_header_refresh() -> u64 {
+    60
+}
+
+fn default_http_path() -> String {
+    "/".to_string()
+}
+
+fn default_http_host() -> String {
+    "localhost".to_string()
+}
+
+impl Http {
+    /// The method used for every request. When this is not set, the method
+    /// follows the command: `get` uses GET, `set` uses PUT with a generated
+    /// value as the body, and `delete` uses DELETE.
+    pub fn method(&self) -> Option<String> {
+        self.method.clone()
+    }
+
+    pub fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    /// The value of the Host header
+    pub fn host(&self) -> String {
+        self.host.clone()
+    }
+
+    /// Headers sent with every request. Values may contain `${key}`,
+    /// `${env:NAME}` and `${file:PATH}` templates.
+    pub fn headers(&self) -> Vec<(String, String)> {
+        self.headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+
+    /// How often files used in header values are read again, so that tokens
+    /// which are rotated on disk are picked up during a run
+    pub fn header_refresh(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.header_refresh)
+    }
+
+    /// A file which is sent as the body of every request, instead of a
+    /// generated value
+    pub fn body_file(&self) -> Option<String> {
+        self.body_file.clone()
+    }
+
+    /// Keep the cookies set by the server on each connection and send them
+    /// with the connection's later requests. Always on with a login.
+    pub fn cookies(&self) -> bool {
+        self.cookies || self.login.is_some()
+    }
+
+    /// A request sent when each connection is established, before any other
+    /// requests are sent on it
+    pub fn login(&self) -> Option<&HttpLogin> {
+        self.login.as_ref()
+    }
+
+    /// Label each connection, and check that a load balancer sends all of a
+    /// connection's requests to the same backend
+    pub fn stickiness(&self) -> Option<&HttpStickiness> {
+        self.stickiness.as_ref()
+    }
+
+    /// Checks made on every response, each counting its failures
+    pub fn assertions(&self) -> &[HttpAssertion] {
+        &self.assertions
+    }
+}
+
+/// A check made on every http response. Each assertion has a unique name and
+/// exactly one check: the status is in a set, a header is present, the body
+/// contains a string or matches a regex, or the json body has a value at a
+/// pointer which equals `equals`.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HttpAssertion {
+    name: String,
+    status: Option<Vec<u16>>,
+    header: Option<String>,
+    body_contains: Option<String>,
+    body_regex: Option<String>,
+    json_pointer: Option<String>,
+    equals: Option<toml::Value>,
+}
+
+impl HttpAssertion {
+    /// The name which the assertion's failures are counted under
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn status(&self) -> Option<&[u16]> {
+        self.status.as_deref()
+    }
+
+    pub fn header(&self) -> Option<&str> {
+        self.header.as_deref()
+    }
+
+    pub fn body_contains(&self) -> Option<&str> {
+        self.body_contains.as_deref()
+    }
+
+    pub fn body_regex(&self) -> Option<&str> {
+        self.body_regex.as_deref()
+    }
+
+    /// A json pointer, such as `/data/0/id`, into the body
+    pub fn json_pointer(&self) -> Option<&str> {
+        self.json_pointer.as_deref()
+    }
+
+    pub fn equals(&self) -> Option<&toml::Value> {
+        self.equals.as_ref()
+    }
+}
+
+/// Gives each connection a unique label for a load balancer to pin it by.
+/// When the backends name themselves in a response header, each response is
+/// checked against the backend which answered the connection's first.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HttpStickiness {
+    #[serde(default)]
+    label: StickinessLabel,
+    #[serde(default = "default_label_header")]
+    header: String,
+    identity: Option<String>,
+}
+
+fn default_label_header() -> String {
+    "X-Connection-Label".to_string()
+}
+
+impl HttpStickiness {
+    pub fn label(&self) -> StickinessLabel {
+        self.label
+    }
+
+    /// The request header which carries the label
+    pub fn header(&self) -> &str {
+        &self.header
+    }
+
+    /// The response header which names the backend that served the request
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum StickinessLabel {
+    /// The label is sent as a header with every request. This is the default.
+    #[default]
+    Header,
+
+    /// The label is sent as a header with only the first request on each
+    /// connection, so the load balancer has to remember the connection
+    FirstRequest,
+
+    /// The label replaces `${label}` in the tls server name
+    Sni,
+}
+
+/// A login request, such as a form post which the server answers with a
+/// session cookie. Header values and the body may contain `${env:NAME}` and
+/// `${file:PATH}` templates.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HttpLogin {
+    #[serde(default = "default_login_method")]
+    method: String,
+    path: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body: String,
+}
+
+fn default_login_method() -> String {
+    "POST".to_string()
+}
+
+impl HttpLogin {
+    pub fn method(&self) -> String {
+        self.method.clone()
+    }
+
+    pub fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    pub fn headers(&self) -> Vec<(String, String)> {
+        self.headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+
+    pub fn body(&self) -> String {
+        self.body.clone()
+    }
+}
+
+/// An OAuth2 client which gets access tokens with the client credentials
+/// flow. The client id and secret may be given as `${env:NAME}` or
+/// `${file:PATH}` templates.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Oauth {
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    scope: Option<String>,
+    audience: Option<String>,
+    #[serde(default)]
+    client_auth: OauthClientAuth,
+    #[serde(default = "default_oauth_refresh")]
+    refresh: u64,
+    #[serde(default = "default_oauth_timeout")]
+    timeout: u64,
+    ca_file: Option<String>,
+}
+
+fn default_oauth_refresh() -> u64 {
+    60
+}
+
+fn default_oauth_timeout() -> u64 {
+    5000
+}
+
+impl Oauth {
+    /// The http or https url of the token endpoint
+    pub fn token_url(&self) -> &str {
+        &self.token_url
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn client_secret(&self) -> &str {
+        &self.client_secret
+    }
+
+    pub fn scope(&self) -> Option<&str> {
+        self.scope.as_deref()
+    }
+
+    /// The audience, which some providers require to choose the api the
+    /// token is for
+    pub fn audience(&self) -> Option<&str> {
+        self.audience.as_deref()
+    }
+
+    pub fn client_auth(&self) -> OauthClientAuth {
+        self.client_auth
+    }
+
+    /// How long before a token expires that a new one is fetched, set in
+    /// seconds
+    pub fn refresh(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.refresh)
+    }
+
+    /// The longest a token request may take, set in milliseconds
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.timeout)
+    }
+
+    /// The certificates used to verify an https token endpoint, instead of
+    /// the system's
+    pub fn ca_file(&self) -> Option<&str> {
+        self.ca_file.as_deref()
+    }
+}
+
+/// How the client authenticates with the token endpoint
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum OauthClientAuth {
+    /// The client id and secret are sent with HTTP basic authentication. This
+    /// is the default.
+    #[default]
+    Basic,
+
+    /// The client id and secret are sent in the body of the request.
+    Body,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Websocket {
+    #[serde(default = "default_http_path")]
+    path: String,
+    #[serde(default = "default_http_host")]
+    host: String,
+    #[serde(default)]
+    frame: FrameType,
+}
+
+impl Default for Websocket {
+    fn default() -> Self {
+        Self {
+            path: default_http_path(),
+            host: default_http_host(),
+            frame: FrameType::default(),
+        }
+    }
+}
+
+impl Websocket {
+    /// The path of the upgrade request
+    pub fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    /// The value of the Host header of the upgrade request
+    pub fn host(&self) -> String {
+        self.host.clone()
+    }
+
+    pub fn frame(&self) -> FrameType {
+        self.frame
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum FrameType {
+    /// Messages are sent as binary frames. This is the default.
+    #[default]
+    Binary,
+
+    /// Messages are sent as text frames, with alphanumeric payloads.
+    Text,
+}
+
+/// The table which the cql protocol reads and writes. Keys and values are
+/// stored as blobs, in a table such as:
+/// `CREATE TABLE rpcperf.kv (key blob PRIMARY KEY, value blob)`
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Cql {
+    #[serde(default = "default_cql_keyspace")]
+    keyspace: String,
+    #[serde(default = "default_cql_table")]
+    table: String,
+    #[serde(default = "default_cql_key_column")]
+    key_column: String,
+    #[serde(default = "default_cql_value_column")]
+    value_column: String,
+    #[serde(default)]
+    consistency: Consistency,
+}
+
+impl Default for Cql {
+    fn default() -> Self {
+        Self {
+            keyspace: default_cql_keyspace(),
+            table: default_cql_table(),
+            key_column: default_cql_key_column(),
+            value_column: default_cql_value_column(),
+            consistency: Consistency::default(),
+        }
+    }
+}
+
+fn default_cql_keyspace() -> String {
+    "rpcperf".to_string()
+}
+
+fn default_cql_table() -> String {
+    "kv".to_string()
+}
+
+fn default_cql_key_column() -> String {
+    "key".to_string()
+}
+
+fn default_cql_value_column() -> String {
+    "value".to_string()
+}
+
+impl Cql {
+    /// The table, qualified by its keyspace
+    pub fn table(&self) -> String {
+        format!("{}.{}", self.keyspace, self.table)
+    }
+
+    pub fn key_column(&self) -> String {
+        self.key_column.clone()
+    }
+
+    pub fn value_column(&self) -> String {
+        self.value_column.clone()
+    }
+
+    pub fn consistency(&self) -> Consistency {
+        self.consistency
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum Consistency {
+    Any,
+    /// A single replica must respond. This is the default.
+    #[default]
+    One,
+    Two,
+    Three,
+    Quorum,
+    All,
+    LocalQuorum,
+    EachQuorum,
+    LocalOne,
+}
+
+impl Consistency {
+    /// The code for the consistency level in the native protocol
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::Any => 0x0000,
+            Self::One => 0x0001,
+            Self::Two => 0x0002,
+            Self::Three => 0x0003,
+            Self::Quorum => 0x0004,
+            Self::All => 0x0005,
+            Self::LocalQuorum => 0x0006,
+            Self::EachQuorum => 0x0007,
+            Self::LocalOne => 0x000A,
+        }
+    }
+}
+
+/// The collection which the mongodb protocol reads and writes. Documents are
+/// keyed by `_id`, with the value in a `value` field.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Mongodb {
+    #[serde(default = "default_mongodb_database")]
+    database: String,
+    #[serde(default = "default_mongodb_collection")]
+    collection: String,
+    write_concern: Option<String>,
+}
+
+impl Default for Mongodb {
+    fn default() -> Self {
+        Self {
+            database: default_mongodb_database(),
+            collection: default_mongodb_collection(),
+            write_concern: None,
+        }
+    }
+}
+
+fn default_mongodb_database() -> String {
+    "rpcperf".to_string()
+}
+
+fn default_mongodb_collection() -> String {
+    "kv".to_string()
+}
+
+impl Mongodb {
+    pub fn database(&self) -> String {
+        self.database.clone()
+    }
+
+    pub fn collection(&self) -> String {
+        self.collection.clone()
+    }
+
+    /// The `w` of the write concern sent with sets and deletes, either a
+    /// number of members or a tag such as `majority`. The server's default
+    /// is used when this is not set.
+    pub fn write_concern(&self) -> Option<String> {
+        self.write_concern.clone()
+    }
+}
+
+/// The table which the postgres protocol reads and writes. Keys and values
+/// are stored as bytea, in a table such as:
+/// `CREATE TABLE kv (key bytea PRIMARY KEY, value bytea)`
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Postgres {
+    #[serde(default = "default_postgres_user")]
+    user: String,
+    password: Option<String>,
+    #[serde(default = "default_postgres_database")]
+    database: String,
+    #[serde(default = "default_cql_table")]
+    table: String,
+    #[serde(default = "default_cql_key_column")]
+    key_column: String,
+    #[serde(default = "default_cql_value_column")]
+    value_column: String,
+}
+
+impl Default for Postgres {
+    fn default() -> Self {
+        Self {
+            user: default_postgres_user(),
+            password: None,
+            database: default_postgres_database(),
+            table: default_cql_table(),
+            key_column: default_cql_key_column(),
+            value_column: default_cql_value_column(),
+        }
+    }
+}
+
+fn default_postgres_user() -> String {
+    "postgres".to_string()
+}
+
+fn default_postgres_database() -> String {
+    "rpcperf".to_string()
+}
+
+impl Postgres {
+    pub fn user(&self) -> String {
+        self.user.clone()
+    }
+
+    /// The password used for cleartext, md5, or SCRAM-SHA-256 authentication,
+    /// which may be given as an `${env:NAME}` or `${file:PATH}` template
+    pub fn password(&self) -> Option<String> {
+        self.password.clone()
+    }
+
+    pub fn database(&self) -> String {
+        self.database.clone()
+    }
+
+    pub fn table(&self) -> String {
+        self.table.clone()
+    }
+
+    pub fn key_column(&self) -> String {
+        self.key_column.clone()
+    }
+
+    pub fn value_column(&self) -> String {
+        self.value_column.clone()
+    }
+}
+
+/// The queries sent with the dns protocol. Each get queries the name, with
+/// `${key}` replaced by the generated key, for one of the record types
+/// chosen at random.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Dns {
+    #[serde(default = "default_dns_name")]
+    name: String,
+    #[serde(default = "default_dns_types")]
+    types: Vec<String>,
+    #[serde(default)]
+    transport: Transport,
+    #[serde(default = "default_dns_timeout")]
+    timeout: u64,
+    #[serde(default)]
+    grace: u64,
+    #[serde(default = "default_recursion_desired")]
+    recursion_desired: bool,
+}
+
+impl Default for Dns {
+    fn default() -> Self {
+        Self {
+            name: default_dns_name(),
+            types: default_dns_types(),
+            transport: Transport::default(),
+            timeout: default_dns_timeout(),
+            grace: 0,
+            recursion_desired: true,
+        }
+    }
+}
+
+fn default_dns_name() -> String {
+    "${key}.example.com".to_string()
+}
+
+fn default_dns_types() -> Vec<String> {
+    vec!["A".to_string()]
+}
+
+fn default_dns_timeout() -> u64 {
+    1000
+}
+
+fn default_recursion_desired() -> bool {
+    true
+}
+
+impl Dns {
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// The record types which are queried, such as `A` or `AAAA`, or any
+    /// type by its number
+    pub fn types(&self) -> Result<Vec<u16>, String> {
+        self.types
+            .iter()
+            .map(|name| {
+                let code = match name.to_ascii_uppercase().as_str() {
+                    "A" => 1,
+                    "NS" => 2,
+                    "CNAME" => 5,
+                    "SOA" => 6,
+                    "PTR" => 12,
+                    "MX" => 15,
+                    "TXT" => 16,
+                    "AAAA" => 28,
+                    "SRV" => 33,
+                    "NAPTR" => 35,
+                    "DS" => 43,
+                    "DNSKEY" => 48,
+                    "HTTPS" => 65,
+                    "CAA" => 257,
+                    "ANY" => 255,
+                    other => other
+                        .parse()
+                        .map_err(|_| format!("unknown dns record type: {}", name))?,
+                };
+                Ok(code)
+            })
+            .collect()
+    }
+
+    /// Queries are sent as datagrams over udp, or over tcp connections which
+    /// may use tls
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// How long a query sent over udp waits for its response before it is
+    /// counted as lost, set in milliseconds
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.timeout)
+    }
+
+    /// How long the socket is kept open after a timeout to read responses
+    /// which arrive late, set in milliseconds. Zero closes it right away.
+    pub fn grace(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.grace)
+    }
+
+    /// Ask the server to resolve the name recursively
+    pub fn recursion_desired(&self) -> bool {
+        self.recursion_desired
+    }
+}
+
+/// Connection setup with the redis protocols
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Redis {
+    #[serde(default)]
+    client_name: bool,
+}
+
+impl Redis {
+    /// Name each connection with `CLIENT SETNAME` as `rpc-perf-<run id>-<n>`,
+    /// where `n` is the connection's index, so that connections can be found
+    /// in the output of `CLIENT LIST` and `SLOWLOG GET`. The library name and
+    /// version are also set with `CLIENT SETINFO` where it is supported.
+    pub fn client_name(&self) -> bool {
+        self.client_name
+    }
+}
+
+/// The transport used with the ping protocol. Over udp, each ping is sent as
+/// a datagram and the server may answer with a pong or by echoing the ping.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Ping {
+    #[serde(default = "default_ping_transport")]
+    transport: Transport,
+    #[serde(default = "default_ping_timeout")]
+    timeout: u64,
+    #[serde(default)]
+    grace: u64,
+}
+
+impl Default for Ping {
+    fn default() -> Self {
+        Self {
+            transport: default_ping_transport(),
+            timeout: default_ping_timeout(),
+            grace: 0,
+        }
+    }
+}
+
+fn default_ping_transport() -> Transport {
+    Transport::Tcp
+}
+
+fn default_ping_timeout() -> u64 {
+    1000
+}
+
+impl Ping {
+    /// Pings are sent over tcp connections unless udp is chosen
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// How long a ping sent over udp waits for its response before it is
+    /// counted as lost, set in milliseconds
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.timeout)
+    }
+
+    /// How long the socket is kept open after a timeout to read responses
+    /// which arrive late, set in milliseconds. Zero closes it right away.
+    pub fn grace(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.grace)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum Transport {
+    /// Each request is sent as a datagram. This is the default.
+    #[default]
+    Udp,
+
+    /// Requests are sent over a connection, with tls when it is configured.
+    Tcp,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Thrift {
+    service: Option<String>,
+    #[serde(default)]
+    methods: Vec<ThriftMethod>,
+}
+
+impl Thrift {
+    /// The service name for servers which multiplex several services over one
+    /// port. Method names are prefixed with the service when this is set.
+    pub fn service(&self) -> Option<String> {
+        self.service.clone()
+    }
+
+    pub fn methods(&self) -> Vec<ThriftMethod> {
+        self.methods.clone()
+    }
+}
+
+/// A method which is called for a command, along with the fields which make
+/// up its arguments
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ThriftMethod {
+    verb: Verb,
+    name: String,
+    #[serde(default)]
+    args: Vec<ThriftArg>,
+}
+
+impl ThriftMethod {
+    pub fn verb(&self) -> Verb {
+        self.verb
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn args(&self) -> Vec<ThriftArg> {
+        self.args.clone()
+    }
 }
 
-impl Request {
-    pub fn ratelimit(&self) -> Option<usize> {
-        self.ratelimit
+#[derive(Deserialize, Copy, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ThriftArg {
+    id: i16,
+    field: ThriftField,
+}
+
+impl ThriftArg {
+    /// The field id of the argument
+    pub fn id(&self) -> i16 {
+        self.id
     }
 
-    pub fn ratelimit_model(&self) -> ratelimit::Refill {
-        match self.ratelimit_model {
-            None | Some(RatelimitModel::Smooth) => ratelimit::Refill::Smooth,
-            Some(RatelimitModel::Uniform) => ratelimit::Refill::Uniform,
-            Some(RatelimitModel::Normal) => ratelimit::Refill::Normal,
-        }
+    pub fn field(&self) -> ThriftField {
+        self.field
     }
 }
 
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum ThriftField {
+    /// A generated key, as binary
+    Key,
+    /// A list of `batch_size` generated keys, as binary
+    Keys,
+    /// A generated inner key, as binary
+    InnerKey,
+    /// A generated value, as binary
+    Value,
+    /// The keyspace ttl, as an i64
+    Ttl,
+}
+
 #[derive(Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Tls {
@@ -591,6 +2637,7 @@ pub struct Tls {
     private_key: Option<String>,
     verify: bool,
     session_cache: Option<u32>,
+    server_name: Option<String>,
 }
 
 impl Tls {
@@ -617,6 +2664,45 @@ impl Tls {
     pub fn session_cache(&self) -> Option<u32> {
         self.session_cache
     }
+
+    /// The server name used for SNI and certificate verification. Defaults to
+    /// `localhost`.
+    pub fn server_name(&self) -> String {
+        self.server_name
+            .clone()
+            .unwrap_or_else(|| "localhost".to_string())
+    }
+}
+
+/// The virtual hosts substituted for `${vhost}` in the tls server name and the
+/// http or websocket host, so that routing by SNI or host and caches kept per host are
+/// exercised across many hosts.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Vhost {
+    count: u64,
+    #[serde(default)]
+    per_request: bool,
+    distribution: Option<KeyDistribution>,
+}
+
+impl Vhost {
+    /// The number of virtual hosts, which are numbered from zero
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Choose a host for each http request rather than once per connection.
+    /// The server name is still chosen per connection, as it is sent in the
+    /// tls handshake.
+    pub fn per_request(&self) -> bool {
+        self.per_request
+    }
+
+    /// How the hosts are chosen, uniform by default
+    pub fn distribution(&self) -> Option<&KeyDistribution> {
+        self.distribution.as_ref()
+    }
 }
 
 struct ExitWatcher;
@@ -626,6 +2712,25 @@ impl Watcher for ExitWatcher {
     }
 }
 
+#[derive(Deserialize, Clone, Copy, Debug, Eq, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum Balance {
+    /// Each request is sent on the next connection which is ready, so faster
+    /// endpoints receive more of the requests.
+    #[default]
+    Ready,
+    /// The endpoints take turns receiving requests.
+    RoundRobin,
+    /// Each request is sent to an endpoint chosen at random.
+    Random,
+    /// Each request is sent to an endpoint chosen by the endpoint weights.
+    Weighted,
+    /// The keys are divided between the endpoints by key index, so each key
+    /// is only ever sent to the same endpoint.
+    KeyHash,
+}
+
 #[derive(Deserialize, Default, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Target {
@@ -633,24 +2738,38 @@ pub struct Target {
     zk_path: Option<String>,
     zk_server: Option<String>,
     zk_endpoint_name: Option<String>,
+    #[serde(default)]
+    balance: Balance,
+    #[serde(default)]
+    weights: Vec<usize>,
+    cutover: Option<Cutover>,
 }
 
 impl Target {
-    pub fn endpoints(&self) -> Vec<SocketAddr> {
-        if self.zk_path.is_some() && self.zk_server.is_some() && self.zk_endpoint_name.is_some() {
-            let zk_endpoint_name = self.zk_endpoint_name.as_deref().unwrap();
+    /// How requests are assigned to the endpoints
+    pub fn balance(&self) -> Balance {
+        self.balance
+    }
+
+    /// The relative share of the requests for each endpoint, in the same
+    /// order as the endpoints, with the weighted balance
+    pub fn weights(&self) -> &[usize] {
+        &self.weights
+    }
+
+    /// The target endpoints. Unix domain sockets are specified with a
+    /// `unix://` prefix, for example `unix:///var/run/memcached.sock`.
+    pub fn endpoints(&self) -> Vec<Endpoint> {
+        if let (Some(zk_path), Some(zk_server), Some(zk_endpoint_name)) = (
+            self.zk_path.as_ref(),
+            self.zk_server.as_ref(),
+            self.zk_endpoint_name.as_deref(),
+        ) {
             let mut ret = Vec::new();
-            let zk = ZooKeeper::connect(
-                self.zk_server.as_ref().unwrap(),
-                Duration::from_secs(15),
-                ExitWatcher,
-            )
-            .unwrap();
-            let children = zk
-                .get_children(self.zk_path.as_ref().unwrap(), true)
-                .unwrap();
+            let zk = ZooKeeper::connect(zk_server, Duration::from_secs(15), ExitWatcher).unwrap();
+            let children = zk.get_children(zk_path, true).unwrap();
             for child in children {
-                let child_path = format!("{}/{}", self.zk_path.as_ref().unwrap(), child);
+                let child_path = format!("{}/{}", zk_path, child);
                 let data = zk.get_data(&child_path, true).unwrap();
                 let data = std::str::from_utf8(&data.0).unwrap();
                 let entry: JsonValue = serde_json::from_str(data).unwrap();
@@ -662,24 +2781,66 @@ impl Target {
                     let host = format!("{}:{}", host, port);
                     if let Ok(mut addrs) = host.to_socket_addrs() {
                         if let Some(socket_addr) = addrs.next() {
-                            ret.push(socket_addr);
+                            ret.push(Endpoint::Tcp(socket_addr));
                         }
                     }
                 }
             }
             ret
         } else {
-            let mut ret = Vec::new();
-            for host in &self.endpoints {
-                if let Ok(mut addrs) = host.to_socket_addrs() {
-                    if let Some(socket_addr) = addrs.next() {
-                        ret.push(socket_addr);
-                    }
-                }
-            }
-            ret
+            parse_endpoints(&self.endpoints)
+        }
+    }
+
+    /// Shift the requests to another set of endpoints during the run
+    pub fn cutover(&self) -> Option<&Cutover> {
+        self.cutover.as_ref()
+    }
+}
+
+fn parse_endpoints(hosts: &[String]) -> Vec<Endpoint> {
+    let mut ret = Vec::new();
+    for host in hosts {
+        if let Some(path) = host.strip_prefix("unix://") {
+            ret.push(Endpoint::Unix(path.into()));
+        } else if let Some(socket_addr) = host.to_socket_addrs().ok().and_then(|mut a| a.next()) {
+            ret.push(Endpoint::Tcp(socket_addr));
+        } else {
+            error!("failed to resolve endpoint: {}", host);
         }
     }
+    ret
+}
+
+/// Moves the load from the target endpoints to a new set of endpoints while
+/// the workload continues, to rehearse a migration. The share of requests
+/// sent to the new endpoints rises linearly from zero to all of them over the
+/// duration, starting at the given time into the run.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Cutover {
+    endpoints: Vec<String>,
+    #[serde(default)]
+    start: u64,
+    duration: u64,
+}
+
+impl Cutover {
+    /// The endpoints which the requests move to
+    pub fn endpoints(&self) -> Vec<Endpoint> {
+        parse_endpoints(&self.endpoints)
+    }
+
+    /// When the cutover begins, relative to the start of the run. Set in
+    /// seconds.
+    pub fn start(&self) -> Duration {
+        Duration::from_secs(self.start)
+    }
+
+    /// How long the requests take to move over. Set in seconds.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(self.duration)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -708,6 +2869,28 @@ fn scale() -> Scale {
     Scale::Linear
 }
 
+fn some_palette<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<Palette>, D::Error> {
+    PaletteDef::deserialize(d).map(Some)
+}
+
+fn some_scale<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<Scale>, D::Error> {
+    ScaleDef::deserialize(d).map(Some)
+}
+
+fn ser_some_palette<S: serde::Serializer>(p: &Option<Palette>, s: S) -> Result<S::Ok, S::Error> {
+    match p {
+        Some(p) => PaletteDef::serialize(p, s),
+        None => s.serialize_none(),
+    }
+}
+
+fn ser_some_scale<S: serde::Serializer>(scale: &Option<Scale>, s: S) -> Result<S::Ok, S::Error> {
+    match scale {
+        Some(scale) => ScaleDef::serialize(scale, s),
+        None => s.serialize_none(),
+    }
+}
+
 fn resolution() -> u64 {
     // one second in milliseconds
     1000
@@ -724,6 +2907,12 @@ pub struct Waterfall {
     #[serde(with = "ScaleDef")]
     #[serde(default = "scale")]
     scale: Scale,
+    #[serde(default)]
+    per_command: bool,
+    #[serde(default)]
+    commands: Vec<CommandWaterfall>,
+    #[serde(default)]
+    metadata: bool,
 }
 
 impl Default for Waterfall {
@@ -733,10 +2922,27 @@ impl Default for Waterfall {
             palette: palette(),
             resolution: resolution(),
             scale: scale(),
+            per_command: false,
+            commands: Vec::new(),
+            metadata: false,
         }
     }
 }
 
+/// How the waterfall of one command is rendered, where the palette and scale
+/// default to those of the request waterfall
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CommandWaterfall {
+    verb: Verb,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(deserialize_with = "some_palette", serialize_with = "ser_some_palette")]
+    palette: Option<Palette>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(deserialize_with = "some_scale", serialize_with = "ser_some_scale")]
+    scale: Option<Scale>,
+}
+
 impl Waterfall {
     pub fn file(&self) -> Option<String> {
         self.file.clone()
@@ -753,4 +2959,405 @@ impl Waterfall {
     pub fn scale(&self) -> Scale {
         self.scale
     }
+
+    /// Also render a waterfall for each command
+    pub fn per_command(&self) -> bool {
+        self.per_command
+    }
+
+    /// The commands whose waterfalls are rendered with their own palette or
+    /// scale, which are rendered even without `per_command`
+    pub fn commands(&self) -> Vec<Verb> {
+        self.commands.iter().map(|command| command.verb).collect()
+    }
+
+    /// Whether a waterfall is rendered for the command
+    pub fn renders(&self, verb: Verb) -> bool {
+        self.per_command || self.commands.iter().any(|command| command.verb == verb)
+    }
+
+    /// The palette of the command's waterfall
+    pub fn command_palette(&self, verb: Verb) -> Palette {
+        self.commands
+            .iter()
+            .find(|command| command.verb == verb)
+            .and_then(|command| command.palette)
+            .unwrap_or(self.palette)
+    }
+
+    /// The scale of the command's waterfall
+    pub fn command_scale(&self, verb: Verb) -> Scale {
+        self.commands
+            .iter()
+            .find(|command| command.verb == verb)
+            .and_then(|command| command.scale)
+            .unwrap_or(self.scale)
+    }
+
+    /// Embed the details of the run in each waterfall image
+    pub fn metadata(&self) -> bool {
+        self.metadata
+    }
+
+    /// The file for the waterfall of a single command, which is the waterfall
+    /// file with the command name appended to the file stem.
+    pub fn command_file(&self, verb: Verb) -> Option<String> {
+        let file = self.file.as_ref()?;
+        let path = std::path::Path::new(file);
+        let stem = path.file_stem()?.to_str()?;
+        let name = match path.extension().and_then(|e| e.to_str()) {
+            Some(extension) => format!("{}_{}.{}", stem, <&str>::from(verb), extension),
+            None => format!("{}_{}", stem, <&str>::from(verb)),
+        };
+        Some(path.with_file_name(name).to_string_lossy().into_owned())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum Role {
+    /// Coordinates the agents and reports their combined results. The
+    /// controller does not send any requests itself.
+    Controller,
+
+    /// Sends requests with a share of the ratelimit and keyspace assigned by
+    /// the controller, reporting each window back to it. The keyspace is
+    /// shared by key index, so it needs a fixed set of keys.
+    Agent,
+}
+
+/// Spreads a run across several rpc-perf instances. One instance is the
+/// controller, serving the control plane on its admin address, and the others
+/// are agents which connect to it.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Distributed {
+    role: Option<Role>,
+    #[serde(default = "one")]
+    agents: usize,
+    controller: Option<String>,
+}
+
+impl Default for Distributed {
+    fn default() -> Self {
+        Self {
+            role: None,
+            agents: 1,
+            controller: None,
+        }
+    }
+}
+
+impl Distributed {
+    pub fn role(&self) -> Option<Role> {
+        self.role
+    }
+
+    /// The number of agents the controller waits for before starting the run
+    pub fn agents(&self) -> usize {
+        self.agents
+    }
+
+    /// The admin address of the controller an agent registers with
+    pub fn controller(&self) -> Option<String> {
+        self.controller.clone()
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Abort {
+    error_rate: Option<f64>,
+    #[serde(default = "one")]
+    windows: usize,
+}
+
+impl Default for Abort {
+    fn default() -> Self {
+        Self {
+            error_rate: None,
+            windows: 1,
+        }
+    }
+}
+
+impl Abort {
+    /// The error rate, as a percentage, above which a window is considered
+    /// failed. The run continues regardless of errors unless this is set.
+    pub fn error_rate(&self) -> Option<f64> {
+        self.error_rate
+    }
+
+    /// The number of consecutive failed windows which stops the run.
+    pub fn windows(&self) -> usize {
+        self.windows
+    }
+}
+
+/// How far each command's results may fall behind the baseline run given with
+/// `--baseline` before the run counts as a regression
+#[derive(Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Baseline {
+    #[serde(default = "default_baseline_tolerance")]
+    latency: f64,
+    #[serde(default = "default_baseline_tolerance")]
+    throughput: f64,
+    #[serde(default = "default_baseline_success")]
+    success: f64,
+}
+
+fn default_baseline_tolerance() -> f64 {
+    10.0
+}
+
+fn default_baseline_success() -> f64 {
+    1.0
+}
+
+impl Default for Baseline {
+    fn default() -> Self {
+        Self {
+            latency: default_baseline_tolerance(),
+            throughput: default_baseline_tolerance(),
+            success: default_baseline_success(),
+        }
+    }
+}
+
+impl Baseline {
+    /// How much higher, as a percentage, each latency percentile may be
+    pub fn latency(&self) -> f64 {
+        self.latency
+    }
+
+    /// How much lower, as a percentage, the response rate may be
+    pub fn throughput(&self) -> f64 {
+        self.throughput
+    }
+
+    /// How many percentage points lower the success rate may be
+    pub fn success(&self) -> f64 {
+        self.success
+    }
+}
+
+/// Probes a reference server built into rpc-perf over loopback, so that
+/// latency added by the load generator's host can be told apart from latency
+/// added by the target
+#[derive(Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Calibration {
+    #[serde(default = "default_calibration_rate")]
+    rate: u64,
+}
+
+fn default_calibration_rate() -> u64 {
+    100
+}
+
+impl Calibration {
+    /// The number of probes sent each second
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Checkpoint {
+    file: Option<String>,
+    #[serde(default = "one")]
+    windows: usize,
+}
+
+impl Default for Checkpoint {
+    fn default() -> Self {
+        Self {
+            file: None,
+            windows: 1,
+        }
+    }
+}
+
+impl Checkpoint {
+    /// The file the state of the workload is saved to, so that a restarted
+    /// run can resume from it. No checkpoints are written unless this is set.
+    pub fn file(&self) -> Option<String> {
+        self.file.clone()
+    }
+
+    /// The number of windows between checkpoints
+    pub fn windows(&self) -> usize {
+        self.windows
+    }
+}
+
+#[derive(Deserialize, Copy, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Metrics {
+    #[serde(default)]
+    backend: LatencyBackend,
+}
+
+impl Metrics {
+    /// How latencies and other distributions are recorded
+    pub fn backend(&self) -> LatencyBackend {
+        self.backend
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum LatencyBackend {
+    /// A histogram for each second of the span the percentiles cover, which
+    /// ages out old samples and can be rendered as a waterfall. This is the
+    /// default.
+    #[default]
+    Heatmap,
+
+    /// A single histogram which is cleared when the span it covers starts
+    /// over. Uses far less memory than a heatmap, but can not render a
+    /// waterfall.
+    Histogram,
+
+    /// A t-digest, whose size is bounded however many distinct values are
+    /// recorded. Its percentiles are estimates which are most accurate at the
+    /// tails. Recording takes a lock, so it is slower with many threads.
+    Tdigest,
+}
+
+/// The targets for one command, which are checked each window. Any target
+/// can be left out, but at least one must be set.
+#[derive(Deserialize, Copy, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Sla {
+    verb: Verb,
+    #[serde(default = "default_sla_percentile")]
+    percentile: f64,
+    latency: Option<u64>,
+    error_rate: Option<f64>,
+    rate: Option<f64>,
+}
+
+fn default_sla_percentile() -> f64 {
+    99.0
+}
+
+impl Sla {
+    pub fn verb(&self) -> Verb {
+        self.verb
+    }
+
+    /// The percentile the latency target applies to, the p99 by default
+    pub fn percentile(&self) -> f64 {
+        self.percentile
+    }
+
+    /// The highest acceptable latency at the percentile, in microseconds
+    pub fn latency(&self) -> Option<u64> {
+        self.latency
+    }
+
+    /// The highest acceptable percentage of requests which failed, with an
+    /// error or a timeout
+    pub fn error_rate(&self) -> Option<f64> {
+        self.error_rate
+    }
+
+    /// The lowest acceptable rate of responses, per second
+    pub fn rate(&self) -> Option<f64> {
+        self.rate
+    }
+}
+
+/// A period of the run, such as planned maintenance of the server, which is
+/// excluded from the latency targets and the error rate abort. Load is still
+/// sent, and the windows which overlap it are marked in the output.
+#[derive(Deserialize, Copy, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Blackout {
+    start: u64,
+    duration: u64,
+}
+
+impl Blackout {
+    /// The time from the start of the run until the blackout begins, set in
+    /// seconds
+    pub fn start(&self) -> Duration {
+        Duration::from_secs(self.start)
+    }
+
+    /// How long the blackout lasts, set in seconds
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(self.duration)
+    }
+
+    /// Whether the blackout overlaps the part of the run between the times
+    pub fn overlaps(&self, start: Duration, end: Duration) -> bool {
+        self.start() < end && start < self.start() + self.duration()
+    }
+}
+
+/// Searches for the highest request rate which meets a latency target. Each
+/// window runs at one rate and the next rate is chosen by bisection.
+#[derive(Deserialize, Copy, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Search {
+    percentile: f64,
+    latency: u64,
+    #[serde(default = "one")]
+    min_rate: usize,
+    max_rate: usize,
+    precision: Option<usize>,
+}
+
+impl Search {
+    pub fn percentile(&self) -> f64 {
+        self.percentile
+    }
+
+    /// The highest acceptable latency at the percentile, in microseconds
+    pub fn latency(&self) -> u64 {
+        self.latency
+    }
+
+    /// The lowest rate searched, which is assumed to meet the target
+    pub fn min_rate(&self) -> usize {
+        self.min_rate
+    }
+
+    /// The highest rate searched
+    pub fn max_rate(&self) -> usize {
+        self.max_rate
+    }
+
+    /// The search stops once the sustainable rate is known to within this
+    /// many requests per second. Defaults to 1% of the highest rate.
+    pub fn precision(&self) -> usize {
+        self.precision.unwrap_or(self.max_rate / 100).max(1)
+    }
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Bundle {
+    directory: Option<String>,
+    run_id: Option<String>,
+}
+
+impl Bundle {
+    /// The directory to write the artifact bundle into. Bundling is disabled
+    /// unless this is set.
+    pub fn directory(&self) -> Option<String> {
+        self.directory.clone()
+    }
+
+    /// An identifier for the run which is included in the bundle name. If not
+    /// set, the hostname is used.
+    pub fn run_id(&self) -> Option<String> {
+        self.run_id.clone()
+    }
 }
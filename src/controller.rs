@@ -0,0 +1,191 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+use crate::config_file::RampModel;
+use crate::metrics::*;
+use crate::Config;
+use ratelimit::Ratelimiter;
+use serde_derive::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Adjusts the request ratelimit over the course of a run so that it moves
+/// from the initial rate to the final rate by the last window, or so that it
+/// oscillates between the initial and maximum rates.
+pub struct RateController {
+    ratelimiter: Arc<Ratelimiter>,
+    model: RampModel,
+    initial: u64,
+    target: u64,
+    current: u64,
+    interval: Duration,
+    windows: u32,
+    // the length of one cycle of the sine ramp
+    period: Duration,
+}
+
+impl RateController {
+    /// Returns a controller if the config specifies a ramp for the request
+    /// ratelimit. Ramping to a final rate requires a fixed number of windows.
+    pub fn new(config: &Config, ratelimiter: Option<Arc<Ratelimiter>>) -> Option<Self> {
+        let ratelimiter = ratelimiter?;
+        let initial = config.request().ratelimit()? as u64;
+        let model = config.request().ratelimit_ramp();
+        let (target, windows) = if model == RampModel::Sine {
+            (config.request().ratelimit_max()? as u64, 0)
+        } else {
+            let target = config.request().ratelimit_final()? as u64;
+            match config.general().windows() {
+                Some(windows) => (target, windows as u32),
+                None => {
+                    warn!("request ratelimit ramp is ignored when running as a service");
+                    return None;
+                }
+            }
+        };
+
+        Some(Self {
+            ratelimiter,
+            initial,
+            current: initial,
+            model,
+            target,
+            interval: config.general().interval(),
+            windows,
+            period: config.request().ratelimit_period().unwrap_or_default(),
+        })
+    }
+
+    /// The target rate once the provided time has elapsed since the start of
+    /// the run.
+    pub fn rate(&self, elapsed: Duration) -> u64 {
+        let progress = match self.model {
+            RampModel::Linear => {
+                elapsed.as_secs_f64() / (self.interval * self.windows).as_secs_f64()
+            }
+            RampModel::Step => {
+                // each window runs at the rate the linear ramp starts it at
+                let window = (elapsed.as_secs_f64() / self.interval.as_secs_f64()).floor();
+                window / self.windows.max(1) as f64
+            }
+            RampModel::Sine => {
+                // starts at the initial rate and peaks half way through
+                let phase = elapsed.as_secs_f64() / self.period.as_secs_f64();
+                (1.0 - (2.0 * std::f64::consts::PI * phase).cos()) / 2.0
+            }
+        }
+        .clamp(0.0, 1.0);
+
+        let delta = (self.target as f64 - self.initial as f64) * progress;
+        // the ratelimiter cannot be set to a rate of zero
+        ((self.initial as f64 + delta).round() as u64).max(1)
+    }
+
+    /// Updates the ratelimiter to the target rate for the elapsed time.
+    pub fn update(&mut self, elapsed: Duration) {
+        let rate = self.rate(elapsed);
+        if rate != self.current {
+            self.current = rate;
+            self.ratelimiter.set_rate(rate);
+            REQUEST_RATELIMIT.set(rate as i64);
+        }
+    }
+}
+
+/// The outcome of running one window at a rate during the search
+#[derive(Serialize)]
+pub struct SearchStep {
+    pub rate: u64,
+    pub latency: u64,
+    pub achieved: f64,
+    pub pass: bool,
+}
+
+/// Bisects the request rate between the lowest and highest rates, one window
+/// per rate, to find the highest rate which meets the latency target.
+pub struct RateSearch {
+    ratelimiter: Arc<Ratelimiter>,
+    percentile: f64,
+    target: u64,
+    // the highest rate known to pass and the lowest rate known to fail
+    lower: u64,
+    upper: u64,
+    // the lowest rate is only known to pass once some window passed
+    passed: bool,
+    precision: u64,
+    current: u64,
+}
+
+impl RateSearch {
+    /// Returns a search if one is configured. The first window runs at the
+    /// request ratelimit.
+    pub fn new(config: &Config, ratelimiter: Option<Arc<Ratelimiter>>) -> Option<Self> {
+        let search = config.search()?;
+        let ratelimiter = ratelimiter?;
+        let lower = search.min_rate() as u64;
+        let upper = search.max_rate() as u64 + 1;
+        let current = (config.request().ratelimit()? as u64).clamp(lower, upper - 1);
+        ratelimiter.set_rate(current);
+        REQUEST_RATELIMIT.set(current as i64);
+        Some(Self {
+            ratelimiter,
+            percentile: search.percentile(),
+            target: search.latency(),
+            lower,
+            upper,
+            passed: false,
+            precision: search.precision() as u64,
+            current,
+        })
+    }
+
+    /// The percentile the latency target applies to
+    pub fn percentile(&self) -> f64 {
+        self.percentile
+    }
+
+    /// The highest acceptable latency, in microseconds
+    pub fn target(&self) -> u64 {
+        self.target
+    }
+
+    /// The highest rate which has met the target so far, if any has
+    pub fn sustainable(&self) -> Option<u64> {
+        if self.passed {
+            Some(self.lower)
+        } else {
+            None
+        }
+    }
+
+    /// Returns true once the sustainable rate is known within the precision
+    pub fn is_complete(&self) -> bool {
+        self.upper - self.lower <= self.precision
+    }
+
+    /// Record the latency and the response rate of the window which ran at
+    /// the current rate, and move to the next rate. The window passes when
+    /// the latency meets the target and the client kept up with the rate.
+    pub fn update(&mut self, latency: u64, achieved: f64) -> SearchStep {
+        let rate = self.current;
+        let pass = latency <= self.target && achieved >= 0.95 * rate as f64;
+        if pass {
+            self.lower = self.lower.max(rate);
+            self.passed = true;
+        } else {
+            self.upper = self.upper.min(rate);
+        }
+        if !self.is_complete() {
+            self.current = self.lower + (self.upper - self.lower) / 2;
+            self.ratelimiter.set_rate(self.current);
+            REQUEST_RATELIMIT.set(self.current as i64);
+        }
+        SearchStep {
+            rate,
+            latency,
+            achieved,
+            pass,
+        }
+    }
+}
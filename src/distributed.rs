@@ -0,0 +1,389 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Runs spread across several rpc-perf instances, for more load than one host
+//! can generate. Agents register with the controller's admin address, wait
+//! until every agent has joined, and then send the JSON snapshot of each
+//! window to the controller, which merges them into a single report.
+
+use crate::Config;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tiny_http::{Method, Request, Response};
+
+/// How long an agent keeps trying to reach the controller
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long an agent waits for the other agents to register
+const START_TIMEOUT: Duration = Duration::from_secs(600);
+
+const PERCENTILES: [(&str, f64); 7] = [
+    ("p25", 25.0),
+    ("p50", 50.0),
+    ("p75", 75.0),
+    ("p90", 90.0),
+    ("p99", 99.0),
+    ("p999", 99.9),
+    ("p9999", 99.99),
+];
+
+/// The share of the run assigned to an agent when it registers
+#[derive(Serialize, Deserialize)]
+pub struct Assignment {
+    pub agent: usize,
+    pub agents: usize,
+    /// the agent's share of the controller's request ratelimit
+    pub ratelimit: Option<u64>,
+}
+
+/// Register with the controller, retrying until it can be reached.
+pub fn register(controller: &str) -> Result<Assignment, Error> {
+    let deadline = Instant::now() + REGISTER_TIMEOUT;
+    loop {
+        match http(controller, "POST", "/distributed/register", "") {
+            Ok((200, body)) => {
+                return serde_json::from_str(&body)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e));
+            }
+            Ok((status, _)) => {
+                return Err(Error::other(format!(
+                    "controller rejected registration with status: {}",
+                    status
+                )));
+            }
+            Err(e) if Instant::now() >= deadline => return Err(e),
+            Err(_) => std::thread::sleep(Duration::from_secs(1)),
+        }
+    }
+}
+
+/// Block until every agent has registered with the controller, failing if
+/// they haven't all registered before the start timeout.
+pub fn wait_for_start(controller: &str) -> Result<(), Error> {
+    let deadline = Instant::now() + START_TIMEOUT;
+    loop {
+        let (status, _) = http(controller, "GET", "/distributed/start", "")?;
+        if status == 200 {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                "timed out waiting for the other agents to register",
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Sends an agent's reports to the controller from a thread of its own, so
+/// that a slow or unreachable controller doesn't hold up the windows
+pub struct Reporter {
+    sender: Sender<String>,
+    thread: JoinHandle<()>,
+}
+
+impl Reporter {
+    pub fn new(controller: String) -> Self {
+        let (sender, receiver) = channel::<String>();
+        let thread = std::thread::spawn(move || {
+            for json in receiver {
+                if let Err(e) = report(&controller, &json) {
+                    error!("failed to report window to controller: {}", e);
+                }
+            }
+        });
+        Self { sender, thread }
+    }
+
+    /// Queue the JSON snapshot for a window to be sent
+    pub fn report(&self, json: String) {
+        let _ = self.sender.send(json);
+    }
+
+    /// Wait for the queued reports to be sent
+    pub fn finish(self) {
+        drop(self.sender);
+        let _ = self.thread.join();
+    }
+}
+
+/// Send the JSON snapshot for a window to the controller
+fn report(controller: &str, json: &str) -> Result<(), Error> {
+    match http(controller, "POST", "/distributed/report", json)? {
+        (200, _) => Ok(()),
+        (status, _) => Err(Error::other(format!(
+            "controller rejected report with status: {}",
+            status
+        ))),
+    }
+}
+
+/// Make a single request to the controller, returning the status and body
+fn http(addr: &str, method: &str, path: &str, body: &str) -> Result<(u16, String), Error> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    write!(
+        stream,
+        "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "bad response from controller"))?;
+    let body = response
+        .split_once("\r\n\r\n")
+        .map(|(_, body)| body.to_string())
+        .unwrap_or_default();
+    Ok((status, body))
+}
+
+/// Hands out agent assignments and collects their reports for the controller
+pub struct Coordinator {
+    agents: usize,
+    ratelimit: Option<u64>,
+    registered: usize,
+    // reports for each window which has not been merged, with the time the
+    // first report for the window arrived
+    pending: BTreeMap<u64, (Instant, Vec<JsonValue>)>,
+    // the windows which were merged, later reports for them are dropped
+    complete: BTreeSet<u64>,
+}
+
+impl Coordinator {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            agents: config.distributed().agents(),
+            ratelimit: config.request().ratelimit().map(|r| r as u64),
+            registered: 0,
+            pending: BTreeMap::new(),
+            complete: BTreeSet::new(),
+        }
+    }
+
+    /// The number of agents taking part in the run
+    pub fn agents(&self) -> usize {
+        self.agents
+    }
+
+    /// Handle a request on the control plane
+    pub fn handle(&mut self, mut request: Request) {
+        let url = request.url().split('?').next().unwrap_or("").to_string();
+        let response = match (request.method(), url.as_str()) {
+            (Method::Post, "/distributed/register") => {
+                if self.registered < self.agents {
+                    let assignment = Assignment {
+                        agent: self.registered,
+                        agents: self.agents,
+                        ratelimit: self
+                            .ratelimit
+                            .map(|r| share(r, self.registered, self.agents)),
+                    };
+                    self.registered += 1;
+                    info!("agent {} of {} registered", self.registered, self.agents);
+                    Response::from_string(serde_json::to_string(&assignment).unwrap())
+                } else {
+                    Response::from_string("").with_status_code(409)
+                }
+            }
+            (Method::Get, "/distributed/start") => {
+                let status = if self.registered == self.agents {
+                    200
+                } else {
+                    503
+                };
+                Response::from_string("").with_status_code(status)
+            }
+            (Method::Post, "/distributed/report") => {
+                let mut content = String::new();
+                let report = request
+                    .as_reader()
+                    .read_to_string(&mut content)
+                    .ok()
+                    .and_then(|_| serde_json::from_str::<JsonValue>(&content).ok());
+                match report.as_ref().and_then(|r| r["window"].as_u64()) {
+                    Some(window) if self.complete.contains(&window) => {
+                        // the window was reported without this agent
+                        warn!("dropped a late report for window {}", window);
+                        Response::from_string("")
+                    }
+                    Some(window) => {
+                        self.pending
+                            .entry(window)
+                            .or_insert_with(|| (Instant::now(), Vec::new()))
+                            .1
+                            .push(report.unwrap());
+                        Response::from_string("")
+                    }
+                    None => Response::from_string("").with_status_code(400),
+                }
+            }
+            (method, url) => {
+                debug!("{} on non-existent url: {}", method, url);
+                Response::from_string("").with_status_code(404)
+            }
+        };
+        let _ = request.respond(response);
+    }
+
+    /// Merge the windows which every agent has reported, or which have waited
+    /// longer than the timeout for the remaining agents.
+    pub fn merged(&mut self, timeout: Duration) -> Vec<Merged> {
+        let ready: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, (first, reports))| {
+                reports.len() >= self.agents || first.elapsed() >= timeout
+            })
+            .map(|(window, _)| *window)
+            .collect();
+        self.complete.extend(ready.iter().copied());
+        ready
+            .into_iter()
+            .filter_map(|window| self.pending.remove(&window))
+            .map(|(_, reports)| Merged::new(&reports))
+            .collect()
+    }
+}
+
+/// An agent's share of the ratelimit. The remainder is spread over the first
+/// agents, so that the shares add up to the ratelimit.
+fn share(ratelimit: u64, agent: usize, agents: usize) -> u64 {
+    let agents = agents as u64;
+    let remainder = ((agent as u64) < ratelimit % agents) as u64;
+    (ratelimit / agents + remainder).max(1)
+}
+
+#[derive(Serialize)]
+pub struct Bucket {
+    value: u64,
+    count: u64,
+}
+
+/// The combined results of all agents for one window
+#[derive(Serialize)]
+pub struct Merged {
+    pub window: u64,
+    pub agents: usize,
+    pub request_count: u64,
+    pub request_errors: u64,
+    pub response_count: u64,
+    pub response_errors: u64,
+    pub connect_count: u64,
+    pub connect_errors: u64,
+    pub get_count: u64,
+    pub hit_count: u64,
+    pub request_rate: f64,
+    pub response_rate: f64,
+    pub request_latency: BTreeMap<&'static str, u64>,
+    pub request: Vec<Bucket>,
+}
+
+impl Merged {
+    /// Combine the agents' snapshots. Counts and rates are summed and the
+    /// latency percentiles are calculated from the combined buckets.
+    fn new(reports: &[JsonValue]) -> Self {
+        let sum = |field: &str| -> u64 { reports.iter().filter_map(|r| r[field].as_u64()).sum() };
+        let sum_f64 =
+            |field: &str| -> f64 { reports.iter().filter_map(|r| r[field].as_f64()).sum() };
+
+        let mut buckets: BTreeMap<u64, u64> = BTreeMap::new();
+        for report in reports {
+            for bucket in report["request"].as_array().into_iter().flatten() {
+                if let (Some(value), Some(count)) =
+                    (bucket["value"].as_u64(), bucket["count"].as_u64())
+                {
+                    *buckets.entry(value).or_insert(0) += count;
+                }
+            }
+        }
+        let total: u64 = buckets.values().sum();
+        let request_latency = PERCENTILES
+            .iter()
+            .map(|(label, percentile)| {
+                let needed = ((total as f64) * percentile / 100.0).ceil() as u64;
+                let mut seen = 0;
+                let value = buckets
+                    .iter()
+                    .find(|(_, count)| {
+                        seen += *count;
+                        seen >= needed.max(1)
+                    })
+                    .map(|(value, _)| *value)
+                    .unwrap_or(0);
+                (*label, value)
+            })
+            .collect();
+
+        Self {
+            window: reports
+                .first()
+                .and_then(|r| r["window"].as_u64())
+                .unwrap_or(0),
+            agents: reports.len(),
+            request_count: sum("request_count"),
+            request_errors: sum("request_errors"),
+            response_count: sum("response_count"),
+            response_errors: sum("response_errors"),
+            connect_count: sum("connect_count"),
+            connect_errors: sum("connect_errors"),
+            get_count: sum("get_count"),
+            hit_count: sum("hit_count"),
+            request_rate: sum_f64("request_rate"),
+            response_rate: sum_f64("response_rate"),
+            request_latency,
+            request: buckets
+                .into_iter()
+                .map(|(value, count)| Bucket { value, count })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge() {
+        let reports: Vec<JsonValue> = [
+            r#"{"window": 3, "request_count": 10, "request_rate": 10.0, "request": [{"value": 10, "count": 9}, {"value": 100, "count": 1}]}"#,
+            r#"{"window": 3, "request_count": 5, "request_rate": 5.0, "request": [{"value": 10, "count": 5}, {"value": 1000, "count": 5}]}"#,
+        ]
+        .iter()
+        .map(|r| serde_json::from_str(r).unwrap())
+        .collect();
+        let merged = Merged::new(&reports);
+        assert_eq!(merged.window, 3);
+        assert_eq!(merged.agents, 2);
+        assert_eq!(merged.request_count, 15);
+        assert_eq!(merged.request_rate, 15.0);
+        assert_eq!(merged.request_latency["p50"], 10);
+        assert_eq!(merged.request_latency["p75"], 100);
+        assert_eq!(merged.request_latency["p90"], 1000);
+        assert_eq!(merged.request.len(), 3);
+    }
+
+    #[test]
+    fn ratelimit_share() {
+        let shares: Vec<u64> = (0..4).map(|agent| share(1003, agent, 4)).collect();
+        assert_eq!(shares, vec![251, 251, 251, 250]);
+        assert_eq!(shares.iter().sum::<u64>(), 1003);
+        assert_eq!(share(2, 3, 4), 1);
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Checks a config without sending any load, and prints the workload it
+//! describes so that the mix of keyspaces and commands can be reviewed before
+//! a run.
+
+use crate::config_file::FieldType;
+use crate::Config;
+use ringlog::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Writes each message to stderr as it is logged and counts the errors. The
+/// config exits on a fatal error, which would lose a buffered message.
+struct StderrLog {
+    errors: AtomicUsize,
+}
+
+impl Log for StderrLog {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() == Level::Error {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        eprintln!(
+            "{}: {}",
+            record.level().to_string().to_lowercase(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+static LOG: StderrLog = StderrLog {
+    errors: AtomicUsize::new(0),
+};
+
+/// Parse and check the config, then print the workload it describes. Returns
+/// an error if any problem was found. Fatal problems exit the process.
+pub fn validate(file: Option<&str>) -> Result<(), String> {
+    let _ = ringlog::set_logger(&LOG);
+    ringlog::set_max_level(LevelFilter::Warn);
+
+    let config = Config::new(file);
+    let general = config.general();
+    let endpoints = config.endpoints();
+    let poolsize = config.connection().poolsize();
+
+    println!("protocol: {:?}", general.protocol());
+    for endpoint in &endpoints {
+        println!("endpoint: {}", endpoint);
+    }
+    println!(
+        "connections: {} ({} threads with {} to each endpoint)",
+        general.threads() * poolsize * endpoints.len(),
+        general.threads(),
+        poolsize
+    );
+    if general.generator_threads() > 0 {
+        println!(
+            "generators: {} threads scheduling requests",
+            general.generator_threads()
+        );
+    }
+    if config.connection().pipeline() > 1 {
+        println!("pipeline: {} requests", config.connection().pipeline());
+    }
+    let ratelimit = config.request().ratelimit();
+    match ratelimit {
+        Some(ratelimit) => println!("ratelimit: {} rps", ratelimit),
+        None => println!("ratelimit: none"),
+    }
+
+    println!(
+        "{:<9} {:<12} {:>8} {:>12} {:>12} {:>7} Distribution",
+        "Keyspace", "Command", "Share", "Rate", "Keys", "Length"
+    );
+    let keyspace_weight: usize = config.keyspaces().iter().map(|k| k.weight()).sum();
+    for keyspace in config.keyspaces() {
+        let command_weight: usize = keyspace.commands().iter().map(|c| c.weight()).sum();
+        let (keys, length) = if keyspace.has_key_template() {
+            ("template".to_string(), "-".to_string())
+        } else if keyspace.key_type() == FieldType::Uuid {
+            (keyspace.cardinality().to_string(), "36".to_string())
+        } else {
+            (
+                keyspace.cardinality().to_string(),
+                keyspace.length().to_string(),
+            )
+        };
+        let distribution = match keyspace.zipf_exponent() {
+            Some(exponent) => format!("zipf {}", exponent),
+            None => "uniform".to_string(),
+        };
+        for command in keyspace.commands() {
+            let share = keyspace.weight() as f64 / keyspace_weight as f64 * command.weight() as f64
+                / command_weight as f64;
+            let rate = ratelimit
+                .map(|ratelimit| format!("{:.2}", share * ratelimit as f64))
+                .unwrap_or_else(|| "-".to_string());
+            println!(
+                "{:<9} {:<12} {:>7.2}% {:>12} {:>12} {:>7} {}",
+                keyspace.index(),
+                <&str>::from(command.verb()),
+                share * 100.0,
+                rate,
+                keys,
+                length,
+                distribution
+            );
+        }
+    }
+    println!("Share is of all requests, and rate is in requests per second");
+
+    match LOG.errors.load(Ordering::Relaxed) {
+        0 => Ok(()),
+        errors => Err(format!("found {} errors in the config", errors)),
+    }
+}
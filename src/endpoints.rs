@@ -0,0 +1,155 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+use crate::time::{Duration, Instant};
+use core::sync::atomic::{AtomicU64, Ordering};
+use serde_derive::Serialize;
+
+/// Counts the connects, requests, and responses for each endpoint, with their
+/// errors, so that a bad replica stands out when there are several endpoints.
+pub struct EndpointStats {
+    endpoints: Vec<(String, Counters)>,
+}
+
+#[derive(Default)]
+struct Counters {
+    connect: AtomicU64,
+    connect_ex: AtomicU64,
+    request: AtomicU64,
+    request_ex: AtomicU64,
+    response: AtomicU64,
+    response_ex: AtomicU64,
+}
+
+/// The counters for one endpoint at a point in time
+#[derive(Serialize, Clone, Copy, Default)]
+pub struct EndpointCounts {
+    pub connect: u64,
+    pub connect_ex: u64,
+    pub request: u64,
+    pub request_ex: u64,
+    pub response: u64,
+    pub response_ex: u64,
+}
+
+impl EndpointCounts {
+    /// The change in each counter since the earlier counts
+    pub fn delta(&self, earlier: &Self) -> Self {
+        Self {
+            connect: self.connect - earlier.connect,
+            connect_ex: self.connect_ex - earlier.connect_ex,
+            request: self.request - earlier.request,
+            request_ex: self.request_ex - earlier.request_ex,
+            response: self.response - earlier.response,
+            response_ex: self.response_ex - earlier.response_ex,
+        }
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.connect_ex + self.request_ex + self.response_ex
+    }
+}
+
+impl EndpointStats {
+    pub fn new(endpoints: Vec<String>) -> Self {
+        Self {
+            endpoints: endpoints
+                .into_iter()
+                .map(|endpoint| (endpoint, Counters::default()))
+                .collect(),
+        }
+    }
+
+    pub fn connect(&self, endpoint: usize) {
+        self.add(endpoint, |c| &c.connect, 1);
+    }
+
+    pub fn connect_ex(&self, endpoint: usize) {
+        self.add(endpoint, |c| &c.connect_ex, 1);
+    }
+
+    pub fn request(&self, endpoint: usize, count: usize) {
+        self.add(endpoint, |c| &c.request, count as u64);
+    }
+
+    pub fn request_ex(&self, endpoint: usize) {
+        self.add(endpoint, |c| &c.request_ex, 1);
+    }
+
+    pub fn response(&self, endpoint: usize) {
+        self.add(endpoint, |c| &c.response, 1);
+    }
+
+    /// A response which could not be parsed
+    pub fn response_ex(&self, endpoint: usize) {
+        self.add(endpoint, |c| &c.response_ex, 1);
+    }
+
+    fn add(&self, endpoint: usize, counter: fn(&Counters) -> &AtomicU64, value: u64) {
+        if let Some((_, counters)) = self.endpoints.get(endpoint) {
+            counter(counters).fetch_add(value, Ordering::Relaxed);
+        }
+    }
+
+    /// The current counts for each endpoint, in the order of the config
+    pub fn counts(&self) -> Vec<(String, EndpointCounts)> {
+        self.endpoints
+            .iter()
+            .map(|(endpoint, c)| {
+                (
+                    endpoint.clone(),
+                    EndpointCounts {
+                        connect: c.connect.load(Ordering::Relaxed),
+                        connect_ex: c.connect_ex.load(Ordering::Relaxed),
+                        request: c.request.load(Ordering::Relaxed),
+                        request_ex: c.request_ex.load(Ordering::Relaxed),
+                        response: c.response.load(Ordering::Relaxed),
+                        response_ex: c.response_ex.load(Ordering::Relaxed),
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+/// Tracks the progress of a cutover from the target endpoints to the cutover
+/// endpoints, which follow them in the list of endpoints.
+pub struct Cutover {
+    first: usize,
+    start: Instant,
+    duration: Duration,
+}
+
+impl Cutover {
+    /// A cutover to the endpoints from `first` onwards, which begins after the
+    /// delay and takes the duration to complete
+    pub fn new(first: usize, delay: Duration, duration: Duration) -> Self {
+        Self {
+            first,
+            start: Instant::now() + delay,
+            duration,
+        }
+    }
+
+    /// The index of the first cutover endpoint
+    pub fn first(&self) -> usize {
+        self.first
+    }
+
+    /// The share of the requests which should go to the cutover endpoints, as
+    /// a fraction
+    pub fn share(&self) -> f64 {
+        let now = Instant::now();
+        if now < self.start {
+            return 0.0;
+        }
+        let elapsed = (now - self.start).as_nanos() as f64;
+        let duration = self.duration.as_nanos() as f64;
+        if elapsed >= duration {
+            1.0
+        } else {
+            elapsed / duration
+        }
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Schedules open-loop requests on threads apart from the workers. Each
+//! generator takes credits from the request ratelimit and queues the time
+//! each request is due, and the workers, which drive the connections, take
+//! the requests from the queue. The ratelimit is then taken by the generators
+//! alone, so the two can be sized separately for the host.
+
+use crate::metrics::*;
+use crate::prefill::Prefill;
+use crate::time::Instant;
+use mpmc::Queue;
+use ratelimit::Ratelimiter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The number of requests which may wait in the schedule for a worker
+pub const SCHEDULE_CAPACITY: usize = 65536;
+
+/// How long a generator sleeps before retrying a full schedule
+const FULL_BACKOFF: Duration = Duration::from_micros(100);
+
+pub struct Generator {
+    ratelimiter: Arc<Ratelimiter>,
+    schedule: Queue<Instant>,
+    prefill: Option<Arc<Prefill>>,
+    running: Arc<AtomicBool>,
+}
+
+impl Generator {
+    pub fn new(
+        ratelimiter: Arc<Ratelimiter>,
+        schedule: Queue<Instant>,
+        prefill: Option<Arc<Prefill>>,
+        running: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            ratelimiter,
+            schedule,
+            prefill,
+            running,
+        }
+    }
+
+    /// Queue requests on the ratelimit's schedule until the run ends.
+    /// Requests are due when their credit is taken, so waiting for room in
+    /// the queue is reported as queue delay once they are sent. A full queue
+    /// is retried after a short sleep rather than spinning, since the workers
+    /// need the cores to drain it.
+    pub fn run(&self) {
+        // the workers prefill before they take any requests
+        while self.running()
+            && self
+                .prefill
+                .as_ref()
+                .is_some_and(|prefill| !prefill.is_complete())
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        while self.running() {
+            // a credit which is already waiting was due before this generator
+            // got to it, so the generators are falling behind the ratelimit
+            if self.ratelimiter.try_wait().is_ok() {
+                REQUEST_GENERATOR_BEHIND.increment();
+            } else {
+                self.ratelimiter.wait();
+            }
+            let mut due = Instant::now();
+            if let Err(d) = self.schedule.push(due) {
+                REQUEST_QUEUE_FULL.increment();
+                let start = Instant::now();
+                due = d;
+                loop {
+                    if !self.running() {
+                        return;
+                    }
+                    std::thread::sleep(FULL_BACKOFF);
+                    match self.schedule.push(due) {
+                        Ok(()) => break,
+                        Err(d) => due = d,
+                    }
+                }
+                let waited = Instant::now() - start;
+                REQUEST_QUEUE_WAIT.add((waited.as_secs_f64() * 1_000_000.0) as u64);
+            }
+            REQUEST_BACKLOG.increment();
+        }
+    }
+
+    fn running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Network counters for the host running the load generator, read from
+//! `/proc`. These are sampled each window so that saturation of the host's
+//! network can be seen next to the latency results.
+
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Clone, Default)]
+pub struct HostStats {
+    pub interfaces: BTreeMap<String, Interface>,
+    /// segments retransmitted by TCP across all connections on the host
+    pub tcp_retransmits: Option<u64>,
+    /// the number of tracked connections, if connection tracking is enabled
+    pub conntrack: Option<u64>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug, Serialize)]
+pub struct Interface {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+}
+
+impl HostStats {
+    /// Read the current counters. Counters which are not available on this
+    /// host are left empty.
+    pub fn read() -> Self {
+        let interfaces = std::fs::read_to_string("/proc/net/dev")
+            .map(|dev| parse_net_dev(&dev))
+            .unwrap_or_default();
+        let tcp_retransmits = std::fs::read_to_string("/proc/net/snmp")
+            .ok()
+            .and_then(|snmp| parse_retransmits(&snmp));
+        let conntrack = std::fs::read_to_string("/proc/sys/net/netfilter/nf_conntrack_count")
+            .ok()
+            .and_then(|count| count.trim().parse().ok());
+        Self {
+            interfaces,
+            tcp_retransmits,
+            conntrack,
+        }
+    }
+
+    /// The change in each interface's counters since the previous sample
+    pub fn interface_deltas(&self, previous: &Self) -> BTreeMap<String, Interface> {
+        self.interfaces
+            .iter()
+            .map(|(name, current)| {
+                let previous = previous.interfaces.get(name).copied().unwrap_or_default();
+                let delta = Interface {
+                    rx_bytes: current.rx_bytes.wrapping_sub(previous.rx_bytes),
+                    rx_packets: current.rx_packets.wrapping_sub(previous.rx_packets),
+                    tx_bytes: current.tx_bytes.wrapping_sub(previous.tx_bytes),
+                    tx_packets: current.tx_packets.wrapping_sub(previous.tx_packets),
+                };
+                (name.clone(), delta)
+            })
+            .collect()
+    }
+
+    /// The change in TCP retransmits since the previous sample
+    pub fn tcp_retransmit_delta(&self, previous: &Self) -> Option<u64> {
+        Some(
+            self.tcp_retransmits?
+                .wrapping_sub(previous.tcp_retransmits?),
+        )
+    }
+}
+
+/// Parse the per-interface counters from the contents of `/proc/net/dev`
+fn parse_net_dev(dev: &str) -> BTreeMap<String, Interface> {
+    let mut interfaces = BTreeMap::new();
+    // the first two lines are headers
+    for line in dev.lines().skip(2) {
+        let (name, counters) = match line.split_once(':') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        let counters: Vec<u64> = counters
+            .split_whitespace()
+            .filter_map(|v| v.parse().ok())
+            .collect();
+        if counters.len() < 10 {
+            continue;
+        }
+        interfaces.insert(
+            name.trim().to_string(),
+            Interface {
+                rx_bytes: counters[0],
+                rx_packets: counters[1],
+                tx_bytes: counters[8],
+                tx_packets: counters[9],
+            },
+        );
+    }
+    interfaces
+}
+
+/// Parse the TCP retransmitted segments from the contents of `/proc/net/snmp`
+fn parse_retransmits(snmp: &str) -> Option<u64> {
+    let mut lines = snmp.lines().filter(|line| line.starts_with("Tcp:"));
+    let names = lines.next()?;
+    let values = lines.next()?;
+    names
+        .split_whitespace()
+        .zip(values.split_whitespace())
+        .find(|(name, _)| *name == "RetransSegs")
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let dev = "Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo: 1145  1951    0    0    0     0          0         0 1146  1952    0    0    0     0       0          0
+  eth0: 10 20 0 0 0 0 0 0 30 40 0 0 0 0 0 0
+";
+        let interfaces = parse_net_dev(dev);
+        assert_eq!(interfaces.len(), 2);
+        assert_eq!(
+            interfaces["eth0"],
+            Interface {
+                rx_bytes: 10,
+                rx_packets: 20,
+                tx_bytes: 30,
+                tx_packets: 40,
+            }
+        );
+
+        let snmp = "Ip: Forwarding DefaultTTL
+Ip: 1 64
+Tcp: RtoAlgorithm RtoMin RtoMax MaxConn ActiveOpens PassiveOpens AttemptFails EstabResets CurrEstab InSegs OutSegs RetransSegs InErrs OutRsts InCsumErrors
+Tcp: 1 200 120000 -1 366 356 7 100 3 195840 195760 42 0 103 0
+";
+        assert_eq!(parse_retransmits(snmp), Some(42));
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Estimates how concentrated the accesses of a zipf keyspace are. A cache
+//! which holds the most popular keys has a hit rate equal to the share of
+//! accesses which go to those keys, so these give the number of keys a cache
+//! needs for a hit rate, and the hit rate of a cache with a number of keys.
+//! Churn and the cost of admission are ignored, so real caches do worse.
+//!
+//! The keys a run actually requests can also be counted, to check that the
+//! skew of the generated keys matches the model.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The hit rates the hot set is reported for at startup
+pub const TARGETS: [f64; 4] = [0.5, 0.9, 0.95, 0.99];
+
+/// Terms of the sum which are added exactly, the rest are approximated
+const EXACT_TERMS: u64 = 1000;
+
+/// The generalized harmonic number, the sum of `k^-exponent` for `k` from one
+/// to `n`. The tail beyond the first terms is approximated by an integral,
+/// which is accurate to within a small fraction of a percent.
+fn harmonic(n: u64, exponent: f64) -> f64 {
+    let exact = n.min(EXACT_TERMS);
+    let mut sum: f64 = (1..=exact).map(|k| (k as f64).powf(-exponent)).sum();
+    if n > exact {
+        let (a, b) = (exact as f64 + 0.5, n as f64 + 0.5);
+        sum += if (exponent - 1.0).abs() < f64::EPSILON {
+            (b / a).ln()
+        } else {
+            (b.powf(1.0 - exponent) - a.powf(1.0 - exponent)) / (1.0 - exponent)
+        };
+    }
+    sum
+}
+
+/// The share of accesses which go to the most popular keys of a zipf
+/// keyspace with the cardinality, which is the hit rate of a cache holding
+/// those keys
+pub fn hit_rate(cardinality: u64, exponent: f64, keys: u64) -> f64 {
+    if keys >= cardinality {
+        return 1.0;
+    }
+    harmonic(keys, exponent) / harmonic(cardinality, exponent)
+}
+
+/// The fewest of the most popular keys which serve the share of accesses
+pub fn hot_set(cardinality: u64, exponent: f64, hit_rate: f64) -> u64 {
+    let total = harmonic(cardinality, exponent);
+    let (mut low, mut high) = (0, cardinality);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if harmonic(mid, exponent) / total >= hit_rate {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    low
+}
+
+/// The share of accesses which go to the key with the index, where the most
+/// popular key has an index of one
+pub fn share(cardinality: u64, exponent: f64, index: u64) -> f64 {
+    if index == 0 || index > cardinality {
+        return 0.0;
+    }
+    (index as f64).powf(-exponent) / harmonic(cardinality, exponent)
+}
+
+/// Counts the requests for the most popular keys of a zipf keyspace. Only the
+/// keys the model expects to be most popular are counted, which are several
+/// times more than are reported so that a misplaced skew still shows.
+pub struct HotKeys {
+    report: usize,
+    counts: Vec<AtomicU64>,
+    total: AtomicU64,
+}
+
+impl HotKeys {
+    pub fn new(report: usize, cardinality: u64) -> Self {
+        // zipf key indices start from one
+        let tracked = (report.saturating_mul(4).max(1000) as u64).min(cardinality + 1);
+        Self {
+            report,
+            counts: (0..tracked).map(|_| AtomicU64::new(0)).collect(),
+            total: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, index: usize) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        if let Some(count) = self.counts.get(index) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The number of keys requested
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// The index and count of the most requested keys, most requested first
+    pub fn top(&self) -> Vec<(usize, u64)> {
+        let mut counts: Vec<(usize, u64)> = self
+            .counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .enumerate()
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts.truncate(self.report);
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates() {
+        // exact for small keyspaces, where every term is summed
+        assert!((hit_rate(4, 1.0, 1) - 12.0 / 25.0).abs() < 1e-9);
+        assert_eq!(hot_set(4, 1.0, 0.47), 1);
+        assert_eq!(hot_set(4, 1.0, 0.49), 2);
+        // uniform accesses need a share of the keys equal to the hit rate
+        assert_eq!(hot_set(1_000_000, 0.0, 0.5), 500_000);
+
+        let rate = hit_rate(1_000_000, 0.99, 10_000);
+        let sum = |keys: std::ops::RangeInclusive<u64>| -> f64 {
+            keys.map(|k| (k as f64).powf(-0.99)).sum()
+        };
+        let exact = sum(1..=10_000) / sum(1..=1_000_000);
+        assert!((rate - exact).abs() < 1e-4);
+    }
+
+    #[test]
+    fn hot_keys() {
+        let hot_keys = HotKeys::new(2, 10);
+        // keys beyond those tracked are only counted in the total
+        for index in [1, 1, 1, 2, 2, 3, 50] {
+            hot_keys.record(index);
+        }
+        assert_eq!(hot_keys.total(), 7);
+        assert_eq!(hot_keys.top(), vec![(1, 3), (2, 2)]);
+
+        let total: f64 = (0..=11).map(|index| share(10, 1.0, index)).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Records the distribution of latencies and other values with the backend
+//! chosen by the metrics config. A heatmap slides over the span its
+//! percentiles cover, while the other backends hold everything since they
+//! were last cleared, so the admin clears them as each span starts over.
+
+use crate::config_file::LatencyBackend;
+use crate::*;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use heatmap::{Error, Heatmap};
+use histogram::Histogram;
+use std::sync::Mutex;
+
+/// The number of centroids a t-digest is compressed towards. More centroids
+/// give more accurate percentiles for more memory.
+const COMPRESSION: f64 = 100.0;
+
+/// Values are buffered and merged into the centroids once this many arrive
+const DIGEST_BUFFER: usize = 1024;
+
+static THREADS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // picks the digest each thread records into
+    static THREAD: usize = THREADS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The range of values a percentile falls in
+#[derive(Clone, Copy)]
+pub struct Bucket {
+    low: u64,
+    high: u64,
+}
+
+impl Bucket {
+    pub fn low(&self) -> u64 {
+        self.low
+    }
+
+    pub fn high(&self) -> u64 {
+        self.high
+    }
+}
+
+impl From<histogram::Bucket> for Bucket {
+    fn from(other: histogram::Bucket) -> Self {
+        Self {
+            low: other.low(),
+            high: other.high(),
+        }
+    }
+}
+
+pub enum Latency {
+    Heatmap(Heatmap),
+    Histogram(Histogram),
+    Digest(Digests),
+}
+
+impl Latency {
+    /// Values up to `2^max_power` are recorded. Heatmaps cover the `span` with
+    /// slices of the `resolution`, the other backends ignore both.
+    pub fn new(
+        backend: LatencyBackend,
+        max_power: u32,
+        span: Duration,
+        resolution: Duration,
+    ) -> Self {
+        match backend {
+            LatencyBackend::Heatmap => {
+                Self::Heatmap(Heatmap::new(0, 10, max_power, span, resolution).unwrap())
+            }
+            LatencyBackend::Histogram => Self::Histogram(Histogram::new(0, 10, max_power).unwrap()),
+            LatencyBackend::Tdigest => Self::Digest(Digests::new()),
+        }
+    }
+
+    pub fn increment(&self, time: Instant, value: u64, count: u32) {
+        match self {
+            Self::Heatmap(heatmap) => heatmap.increment(time, value, count),
+            Self::Histogram(histogram) => {
+                let _ = histogram.increment(value, count);
+            }
+            Self::Digest(digests) => digests.insert(value, count),
+        }
+    }
+
+    /// The range of values the percentile (0.0 - 100.0) falls in. A t-digest
+    /// estimates the value, so its range is a single value.
+    pub fn percentile(&self, percentile: f64) -> Result<Bucket, Error> {
+        match self {
+            Self::Heatmap(heatmap) => heatmap.percentile(percentile).map(Bucket::from),
+            Self::Histogram(histogram) => histogram
+                .percentile(percentile)
+                .map(Bucket::from)
+                .map_err(Error::from),
+            Self::Digest(digests) => digests.merged().percentile(percentile),
+        }
+    }
+
+    /// The highest value and the count of each bucket which holds values. A
+    /// t-digest has the mean and weight of each centroid instead.
+    pub fn buckets(&self) -> Vec<(u64, u32)> {
+        let histogram = match self {
+            Self::Heatmap(heatmap) => heatmap.summary(),
+            Self::Histogram(histogram) => histogram,
+            Self::Digest(digests) => {
+                let mut digest = digests.merged();
+                digest.merge();
+                return digest
+                    .centroids
+                    .iter()
+                    .map(|c| (c.mean.round() as u64, c.weight.min(u32::MAX as u64) as u32))
+                    .collect();
+            }
+        };
+        histogram
+            .into_iter()
+            .filter(|bucket| bucket.count() != 0)
+            .map(|bucket| (bucket.high(), bucket.count()))
+            .collect()
+    }
+
+    /// Start over for a new span. Heatmaps age out old values themselves, so
+    /// they are left alone.
+    pub fn clear(&self) {
+        match self {
+            Self::Heatmap(_) => {}
+            Self::Histogram(histogram) => histogram.clear(),
+            Self::Digest(digests) => digests.clear(),
+        }
+    }
+}
+
+impl From<Heatmap> for Latency {
+    fn from(heatmap: Heatmap) -> Self {
+        Self::Heatmap(heatmap)
+    }
+}
+
+/// A t-digest for each thread, so that threads record without contending for
+/// a lock. They are merged when read by the admin.
+pub struct Digests {
+    digests: Box<[Mutex<Digest>]>,
+}
+
+impl Digests {
+    fn new() -> Self {
+        let threads = std::thread::available_parallelism()
+            .map(|threads| threads.get())
+            .unwrap_or(1);
+        Self {
+            digests: (0..threads)
+                .map(|_| Mutex::new(Digest::default()))
+                .collect(),
+        }
+    }
+
+    fn insert(&self, value: u64, count: u32) {
+        let digest = THREAD.with(|thread| *thread) % self.digests.len();
+        self.digests[digest].lock().unwrap().insert(value, count);
+    }
+
+    /// A digest of the values recorded by every thread
+    fn merged(&self) -> Digest {
+        let mut merged = Digest::default();
+        for digest in self.digests.iter() {
+            merged.absorb(&digest.lock().unwrap());
+        }
+        merged
+    }
+
+    fn clear(&self) {
+        for digest in self.digests.iter() {
+            *digest.lock().unwrap() = Digest::default();
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Centroid {
+    mean: f64,
+    weight: u64,
+}
+
+/// A merging t-digest. The centroids at the tails of the distribution are
+/// kept small, so the extreme percentiles are the most accurate.
+#[derive(Default)]
+struct Digest {
+    centroids: Vec<Centroid>,
+    buffer: Vec<Centroid>,
+    count: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Digest {
+    fn insert(&mut self, value: u64, count: u32) {
+        if count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.count += count as u64;
+        self.buffer.push(Centroid {
+            mean: value as f64,
+            weight: count as u64,
+        });
+        if self.buffer.len() >= DIGEST_BUFFER {
+            self.merge();
+        }
+    }
+
+    /// Buffer the centroids and values of another digest, to be merged with
+    /// those of this one
+    fn absorb(&mut self, other: &Digest) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            self.min = other.min;
+            self.max = other.max;
+        } else {
+            self.min = self.min.min(other.min);
+            self.max = self.max.max(other.max);
+        }
+        self.count += other.count;
+        self.buffer.extend_from_slice(&other.centroids);
+        self.buffer.extend_from_slice(&other.buffer);
+    }
+
+    /// Merge the buffered values into the centroids. Neighbors are combined
+    /// while the combined centroid stays within the size allowed at its
+    /// quantile.
+    fn merge(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut centroids = std::mem::take(&mut self.centroids);
+        centroids.append(&mut self.buffer);
+        centroids.sort_by(|a, b| a.mean.total_cmp(&b.mean));
+
+        let total = self.count as f64;
+        let mut merged = Vec::new();
+        let mut before = 0.0;
+        let mut centroids = centroids.into_iter();
+        let mut current = centroids.next().unwrap();
+        for next in centroids {
+            let weight = (current.weight + next.weight) as f64;
+            let quantile = (before + weight / 2.0) / total;
+            if weight <= 4.0 * total * quantile * (1.0 - quantile) / COMPRESSION {
+                current.mean += (next.mean - current.mean) * next.weight as f64 / weight;
+                current.weight += next.weight;
+            } else {
+                before += current.weight as f64;
+                merged.push(current);
+                current = next;
+            }
+        }
+        merged.push(current);
+        self.centroids = merged;
+    }
+
+    /// Estimate the percentile by interpolating between the centers of the
+    /// centroids either side of its rank
+    fn percentile(&mut self, percentile: f64) -> Result<Bucket, Error> {
+        if !(0.0..=100.0).contains(&percentile) {
+            return Err(Error::InvalidPercentile);
+        }
+        if self.count == 0 {
+            return Err(Error::Empty);
+        }
+        self.merge();
+
+        let rank = percentile / 100.0 * self.count as f64;
+        let mut previous = (0.0, self.min as f64);
+        let mut before = 0.0;
+        let mut estimate = None;
+        for centroid in &self.centroids {
+            let center = before + centroid.weight as f64 / 2.0;
+            if rank < center {
+                estimate = Some(interpolate(previous, (center, centroid.mean), rank));
+                break;
+            }
+            previous = (center, centroid.mean);
+            before += centroid.weight as f64;
+        }
+        let estimate = estimate
+            .unwrap_or_else(|| interpolate(previous, (self.count as f64, self.max as f64), rank));
+        let value = (estimate.round() as u64).clamp(self.min, self.max);
+        Ok(Bucket {
+            low: value,
+            high: value,
+        })
+    }
+}
+
+/// The value at the rank on the line between two (rank, value) points
+fn interpolate(low: (f64, f64), high: (f64, f64), rank: f64) -> f64 {
+    if high.0 <= low.0 {
+        return high.1;
+    }
+    low.1 + (high.1 - low.1) * (rank - low.0) / (high.0 - low.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::seq::SliceRandom;
+    use rand::SeedableRng;
+
+    #[test]
+    fn digest() {
+        let mut digest = Digest::default();
+        assert!(digest.percentile(50.0).is_err());
+
+        // shuffled so the merges see values from across the range
+        let mut values: Vec<u64> = (1..=100_000).collect();
+        values.shuffle(&mut SmallRng::seed_from_u64(0));
+        for value in values {
+            digest.insert(value, 1);
+        }
+        digest.merge();
+        assert!(digest.centroids.len() < 1000);
+
+        let estimate =
+            |digest: &mut Digest, percentile| digest.percentile(percentile).unwrap().high() as f64;
+        assert!((estimate(&mut digest, 50.0) - 50_000.0).abs() < 500.0);
+        assert!((estimate(&mut digest, 99.0) - 99_000.0).abs() < 100.0);
+        assert!((estimate(&mut digest, 99.9) - 99_900.0).abs() < 20.0);
+        assert_eq!(estimate(&mut digest, 100.0), 100_000.0);
+        assert_eq!(estimate(&mut digest, 0.0), 1.0);
+
+        // digests recorded by separate threads merge into one
+        let mut low = Digest::default();
+        let mut high = Digest::default();
+        for value in 1..=50_000 {
+            low.insert(value, 1);
+            high.insert(value + 50_000, 1);
+        }
+        let mut merged = Digest::default();
+        merged.absorb(&low);
+        merged.absorb(&high);
+        assert_eq!(merged.count, 100_000);
+        assert!((estimate(&mut merged, 50.0) - 50_000.0).abs() < 500.0);
+        assert_eq!(estimate(&mut merged, 100.0), 100_000.0);
+        assert_eq!(estimate(&mut merged, 0.0), 1.0);
+    }
+}
@@ -9,38 +9,116 @@ extern crate ringlog;
 mod macros;
 
 mod admin;
+mod affinity;
+mod assertion;
+mod baseline;
+mod bundle;
+mod calibration;
+mod checkpoint;
 mod codec;
 mod config;
 mod config_file;
+mod controller;
+mod distributed;
+mod dry_run;
+mod endpoints;
+mod generator;
+mod host;
+mod hot_set;
+mod latency;
 mod metrics;
+mod oauth;
+mod placement;
+mod png;
+mod prefill;
+mod preset;
 mod session;
 mod time;
+mod validate;
 mod worker;
 
 pub use crate::admin::Admin;
+pub use crate::codec::Outcome;
 pub use crate::config::Config;
+pub use crate::config_file::{LatencyBackend, Verbosity};
+pub use crate::dry_run::validate;
+pub use crate::latency::Latency;
 pub use crate::metrics::*;
 pub use crate::session::{Session, TcpStream};
 pub use crate::time::*;
 
+use crate::affinity::Affinity;
+use crate::calibration::Calibration;
+use crate::config_file::{Accumulation, LoadMode, Role, Verb};
+use crate::controller::{RateController, RateSearch};
+use crate::distributed::Assignment;
+use crate::endpoints::{Cutover, EndpointStats};
+use crate::generator::{Generator, SCHEDULE_CAPACITY};
+use crate::oauth::TokenProvider;
+use crate::prefill::Prefill;
 use heatmap::Heatmap;
+use mpmc::Queue;
 use ratelimit::Ratelimiter;
 use ringlog::{File, LogBuilder, MultiLogBuilder, Output, Stdout};
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
+use strum::EnumCount;
 use worker::Worker;
 
 /// A structure which represents a runtime builder
 pub struct Builder {
     admin: Admin,
     workers: Vec<Worker>,
+    // schedule the open-loop requests for the workers, when configured
+    generators: Vec<Generator>,
+    // cleared to stop the generators once the run has ended
+    running: Arc<AtomicBool>,
+    // the controller address when this is an agent in a distributed run
+    controller: Option<String>,
+    // keeps the oauth token fresh, with how long the first token is valid
+    oauth: Option<(TokenProvider, std::time::Duration)>,
+    // probes the built-in loopback reference server
+    calibration: Option<Calibration>,
 }
 
 impl Builder {
     /// Create a new runtime builder from the given config
     pub fn new(config: Option<&str>) -> Self {
-        let config = Config::new(config);
+        let mut config = Config::new(config);
+
+        // agents are assigned their share of the keys and ratelimit by the
+        // controller
+        let controller = match config.distributed().role() {
+            Some(Role::Agent) => config.distributed().controller(),
+            _ => None,
+        };
+        let assignment =
+            controller
+                .as_ref()
+                .map(|controller| match distributed::register(controller) {
+                    Ok(assignment) => assignment,
+                    Err(e) => {
+                        eprintln!("failed to register with controller: {}: {}", controller, e);
+                        std::process::exit(1);
+                    }
+                });
+        if let Some(ref assignment) = assignment {
+            config.set_agent_shard(assignment.agent, assignment.agents);
+        }
+
+        let oauth = config
+            .request()
+            .oauth()
+            .map(|oauth| match TokenProvider::new(oauth) {
+                Ok(provider) => provider,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            });
+        config.set_oauth_token(oauth.as_ref().map(|provider| provider.token()));
 
         let config = Arc::new(config);
 
@@ -72,6 +150,18 @@ impl Builder {
             .build()
             .start();
 
+        // requests are only sent once there is a token to send with them
+        let oauth = oauth.map(|provider| match provider.refresh() {
+            Ok(expires_in) => {
+                info!("oauth token acquired, expires in {}s", expires_in.as_secs());
+                (provider, expires_in)
+            }
+            Err(e) => {
+                eprintln!("failed to get oauth token: {}", e);
+                std::process::exit(1);
+            }
+        });
+
         let threads = config.general().threads() as u64;
 
         let connect_ratelimit = if let Some(r) = config.connection().ratelimit() {
@@ -90,7 +180,14 @@ impl Builder {
             None
         };
 
-        let request_ratelimit = if let Some(r) = config.request().ratelimit() {
+        let request_rate = match assignment {
+            Some(Assignment {
+                ratelimit: Some(r), ..
+            }) => Some(r as usize),
+            _ => config.request().ratelimit(),
+        };
+        let request_ratelimit = if let Some(r) = request_rate {
+            REQUEST_RATELIMIT.set(r as i64);
             let r = Ratelimiter::new(threads, 1, r as u64);
             r.set_strategy(config.request().ratelimit_model());
             Some(Arc::new(r))
@@ -98,27 +195,110 @@ impl Builder {
             None
         };
 
-        let connect_heatmap = Some(Arc::new(
-            Heatmap::new(
-                0,
-                10,
-                30,
-                Duration::from_secs(config.general().interval().as_secs()),
-                Duration::from_millis(1000),
-            )
-            .unwrap(),
-        ));
-
-        let request_heatmap = Some(Arc::new(
-            Heatmap::new(
-                0,
-                10,
-                30,
+        // the heatmap span determines how much history the latency percentiles
+        // cover, either the most recent window or the entire run
+        let (span, resolution) = match config.general().accumulation() {
+            Accumulation::Window => (
                 Duration::from_secs(config.general().interval().as_secs()),
                 Duration::from_millis(1000),
+            ),
+            // the run ends after the window numbered by its windows, so it
+            // spans one more window than that. The config requires a fixed
+            // number of windows for run accumulation.
+            Accumulation::Run => {
+                let windows = config.general().windows().unwrap_or_default() as u64;
+                (
+                    Duration::from_secs(config.general().interval().as_secs() * (windows + 1)),
+                    Duration::from_secs(config.general().interval().as_secs()),
+                )
+            }
+        };
+
+        let backend = config.metrics().backend();
+
+        let connect_heatmap = Some(Arc::new(Latency::new(backend, 30, span, resolution)));
+
+        let request_heatmap = Some(Arc::new(Latency::new(backend, 30, span, resolution)));
+
+        // latency from when each request was due to be sent
+        let corrected_heatmap = config
+            .request()
+            .corrected_latency()
+            .then(|| Arc::new(Latency::new(backend, 30, span, resolution)));
+
+        // latency of the built-in reference server, and how late its probes
+        // wake up
+        let calibration_heatmaps = config.calibration().map(|_| {
+            (
+                Arc::new(Latency::new(backend, 30, span, resolution)),
+                Arc::new(Latency::new(backend, 30, span, resolution)),
             )
-            .unwrap(),
-        ));
+        });
+        let calibration = config.calibration().zip(calibration_heatmaps.clone()).map(
+            |(calibration, (loopback, wakeup))| match Calibration::new(
+                calibration.rate(),
+                loopback,
+                wakeup,
+            ) {
+                Ok(calibration) => calibration,
+                Err(e) => {
+                    eprintln!("failed to start the calibration server: {}", e);
+                    std::process::exit(1);
+                }
+            },
+        );
+
+        // time open-loop requests spend waiting to be sent
+        let queue_delay_heatmap = (config.request().mode() == LoadMode::Open)
+            .then(|| Arc::new(Latency::new(backend, 30, span, resolution)));
+
+        let handshake_heatmap = config
+            .tls()
+            .map(|_| Arc::new(Latency::new(backend, 30, span, resolution)));
+
+        // how long connections stay open, when they are churned
+        let session_lifetime_heatmap = (config.connection().reconnect().is_some()
+            || config.connection().lifetime().is_some())
+        .then(|| Arc::new(Latency::new(backend, 30, span, resolution)));
+
+        // per-endpoint latency is only interesting when there are several
+        let endpoint_heatmaps: Vec<(String, Arc<Latency>)> = if config.endpoints().len() > 1 {
+            config
+                .endpoints()
+                .iter()
+                .map(|endpoint| {
+                    (
+                        endpoint.to_string(),
+                        Arc::new(Latency::new(backend, 30, span, resolution)),
+                    )
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let endpoint_stats = (config.endpoints().len() > 1).then(|| {
+            Arc::new(EndpointStats::new(
+                config.endpoints().iter().map(|e| e.to_string()).collect(),
+            ))
+        });
+
+        let cutover = config
+            .cutover_endpoint()
+            .zip(config.target().cutover())
+            .map(|(first, cutover)| {
+                info!(
+                    "cutover to {} endpoints starts after {}s and takes {}s",
+                    config.endpoints().len() - first,
+                    cutover.start().as_secs(),
+                    cutover.duration().as_secs()
+                );
+                Arc::new(Cutover::new(
+                    first,
+                    Duration::from_nanos(cutover.start().as_nanos() as u64),
+                    Duration::from_nanos(cutover.duration().as_nanos() as u64),
+                ))
+            });
 
         let request_waterfall =
             if config.waterfall().file().is_some() && config.general().windows().is_some() {
@@ -139,34 +319,288 @@ impl Builder {
                 None
             };
 
+        // latency for each command used in the workload, and optionally a
+        // waterfall for each command
+        let command_heatmaps: Vec<(Verb, Arc<Latency>)> = config
+            .verbs()
+            .into_iter()
+            .map(|verb| (verb, Arc::new(Latency::new(backend, 30, span, resolution))))
+            .collect();
+        let command_waterfalls: Vec<(Verb, Arc<Heatmap>)> = if request_waterfall.is_some() {
+            config
+                .verbs()
+                .into_iter()
+                .filter(|verb| config.waterfall().renders(*verb))
+                .map(|verb| {
+                    (
+                        verb,
+                        Arc::new(
+                            Heatmap::new(
+                                0,
+                                10,
+                                30,
+                                Duration::from_secs(
+                                    config.general().interval().as_secs()
+                                        * config.general().windows().unwrap() as u64,
+                                ),
+                                Duration::from_millis(config.waterfall().resolution()),
+                            )
+                            .unwrap(),
+                        ),
+                    )
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        // latency for each command over the measured part of the run, which
+        // is every window after the warmup, for the summary at the end
+        let summary_heatmaps: Vec<(Verb, Arc<Latency>)> = match config.general().windows() {
+            Some(windows) => {
+                let interval = config.general().interval().as_secs();
+                // the run ends after the window numbered by its windows
+                let measured = (windows + 1 - config.general().warmup()) as u64;
+                config
+                    .verbs()
+                    .into_iter()
+                    .map(|verb| {
+                        (
+                            verb,
+                            Arc::new(Latency::new(
+                                backend,
+                                30,
+                                Duration::from_secs(interval * measured),
+                                Duration::from_secs(interval),
+                            )),
+                        )
+                    })
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+
+        let (write_size_heatmap, write_element_heatmap) =
+            if config.verbs().iter().any(|v| v.is_collection_write()) {
+                (
+                    Some(Arc::new(Latency::new(backend, 30, span, resolution))),
+                    Some(Arc::new(Latency::new(backend, 20, span, resolution))),
+                )
+            } else {
+                (None, None)
+            };
+
+        let response_field_heatmap = if config.verbs().contains(&Verb::Hgetall) {
+            Some(Arc::new(Latency::new(backend, 20, span, resolution)))
+        } else {
+            None
+        };
+
+        if let Some(preset) = config.general().preset() {
+            info!("workload preset: {}", preset);
+        }
         for endpoint in config.endpoints() {
             info!("endpoint: {}", endpoint);
         }
+        for (index, keyspace) in config.keyspaces().iter().enumerate() {
+            let exponent = match keyspace.zipf_exponent() {
+                Some(exponent) => exponent,
+                None => continue,
+            };
+            let cardinality = keyspace.cardinality();
+            let hot_sets: Vec<String> = hot_set::TARGETS
+                .iter()
+                .map(|target| {
+                    format!(
+                        "{}%: {}",
+                        target * 100.0,
+                        hot_set::hot_set(cardinality, exponent, *target)
+                    )
+                })
+                .collect();
+            info!(
+                "keyspace {}: zipf exponent {} over {} keys, hot set for a hit rate of {}",
+                index,
+                exponent,
+                cardinality,
+                hot_sets.join(" ")
+            );
+            if let Some(size) = keyspace.cache_size() {
+                info!(
+                    "keyspace {}: expected hit rate of a {} key cache: {:.2} %",
+                    index,
+                    size,
+                    hot_set::hit_rate(cardinality, exponent, size) * 100.0
+                );
+            }
+        }
+
+        let connections = config.connection().poolsize() * config.endpoints().len();
+        let affinity = if config.connection().affinity() {
+            Some(Arc::new(Affinity::new(threads as usize * connections)))
+        } else {
+            None
+        };
+
+        let prefill = Prefill::new(&config, threads as usize).map(Arc::new);
+
+        // the controller of a distributed run only merges the agents' results
+        let threads = if config.distributed().role() == Some(Role::Controller) {
+            0
+        } else {
+            threads
+        };
+
+        let placement = match placement::workers(config.general(), threads as usize) {
+            Ok(placement) => placement,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+
+        // with generator threads, the workers take the requests which are due
+        // from a shared schedule instead of from the ratelimit
+        let generator_threads = if threads == 0 {
+            0
+        } else {
+            config.general().generator_threads()
+        };
+        let schedule = (generator_threads > 0).then(|| Queue::with_capacity(SCHEDULE_CAPACITY));
+        let running = Arc::new(AtomicBool::new(true));
+        let generators = match (&schedule, &request_ratelimit) {
+            (Some(schedule), Some(ratelimiter)) => (0..generator_threads)
+                .map(|_| {
+                    Generator::new(
+                        ratelimiter.clone(),
+                        schedule.clone(),
+                        prefill.clone(),
+                        running.clone(),
+                    )
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
 
         let mut workers = Vec::new();
-        for _ in 0..threads {
+        for (id, cpus) in placement.into_iter().enumerate() {
+            if let Some(ref cpus) = cpus {
+                info!("worker {} pinned to cpus: {:?}", id, cpus);
+            }
             let mut worker = Worker::new(config.clone()).unwrap();
+            worker.set_cpus(cpus);
+            worker.set_prefill(prefill.clone());
+            worker.set_affinity(affinity.clone(), id * connections);
             worker.set_connect_ratelimit(connect_ratelimit.clone());
             worker.set_reconnect_ratelimit(reconnect_ratelimit.clone());
             worker.set_request_ratelimit(request_ratelimit.clone());
+            worker.set_schedule(schedule.clone());
             worker.set_connect_heatmap(connect_heatmap.clone());
+            worker.set_handshake_heatmap(handshake_heatmap.clone());
+            worker.set_session_lifetime_heatmap(session_lifetime_heatmap.clone());
+            worker.set_endpoint_heatmaps(
+                endpoint_heatmaps
+                    .iter()
+                    .map(|(_, heatmap)| heatmap.clone())
+                    .collect(),
+            );
+            worker.set_endpoint_stats(endpoint_stats.clone());
+            worker.set_cutover(cutover.clone());
+            worker.set_command_heatmaps(by_verb(&command_heatmaps));
+            worker.set_command_waterfalls(by_verb(&command_waterfalls));
+            worker.set_summary_heatmaps(by_verb(&summary_heatmaps));
+            worker.set_write_size_heatmap(write_size_heatmap.clone());
+            worker.set_write_element_heatmap(write_element_heatmap.clone());
+            worker.set_response_field_heatmap(response_field_heatmap.clone());
             worker.set_request_heatmap(request_heatmap.clone());
             worker.set_request_waterfall(request_waterfall.clone());
+            worker.set_corrected_heatmap(corrected_heatmap.clone());
+            worker.set_queue_delay_heatmap(queue_delay_heatmap.clone());
             workers.push(worker);
         }
 
+        let rate_controller = RateController::new(&config, request_ratelimit.clone());
+        let rate_search = RateSearch::new(&config, request_ratelimit.clone());
+
         let mut admin = Admin::new(config, log);
+        admin.set_rate_controller(rate_controller);
+        admin.set_rate_search(rate_search);
+        admin.set_prefill(prefill);
+        admin.set_affinity(affinity);
         admin.set_connect_heatmap(connect_heatmap);
+        admin.set_handshake_heatmap(handshake_heatmap);
+        admin.set_session_lifetime_heatmap(session_lifetime_heatmap);
+        admin.set_endpoint_heatmaps(endpoint_heatmaps);
+        admin.set_endpoint_stats(endpoint_stats);
+        admin.set_cutover(cutover);
+        admin.set_command_heatmaps(command_heatmaps);
+        admin.set_command_waterfalls(command_waterfalls);
+        admin.set_summary_heatmaps(summary_heatmaps);
+        admin.set_write_size_heatmap(write_size_heatmap);
+        admin.set_write_element_heatmap(write_element_heatmap);
+        admin.set_response_field_heatmap(response_field_heatmap);
         admin.set_reconnect_ratelimit(reconnect_ratelimit);
         admin.set_request_heatmap(request_heatmap);
         admin.set_request_ratelimit(request_ratelimit);
         admin.set_request_waterfall(request_waterfall);
+        admin.set_corrected_heatmap(corrected_heatmap);
+        admin.set_queue_delay_heatmap(queue_delay_heatmap);
+        admin.set_calibration_heatmaps(calibration_heatmaps);
+
+        Self {
+            admin,
+            workers,
+            generators,
+            running,
+            controller,
+            oauth,
+            calibration,
+        }
+    }
 
-        Self { admin, workers }
+    /// Override the output verbosity from the config
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.admin.set_verbosity(verbosity);
+        self
+    }
+
+    /// Continue from the checkpoint of a run which was restarted
+    pub fn resume(mut self) -> Self {
+        if let Err(e) = self.admin.resume() {
+            eprintln!("failed to resume: {}", e);
+            std::process::exit(1);
+        }
+        self
+    }
+
+    /// Compare the end of the run against the summary of an earlier run,
+    /// failing the run on a regression
+    pub fn baseline(mut self, file: &str) -> Self {
+        if let Err(e) = self.admin.set_baseline(file) {
+            eprintln!("failed to load baseline: {}", e);
+            std::process::exit(1);
+        }
+        self
     }
 
     /// Launch the runtime
     pub fn spawn(mut self) -> Runtime {
+        // agents start together once every agent has registered
+        if let Some(ref controller) = self.controller {
+            if let Err(e) = distributed::wait_for_start(controller) {
+                eprintln!("failed to start with controller: {}: {}", controller, e);
+                std::process::exit(1);
+            }
+        }
+
+        if let Some((provider, expires_in)) = self.oauth.take() {
+            std::thread::spawn(move || provider.run(expires_in));
+        }
+
+        if let Some(calibration) = self.calibration.take() {
+            std::thread::spawn(move || calibration.run());
+        }
+
         let admin = self.admin;
         let admin_thread = std::thread::spawn(move || admin.run());
 
@@ -175,23 +609,54 @@ impl Builder {
             worker_threads.push(std::thread::spawn(move || worker.run()));
         }
 
+        let mut generator_threads = Vec::new();
+        for generator in self.generators.drain(..) {
+            generator_threads.push(std::thread::spawn(move || generator.run()));
+        }
+
         Runtime {
             admin_thread,
             worker_threads,
+            generator_threads,
+            running: self.running,
         }
     }
 }
 
-#[allow(dead_code)]
 /// Holds the runtime threads
 pub struct Runtime {
-    admin_thread: JoinHandle<()>,
+    admin_thread: JoinHandle<Result<(), String>>,
+    #[allow(dead_code)]
     worker_threads: Vec<JoinHandle<()>>,
+    generator_threads: Vec<JoinHandle<()>>,
+    running: Arc<AtomicBool>,
 }
 
 impl Runtime {
-    /// Run the threads to completion
-    pub fn wait(self) {
-        let _ = self.admin_thread.join();
+    /// Run the threads to completion. Returns an error with the reason if the
+    /// run was stopped early or regressed against the baseline.
+    pub fn wait(self) -> Result<(), String> {
+        let result = self
+            .admin_thread
+            .join()
+            .unwrap_or_else(|_| Err("admin thread panicked".to_string()));
+
+        // the run has ended, so stop scheduling requests
+        self.running.store(false, Ordering::Relaxed);
+        for generator in self.generator_threads {
+            let _ = generator.join();
+        }
+
+        result
+    }
+}
+
+/// Index heatmaps by verb so that workers can find the heatmap for a command
+/// without searching.
+fn by_verb<T>(heatmaps: &[(Verb, Arc<T>)]) -> Vec<Option<Arc<T>>> {
+    let mut indexed = vec![None; Verb::COUNT];
+    for (verb, heatmap) in heatmaps {
+        indexed[*verb as usize] = Some(heatmap.clone());
     }
+    indexed
 }
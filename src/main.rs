@@ -4,7 +4,7 @@
 
 use backtrace::Backtrace;
 use clap::{App, Arg};
-use rpc_perf::Builder;
+use rpc_perf::{Builder, Verbosity};
 
 fn main() {
     // custom panic hook to terminate whole process after unwinding
@@ -26,8 +26,63 @@ fn main() {
         )
         .about("Measure RPC performance using synthetic traffic")
         .arg(Arg::with_name("CONFIG").help("Configuration file").index(1))
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .short('q')
+                .help("Only output a summary once the run has completed")
+                .conflicts_with("verbose"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .help("Include a per-command breakdown in each window"),
+        )
+        .arg(
+            Arg::with_name("validate")
+                .long("validate")
+                .help("Check the config and print the workload without sending any load"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help("Resume a restarted run from its checkpoint file"),
+        )
+        .arg(
+            Arg::with_name("baseline")
+                .long("baseline")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Compare the run's command summary against the summary of an earlier run"),
+        )
         .get_matches();
 
+    if matches.is_present("validate") {
+        if let Err(e) = rpc_perf::validate(matches.value_of("CONFIG")) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut builder = Builder::new(matches.value_of("CONFIG"));
+
+    // command line options take precedence over the config
+    if matches.is_present("quiet") {
+        builder = builder.verbosity(Verbosity::Quiet);
+    } else if matches.is_present("verbose") {
+        builder = builder.verbosity(Verbosity::Verbose);
+    }
+    if matches.is_present("resume") {
+        builder = builder.resume();
+    }
+    if let Some(file) = matches.value_of("baseline") {
+        builder = builder.baseline(file);
+    }
+
     // launch
-    Builder::new(matches.value_of("CONFIG")).spawn().wait()
+    if let Err(reason) = builder.spawn().wait() {
+        eprintln!("run failed: {}", reason);
+        std::process::exit(1);
+    }
 }
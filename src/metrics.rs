@@ -2,8 +2,10 @@
 // Licensed under the Apache License, Version 2.0
 // http://www.apache.org/licenses/LICENSE-2.0
 
+use crate::config_file::Verb;
 use metriken::metric;
 pub use metriken::{Counter, Gauge};
+use strum::EnumCount;
 
 #[metric(name = "connect", description = "connect attempts")]
 pub static CONNECT: Counter = Counter::new();
@@ -14,15 +16,157 @@ pub static CONNECT_EX: Counter = Counter::new();
 #[metric(name = "connect_timeout", description = "connect timeouts")]
 pub static CONNECT_TIMEOUT: Counter = Counter::new();
 
+#[metric(
+    name = "reconnect_abandoned",
+    description = "requests in flight which were abandoned when a connection was closed to reconnect"
+)]
+pub static RECONNECT_ABANDONED: Counter = Counter::new();
+
+#[metric(
+    name = "reconnect_drain_timeout",
+    description = "connections closed to reconnect before their requests in flight completed"
+)]
+pub static RECONNECT_DRAIN_TIMEOUT: Counter = Counter::new();
+
+#[metric(
+    name = "session_expired",
+    description = "connections closed to reconnect after living out their lifetime"
+)]
+pub static SESSION_EXPIRED: Counter = Counter::new();
+
+#[metric(
+    name = "session_setup_ex",
+    description = "connections closed because the request which sets them up, such as a login, failed"
+)]
+pub static SESSION_SETUP_EX: Counter = Counter::new();
+
+#[metric(name = "oauth_token_refresh", description = "oauth tokens fetched")]
+pub static OAUTH_TOKEN_REFRESH: Counter = Counter::new();
+
+#[metric(
+    name = "oauth_token_refresh_ex",
+    description = "oauth token requests which failed"
+)]
+pub static OAUTH_TOKEN_REFRESH_EX: Counter = Counter::new();
+
+#[metric(
+    name = "slow_start",
+    description = "connections which started to warm up after reconnecting"
+)]
+pub static SLOW_START: Counter = Counter::new();
+
+#[metric(
+    name = "slow_start_deferred",
+    description = "times a warming connection passed its turn to send to another connection"
+)]
+pub static SLOW_START_DEFERRED: Counter = Counter::new();
+
+#[metric(name = "slow_start_warming", description = "connections warming up")]
+pub static SLOW_START_WARMING: Gauge = Gauge::new();
+
 #[metric(name = "request", description = "requests sent")]
 pub static REQUEST: Counter = Counter::new();
 
 #[metric(name = "request_ex", description = "exceptions when sending a request")]
 pub static REQUEST_EX: Counter = Counter::new();
 
+#[metric(
+    name = "request_backlog",
+    description = "scheduled requests waiting for a connection in open-loop mode, or in the work queue of a replay"
+)]
+pub static REQUEST_BACKLOG: Gauge = Gauge::new();
+
+#[metric(
+    name = "request_queue_full",
+    description = "requests which waited for room in the full work queue of a replay, or in the schedule of the request generators"
+)]
+pub static REQUEST_QUEUE_FULL: Counter = Counter::new();
+
+#[metric(
+    name = "request_queue_wait",
+    description = "microseconds spent waiting for room in the full work queue of a replay, or in the schedule of the request generators"
+)]
+pub static REQUEST_QUEUE_WAIT: Counter = Counter::new();
+
+#[metric(
+    name = "request_generator_behind",
+    description = "requests whose ratelimit credit was already waiting when a request generator asked for it, a sign the generators are starved"
+)]
+pub static REQUEST_GENERATOR_BEHIND: Counter = Counter::new();
+
 #[metric(name = "request_get", description = "get requests sent")]
 pub static REQUEST_GET: Counter = Counter::new();
 
+#[metric(
+    name = "request_expired",
+    description = "queued requests dropped for exceeding the maximum age"
+)]
+pub static REQUEST_EXPIRED: Counter = Counter::new();
+
+/// requests sent, broken down by command
+pub static REQUEST_COMMAND: [Counter; Verb::COUNT] = [const { Counter::new() }; Verb::COUNT];
+
+/// bytes of requests sent, broken down by command
+pub static REQUEST_COMMAND_BYTE: [Counter; Verb::COUNT] = [const { Counter::new() }; Verb::COUNT];
+
+/// responses received, broken down by command
+pub static RESPONSE_COMMAND: [Counter; Verb::COUNT] = [const { Counter::new() }; Verb::COUNT];
+
+/// bytes of responses received, broken down by command
+pub static RESPONSE_COMMAND_BYTE: [Counter; Verb::COUNT] = [const { Counter::new() }; Verb::COUNT];
+
+/// responses that indicated an error, broken down by command
+pub static RESPONSE_COMMAND_EX: [Counter; Verb::COUNT] = [const { Counter::new() }; Verb::COUNT];
+
+/// responses that indicated a cache hit, broken down by command
+pub static RESPONSE_COMMAND_HIT: [Counter; Verb::COUNT] = [const { Counter::new() }; Verb::COUNT];
+
+/// requests that were not answered in time, broken down by command
+pub static RESPONSE_COMMAND_TIMEOUT: [Counter; Verb::COUNT] =
+    [const { Counter::new() }; Verb::COUNT];
+
+#[metric(
+    name = "request_ratelimit",
+    description = "current target rate for requests"
+)]
+pub static REQUEST_RATELIMIT: Gauge = Gauge::new();
+
+#[metric(
+    name = "affinity_skew",
+    description = "requests sent on the busiest connection as a percentage of the mean when keys have connection affinity"
+)]
+pub static AFFINITY_SKEW: Gauge = Gauge::new();
+
+#[metric(
+    name = "namespace_version",
+    description = "the latest namespace version which prefixes the keys"
+)]
+pub static NAMESPACE_VERSION: Gauge = Gauge::new();
+
+#[metric(
+    name = "namespace_recovery",
+    description = "milliseconds the hit-rate took to recover after the latest namespace bump"
+)]
+pub static NAMESPACE_RECOVERY: Gauge = Gauge::new();
+
+#[metric(
+    name = "cutover_share",
+    description = "planned percentage of requests sent to the cutover endpoints"
+)]
+pub static CUTOVER_SHARE: Gauge = Gauge::new();
+
+#[metric(
+    name = "collection_write",
+    description = "requests sent which write elements into a collection"
+)]
+pub static COLLECTION_WRITE: Counter = Counter::new();
+
+#[metric(
+    name = "collection_write_element",
+    description = "elements written by collection write requests"
+)]
+pub static COLLECTION_WRITE_ELEMENT: Counter = Counter::new();
+
 #[metric(name = "response", description = "responses received")]
 pub static RESPONSE: Counter = Counter::new();
 
@@ -32,12 +176,246 @@ pub static RESPONSE: Counter = Counter::new();
 )]
 pub static RESPONSE_EX: Counter = Counter::new();
 
+#[metric(
+    name = "response_throttled",
+    description = "responses rejecting the request because the server is rate limiting or overloaded"
+)]
+pub static RESPONSE_THROTTLED: Counter = Counter::new();
+
+#[metric(
+    name = "response_write_concern_ex",
+    description = "writes which were applied but did not satisfy the write concern"
+)]
+pub static RESPONSE_WRITE_CONCERN_EX: Counter = Counter::new();
+
+#[metric(
+    name = "response_unauthorized",
+    description = "responses which rejected the request as unauthorized"
+)]
+pub static RESPONSE_UNAUTHORIZED: Counter = Counter::new();
+
+#[metric(
+    name = "response_assertion_failed",
+    description = "http responses which failed at least one assertion"
+)]
+pub static RESPONSE_ASSERTION_FAILED: Counter = Counter::new();
+
+#[metric(
+    name = "response_sticky",
+    description = "responses which named the backend that served them, checked for stickiness"
+)]
+pub static RESPONSE_STICKY: Counter = Counter::new();
+
+#[metric(
+    name = "response_sticky_violation",
+    description = "responses from a different backend than the first response on their connection"
+)]
+pub static RESPONSE_STICKY_VIOLATION: Counter = Counter::new();
+
+#[metric(
+    name = "response_timeout",
+    description = "requests which were not answered in time, including datagrams which were lost"
+)]
+pub static RESPONSE_TIMEOUT: Counter = Counter::new();
+
+#[metric(
+    name = "response_late",
+    description = "responses to datagram requests which arrived after the request timed out"
+)]
+pub static RESPONSE_LATE: Counter = Counter::new();
+
+#[metric(
+    name = "response_missing",
+    description = "datagram requests which timed out and had no response within the grace period"
+)]
+pub static RESPONSE_MISSING: Counter = Counter::new();
+
+#[metric(
+    name = "response_noerror",
+    description = "dns responses with the NOERROR response code"
+)]
+pub static RESPONSE_NOERROR: Counter = Counter::new();
+
+#[metric(
+    name = "response_nxdomain",
+    description = "dns responses with the NXDOMAIN response code"
+)]
+pub static RESPONSE_NXDOMAIN: Counter = Counter::new();
+
+#[metric(
+    name = "response_servfail",
+    description = "dns responses with the SERVFAIL response code"
+)]
+pub static RESPONSE_SERVFAIL: Counter = Counter::new();
+
+#[metric(
+    name = "response_truncated",
+    description = "dns responses which were truncated to fit in a datagram"
+)]
+pub static RESPONSE_TRUNCATED: Counter = Counter::new();
+
+#[metric(
+    name = "response_moved",
+    description = "redis responses redirecting the request to the node which now owns its slot"
+)]
+pub static RESPONSE_MOVED: Counter = Counter::new();
+
+#[metric(
+    name = "response_ask",
+    description = "redis responses redirecting the request while its slot is migrating"
+)]
+pub static RESPONSE_ASK: Counter = Counter::new();
+
+#[metric(
+    name = "transaction_abort",
+    description = "redis transactions which were aborted, or whose script failed"
+)]
+pub static TRANSACTION_ABORT: Counter = Counter::new();
+
+#[metric(
+    name = "cas_success",
+    description = "compare-and-swap writes which were applied"
+)]
+pub static CAS_SUCCESS: Counter = Counter::new();
+
+#[metric(
+    name = "cas_conflict",
+    description = "compare-and-swap writes which were rejected as the key changed after it was read"
+)]
+pub static CAS_CONFLICT: Counter = Counter::new();
+
+#[metric(
+    name = "response_noscript",
+    description = "redis responses to EVALSHA for a script the server has not loaded"
+)]
+pub static RESPONSE_NOSCRIPT: Counter = Counter::new();
+
+#[metric(
+    name = "script_fallback",
+    description = "redis scripts sent with EVAL to load them again after a NOSCRIPT response"
+)]
+pub static SCRIPT_FALLBACK: Counter = Counter::new();
+
+#[metric(
+    name = "push_pubsub",
+    description = "redis pub/sub messages pushed on a data connection"
+)]
+pub static PUSH_PUBSUB: Counter = Counter::new();
+
+#[metric(
+    name = "push_invalidate",
+    description = "redis client side caching invalidations pushed on a data connection"
+)]
+pub static PUSH_INVALIDATE: Counter = Counter::new();
+
+#[metric(
+    name = "push_other",
+    description = "other redis messages pushed on a data connection"
+)]
+pub static PUSH_OTHER: Counter = Counter::new();
+
 #[metric(
     name = "response_hit",
     description = "responses that indicated a cache hit"
 )]
 pub static RESPONSE_HIT: Counter = Counter::new();
 
+#[metric(
+    name = "get_key",
+    description = "keys requested by gets, several for each multi-key get"
+)]
+pub static GET_KEY: Counter = Counter::new();
+
+#[metric(
+    name = "get_key_hit",
+    description = "keys requested by gets which were found"
+)]
+pub static GET_KEY_HIT: Counter = Counter::new();
+
+#[metric(
+    name = "calibration_probe",
+    description = "probes answered by the built-in loopback reference server"
+)]
+pub static CALIBRATION_PROBE: Counter = Counter::new();
+
+#[metric(
+    name = "calibration_probe_ex",
+    description = "probes to the built-in loopback reference server which failed"
+)]
+pub static CALIBRATION_PROBE_EX: Counter = Counter::new();
+
+#[metric(
+    name = "edge_case_request",
+    description = "requests which used an edge-case key"
+)]
+pub static EDGE_CASE_REQUEST: Counter = Counter::new();
+
+#[metric(
+    name = "edge_case_response",
+    description = "responses to requests which used an edge-case key"
+)]
+pub static EDGE_CASE_RESPONSE: Counter = Counter::new();
+
+#[metric(
+    name = "edge_case_response_ex",
+    description = "responses to requests with an edge-case key which indicated an error"
+)]
+pub static EDGE_CASE_RESPONSE_EX: Counter = Counter::new();
+
+#[metric(
+    name = "edge_case_response_hit",
+    description = "responses to requests with an edge-case key which indicated a cache hit"
+)]
+pub static EDGE_CASE_RESPONSE_HIT: Counter = Counter::new();
+
+#[metric(
+    name = "edge_case_close",
+    description = "connections closed by a response to a request with an edge-case key which could not be parsed or was an error"
+)]
+pub static EDGE_CASE_CLOSE: Counter = Counter::new();
+
+#[metric(
+    name = "response_field",
+    description = "fields returned by hash get all responses"
+)]
+pub static RESPONSE_FIELD: Counter = Counter::new();
+
+#[metric(
+    name = "response_member",
+    description = "members returned by set members and sorted set range responses"
+)]
+pub static RESPONSE_MEMBER: Counter = Counter::new();
+
+#[metric(
+    name = "response_element",
+    description = "elements returned by list range responses"
+)]
+pub static RESPONSE_ELEMENT: Counter = Counter::new();
+
+#[metric(
+    name = "response_validated",
+    description = "values in responses which were checked for integrity"
+)]
+pub static RESPONSE_VALIDATED: Counter = Counter::new();
+
+#[metric(
+    name = "response_corrupt",
+    description = "values in responses which did not match their checksum"
+)]
+pub static RESPONSE_CORRUPT: Counter = Counter::new();
+
+#[metric(
+    name = "response_wrong_key",
+    description = "values in responses which were stored under a different key"
+)]
+pub static RESPONSE_WRONG_KEY: Counter = Counter::new();
+
+#[metric(
+    name = "response_stale",
+    description = "values in responses which are older than an acknowledged write"
+)]
+pub static RESPONSE_STALE: Counter = Counter::new();
+
 /// distribution of response latencies
 // #[metric(name = "response_latency")]
 // pub static RESPONSE_LATENCY: Relaxed<Heatmap> = Relaxed::new(||
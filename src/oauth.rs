@@ -0,0 +1,385 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Acquires OAuth2 access tokens with the client credentials flow and keeps
+//! them fresh for the length of the run. The first token is fetched before
+//! the workers start, and a thread fetches a new one ahead of each token's
+//! expiry. Codecs read the current token for each request and send it as a
+//! bearer token. When the target rejects a request as unauthorized, the token
+//! is marked stale so that a new one is fetched early.
+
+use crate::codec::base64;
+use crate::config_file::{Oauth, OauthClientAuth};
+use crate::*;
+use boring::ssl::{SslConnector, SslMethod};
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::RwLock;
+use std::time::Instant;
+
+/// Tokens without an expiry are refreshed after this long, in seconds
+const DEFAULT_EXPIRES_IN: u64 = 3600;
+/// Failed fetches are retried after this long, doubling up to the maximum
+const RETRY_MIN: std::time::Duration = std::time::Duration::from_secs(1);
+const RETRY_MAX: std::time::Duration = std::time::Duration::from_secs(30);
+/// A stale token is replaced at most this often
+const STALE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// The current access token, shared with the codecs
+#[derive(Default)]
+pub struct Token {
+    value: RwLock<Option<Arc<str>>>,
+    stale: AtomicBool,
+}
+
+impl Token {
+    /// The access token to send as a bearer token
+    pub fn bearer(&self) -> Option<Arc<str>> {
+        self.value.read().unwrap().clone()
+    }
+
+    /// Mark the token as rejected by the target, so a new one is fetched
+    pub fn set_stale(&self) {
+        self.stale.store(true, Ordering::Relaxed);
+    }
+
+    fn set(&self, value: &str) {
+        *self.value.write().unwrap() = Some(value.into());
+        self.stale.store(false, Ordering::Relaxed);
+    }
+}
+
+/// The parts of the token endpoint's url
+#[derive(Debug, PartialEq)]
+pub struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+/// Split an `http` or `https` url into its parts
+pub fn parse_url(url: &str) -> Result<Url, String> {
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        return Err(format!("oauth token url must be http or https: {url}"));
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(start) => (&rest[..start], &rest[start..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port
+                .parse()
+                .map_err(|_| format!("bad port in oauth token url: {url}"))?;
+            (host, port)
+        }
+        None => (authority, if tls { 443 } else { 80 }),
+    };
+    if host.is_empty() {
+        return Err(format!("oauth token url has no host: {url}"));
+    }
+    Ok(Url {
+        tls,
+        host: host.to_string(),
+        port,
+        path: path.to_string(),
+    })
+}
+
+/// Fetches tokens from the token endpoint
+pub struct TokenProvider {
+    config: Oauth,
+    url: Url,
+    tls: Option<SslConnector>,
+    token: Arc<Token>,
+}
+
+impl TokenProvider {
+    pub fn new(config: Oauth) -> Result<Self, String> {
+        let url = parse_url(config.token_url())?;
+        let tls = if url.tls {
+            let mut builder = SslConnector::builder(SslMethod::tls_client())
+                .map_err(|e| format!("failed to create oauth tls connector: {e}"))?;
+            if let Some(ca_file) = config.ca_file() {
+                builder
+                    .set_ca_file(ca_file)
+                    .map_err(|e| format!("bad oauth ca file: {ca_file}: {e}"))?;
+            }
+            Some(builder.build())
+        } else {
+            None
+        };
+        Ok(Self {
+            config,
+            url,
+            tls,
+            token: Arc::new(Token::default()),
+        })
+    }
+
+    pub fn token(&self) -> Arc<Token> {
+        self.token.clone()
+    }
+
+    /// Fetch a new token, returning how long it is valid for
+    pub fn refresh(&self) -> Result<std::time::Duration, Error> {
+        let result = self.fetch();
+        match result {
+            Ok((ref token, _)) => {
+                OAUTH_TOKEN_REFRESH.increment();
+                self.token.set(token);
+            }
+            Err(_) => {
+                OAUTH_TOKEN_REFRESH_EX.increment();
+            }
+        }
+        result.map(|(_, expires_in)| expires_in)
+    }
+
+    /// Keep the token fresh, fetching a new one ahead of the expiry of the
+    /// current one or once it has been marked stale
+    pub fn run(self, expires_in: std::time::Duration) {
+        let mut next = Instant::now() + self.refresh_after(expires_in);
+        let mut last = Instant::now();
+        let mut retry = RETRY_MIN;
+        loop {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            let now = Instant::now();
+            let stale = self.token.stale.load(Ordering::Relaxed) && now - last >= STALE_INTERVAL;
+            if now < next && !stale {
+                continue;
+            }
+            last = now;
+            match self.refresh() {
+                Ok(expires_in) => {
+                    debug!(
+                        "oauth token refreshed, expires in {}s",
+                        expires_in.as_secs()
+                    );
+                    next = now + self.refresh_after(expires_in);
+                    retry = RETRY_MIN;
+                }
+                Err(e) => {
+                    // the current token is kept until a new one is fetched
+                    warn!("failed to refresh oauth token: {}", e);
+                    next = now + retry;
+                    retry = (retry * 2).min(RETRY_MAX);
+                }
+            }
+        }
+    }
+
+    /// How long after a token is fetched it should be replaced, which is the
+    /// configured margin before it expires, but no sooner than halfway
+    /// through its lifetime
+    fn refresh_after(&self, expires_in: std::time::Duration) -> std::time::Duration {
+        expires_in
+            .saturating_sub(self.config.refresh())
+            .max(expires_in / 2)
+    }
+
+    fn fetch(&self) -> Result<(String, std::time::Duration), Error> {
+        let client_id = resolve(self.config.client_id())?;
+        let client_secret = resolve(self.config.client_secret())?;
+
+        let mut form = vec![("grant_type", "client_credentials".to_string())];
+        if let Some(scope) = self.config.scope() {
+            form.push(("scope", scope.to_string()));
+        }
+        if let Some(audience) = self.config.audience() {
+            form.push(("audience", audience.to_string()));
+        }
+        let mut headers = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\nContent-Type: application/x-www-form-urlencoded\r\n",
+            self.url.path, self.url.host
+        );
+        match self.config.client_auth() {
+            OauthClientAuth::Basic => {
+                let credentials = format!(
+                    "{}:{}",
+                    form_encode(&client_id),
+                    form_encode(&client_secret)
+                );
+                headers.push_str(&format!(
+                    "Authorization: Basic {}\r\n",
+                    base64(credentials.as_bytes())
+                ));
+            }
+            OauthClientAuth::Body => {
+                form.push(("client_id", client_id));
+                form.push(("client_secret", client_secret));
+            }
+        }
+        let body = form
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, form_encode(value)))
+            .collect::<Vec<String>>()
+            .join("&");
+        let request = format!("{headers}Content-Length: {}\r\n\r\n{body}", body.len());
+
+        let response = self.send(request.as_bytes())?;
+        parse_response(&response)
+    }
+
+    /// Send the request and read the whole response, which ends when the
+    /// server closes the connection
+    fn send(&self, request: &[u8]) -> Result<Vec<u8>, Error> {
+        let address = (self.url.host.as_str(), self.url.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "oauth token url did not resolve"))?;
+        let stream = TcpStream::connect_timeout(&address, self.config.timeout())?;
+        stream.set_read_timeout(Some(self.config.timeout()))?;
+        stream.set_write_timeout(Some(self.config.timeout()))?;
+
+        let mut response = Vec::new();
+        match self.tls {
+            Some(ref tls) => {
+                let mut stream = tls
+                    .connect(&self.url.host, stream)
+                    .map_err(|e| Error::other(e.to_string()))?;
+                stream.write_all(request)?;
+                match stream.read_to_end(&mut response) {
+                    Ok(_) => {}
+                    // some servers close without a tls close notify
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof && !response.is_empty() => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            None => {
+                let mut stream = stream;
+                stream.write_all(request)?;
+                stream.read_to_end(&mut response)?;
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// The access token and its lifetime from the token endpoint's response
+fn parse_response(response: &[u8]) -> Result<(String, std::time::Duration), Error> {
+    let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| invalid("incomplete response from oauth token url"))?;
+    let status = std::str::from_utf8(&response[..header_end])
+        .ok()
+        .and_then(|header| header.split(' ').nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| invalid("bad response from oauth token url"))?;
+    let body: serde_json::Value = serde_json::from_slice(&response[(header_end + 4)..])
+        .map_err(|_| invalid("oauth token response is not json"))?;
+    if status != 200 {
+        // the error and its description, as described in rfc 6749
+        let error = body
+            .get("error")
+            .and_then(|error| error.as_str())
+            .unwrap_or("unknown error");
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("oauth token request failed with status {status}: {error}"),
+        ));
+    }
+    let token = body
+        .get("access_token")
+        .and_then(|token| token.as_str())
+        .ok_or_else(|| invalid("oauth token response has no access_token"))?;
+    let expires_in = body
+        .get("expires_in")
+        .and_then(|expires_in| expires_in.as_u64())
+        .unwrap_or(DEFAULT_EXPIRES_IN);
+    Ok((
+        token.to_string(),
+        std::time::Duration::from_secs(expires_in),
+    ))
+}
+
+/// Fill in a secret which is given as an `${env:NAME}` or `${file:PATH}`
+/// template. Files are read on every call, so that secrets which are rotated
+/// on disk are picked up during a run.
+pub(crate) fn resolve(value: &str) -> Result<String, Error> {
+    if let Some(name) = value
+        .strip_prefix("${env:")
+        .and_then(|rest| rest.strip_suffix('}'))
+    {
+        std::env::var(name).map_err(|_| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("environment variable is not set: {name}"),
+            )
+        })
+    } else if let Some(path) = value
+        .strip_prefix("${file:")
+        .and_then(|rest| rest.strip_suffix('}'))
+    {
+        Ok(std::fs::read_to_string(path)?.trim().to_string())
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+/// Percent encode a value for an `application/x-www-form-urlencoded` body
+fn form_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b' ' => encoded.push('+'),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_url() {
+        assert_eq!(
+            parse_url("https://auth.example.com/oauth/token"),
+            Ok(Url {
+                tls: true,
+                host: "auth.example.com".to_string(),
+                port: 443,
+                path: "/oauth/token".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_url("http://127.0.0.1:8080"),
+            Ok(Url {
+                tls: false,
+                host: "127.0.0.1".to_string(),
+                port: 8080,
+                path: "/".to_string(),
+            })
+        );
+        assert!(parse_url("ftp://example.com/").is_err());
+        assert!(parse_url("http://example.com:port/").is_err());
+    }
+
+    #[test]
+    fn token_response() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{\"access_token\":\"abc\",\"token_type\":\"Bearer\",\"expires_in\":300}";
+        let (token, expires_in) = parse_response(response).unwrap();
+        assert_eq!(token, "abc");
+        assert_eq!(expires_in.as_secs(), 300);
+
+        let response = b"HTTP/1.1 401 Unauthorized\r\n\r\n{\"error\":\"invalid_client\"}";
+        assert_eq!(
+            parse_response(response).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+    }
+}
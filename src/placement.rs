@@ -0,0 +1,130 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Places the worker threads on cores or NUMA nodes. Each worker is a
+//! complete event loop with its own sessions and queue of requests, so
+//! pinning a worker keeps its connections, buffers, and the interrupts it
+//! services on the same cores. Buffers are allocated by the worker once it is
+//! running, so they are placed on the worker's node when the kernel uses its
+//! default first-touch policy.
+
+use crate::config_file::General;
+use std::io::Error;
+
+/// The largest cpu number that can be put in an affinity mask
+pub const MAX_CPU: usize = 1024;
+
+/// The cpus which each of the workers should be pinned to, or `None` for each
+/// worker when no placement is configured. Workers are assigned to the cores
+/// or nodes in turn.
+pub fn workers(general: &General, threads: usize) -> Result<Vec<Option<Vec<usize>>>, String> {
+    if let Some(cpus) = general.cpus() {
+        return Ok((0..threads)
+            .map(|id| Some(vec![cpus[id % cpus.len()]]))
+            .collect());
+    }
+    if let Some(nodes) = general.numa_nodes() {
+        let nodes = nodes
+            .iter()
+            .map(|node| node_cpus(*node))
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok((0..threads)
+            .map(|id| Some(nodes[id % nodes.len()].clone()))
+            .collect());
+    }
+    Ok(vec![None; threads])
+}
+
+/// The cpus which belong to a NUMA node, as listed in sysfs
+pub fn node_cpus(node: usize) -> Result<Vec<usize>, String> {
+    let path = format!("/sys/devices/system/node/node{node}/cpulist");
+    let list = std::fs::read_to_string(&path)
+        .map_err(|e| format!("failed to read the cpus of numa node {node}: {path}: {e}"))?;
+    let cpus = parse_cpulist(&list).ok_or_else(|| format!("bad cpu list in {path}"))?;
+    if cpus.is_empty() {
+        return Err(format!("numa node {node} has no cpus"));
+    }
+    Ok(cpus)
+}
+
+/// Parse a cpu list in the kernel's format, such as `0-3,8-11`
+fn parse_cpulist(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last): (usize, usize) = (first.parse().ok()?, last.parse().ok()?);
+                if first > last {
+                    return None;
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+/// The cpus which the calling thread may run on, which are the only ones its
+/// threads can be pinned to
+#[cfg(target_os = "linux")]
+pub fn allowed() -> Result<Vec<usize>, Error> {
+    // SAFETY: the set is zeroed and sized for the call
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok((0..MAX_CPU)
+            .filter(|cpu| libc::CPU_ISSET(*cpu, &set))
+            .collect())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn allowed() -> Result<Vec<usize>, Error> {
+    Err(Error::new(
+        std::io::ErrorKind::Unsupported,
+        "thread placement is only available on linux",
+    ))
+}
+
+/// Pin the calling thread to the cpus
+#[cfg(target_os = "linux")]
+pub fn pin(cpus: &[usize]) -> Result<(), Error> {
+    // SAFETY: the set is zeroed before use and each cpu is below the size of
+    // the set, which is checked when the config is loaded
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for cpu in cpus {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin(_cpus: &[usize]) -> Result<(), Error> {
+    Err(Error::new(
+        std::io::ErrorKind::Unsupported,
+        "thread placement is only available on linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpulist() {
+        assert_eq!(parse_cpulist("0\n"), Some(vec![0]));
+        assert_eq!(parse_cpulist("0-3,8-9\n"), Some(vec![0, 1, 2, 3, 8, 9]));
+        assert_eq!(parse_cpulist("\n"), Some(vec![]));
+        assert_eq!(parse_cpulist("3-1"), None);
+        assert_eq!(parse_cpulist("a"), None);
+    }
+}
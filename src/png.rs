@@ -0,0 +1,94 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Embeds text in PNG images, such as the details of the run a waterfall was
+//! rendered for. Each entry is stored as a tEXt chunk, which image viewers and
+//! tools like exiftool show alongside the image.
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+use std::io::{Error, ErrorKind};
+
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+// the image header is always the first chunk and has 13 bytes of data, which
+// follow the length and type and are followed by the checksum
+const HEADER_END: usize = SIGNATURE.len() + 4 + 4 + 13 + 4;
+
+/// Add a text chunk for each keyword and text to the image in the file
+pub fn add_text(path: &str, text: &[(&str, String)]) -> Result<(), Error> {
+    let image = std::fs::read(path)?;
+    let image = insert_text(&image, text)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("{path}: not a png image")))?;
+    std::fs::write(path, image)
+}
+
+/// Insert the text chunks after the image header, where they are read before
+/// the image data
+fn insert_text(image: &[u8], text: &[(&str, String)]) -> Option<Vec<u8>> {
+    if !image.starts_with(SIGNATURE)
+        || image.len() < HEADER_END
+        || &image[(SIGNATURE.len() + 4)..(SIGNATURE.len() + 8)] != b"IHDR"
+    {
+        return None;
+    }
+    let mut output = image[0..HEADER_END].to_vec();
+    for (keyword, text) in text {
+        let mut data = latin1(keyword);
+        // keywords are at most 79 bytes
+        data.truncate(79);
+        data.push(0);
+        data.extend_from_slice(&latin1(text));
+        output.extend_from_slice(&chunk(b"tEXt", &data));
+    }
+    output.extend_from_slice(&image[HEADER_END..]);
+    Some(output)
+}
+
+/// Text chunks hold latin-1, characters outside of it are replaced
+fn latin1(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c as u32 {
+            1..=255 => c as u8,
+            _ => b'?',
+        })
+        .collect()
+}
+
+fn chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    let mut digest = CRC.digest();
+    digest.update(kind);
+    digest.update(data);
+    chunk.extend_from_slice(&digest.finalize().to_be_bytes());
+    chunk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text() {
+        // every image ends with the same empty chunk
+        let end = chunk(b"IEND", &[]);
+        assert_eq!(end, b"\0\0\0\0IEND\xae\x42\x60\x82");
+
+        let mut image = SIGNATURE.to_vec();
+        image.extend_from_slice(&chunk(b"IHDR", &[0; 13]));
+        image.extend_from_slice(&end);
+
+        let output = insert_text(&image, &[("Title", "get latency".to_string())]).unwrap();
+        let text = chunk(b"tEXt", b"Title\0get latency");
+        assert_eq!(&output[0..HEADER_END], &image[0..HEADER_END]);
+        assert_eq!(&output[HEADER_END..(HEADER_END + text.len())], text);
+        assert!(output.ends_with(&end));
+
+        assert!(insert_text(&image[1..], &[]).is_none());
+        assert_eq!(latin1("p99 \u{2264} 1ms"), b"p99 ? 1ms");
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Coordinates the prefill phase, where every key in each keyspace is stored
+//! once before the measured part of the run starts.
+
+use crate::Config;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct Prefill {
+    // the keyspace index and the index of its first key in the prefill order
+    offsets: Vec<(usize, usize)>,
+    total: usize,
+    cursor: AtomicUsize,
+    // the most keys which may be claimed but not yet stored
+    inflight: usize,
+    // workers which still have prefill requests to send or responses to read
+    workers: AtomicUsize,
+}
+
+impl Prefill {
+    /// Returns the prefill for the config if it is enabled. Only keyspaces
+    /// with a fixed set of keys can be prefilled, randomly generated keys are
+    /// skipped.
+    pub fn new(config: &Config, workers: usize) -> Option<Self> {
+        if !config.general().prefill() {
+            return None;
+        }
+
+        let mut offsets = Vec::new();
+        let mut total = 0;
+        for keyspace in config.keyspaces() {
+            if !keyspace.has_fixed_keys() {
+                warn!(
+                    "keyspace {} has random keys and will not be prefilled",
+                    keyspace.index()
+                );
+                continue;
+            }
+            offsets.push((keyspace.index(), total));
+            total += keyspace.cardinality() as usize;
+        }
+
+        let connections = config.connection().poolsize() * config.endpoints().len();
+        Some(Self {
+            offsets,
+            total,
+            cursor: AtomicUsize::new(0),
+            inflight: workers * connections * config.request().max_inflight(),
+            workers: AtomicUsize::new(workers),
+        })
+    }
+
+    /// Claims the next key to store as the keyspace index and the key index
+    /// within that keyspace. Returns `None` once all keys have been claimed.
+    pub fn next(&self) -> Option<(usize, usize)> {
+        let position = self.cursor.fetch_add(1, Ordering::Relaxed);
+        if position >= self.total {
+            return None;
+        }
+        let idx = self
+            .offsets
+            .partition_point(|(_, offset)| *offset <= position)
+            - 1;
+        let (keyspace, offset) = self.offsets[idx];
+        Some((keyspace, position - offset))
+    }
+
+    /// Returns true once all keys have been claimed.
+    pub fn is_exhausted(&self) -> bool {
+        self.cursor.load(Ordering::Relaxed) >= self.total
+    }
+
+    /// Called once by each worker after all of its prefill responses have
+    /// been received.
+    pub fn worker_done(&self) {
+        self.workers.fetch_sub(1, Ordering::Release);
+    }
+
+    /// Returns true once all workers have finished the prefill.
+    pub fn is_complete(&self) -> bool {
+        self.workers.load(Ordering::Acquire) == 0
+    }
+
+    /// The number of keys which are known to be stored. Responses are not
+    /// tracked for each key, so every key which could still be in flight is
+    /// assumed not to be.
+    pub fn stored(&self) -> usize {
+        if self.is_complete() {
+            self.total
+        } else {
+            self.progress().0.saturating_sub(self.inflight)
+        }
+    }
+
+    /// Skip the keys which were stored before a restart
+    pub fn resume(&self, stored: usize) {
+        self.cursor.store(stored.min(self.total), Ordering::Relaxed);
+    }
+
+    /// The number of keys claimed so far and the total number of keys.
+    pub fn progress(&self) -> (usize, usize) {
+        (
+            self.cursor.load(Ordering::Relaxed).min(self.total),
+            self.total,
+        )
+    }
+}
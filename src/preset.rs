@@ -0,0 +1,148 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Named workloads which expand into keyspace definitions, so that runs by
+//! different teams start from the same mix. A preset is chosen with
+//! `preset` in the `general` section. Any `[[keyspace]]` tables in the config
+//! override the fields of the preset keyspace at the same position, and
+//! tables beyond the preset's keyspaces are added to the workload.
+
+use toml::Value;
+
+/// Each preset is a set of keyspaces written as they would be in a config
+const PRESETS: &[(&str, &str)] = &[
+    (
+        // a look-aside cache with a skewed, read dominated access pattern
+        "cache-read-heavy",
+        r#"
+        [[keyspace]]
+        commands = [{ verb = "get", weight = 9 }, { verb = "set", weight = 1 }]
+        length = 32
+        cardinality = 1_000_000
+        key_distribution = { model = "zipf" }
+        values = [{ length = 128, weight = 8 }, { length = 1024, weight = 2 }]
+        "#,
+    ),
+    (
+        // a cache which is refreshed about as often as it is read
+        "cache-write-heavy",
+        r#"
+        [[keyspace]]
+        commands = [
+            { verb = "get", weight = 5 },
+            { verb = "set", weight = 4 },
+            { verb = "delete", weight = 1 },
+        ]
+        length = 32
+        cardinality = 1_000_000
+        values = [{ length = 256 }]
+        "#,
+    ),
+    (
+        // user sessions held as hashes, read whole and updated a field at a
+        // time, which expire after half an hour
+        "session-store",
+        r#"
+        [[keyspace]]
+        commands = [
+            { verb = "hgetall", weight = 6 },
+            { verb = "hget", weight = 2 },
+            { verb = "hset", weight = 2 },
+        ]
+        length = 24
+        cardinality = 100_000
+        inner_keys = [{ length = 12, cardinality = 16 }]
+        values = [{ length = 64 }]
+        ttl = 1800
+        "#,
+    ),
+];
+
+/// The names of the presets which can be chosen
+pub fn names() -> Vec<&'static str> {
+    PRESETS.iter().map(|(name, _)| *name).collect()
+}
+
+/// Replace the keyspaces in the config with those of the preset named in the
+/// general section, if there is one, applying any keyspaces in the config as
+/// overrides.
+pub fn expand(config: &mut Value) -> Result<(), String> {
+    let name = match config.get("general").and_then(|g| g.get("preset")) {
+        Some(Value::String(name)) => name.clone(),
+        Some(_) => return Err("preset must be a string".to_string()),
+        None => return Ok(()),
+    };
+    let preset = PRESETS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, preset)| preset.parse::<Value>().expect("bad preset"))
+        .ok_or_else(|| {
+            format!(
+                "unknown preset: {}, choose from: {}",
+                name,
+                names().join(", ")
+            )
+        })?;
+
+    let mut keyspaces = match preset.get("keyspace") {
+        Some(Value::Array(keyspaces)) => keyspaces.clone(),
+        _ => unreachable!(),
+    };
+    let overrides = match config.get("keyspace") {
+        Some(Value::Array(overrides)) => overrides.clone(),
+        Some(_) => return Err("keyspace must be an array of tables".to_string()),
+        None => Vec::new(),
+    };
+    for (index, table) in overrides.into_iter().enumerate() {
+        let table = match table {
+            Value::Table(table) => table,
+            _ => return Err("keyspace must be an array of tables".to_string()),
+        };
+        match keyspaces.get_mut(index) {
+            Some(Value::Table(keyspace)) => keyspace.extend(table),
+            _ => keyspaces.push(Value::Table(table)),
+        }
+    }
+
+    if let Value::Table(config) = config {
+        config.insert("keyspace".to_string(), Value::Array(keyspaces));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file::Keyspace;
+
+    #[test]
+    fn presets() {
+        for name in names() {
+            let mut config: Value = format!("[general]\npreset = \"{name}\"").parse().unwrap();
+            expand(&mut config).unwrap();
+            let keyspaces: Vec<Keyspace> = config["keyspace"].clone().try_into().unwrap();
+            assert!(!keyspaces.is_empty());
+        }
+
+        let mut config: Value = r#"
+            [general]
+            preset = "cache-read-heavy"
+            [[keyspace]]
+            cardinality = 10
+            [[keyspace]]
+            commands = [{ verb = "delete" }]
+            "#
+        .parse()
+        .unwrap();
+        expand(&mut config).unwrap();
+        let keyspaces: Vec<Keyspace> = config["keyspace"].clone().try_into().unwrap();
+        assert_eq!(keyspaces.len(), 2);
+        assert_eq!(keyspaces[0].cardinality(), 10);
+        assert_eq!(keyspaces[0].length(), 32);
+        assert_eq!(keyspaces[1].commands().len(), 1);
+
+        let mut config: Value = "[general]\npreset = \"nope\"".parse().unwrap();
+        assert!(expand(&mut config).is_err());
+    }
+}
@@ -84,6 +84,13 @@ fn main() {
                 .help("replay speed as a multiplier relative to realtime")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("loop")
+                .long("loop")
+                .help("restart from the beginning of the trace when the end is reached")
+                .takes_value(false)
+                .required(false),
+        )
         .arg(
             Arg::with_name("rate")
                 .long("rate")
@@ -91,6 +98,20 @@ fn main() {
                 .help("replay speed in requests/s")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("max-age")
+                .long("max-age")
+                .value_name("MILLISECONDS")
+                .help("drop queued requests which are older than this instead of sending them late")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("queue-capacity")
+                .long("queue-capacity")
+                .value_name("INT")
+                .help("requests held in the work queue before reading the trace waits")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("poolsize")
                 .long("poolsize")
@@ -151,7 +172,19 @@ fn main() {
         .unwrap_or("1")
         .parse()
         .expect("invalid value for 'workers'");
+    let max_age: Option<Duration> = matches
+        .value_of("max-age")
+        .map(|v| Duration::from_millis(v.parse().expect("invalid value for 'max-age'")));
+    let queue_capacity: usize = matches
+        .value_of("queue-capacity")
+        .unwrap_or("1048576")
+        .parse()
+        .expect("invalid value for 'queue-capacity'");
+    if queue_capacity == 0 {
+        fatal!("invalid configuration: 'queue-capacity' must be at least one");
+    }
     let binary = matches.is_present("binary-trace");
+    let looping = matches.is_present("loop");
 
     // configure tls connector
     let key = matches.value_of("tls-key");
@@ -186,10 +219,23 @@ fn main() {
     // lookup socket address
     let sockaddr = endpoint.to_socket_addrs().unwrap().next().unwrap();
 
-    // initialize work queue
-    let work = Queue::with_capacity(1024 * 1024); // arbitrarily large
+    // initialize work queue, once it is full reading the trace waits for the
+    // workers to catch up
+    let work = Queue::with_capacity(queue_capacity);
+
+    let request_heatmap = Some(Arc::new(Latency::from(
+        Heatmap::new(
+            0,
+            10,
+            30,
+            Duration::from_secs(60),
+            Duration::from_millis(1000),
+        )
+        .unwrap(),
+    )));
 
-    let request_heatmap = Some(Arc::new(
+    // time requests spend in the work queue before they are sent
+    let queue_delay_heatmap = Some(Arc::new(Latency::from(
         Heatmap::new(
             0,
             10,
@@ -198,11 +244,12 @@ fn main() {
             Duration::from_millis(1000),
         )
         .unwrap(),
-    ));
+    )));
 
     // spawn admin
     let mut admin = Admin::for_replay(None, log);
     admin.set_request_heatmap(request_heatmap.clone());
+    admin.set_queue_delay_heatmap(queue_delay_heatmap.clone());
     let _admin_thread = std::thread::spawn(move || admin.run());
 
     // spawn workers
@@ -212,7 +259,9 @@ fn main() {
             poolsize,
             tls.clone(),
             work.clone(),
+            max_age,
             request_heatmap.clone(),
+            queue_delay_heatmap.clone(),
         );
         std::thread::spawn(move || worker.run());
     }
@@ -224,7 +273,7 @@ fn main() {
         Box::new(SpeedController::new(speed))
     };
 
-    let mut generator = Generator::new(trace, work, binary, controller);
+    let mut generator = Generator::new(trace, work, binary, looping, controller);
     generator.run()
 }
 
@@ -232,17 +281,12 @@ pub trait Controller {
     fn delay(&mut self, ts: u64);
 }
 
+#[derive(Default)]
 pub struct GeneratorStats {
     sent: usize,
     skip: usize,
 }
 
-impl Default for GeneratorStats {
-    fn default() -> Self {
-        Self { sent: 0, skip: 0 }
-    }
-}
-
 pub struct RateController {
     ratelimiter: Ratelimiter,
 }
@@ -318,15 +362,22 @@ pub struct Generator {
     stats: GeneratorStats,
     controller: Box<dyn Controller>,
     trace: String,
-    work: Queue<Request>,
+    work: Queue<WorkItem>,
     binary: bool,
+    looping: bool,
+    // added to trace timestamps so they keep increasing when looping
+    ts_offset: u64,
+    // the lowest and highest timestamps of this pass, which may be out of
+    // order in the trace
+    ts_range: Option<(u64, u64)>,
 }
 
 impl Generator {
     pub fn new(
         trace: &str,
-        work: Queue<Request>,
+        work: Queue<WorkItem>,
         binary: bool,
+        looping: bool,
         controller: Box<dyn Controller>,
     ) -> Self {
         Self {
@@ -335,17 +386,71 @@ impl Generator {
             trace: trace.to_string(),
             work,
             binary,
+            looping,
+            ts_offset: 0,
+            ts_range: None,
         }
     }
 
     pub fn run(&mut self) {
-        if self.binary {
-            self.binary()
-        } else {
-            self.ascii()
+        loop {
+            let sent = self.stats.sent;
+            if self.binary {
+                self.binary()
+            } else {
+                self.ascii()
+            }
+
+            info!(
+                "end of trace: sent: {} skip: {}",
+                self.stats.sent, self.stats.skip
+            );
+
+            if !self.looping {
+                return;
+            }
+
+            // a trace without any requests would otherwise loop forever
+            if self.stats.sent == sent {
+                fatal!("no requests were sent from the trace: {}", self.trace);
+            }
+
+            // the next pass starts one second after the end of this one
+            if let Some((ts_min, ts_max)) = self.ts_range.take() {
+                self.ts_offset += ts_max.saturating_sub(ts_min) + 1;
+            }
         }
     }
 
+    fn delay(&mut self, ts: u64) {
+        let (ts_min, ts_max) = self.ts_range.get_or_insert((ts, ts));
+        *ts_min = (*ts_min).min(ts);
+        *ts_max = (*ts_max).max(ts);
+        self.controller.delay(ts + self.ts_offset);
+    }
+
+    /// Add the request to the work queue, waiting for room if it is full.
+    /// Waiting holds back the trace, so the time spent is recorded.
+    fn enqueue(&mut self, request: Request) {
+        let mut item = WorkItem {
+            request,
+            timestamp: Instant::now(),
+        };
+        if let Err(i) = self.work.push(item) {
+            REQUEST_QUEUE_FULL.increment();
+            let start = Instant::now();
+            item = i;
+            while let Err(i) = self.work.push(item) {
+                item = i;
+            }
+            let waited = Instant::now() - start;
+            REQUEST_QUEUE_WAIT.add((waited.as_secs_f64() * 1_000_000.0) as u64);
+        }
+        REQUEST_BACKLOG.increment();
+
+        self.stats.sent += 1;
+    }
+
     fn ascii(&mut self) {
         // open files
         let zlog = File::open(&self.trace).expect("failed to open input zlog");
@@ -364,7 +469,7 @@ impl Generator {
             let vlen: usize = parts[3].parse().expect("failed to parse vlen");
             let ttl: u32 = parts[6].parse().expect("failed to parse ttl");
 
-            let mut request = match verb {
+            let request = match verb {
                 "get" => Request::Get { key },
                 "gets" => Request::Gets { key },
                 "set" => Request::Set { key, vlen, ttl },
@@ -377,13 +482,8 @@ impl Generator {
                 }
             };
 
-            self.controller.delay(ts);
-
-            while let Err(r) = self.work.push(request) {
-                request = r;
-            }
-
-            self.stats.sent += 1;
+            self.delay(ts);
+            self.enqueue(request);
         }
     }
 
@@ -408,7 +508,7 @@ impl Generator {
 
             let key = format!("{:01$}", keyid, klen as usize);
 
-            let mut request = match op {
+            let request = match op {
                 1 => Request::Get { key },
                 2 => Request::Gets { key },
                 3 => Request::Set { key, vlen, ttl },
@@ -421,13 +521,8 @@ impl Generator {
                 }
             };
 
-            self.controller.delay(ts);
-
-            while let Err(r) = self.work.push(request) {
-                request = r;
-            }
-
-            self.stats.sent += 1;
+            self.delay(ts);
+            self.enqueue(request);
         }
     }
 }
@@ -441,8 +536,10 @@ struct Worker {
     sessions: Slab<Session>,
     ready_queue: VecDeque<Token>,
     poll: Poll,
-    work: Queue<Request>,
-    request_heatmap: Option<Arc<Heatmap>>,
+    work: Queue<WorkItem>,
+    max_age: Option<Duration>,
+    request_heatmap: Option<Arc<Latency>>,
+    queue_delay_heatmap: Option<Arc<Latency>>,
     rng: rand_xoshiro::Xoshiro256PlusPlus,
 }
 
@@ -451,8 +548,10 @@ impl Worker {
         addr: SocketAddr,
         poolsize: usize,
         tls: Option<SslConnector>,
-        work: Queue<Request>,
-        request_heatmap: Option<Arc<Heatmap>>,
+        work: Queue<WorkItem>,
+        max_age: Option<Duration>,
+        request_heatmap: Option<Arc<Latency>>,
+        queue_delay_heatmap: Option<Arc<Latency>>,
     ) -> Self {
         let poll = mio::Poll::new().unwrap();
 
@@ -488,11 +587,34 @@ impl Worker {
             ready_queue,
             poll,
             work,
+            max_age,
             request_heatmap,
+            queue_delay_heatmap,
             rng: rng(),
         }
     }
 
+    /// Take the next request from the work queue, dropping any which have
+    /// been queued for longer than the maximum age.
+    fn next_request(&mut self) -> Option<Request> {
+        while let Some(item) = self.work.pop() {
+            REQUEST_BACKLOG.decrement();
+            let now = Instant::now();
+            if let Some(ref heatmap) = self.queue_delay_heatmap {
+                let us = ((now - item.timestamp).as_secs_f64() * 1_000_000.0) as u64;
+                heatmap.increment(now, us, 1);
+            }
+            if let Some(max_age) = self.max_age {
+                if now - item.timestamp > max_age {
+                    REQUEST_EXPIRED.increment();
+                    continue;
+                }
+            }
+            return Some(item.request);
+        }
+        None
+    }
+
     pub fn send_request(&mut self, token: Token, request: Request) {
         let session = self.sessions.get_mut(token.0).expect("bad token");
         REQUEST.increment();
@@ -551,7 +673,7 @@ impl Worker {
         let mut events = Events::with_capacity(1024);
         loop {
             if let Some(token) = self.ready_queue.pop_front() {
-                if let Some(request) = self.work.pop() {
+                if let Some(request) = self.next_request() {
                     self.send_request(token, request);
                 } else {
                     self.ready_queue.push_front(token);
@@ -605,7 +727,7 @@ impl Worker {
                                     let us = (elapsed.as_secs_f64() * 1_000_000.0) as u64;
                                     heatmap.increment(now, us, 1);
                                 }
-                                if let Some(request) = self.work.pop() {
+                                if let Some(request) = self.next_request() {
                                     self.send_request(token, request);
                                 } else {
                                     self.ready_queue.push_back(token);
@@ -642,6 +764,12 @@ impl Worker {
     }
 }
 
+/// A request along with the time it was queued
+pub struct WorkItem {
+    request: Request,
+    timestamp: Instant,
+}
+
 pub enum Request {
     Get { key: String },
     Gets { key: String },
@@ -675,7 +803,7 @@ fn decode(buffer: &mut Session) -> Result<(), ParseError> {
     ] {
         let bytes = response.as_bytes();
         if buf.len() >= bytes.len() && &buf[0..bytes.len()] == bytes {
-            let _ = buffer.consume(bytes.len());
+            buffer.consume(bytes.len());
             return Ok(());
         }
     }
@@ -685,7 +813,7 @@ fn decode(buffer: &mut Session) -> Result<(), ParseError> {
         if response_end > 0 {
             RESPONSE_HIT.increment();
         }
-        let _ = buffer.consume(response_end + 5);
+        buffer.consume(response_end + 5);
         return Ok(());
     }
 
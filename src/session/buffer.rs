@@ -4,6 +4,7 @@
 
 //! A very simple buffer type that can be replaced in the future.
 
+use crate::config_file::BufferGrowth;
 use core::borrow::{Borrow, BorrowMut};
 
 /// A growable byte buffer
@@ -12,6 +13,9 @@ pub struct Buffer {
     read_offset: usize,
     write_offset: usize,
     target_capacity: usize,
+    growth: BufferGrowth,
+    // keep the grown size instead of shrinking back to the target capacity
+    retain: bool,
 }
 
 impl Buffer {
@@ -29,9 +33,19 @@ impl Buffer {
             read_offset: 0,
             write_offset: 0,
             target_capacity: capacity,
+            growth: BufferGrowth::Double,
+            retain: false,
         }
     }
 
+    /// Choose how the buffer grows, and whether it shrinks back to its
+    /// initial capacity once drained.
+    pub fn with_growth(mut self, growth: BufferGrowth, retain: bool) -> Self {
+        self.growth = growth;
+        self.retain = retain;
+        self
+    }
+
     /// Returns the amount of space available to write into the buffer without
     /// reallocating.
     pub fn available_capacity(&self) -> usize {
@@ -48,19 +62,21 @@ impl Buffer {
         self.len() == 0
     }
 
-    // TODO(bmartin): we're currently relying on the resize behaviors of the
-    // underlying `Vec` storage. This currently results in growth to the next
-    // nearest power of two. Effectively resulting in buffer doubling when a
-    // resize is required.
     /// Reserve room for `additional` bytes in the buffer. This may reserve more
-    /// space than requested to avoid frequent allocations. If the buffer
-    /// already has sufficient available capacity, this is a no-op.
+    /// space than requested to avoid frequent allocations, either growing to
+    /// the next power of two or in steps of the initial capacity. If the
+    /// buffer already has sufficient available capacity, this is a no-op.
     pub fn reserve(&mut self, additional: usize) {
         // let old_cap = self.buffer.capacity();
         let needed = additional.saturating_sub(self.available_capacity());
         if needed > 0 {
             let current = self.buffer.len();
-            let target = (current + needed).next_power_of_two();
+            let target = match self.growth {
+                BufferGrowth::Linear if self.target_capacity > 0 => {
+                    current + needed.div_ceil(self.target_capacity) * self.target_capacity
+                }
+                _ => (current + needed).next_power_of_two(),
+            };
             self.buffer.resize(target, 0);
             // SESSION_BUFFER_BYTE.add((self.buffer.capacity() - old_cap) as _);
         }
@@ -88,6 +104,10 @@ impl Buffer {
         self.write_offset -= self.read_offset;
         self.read_offset = 0;
 
+        if self.retain {
+            return;
+        }
+
         // determine the target size of the buffer
         let target_size = if self.len() * 2 > self.buffer.len() {
             // buffer too full to shrink, early return
@@ -138,8 +158,28 @@ impl Drop for Buffer {
 #[cfg(test)]
 mod tests {
     use super::Buffer;
+    use crate::config_file::BufferGrowth;
     use std::borrow::Borrow;
 
+    #[test]
+    // linear growth adds multiples of the initial capacity, and a retained
+    // buffer keeps its size once drained
+    fn growth() {
+        let mut buffer = Buffer::with_capacity(100).with_growth(BufferGrowth::Linear, true);
+        buffer.extend_from_slice(&[0; 250]);
+        assert_eq!(buffer.len(), 250);
+        assert_eq!(buffer.available_capacity(), 50);
+        buffer.consume(250);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.available_capacity(), 300);
+
+        let mut buffer = Buffer::with_capacity(100);
+        buffer.extend_from_slice(&[0; 250]);
+        assert_eq!(buffer.available_capacity(), 6);
+        buffer.consume(250);
+        assert_eq!(buffer.available_capacity(), 100);
+    }
+
     #[test]
     // test buffer initialization with various capacities
     fn new() {
@@ -2,11 +2,12 @@
 // Licensed under the Apache License, Version 2.0
 // http://www.apache.org/licenses/LICENSE-2.0
 
-//! This crate provides buffered TCP sessions with or without TLS which can be
-//! used with [`::mio`]. TLS/SSL is provided by BoringSSL with the [`::boring`]
+//! This crate provides buffered TCP and Unix domain socket sessions with or
+//! without TLS which can be used with [`::mio`]. TLS/SSL is provided by BoringSSL with the [`::boring`]
 //! crate.
 
 mod buffer;
+mod proxy;
 mod stream;
 mod tcp_stream;
 
@@ -14,9 +15,14 @@ use crate::metrics::*;
 use crate::*;
 use boring::ssl::SslSession;
 
+use crate::config::VersionedWrite;
+use crate::config_file::{Connection, Verb};
 use std::borrow::{Borrow, BorrowMut};
+use std::collections::{HashSet, VecDeque};
 use std::io::{BufRead, ErrorKind, Read, Write};
 use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
 
 use boring::ssl::{MidHandshakeSslStream, SslStream};
 use mio::event::Source;
@@ -27,6 +33,25 @@ use stream::Stream;
 
 pub use tcp_stream::TcpStream;
 
+/// The address of a target, either a TCP socket address, the path of a Unix
+/// domain socket, or a UDP socket address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    Udp(SocketAddr),
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix://{}", path.display()),
+            Self::Udp(addr) => write!(f, "udp://{addr}"),
+        }
+    }
+}
+
 pub struct Session {
     token: Token,
     stream: Stream,
@@ -35,12 +60,75 @@ pub struct Session {
     min_capacity: usize,
     max_capacity: usize,
     connected: bool,
+    closed: bool,
     // hold current interest set
     interest: Interest,
     /// A timestamp which is used to calculate response latency
     timestamp: Instant,
     /// the number of outstanding responses
     outstanding: usize,
+    /// the commands of the outstanding requests, in the order they were sent,
+    /// and whether each used an edge-case key
+    commands: VecDeque<(Verb, bool)>,
+    /// when each outstanding request was sent and when it was scheduled
+    sent: VecDeque<(Instant, Instant)>,
+    /// the keys of the outstanding gets, recorded when responses are validated,
+    /// with the lowest version of each value the get may return
+    keys: VecDeque<Vec<(Vec<u8>, u32)>>,
+    /// the versioned writes of the outstanding stores, when values are mutated
+    writes: VecDeque<Option<VersionedWrite>>,
+    /// the key, value, and ttl written by the last step of a command sent in
+    /// several steps, such as a compare-and-swap, while its first step is
+    /// outstanding
+    next_step: Option<(Vec<u8>, Vec<u8>, usize)>,
+    /// the keyspace and index of the last command sent, used to sequence
+    /// commands when a keyspace has a transition matrix
+    previous_command: Option<(usize, usize)>,
+    /// the shard index and shard count when keys have connection affinity
+    shard: Option<(usize, usize)>,
+    /// the connection's slot in the worker's pool, kept across reconnects
+    slot: usize,
+    /// the connection's index in the client-wide pool, kept across reconnects
+    connection: usize,
+    /// the virtual host chosen for the connection, when hosts are templated
+    vhost: Option<usize>,
+    /// the label a load balancer pins the connection by, when testing
+    /// stickiness
+    label: Option<String>,
+    /// set once the label has been sent
+    label_sent: bool,
+    /// the backend which served the first response naming one
+    backend: Option<String>,
+    /// when the TLS handshake started, used to calculate handshake latency
+    handshake_start: Option<Instant>,
+    /// set when the session is closing to reconnect, no more requests are sent
+    /// once it is draining
+    draining: bool,
+    /// set when a request sent as a datagram has timed out, the responses
+    /// read after that are late
+    timed_out: bool,
+    /// set once a protocol upgrade has been requested on the session
+    upgraded: bool,
+    /// when the connection started to warm up after reconnecting, cleared
+    /// once it takes a full share of the requests
+    warmup_start: Option<Instant>,
+    /// set when a response asked the client to wait before retrying
+    retry_after: Option<Instant>,
+    /// set while the request which sets up the connection, such as a login,
+    /// is outstanding
+    setting_up: bool,
+    /// state kept between the steps of a setup, such as the nonce sent in an
+    /// authentication exchange
+    setup_state: Option<Vec<u8>>,
+    /// the cookies set by the server on this connection, by name
+    cookies: Vec<(String, String)>,
+    /// the digests of the scripts to send by their source, as the server on
+    /// this connection has answered NOSCRIPT since they were last sent
+    stale_scripts: HashSet<String>,
+    /// when the connection was established, used to record its lifetime
+    established: Option<Instant>,
+    /// when the connection has lived out its lifetime and should reconnect
+    expires: Option<Instant>,
 }
 
 impl std::fmt::Debug for Session {
@@ -83,19 +171,94 @@ impl Session {
         Self::new(Stream::handshaking(stream), min_capacity, max_capacity)
     }
 
+    /// Create a new `Session` representing a plain `TcpStream` with buffers
+    /// sized and grown according to the connection config
+    pub fn plain_with_buffers(stream: TcpStream, config: &Connection) -> Self {
+        Self::with_buffers(Stream::plain(stream), config)
+    }
+
+    /// Create a new `Session` representing a negotiated `SslStream` with
+    /// buffers sized and grown according to the connection config
+    pub fn tls_with_buffers(stream: SslStream<TcpStream>, config: &Connection) -> Self {
+        Self::with_buffers(Stream::tls(stream), config)
+    }
+
+    /// Create a new `Session` representing a `MidHandshakeSslStream` with
+    /// buffers sized and grown according to the connection config
+    pub fn handshaking_with_buffers(
+        stream: MidHandshakeSslStream<TcpStream>,
+        config: &Connection,
+    ) -> Self {
+        Self::with_buffers(Stream::handshaking(stream), config)
+    }
+
+    fn with_buffers(stream: Stream, config: &Connection) -> Self {
+        let growth = config.buffer_growth();
+        let retain = config.buffer_retain();
+        Self::from_buffers(
+            stream,
+            Buffer::with_capacity(config.read_buffer()).with_growth(growth, retain),
+            Buffer::with_capacity(config.write_buffer()).with_growth(growth, retain),
+            config.read_buffer(),
+            config.max_read_buffer(),
+        )
+    }
+
     /// Create a new `Session`
     fn new(stream: Stream, min_capacity: usize, max_capacity: usize) -> Self {
+        Self::from_buffers(
+            stream,
+            Buffer::with_capacity(min_capacity),
+            Buffer::with_capacity(min_capacity),
+            min_capacity,
+            max_capacity,
+        )
+    }
+
+    fn from_buffers(
+        stream: Stream,
+        read_buffer: Buffer,
+        write_buffer: Buffer,
+        min_capacity: usize,
+        max_capacity: usize,
+    ) -> Self {
         Self {
             token: Token(0),
             connected: false,
+            closed: false,
             stream,
-            read_buffer: Buffer::with_capacity(min_capacity),
-            write_buffer: Buffer::with_capacity(min_capacity),
+            read_buffer,
+            write_buffer,
             min_capacity,
             max_capacity,
             interest: Interest::WRITABLE,
             timestamp: Instant::now(),
             outstanding: 0,
+            commands: VecDeque::new(),
+            sent: VecDeque::new(),
+            keys: VecDeque::new(),
+            writes: VecDeque::new(),
+            next_step: None,
+            previous_command: None,
+            shard: None,
+            slot: 0,
+            connection: 0,
+            vhost: None,
+            label: None,
+            label_sent: false,
+            backend: None,
+            handshake_start: None,
+            draining: false,
+            timed_out: false,
+            upgraded: false,
+            warmup_start: None,
+            retry_after: None,
+            setting_up: false,
+            setup_state: None,
+            cookies: Vec::new(),
+            stale_scripts: HashSet::new(),
+            established: None,
+            expires: None,
         }
     }
 
@@ -182,6 +345,48 @@ impl Session {
     /// Closes the session and the underlying stream.
     pub fn close(&mut self) {
         self.stream.close();
+        self.closed = true;
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// The socket of a plaintext session, for sending and receiving outside
+    /// of the stream
+    pub fn plain_fd(&self) -> Option<RawFd> {
+        self.stream.plain_fd()
+    }
+
+    /// The bytes in the write buffer which have not been sent yet
+    pub fn unsent(&self) -> &[u8] {
+        self.write_buffer.borrow()
+    }
+
+    /// Remove bytes which were sent outside of `flush` from the write buffer
+    pub fn mark_sent(&mut self, bytes: usize) {
+        SESSION_SEND.increment();
+        SESSION_SEND_BYTE.add(bytes as _);
+        self.write_buffer.consume(bytes);
+    }
+
+    /// The free space at the end of the read buffer, which is grown when it
+    /// is full, for receiving outside of `fill_buf`
+    pub fn read_space(&mut self) -> Result<&mut [u8], std::io::Error> {
+        if self.read_buffer.len() >= self.max_capacity {
+            return Err(std::io::Error::other("buffer full"));
+        }
+        if self.read_buffer.available_capacity() == 0 {
+            self.read_buffer.reserve(self.min_capacity);
+        }
+        Ok(self.read_buffer.borrow_mut())
+    }
+
+    /// Add bytes which were received into the read space to the read buffer
+    pub fn mark_received(&mut self, bytes: usize) {
+        SESSION_RECV.increment();
+        SESSION_RECV_BYTE.add(bytes as _);
+        self.read_buffer.increase_len(bytes);
     }
 
     /// Returns the number of bytes in the read buffer
@@ -211,7 +416,7 @@ impl Session {
         self.read_buffer.borrow()
     }
 
-    pub fn peer_addr(&self) -> Result<SocketAddr, std::io::Error> {
+    pub fn peer_addr(&self) -> Result<Endpoint, std::io::Error> {
         self.stream.peer_addr()
     }
 
@@ -234,6 +439,270 @@ impl Session {
     pub fn outstanding(&self) -> usize {
         self.outstanding
     }
+
+    /// Record the command of a request which was sent on the session, and
+    /// whether it used an edge-case key
+    pub fn push_command(&mut self, verb: Verb, edge_case: bool) {
+        self.commands.push_back((verb, edge_case));
+    }
+
+    /// Take the command of the oldest outstanding request, and whether it used
+    /// an edge-case key
+    pub fn pop_command(&mut self) -> Option<(Verb, bool)> {
+        self.commands.pop_front()
+    }
+
+    /// Record when a request was sent on the session and when it was
+    /// scheduled to be sent
+    pub fn push_sent(&mut self, sent: Instant, scheduled: Instant) {
+        self.sent.push_back((sent, scheduled));
+    }
+
+    /// When the oldest outstanding request was sent
+    pub fn oldest_sent(&self) -> Option<Instant> {
+        self.sent.front().map(|(sent, _)| *sent)
+    }
+
+    /// Take the send and scheduled times of the oldest outstanding request
+    pub fn pop_sent(&mut self) -> Option<(Instant, Instant)> {
+        self.sent.pop_front()
+    }
+
+    /// The command of the oldest outstanding request
+    pub fn command(&self) -> Option<Verb> {
+        self.commands.front().map(|(verb, _)| *verb)
+    }
+
+    /// The commands of the outstanding requests, oldest first
+    pub fn commands(&self) -> impl Iterator<Item = Verb> + '_ {
+        self.commands.iter().map(|(verb, _)| *verb)
+    }
+
+    /// Whether the oldest outstanding request used an edge-case key
+    pub fn is_edge_case(&self) -> bool {
+        self.commands
+            .front()
+            .is_some_and(|(_, edge_case)| *edge_case)
+    }
+
+    /// Record the keys of a get which was sent on the session
+    pub fn push_keys(&mut self, keys: Vec<(Vec<u8>, u32)>) {
+        self.keys.push_back(keys);
+    }
+
+    /// The keys of the oldest outstanding get
+    pub fn keys(&self) -> &[(Vec<u8>, u32)] {
+        self.keys.front().map(|keys| keys.as_slice()).unwrap_or(&[])
+    }
+
+    /// Take the keys of the oldest outstanding get
+    pub fn pop_keys(&mut self) -> Option<Vec<(Vec<u8>, u32)>> {
+        self.keys.pop_front()
+    }
+
+    /// Record the write of a store which was sent on the session
+    pub fn push_write(&mut self, write: Option<VersionedWrite>) {
+        self.writes.push_back(write);
+    }
+
+    /// Take the write of the oldest outstanding store
+    pub fn pop_write(&mut self) -> Option<VersionedWrite> {
+        self.writes.pop_front().flatten()
+    }
+
+    /// Record the key, value, and ttl for the last step of the command just
+    /// sent, which is written once the reply to its first step arrives
+    pub fn set_next_step(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: usize) {
+        self.next_step = Some((key, value, ttl));
+    }
+
+    /// Whether the outstanding command has a step left to send
+    pub fn has_next_step(&self) -> bool {
+        self.next_step.is_some()
+    }
+
+    pub fn take_next_step(&mut self) -> Option<(Vec<u8>, Vec<u8>, usize)> {
+        self.next_step.take()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    pub fn set_draining(&mut self) {
+        self.draining = true;
+    }
+
+    pub fn is_timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// Stop sending on the session, and count the responses still to come as
+    /// late
+    pub fn set_timed_out(&mut self) {
+        self.timed_out = true;
+        self.draining = true;
+    }
+
+    pub fn is_upgraded(&self) -> bool {
+        self.upgraded
+    }
+
+    pub fn set_upgraded(&mut self) {
+        self.upgraded = true;
+    }
+
+    pub fn previous_command(&self) -> Option<(usize, usize)> {
+        self.previous_command
+    }
+
+    pub fn set_previous_command(&mut self, keyspace: usize, command: usize) {
+        self.previous_command = Some((keyspace, command));
+    }
+
+    pub fn shard(&self) -> Option<(usize, usize)> {
+        self.shard
+    }
+
+    pub fn set_shard(&mut self, index: usize, count: usize) {
+        self.shard = Some((index, count));
+    }
+
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    pub fn set_slot(&mut self, slot: usize) {
+        self.slot = slot;
+    }
+
+    pub fn connection(&self) -> usize {
+        self.connection
+    }
+
+    pub fn set_connection(&mut self, connection: usize) {
+        self.connection = connection;
+    }
+
+    pub fn vhost(&self) -> Option<usize> {
+        self.vhost
+    }
+
+    pub fn set_vhost(&mut self, vhost: Option<usize>) {
+        self.vhost = vhost;
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn set_label(&mut self, label: Option<String>) {
+        self.label = label;
+    }
+
+    pub fn is_label_sent(&self) -> bool {
+        self.label_sent
+    }
+
+    pub fn set_label_sent(&mut self) {
+        self.label_sent = true;
+    }
+
+    /// Whether the backend is the one which served the connection's first
+    /// response, which is remembered if this is the first
+    pub fn check_backend(&mut self, backend: &str) -> bool {
+        match self.backend {
+            Some(ref first) => first == backend,
+            None => {
+                self.backend = Some(backend.to_string());
+                true
+            }
+        }
+    }
+
+    pub fn handshake_start(&self) -> Option<Instant> {
+        self.handshake_start
+    }
+
+    pub fn set_handshake_start(&mut self, timestamp: Instant) {
+        self.handshake_start = Some(timestamp);
+    }
+
+    pub fn warmup_start(&self) -> Option<Instant> {
+        self.warmup_start
+    }
+
+    pub fn set_warmup_start(&mut self, timestamp: Option<Instant>) {
+        self.warmup_start = timestamp;
+    }
+
+    /// The earliest time the server asked for the next request to be sent
+    pub fn retry_after(&self) -> Option<Instant> {
+        self.retry_after
+    }
+
+    pub fn set_retry_after(&mut self, timestamp: Option<Instant>) {
+        self.retry_after = timestamp;
+    }
+
+    pub fn is_setting_up(&self) -> bool {
+        self.setting_up
+    }
+
+    pub fn set_setting_up(&mut self, setting_up: bool) {
+        self.setting_up = setting_up;
+    }
+
+    pub fn setup_state(&self) -> Option<&[u8]> {
+        self.setup_state.as_deref()
+    }
+
+    pub fn set_setup_state(&mut self, state: Option<Vec<u8>>) {
+        self.setup_state = state;
+    }
+
+    pub fn cookies(&self) -> &[(String, String)] {
+        &self.cookies
+    }
+
+    /// Store a cookie, replacing any with the same name. A cookie without a
+    /// value is removed.
+    pub fn set_cookie(&mut self, name: &str, value: Option<&str>) {
+        self.cookies.retain(|(n, _)| n != name);
+        if let Some(value) = value {
+            self.cookies.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    /// Mark the scripts as unknown to the server on this connection
+    pub fn set_scripts_stale<'a>(&mut self, digests: impl Iterator<Item = &'a str>) {
+        self.stale_scripts
+            .extend(digests.map(|digest| digest.to_string()));
+    }
+
+    /// Whether the script is unknown to the server on this connection, which
+    /// it no longer is once sent by its source
+    pub fn take_stale_script(&mut self, digest: &str) -> bool {
+        self.stale_scripts.remove(digest)
+    }
+
+    pub fn established(&self) -> Option<Instant> {
+        self.established
+    }
+
+    pub fn set_established(&mut self, timestamp: Instant) {
+        self.established = Some(timestamp);
+    }
+
+    /// When the connection should be closed to reconnect, if it has a limited
+    /// lifetime
+    pub fn expires(&self) -> Option<Instant> {
+        self.expires
+    }
+
+    pub fn set_expires(&mut self, timestamp: Option<Instant>) {
+        self.expires = timestamp;
+    }
 }
 
 impl Read for Session {
@@ -254,8 +723,8 @@ impl BufRead for Session {
         SESSION_RECV.increment();
         let mut total_bytes = 0;
         loop {
-            if self.read_buffer.len() == self.max_capacity {
-                return Err(std::io::Error::new(ErrorKind::Other, "buffer full"));
+            if self.read_buffer.len() >= self.max_capacity {
+                return Err(std::io::Error::other("buffer full"));
             }
 
             // reserve additional space in the buffer if needed
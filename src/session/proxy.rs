@@ -0,0 +1,272 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Establishes tunnels to the target through a SOCKS5 or HTTP CONNECT proxy.
+//!
+//! The tunnel is negotiated with a blocking socket before the stream is handed
+//! to the event loop, so the worker waits for up to the proxy timeout while a
+//! connection is being made. Once the tunnel is up the stream behaves exactly
+//! like a direct connection to the target.
+
+use super::TcpStream;
+use crate::codec::base64;
+use crate::config_file::{Proxy, ProxyProtocol};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Instant;
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_PASSWORD_AUTH: u8 = 2;
+const SOCKS_NO_ACCEPTABLE_AUTH: u8 = 0xFF;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_IPV4: u8 = 1;
+const SOCKS_DOMAIN: u8 = 3;
+const SOCKS_IPV6: u8 = 4;
+
+impl TcpStream {
+    /// Connects to the target through the proxy. The returned stream is
+    /// non-blocking and carries the target as its endpoint.
+    pub fn connect_proxied(proxy: &Proxy, target: SocketAddr) -> Result<Self, Error> {
+        let deadline = Instant::now() + proxy.timeout();
+        let address = proxy
+            .address()
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "proxy address did not resolve"))?;
+        let mut stream = std::net::TcpStream::connect_timeout(&address, proxy.timeout())?;
+
+        // each read and write may wait for whatever remains of the timeout
+        let remaining = || {
+            deadline
+                .checked_duration_since(Instant::now())
+                .filter(|remaining| !remaining.is_zero())
+                .ok_or_else(|| Error::new(ErrorKind::TimedOut, "proxy handshake timed out"))
+        };
+        let mut timed = TimedStream {
+            stream: &mut stream,
+            remaining: &remaining,
+        };
+        match proxy.protocol() {
+            ProxyProtocol::Socks5 => socks5(&mut timed, proxy.credentials(), target)?,
+            ProxyProtocol::Http => http_connect(&mut timed, proxy.credentials(), target)?,
+        }
+
+        stream.set_nonblocking(true)?;
+        Ok(Self::tunneled(
+            mio::net::TcpStream::from_std(stream),
+            target,
+        ))
+    }
+}
+
+/// A blocking stream where each operation must complete before the deadline
+struct TimedStream<'a, F> {
+    stream: &'a mut std::net::TcpStream,
+    remaining: &'a F,
+}
+
+impl<F: Fn() -> Result<std::time::Duration, Error>> Read for TimedStream<'_, F> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.stream.set_read_timeout(Some((self.remaining)()?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl<F: Fn() -> Result<std::time::Duration, Error>> Write for TimedStream<'_, F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.stream.set_write_timeout(Some((self.remaining)()?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.stream.flush()
+    }
+}
+
+/// Negotiate a SOCKS5 tunnel to the target, as described in RFC 1928, with
+/// username and password authentication from RFC 1929.
+fn socks5<S: Read + Write>(
+    stream: &mut S,
+    credentials: Option<(&str, &str)>,
+    target: SocketAddr,
+) -> Result<(), Error> {
+    let method = if credentials.is_some() {
+        SOCKS_PASSWORD_AUTH
+    } else {
+        SOCKS_NO_AUTH
+    };
+    stream.write_all(&[SOCKS_VERSION, 1, method])?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "proxy is not a socks5 proxy",
+        ));
+    }
+    match (reply[1], credentials) {
+        (SOCKS_NO_AUTH, _) => {}
+        (SOCKS_PASSWORD_AUTH, Some((username, password))) => {
+            // each is sent with a single byte length, which is checked when
+            // the config is loaded
+            let length = |field: &str| {
+                u8::try_from(field.len()).map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        "socks5 usernames and passwords must be at most 255 bytes",
+                    )
+                })
+            };
+            let mut request = vec![1, length(username)?];
+            request.extend_from_slice(username.as_bytes());
+            request.push(length(password)?);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request)?;
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0 {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "socks5 proxy rejected the credentials",
+                ));
+            }
+        }
+        (SOCKS_NO_ACCEPTABLE_AUTH, _) | (SOCKS_PASSWORD_AUTH, None) => {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "socks5 proxy requires authentication",
+            ));
+        }
+        (method, _) => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("socks5 proxy chose unsupported method: {}", method),
+            ));
+        }
+    }
+
+    stream.write_all(&socks5_connect_request(target))?;
+    let mut header = [0; 4];
+    stream.read_exact(&mut header)?;
+    if header[1] != 0 {
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("socks5 proxy failed to connect with reply: {}", header[1]),
+        ));
+    }
+    // the address the proxy bound for the tunnel is not needed
+    let bound = match header[3] {
+        SOCKS_IPV4 => 4,
+        SOCKS_IPV6 => 16,
+        SOCKS_DOMAIN => {
+            let mut len = [0];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "socks5 proxy sent an unknown address type",
+            ));
+        }
+    };
+    let mut bound = vec![0; bound + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(())
+}
+
+fn socks5_connect_request(target: SocketAddr) -> Vec<u8> {
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0];
+    match target {
+        SocketAddr::V4(addr) => {
+            request.push(SOCKS_IPV4);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            request.push(SOCKS_IPV6);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    request
+}
+
+/// Ask an HTTP proxy to open a tunnel to the target with the CONNECT method
+fn http_connect<S: Read + Write>(
+    stream: &mut S,
+    credentials: Option<(&str, &str)>,
+    target: SocketAddr,
+) -> Result<(), Error> {
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some((username, password)) = credentials {
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64(format!("{username}:{password}").as_bytes())
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+
+    // read the response a byte at a time so that nothing sent by the target
+    // after the tunnel is established is consumed
+    let mut response = Vec::new();
+    let mut byte = [0];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > 8192 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "http proxy response is too long",
+            ));
+        }
+        stream.read_exact(&mut byte)?;
+        response.push(byte[0]);
+    }
+    match http_status(&response) {
+        Some(status) if (200..300).contains(&status) => Ok(()),
+        Some(407) => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "http proxy requires authentication",
+        )),
+        Some(status) => Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("http proxy failed to connect with status: {}", status),
+        )),
+        None => Err(Error::new(
+            ErrorKind::InvalidData,
+            "bad response from http proxy",
+        )),
+    }
+}
+
+fn http_status(response: &[u8]) -> Option<u16> {
+    std::str::from_utf8(response)
+        .ok()?
+        .strip_prefix("HTTP/1.")?
+        .split(' ')
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake() {
+        assert_eq!(
+            socks5_connect_request("10.0.0.1:11211".parse().unwrap()),
+            vec![5, 1, 0, 1, 10, 0, 0, 1, 0x2B, 0xCB]
+        );
+        assert_eq!(
+            http_status(b"HTTP/1.1 200 Connection established\r\n\r\n"),
+            Some(200)
+        );
+        assert_eq!(
+            http_status(b"HTTP/1.0 407 Proxy Authentication Required\r\n\r\n"),
+            Some(407)
+        );
+        assert_eq!(http_status(b"SSH-2.0-OpenSSH\r\n"), None);
+    }
+}
@@ -4,10 +4,11 @@
 
 //! Encapsulates plaintext and TLS TCP streams into a single type.
 
+use super::Endpoint;
 use boring::ssl::SslSession;
 use std::io::{Error, ErrorKind};
 use std::io::{Read, Write};
-use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 use boring::ssl::{HandshakeError, MidHandshakeSslStream, SslStream};
 
@@ -64,16 +65,13 @@ impl Stream {
                     Some(StreamType::Handshaking(handshaking))
                 }
                 _ => {
-                    ret = Err(Error::new(ErrorKind::Other, "handshaking error"));
+                    ret = Err(Error::other("handshaking error"));
                     None
                 }
             };
             ret
         } else {
-            Err(Error::new(
-                ErrorKind::Other,
-                "session is not in handshaking state",
-            ))
+            Err(Error::other("session is not in handshaking state"))
         }
     }
 
@@ -102,7 +100,7 @@ impl Stream {
         }
     }
 
-    pub fn peer_addr(&self) -> Result<SocketAddr, std::io::Error> {
+    pub fn peer_addr(&self) -> Result<Endpoint, std::io::Error> {
         if let Some(ref stream) = self.inner.as_ref() {
             Ok(match stream {
                 StreamType::Plain(s) => s.peer_addr()?,
@@ -117,6 +115,15 @@ impl Stream {
         }
     }
 
+    /// The socket of a plaintext stream, which can be read and written
+    /// outside of the stream
+    pub fn plain_fd(&self) -> Option<RawFd> {
+        match &self.inner {
+            Some(StreamType::Plain(s)) => Some(s.as_raw_fd()),
+            _ => None,
+        }
+    }
+
     pub fn ssl_session(&self) -> Option<SslSession> {
         if let Some(StreamType::Tls(s)) = &self.inner {
             if let Some(session) = s.ssl().session() {
@@ -2,31 +2,174 @@
 // Licensed under the Apache License, Version 2.0
 // http://www.apache.org/licenses/LICENSE-2.0
 
-//! A new type wrapper for `TcpStream`s which allows for capturing metrics about
-//! operations on the underlying TCP stream.
+//! A new type wrapper for TCP and Unix domain socket streams which allows for
+//! capturing metrics about operations on the underlying stream. Connected UDP
+//! sockets are wrapped as streams too, see [`Datagrams`].
 
+use super::Endpoint;
 use std::convert::TryFrom;
 use std::io::{Read, Write};
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
 
 // use crate::metrics::{TCP_RECV_BYTE, TCP_SEND_BYTE, TCP_SEND_PARTIAL};
 
 pub struct TcpStream {
-    inner: mio::net::TcpStream,
+    inner: Inner,
+    endpoint: Endpoint,
+}
+
+enum Inner {
+    Tcp(mio::net::TcpStream),
+    Unix(mio::net::UnixStream),
+    Udp(Datagrams),
+}
+
+/// The largest payload of a UDP datagram
+const MAX_DATAGRAM: usize = 65_507;
+
+/// A connected UDP socket, read and written as a stream of messages which
+/// are each prefixed with their length as two bytes, like DNS over TCP. Each
+/// message is sent as one datagram, and each datagram received is read back
+/// with its length, so codecs handle both transports the same way.
+struct Datagrams {
+    socket: mio::net::UdpSocket,
+    // the datagram being read, with its length prefixed
+    pending: Box<[u8]>,
+    len: usize,
+    offset: usize,
+}
+
+impl Datagrams {
+    fn new(socket: mio::net::UdpSocket) -> Self {
+        Self {
+            socket,
+            pending: vec![0; 2 + MAX_DATAGRAM].into_boxed_slice(),
+            len: 0,
+            offset: 0,
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        if self.offset == self.len {
+            let len = self.socket.recv(&mut self.pending[2..])?;
+            self.pending[0..2].copy_from_slice(&(len as u16).to_be_bytes());
+            self.len = 2 + len;
+            self.offset = 0;
+        }
+        let bytes = std::cmp::min(buf.len(), self.len - self.offset);
+        buf[0..bytes].copy_from_slice(&self.pending[self.offset..(self.offset + bytes)]);
+        self.offset += bytes;
+        Ok(bytes)
+    }
+
+    /// Send each complete message as a datagram, returning the bytes of the
+    /// messages which were sent
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        let mut written = 0;
+        while buf.len() - written >= 2 {
+            let len = u16::from_be_bytes([buf[written], buf[written + 1]]) as usize;
+            let end = written + 2 + len;
+            if end > buf.len() {
+                break;
+            }
+            match self.socket.send(&buf[(written + 2)..end]) {
+                Ok(_) => written = end,
+                Err(e) if written == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(written)
+    }
 }
 
 impl TcpStream {
     pub fn connect(addr: SocketAddr) -> Result<Self, std::io::Error> {
         let stream = mio::net::TcpStream::connect(addr)?;
-        Ok(Self { inner: stream })
+        Ok(Self {
+            inner: Inner::Tcp(stream),
+            endpoint: Endpoint::Tcp(addr),
+        })
+    }
+
+    /// Connects to a Unix domain socket at the provided path.
+    pub fn connect_unix(path: &Path) -> Result<Self, std::io::Error> {
+        let stream = mio::net::UnixStream::connect(path)?;
+        Ok(Self {
+            inner: Inner::Unix(stream),
+            endpoint: Endpoint::Unix(path.to_path_buf()),
+        })
+    }
+
+    /// Connects a UDP socket to the address, from an ephemeral port.
+    pub fn connect_udp(addr: SocketAddr) -> Result<Self, std::io::Error> {
+        let local = match addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = mio::net::UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Ok(Self {
+            inner: Inner::Udp(Datagrams::new(socket)),
+            endpoint: Endpoint::Udp(addr),
+        })
+    }
+
+    /// Connects to a TCP, Unix domain socket, or UDP endpoint.
+    pub fn connect_endpoint(endpoint: &Endpoint) -> Result<Self, std::io::Error> {
+        match endpoint {
+            Endpoint::Tcp(addr) => Self::connect(*addr),
+            Endpoint::Unix(path) => Self::connect_unix(path),
+            Endpoint::Udp(addr) => Self::connect_udp(*addr),
+        }
+    }
+
+    /// Wraps a stream which is tunneled to the target through a proxy
+    pub(super) fn tunneled(stream: mio::net::TcpStream, target: SocketAddr) -> Self {
+        Self {
+            inner: Inner::Tcp(stream),
+            endpoint: Endpoint::Tcp(target),
+        }
     }
 
     pub fn shutdown(&self, how: std::net::Shutdown) -> Result<(), std::io::Error> {
-        self.inner.shutdown(how)
+        match &self.inner {
+            Inner::Tcp(s) => s.shutdown(how),
+            Inner::Unix(s) => s.shutdown(how),
+            // there is no connection to shut down
+            Inner::Udp(_) => Ok(()),
+        }
+    }
+
+    /// Returns the endpoint the stream is connected to, or an error if the
+    /// stream is not connected. For a stream tunneled through a proxy this is
+    /// the target rather than the proxy.
+    pub fn peer_addr(&self) -> Result<Endpoint, std::io::Error> {
+        match &self.inner {
+            Inner::Tcp(s) => {
+                let _ = s.peer_addr()?;
+                Ok(self.endpoint.clone())
+            }
+            Inner::Unix(s) => {
+                let _ = s.peer_addr()?;
+                Ok(self.endpoint.clone())
+            }
+            Inner::Udp(s) => {
+                let _ = s.socket.peer_addr()?;
+                Ok(self.endpoint.clone())
+            }
+        }
     }
+}
 
-    pub fn peer_addr(&self) -> Result<SocketAddr, std::io::Error> {
-        self.inner.peer_addr()
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.inner {
+            Inner::Tcp(s) => s.as_raw_fd(),
+            Inner::Unix(s) => s.as_raw_fd(),
+            Inner::Udp(s) => s.socket.as_raw_fd(),
+        }
     }
 }
 
@@ -34,14 +177,21 @@ impl TryFrom<mio::net::TcpStream> for TcpStream {
     type Error = std::io::Error;
 
     fn try_from(other: mio::net::TcpStream) -> Result<Self, std::io::Error> {
-        let _ = other.peer_addr()?;
-        Ok(Self { inner: other })
+        let addr = other.peer_addr()?;
+        Ok(Self {
+            inner: Inner::Tcp(other),
+            endpoint: Endpoint::Tcp(addr),
+        })
     }
 }
 
 impl Read for TcpStream {
     fn read(&mut self, buf: &mut [u8]) -> std::result::Result<usize, std::io::Error> {
-        self.inner.read(buf)
+        match &mut self.inner {
+            Inner::Tcp(s) => s.read(buf),
+            Inner::Unix(s) => s.read(buf),
+            Inner::Udp(s) => s.read(buf),
+        }
         // if let Ok(bytes) = result {
         //     // TCP_RECV_BYTE.add(bytes as _);
         // }
@@ -51,7 +201,11 @@ impl Read for TcpStream {
 
 impl Write for TcpStream {
     fn write(&mut self, buf: &[u8]) -> std::result::Result<usize, std::io::Error> {
-        let result = self.inner.write(buf);
+        let result = match &mut self.inner {
+            Inner::Tcp(s) => s.write(buf),
+            Inner::Unix(s) => s.write(buf),
+            Inner::Udp(s) => s.write(buf),
+        };
         if let Ok(bytes) = result {
             if bytes != buf.len() {
                 // TCP_SEND_PARTIAL.increment();
@@ -61,7 +215,11 @@ impl Write for TcpStream {
         result
     }
     fn flush(&mut self) -> std::result::Result<(), std::io::Error> {
-        self.inner.flush()
+        match &mut self.inner {
+            Inner::Tcp(s) => s.flush(),
+            Inner::Unix(s) => s.flush(),
+            Inner::Udp(_) => Ok(()),
+        }
     }
 }
 
@@ -72,7 +230,11 @@ impl mio::event::Source for TcpStream {
         token: mio::Token,
         interest: mio::Interest,
     ) -> std::result::Result<(), std::io::Error> {
-        self.inner.register(registry, token, interest)
+        match &mut self.inner {
+            Inner::Tcp(s) => s.register(registry, token, interest),
+            Inner::Unix(s) => s.register(registry, token, interest),
+            Inner::Udp(s) => s.socket.register(registry, token, interest),
+        }
     }
 
     fn reregister(
@@ -81,10 +243,18 @@ impl mio::event::Source for TcpStream {
         token: mio::Token,
         interest: mio::Interest,
     ) -> std::result::Result<(), std::io::Error> {
-        self.inner.reregister(registry, token, interest)
+        match &mut self.inner {
+            Inner::Tcp(s) => s.reregister(registry, token, interest),
+            Inner::Unix(s) => s.reregister(registry, token, interest),
+            Inner::Udp(s) => s.socket.reregister(registry, token, interest),
+        }
     }
 
     fn deregister(&mut self, registry: &mio::Registry) -> std::result::Result<(), std::io::Error> {
-        self.inner.deregister(registry)
+        match &mut self.inner {
+            Inner::Tcp(s) => s.deregister(registry),
+            Inner::Unix(s) => s.deregister(registry),
+            Inner::Udp(s) => s.socket.deregister(registry),
+        }
     }
 }
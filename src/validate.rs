@@ -0,0 +1,115 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Values which can be checked when they are read back. A sealed value ends
+//! with a trailer holding a fingerprint of the key it was stored under and a
+//! checksum which covers the rest of the value, allowing responses which were
+//! corrupted or which belong to a different key to be detected.
+
+use crate::metrics::*;
+
+/// The length of the trailer at the end of a sealed value, which holds the key
+/// fingerprint and the checksum as hex digits.
+pub const TRAILER_LEN: usize = 16;
+
+/// The length of the version at the start of a mutated value, as hex digits
+pub const VERSION_LEN: usize = 8;
+
+#[derive(Debug, PartialEq)]
+pub enum Invalid {
+    /// The value does not match its checksum
+    Corrupt,
+    /// The value is intact, but was stored under another key
+    WrongKey,
+}
+
+/// Replace the end of the value with a trailer for the key. Values which are
+/// shorter than the trailer are extended to hold it.
+pub fn seal(key: &[u8], value: &mut Vec<u8>) {
+    value.truncate(value.len().saturating_sub(TRAILER_LEN));
+    let fingerprint = fnv1a(FNV_OFFSET, key);
+    let checksum = fnv1a(fnv1a(FNV_OFFSET, value), &fingerprint.to_be_bytes());
+    value.extend_from_slice(format!("{fingerprint:08x}{checksum:08x}").as_bytes());
+}
+
+/// Check that a value read for the key was sealed with that key
+pub fn check(key: &[u8], value: &[u8]) -> Result<(), Invalid> {
+    if value.len() < TRAILER_LEN {
+        return Err(Invalid::Corrupt);
+    }
+    let (payload, trailer) = value.split_at(value.len() - TRAILER_LEN);
+    let fingerprint = parse_hex(&trailer[0..8]).ok_or(Invalid::Corrupt)?;
+    let checksum = parse_hex(&trailer[8..16]).ok_or(Invalid::Corrupt)?;
+    if fnv1a(fnv1a(FNV_OFFSET, payload), &fingerprint.to_be_bytes()) != checksum {
+        return Err(Invalid::Corrupt);
+    }
+    if fnv1a(FNV_OFFSET, key) != fingerprint {
+        return Err(Invalid::WrongKey);
+    }
+    Ok(())
+}
+
+/// The version at the start of a mutated value
+pub fn version(value: &[u8]) -> Option<u32> {
+    value.get(0..VERSION_LEN).and_then(parse_hex)
+}
+
+/// Check a value read for the key and update the validation metrics. Values
+/// which are older than the lowest version the read may return are stale.
+pub fn record(key: &[u8], value: &[u8], lowest_version: u32) {
+    RESPONSE_VALIDATED.increment();
+    match check(key, value) {
+        Ok(()) => {
+            if lowest_version > 0 && version(value).unwrap_or(0) < lowest_version {
+                RESPONSE_STALE.increment();
+            }
+        }
+        Err(Invalid::Corrupt) => {
+            RESPONSE_CORRUPT.increment();
+        }
+        Err(Invalid::WrongKey) => {
+            RESPONSE_WRONG_KEY.increment();
+        }
+    }
+}
+
+const FNV_OFFSET: u32 = 0x811c9dc5;
+const FNV_PRIME: u32 = 0x01000193;
+
+fn fnv1a(mut hash: u32, bytes: &[u8]) -> u32 {
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u32> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed() {
+        let mut value = b"abcdefghijklmnopqrstuvwxyz".to_vec();
+        seal(b"key", &mut value);
+        assert_eq!(value.len(), 26);
+        assert_eq!(check(b"key", &value), Ok(()));
+        assert_eq!(check(b"other", &value), Err(Invalid::WrongKey));
+
+        value[0] = b'z';
+        assert_eq!(check(b"key", &value), Err(Invalid::Corrupt));
+
+        let mut short = b"abc".to_vec();
+        seal(b"key", &mut short);
+        assert_eq!(short.len(), TRAILER_LEN);
+        assert_eq!(check(b"key", &short), Ok(()));
+        assert_eq!(check(b"key", &short[1..]), Err(Invalid::Corrupt));
+    }
+}
@@ -2,43 +2,147 @@
 // Licensed under the Apache License, Version 2.0
 // http://www.apache.org/licenses/LICENSE-2.0
 
+use crate::affinity::Affinity;
 use crate::codec::*;
-use crate::config_file::Tls;
+use crate::config::{vhost_name, Vhosts, LABEL};
+use crate::config_file::{
+    Backend, Balance, Connection, Lifetime, LoadMode, Proxy, ReconnectMode, Tls,
+};
+use crate::endpoints::{Cutover, EndpointStats};
 use crate::metrics::*;
-use crate::session::TcpStream;
+use crate::prefill::Prefill;
+use crate::session::{Endpoint, TcpStream};
 use crate::*;
 use boring::x509::X509;
 use heatmap::Heatmap;
 use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::{thread_rng, Rng};
+use rand_distr::{Distribution, Exp, WeightedAliasIndex};
 use ratelimit::Ratelimiter;
 use std::io::{BufRead, Write};
-use std::net::SocketAddr;
 
 use crate::config_file::Protocol;
 
 use boring::ssl::*;
 use mio::{Events, Poll, Token};
+use mpmc::Queue;
 use slab::Slab;
 
 use std::collections::VecDeque;
 use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use strum::IntoEnumIterator;
+
+/// Numbers the labels of connections across all workers, so each is unique
+static LABELS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(target_os = "linux")]
+mod uring;
 
 pub struct Worker {
+    affinity: Option<Arc<Affinity>>,
     codec: Box<dyn Codec>,
-    connect_queue: VecDeque<(SocketAddr, Option<SslSession>)>,
+    // the endpoint, a session to resume, and the connection's slot in the pool
+    connect_queue: VecDeque<(Endpoint, Option<SslSession>, usize)>,
     connect_ratelimit: Option<Arc<Ratelimiter>>,
     poll: Poll,
     ready_queue: VecDeque<Token>,
     reconnect_ratelimit: Option<Arc<Ratelimiter>>,
+    reconnect_mode: ReconnectMode,
+    reconnect_drain_timeout: Option<Duration>,
+    // requests time out once they wait this long for a response, indexed by
+    // the verb, and when they were last checked
+    request_timeouts: Vec<Option<Duration>>,
+    timeouts_checked: Instant,
+    // sessions which timed out are kept open this long to read late
+    // responses, and the time each is closed by
+    datagram_grace: Option<Duration>,
+    timed_out: Vec<(Token, Instant)>,
+    // reconnects waiting for a session to send its next requests
+    reconnects_pending: usize,
+    // each connection reconnects once it has lived for a lifetime drawn from
+    // this distribution
+    lifetime: Option<Lifetime>,
+    session_lifetime_heatmap: Option<Arc<Latency>>,
+    // connections warm up over this period after reconnecting, starting with
+    // a fraction of a full share of the requests
+    slow_start: Option<Duration>,
+    slow_start_share: f64,
+    // set for each slot once it has connected, later connections reconnect
+    slot_connected: Vec<bool>,
+    // wait before sending on a session which was told to retry later
+    honor_retry_after: bool,
+    // how requests are assigned to endpoints, with the next endpoint for the
+    // round robin and the endpoint weights for the weighted balance
+    balance: Balance,
+    cutover: Option<Arc<Cutover>>,
+    endpoints: usize,
+    next_endpoint: usize,
+    endpoint_weights: Option<WeightedAliasIndex<usize>>,
+    endpoint_stats: Option<Arc<EndpointStats>>,
+    // sessions draining to reconnect, with the time they are closed by
+    draining: Vec<(Token, Instant)>,
     request_ratelimit: Option<Arc<Ratelimiter>>,
+    // open-loop requests which are due but waiting for a session with room in
+    // its in-flight window, with the time each was scheduled
+    open_loop: bool,
+    max_inflight: usize,
+    backlog: VecDeque<Instant>,
+    // open-loop requests scheduled by the generator threads, when there are
+    // any, which are taken instead of the ratelimit
+    schedule: Option<Queue<Instant>>,
+    queue_delay_heatmap: Option<Arc<Latency>>,
     sessions: Slab<Session>,
+    // sessions which were closed, which are removed from the slab once the
+    // events and operations for them have been handled
+    closed: Vec<Token>,
     tls: Option<SslConnector>,
-    connect_heatmap: Option<Arc<Heatmap>>,
-    request_heatmap: Option<Arc<Heatmap>>,
+    tls_server_name: String,
+    // chooses the host substituted into the server name of each connection
+    vhosts: Option<Vhosts>,
+    // whether each connection gets a label for load balancer stickiness
+    labeled: bool,
+    proxy: Option<Proxy>,
+    // the connection config, which sizes the session buffers
+    buffers: Connection,
+    connect_heatmap: Option<Arc<Latency>>,
+    handshake_heatmap: Option<Arc<Latency>>,
+    // request latency for each endpoint, indexed by the endpoint's position
+    // in the config
+    endpoint_heatmaps: Vec<Arc<Latency>>,
+    // request latency and waterfall for each command, indexed by the verb
+    command_heatmaps: Vec<Option<Arc<Latency>>>,
+    command_waterfalls: Vec<Option<Arc<Heatmap>>>,
+    summary_heatmaps: Vec<Option<Arc<Latency>>>,
+    // the size in bytes of each collection write request
+    write_size_heatmap: Option<Arc<Latency>>,
+    write_element_heatmap: Option<Arc<Latency>>,
+    response_field_heatmap: Option<Arc<Latency>>,
+    request_heatmap: Option<Arc<Latency>>,
+    // latency from when each request was scheduled, corrected for
+    // coordinated omission
+    corrected_heatmap: Option<Arc<Latency>>,
     request_waterfall: Option<Arc<Heatmap>>,
     pipeline: usize,
+    poolsize: usize,
+    // the number of connections across all workers
+    connections: usize,
+    prefill: Option<Arc<Prefill>>,
+    // the cpus this worker's thread is pinned to
+    cpus: Option<Vec<usize>>,
+    // prefill state, sessions are parked once there are no more keys to
+    // store and resume sending requests once all workers have finished
+    prefilling: bool,
+    prefill_outstanding: usize,
+    prefill_parked: Vec<Token>,
+    prefill_reported: bool,
+    // offset of this worker's connection slots in the client-wide pool
+    slot_offset: usize,
+    backend: Backend,
+    // sessions with requests to send through the ring, with the io_uring
+    // backend
+    unflushed: Vec<Token>,
 }
 
 impl Worker {
@@ -54,12 +158,14 @@ impl Worker {
         // initialize sessions
         for endpoint in config.endpoints() {
             for _ in 0..config.connection().poolsize() {
-                connect_queue.push_back((endpoint, None));
+                let slot = connect_queue.len();
+                connect_queue.push_back((endpoint.clone(), None, slot));
             }
         }
 
         // shuffle connect queue
-        let mut tmp: Vec<(SocketAddr, Option<SslSession>)> = connect_queue.drain(0..).collect();
+        let mut tmp: Vec<(Endpoint, Option<SslSession>, usize)> =
+            connect_queue.drain(0..).collect();
         let mut rng = thread_rng();
         tmp.shuffle(&mut rng);
         for addr in tmp {
@@ -67,11 +173,13 @@ impl Worker {
         }
 
         // configure tls connector
-        let tls = if let Some(tls_config) = config.tls() {
-            ssl_connector(tls_config).expect("bad tls config")
-        } else {
-            None
-        };
+        let tls = config
+            .tls()
+            .map(|tls_config| ssl_connector(tls_config).expect("bad tls config"));
+        let tls_server_name = config
+            .tls()
+            .map(|tls_config| tls_config.server_name())
+            .unwrap_or_default();
 
         // initialize the codec
         let codec = match config.general().protocol() {
@@ -82,26 +190,134 @@ impl Worker {
                 Box::new(Redis::new(config.clone())) as Box<dyn Codec>
             }
             Protocol::ThriftCache => Box::new(ThriftCache::new(config.clone())) as Box<dyn Codec>,
+            Protocol::Http => Box::new(Http::new(config.clone())) as Box<dyn Codec>,
+            Protocol::Websocket => Box::new(Websocket::new(config.clone())) as Box<dyn Codec>,
+            Protocol::Thrift => Box::new(ThriftRpc::new(config.clone())) as Box<dyn Codec>,
+            Protocol::Cql => Box::new(Cql::new(config.clone())) as Box<dyn Codec>,
+            Protocol::Mongodb => Box::new(Mongodb::new(config.clone())) as Box<dyn Codec>,
+            Protocol::Postgres => Box::new(Postgres::new(config.clone())) as Box<dyn Codec>,
+            Protocol::Dns => Box::new(Dns::new(config.clone())) as Box<dyn Codec>,
         };
 
         // return the worker
         Ok(Worker {
+            affinity: None,
             poll,
             connect_queue,
             connect_ratelimit: None,
             ready_queue,
             reconnect_ratelimit: None,
+            reconnect_mode: config.connection().reconnect_mode(),
+            reconnect_drain_timeout: config
+                .connection()
+                .reconnect_drain_timeout()
+                .map(|timeout| Duration::from_nanos(timeout.as_nanos() as u64)),
+            request_timeouts: Verb::iter()
+                .map(|verb| {
+                    config
+                        .request_timeout(verb)
+                        .map(|timeout| Duration::from_nanos(timeout.as_nanos() as u64))
+                })
+                .collect(),
+            timeouts_checked: Instant::now(),
+            datagram_grace: config
+                .datagram_grace()
+                .map(|grace| Duration::from_nanos(grace.as_nanos() as u64)),
+            timed_out: Vec::new(),
+            reconnects_pending: 0,
+            lifetime: config.connection().lifetime(),
+            session_lifetime_heatmap: None,
+            slow_start: config
+                .connection()
+                .slow_start()
+                .map(|period| Duration::from_nanos(period.as_nanos() as u64)),
+            slow_start_share: config.connection().slow_start_share(),
+            slot_connected: vec![false; connections],
+            honor_retry_after: config.request().honor_retry_after(),
+            balance: config.target().balance(),
+            cutover: None,
+            endpoints: config.endpoints().len(),
+            next_endpoint: 0,
+            endpoint_weights: match config.target().balance() {
+                Balance::Weighted => {
+                    Some(WeightedAliasIndex::new(config.target().weights().to_vec()).unwrap())
+                }
+                _ => None,
+            },
+            endpoint_stats: None,
+            draining: Vec::new(),
             request_ratelimit: None,
+            open_loop: config.request().mode() == LoadMode::Open,
+            max_inflight: config.request().max_inflight(),
+            backlog: VecDeque::new(),
+            schedule: None,
+            queue_delay_heatmap: None,
             sessions,
+            closed: Vec::new(),
             tls,
+            tls_server_name,
+            vhosts: config.vhosts().cloned(),
+            labeled: config.stickiness().is_some(),
+            proxy: config.connection().proxy().cloned(),
+            buffers: config.connection().clone(),
             codec,
             connect_heatmap: None,
+            handshake_heatmap: None,
+            endpoint_heatmaps: Vec::new(),
+            command_heatmaps: Vec::new(),
+            command_waterfalls: Vec::new(),
+            summary_heatmaps: Vec::new(),
+            write_size_heatmap: None,
+            write_element_heatmap: None,
+            response_field_heatmap: None,
             request_heatmap: None,
+            corrected_heatmap: None,
             request_waterfall: None,
             pipeline,
+            poolsize: config.connection().poolsize(),
+            connections: connections * config.general().threads(),
+            prefill: None,
+            cpus: None,
+            prefilling: false,
+            prefill_outstanding: 0,
+            prefill_parked: Vec::new(),
+            prefill_reported: false,
+            slot_offset: 0,
+            backend: config.general().backend(),
+            unflushed: Vec::new(),
         })
     }
 
+    /// Provide the counters for each endpoint
+    pub fn set_endpoint_stats(&mut self, stats: Option<Arc<EndpointStats>>) {
+        self.endpoint_stats = stats;
+    }
+
+    /// Enables key affinity, where each connection only sends requests for the
+    /// keys in its shard. The offset is the index of this worker's first
+    /// connection in the client-wide pool.
+    pub fn set_affinity(&mut self, affinity: Option<Arc<Affinity>>, offset: usize) {
+        self.affinity = affinity;
+        self.slot_offset = offset;
+    }
+
+    /// Store every key before sending requests. The prefill is shared with the
+    /// other workers so that each key is only stored once.
+    pub fn set_prefill(&mut self, prefill: Option<Arc<Prefill>>) {
+        self.prefilling = prefill.is_some();
+        self.prefill = prefill;
+    }
+
+    /// Pin the worker's thread to the cpus once it starts running
+    pub fn set_cpus(&mut self, cpus: Option<Vec<usize>>) {
+        self.cpus = cpus;
+    }
+
+    /// Shift the requests to the cutover endpoints as the cutover progresses
+    pub fn set_cutover(&mut self, cutover: Option<Arc<Cutover>>) {
+        self.cutover = cutover;
+    }
+
     /// Controls the total connect rate via an optional shared ratelimiter.
     pub fn set_connect_ratelimit(&mut self, ratelimiter: Option<Arc<Ratelimiter>>) {
         self.connect_ratelimit = ratelimiter;
@@ -119,16 +335,85 @@ impl Worker {
         self.request_ratelimit = ratelimiter;
     }
 
+    /// Take open-loop requests from the schedule of the generator threads
+    pub fn set_schedule(&mut self, schedule: Option<Queue<Instant>>) {
+        self.schedule = schedule;
+    }
+
     /// Provide a heatmap for recording connect latency
-    pub fn set_connect_heatmap(&mut self, heatmap: Option<Arc<Heatmap>>) {
+    pub fn set_connect_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
         self.connect_heatmap = heatmap;
     }
 
+    /// Provide a heatmap for recording how long connections stay open, in
+    /// milliseconds
+    pub fn set_session_lifetime_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
+        self.session_lifetime_heatmap = heatmap;
+    }
+
+    /// Provide a heatmap for recording TLS handshake latency
+    pub fn set_handshake_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
+        self.handshake_heatmap = heatmap;
+    }
+
+    /// Provide a heatmap for each endpoint for recording request latency by
+    /// endpoint
+    pub fn set_endpoint_heatmaps(&mut self, heatmaps: Vec<Arc<Latency>>) {
+        self.endpoint_heatmaps = heatmaps;
+    }
+
+    /// Provide heatmaps for recording request latency by command, indexed by
+    /// the verb
+    pub fn set_command_heatmaps(&mut self, heatmaps: Vec<Option<Arc<Latency>>>) {
+        self.command_heatmaps = heatmaps;
+    }
+
+    /// Provide heatmaps for recording request latencies into a waterfall for
+    /// each command, indexed by the verb
+    pub fn set_command_waterfalls(&mut self, heatmaps: Vec<Option<Arc<Heatmap>>>) {
+        self.command_waterfalls = heatmaps;
+    }
+
+    /// Provide heatmaps for recording request latency by command over the
+    /// measured part of the run, indexed by the verb
+    pub fn set_summary_heatmaps(&mut self, heatmaps: Vec<Option<Arc<Latency>>>) {
+        self.summary_heatmaps = heatmaps;
+    }
+
+    /// Provide a heatmap for recording the size of collection write requests
+    pub fn set_write_size_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
+        self.write_size_heatmap = heatmap;
+    }
+
+    /// Provide a heatmap for recording the number of elements written by
+    /// collection write requests
+    pub fn set_write_element_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
+        self.write_element_heatmap = heatmap;
+    }
+
+    /// Provide a heatmap for recording the number of fields returned by hash
+    /// get all responses
+    pub fn set_response_field_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
+        self.response_field_heatmap = heatmap;
+    }
+
     /// Provide a heatmap for recording request latency
-    pub fn set_request_heatmap(&mut self, heatmap: Option<Arc<Heatmap>>) {
+    pub fn set_request_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
         self.request_heatmap = heatmap;
     }
 
+    /// Provide a heatmap for recording latency from when requests were
+    /// scheduled to be sent
+    pub fn set_corrected_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
+        self.corrected_heatmap = heatmap;
+    }
+
+    /// Provide a heatmap for recording how long open-loop requests wait
+    /// between being scheduled and sent
+    pub fn set_queue_delay_heatmap(&mut self, heatmap: Option<Arc<Latency>>) {
+        self.queue_delay_heatmap = heatmap;
+    }
+
     /// Provide a heatmap for recording request latencies into the waterfall
     pub fn set_request_waterfall(&mut self, heatmap: Option<Arc<Heatmap>>) {
         self.request_waterfall = heatmap;
@@ -137,49 +422,74 @@ impl Worker {
     /// Internal function to connect the session
     fn connect(
         &mut self,
-        addr: SocketAddr,
+        endpoint: Endpoint,
         ssl_session: Option<SslSession>,
+        slot: usize,
     ) -> Result<Token, std::io::Error> {
         CONNECT.increment();
-        let stream = TcpStream::connect(addr)?;
+        if let Some(ref stats) = self.endpoint_stats {
+            stats.connect(slot / self.poolsize);
+        }
+        let stream = match (&self.proxy, &endpoint) {
+            (Some(proxy), Endpoint::Tcp(addr)) => TcpStream::connect_proxied(proxy, *addr)?,
+            _ => TcpStream::connect_endpoint(&endpoint)?,
+        };
+        let vhost = self
+            .vhosts
+            .as_ref()
+            .map(|vhosts| vhosts.choose(&mut thread_rng()));
+        let label = self
+            .labeled
+            .then(|| format!("conn-{}", LABELS.fetch_add(1, Ordering::Relaxed)));
+        let server_name = vhost_name(&self.tls_server_name, vhost)
+            .replace(LABEL, label.as_deref().unwrap_or_default());
         let mut session = if let Some(tls) = &self.tls {
             if let Ok(mut connect_config) = tls.configure() {
                 if let Some(ssl_session) = ssl_session {
                     unsafe {
                         if connect_config.set_session(&ssl_session).is_err() {
-                            return Err(Error::new(ErrorKind::Other, "tls session cache failure"));
+                            return Err(Error::other("tls session cache failure"));
                         }
                     }
                 }
 
-                match connect_config.connect("localhost", stream) {
+                match connect_config.connect(&server_name, stream) {
                     Ok(stream) => {
                         if stream.ssl().session_reused() {
                             SESSION_REUSE.increment();
                         }
-                        Session::tls_with_capacity(stream, 1024, 512 * 1024)
+                        Session::tls_with_buffers(stream, &self.buffers)
                     }
                     Err(HandshakeError::WouldBlock(stream)) => {
                         if stream.ssl().session_reused() {
                             SESSION_REUSE.increment();
                         }
-                        Session::handshaking_with_capacity(stream, 1024, 512 * 1024)
+                        Session::handshaking_with_buffers(stream, &self.buffers)
                     }
                     Err(_) => {
-                        return Err(Error::new(ErrorKind::Other, "tls failure"));
+                        return Err(Error::other("tls failure"));
                     }
                 }
             } else {
-                return Err(Error::new(ErrorKind::Other, "tls connect config failure"));
+                return Err(Error::other("tls connect config failure"));
             }
         } else {
-            Session::plain_with_capacity(stream, 1024, 512 * 1024)
+            Session::plain_with_buffers(stream, &self.buffers)
         };
 
         let entry = self.sessions.vacant_entry();
         let token = Token(entry.key());
         session.set_token(token);
         session.set_timestamp(Instant::now());
+        session.set_slot(slot);
+        session.set_connection(self.slot_offset + slot);
+        session.set_vhost(vhost);
+        session.set_label(label);
+        if let Some(ref affinity) = self.affinity {
+            session.set_shard(self.slot_offset + slot, affinity.shards());
+        } else if self.balance == Balance::KeyHash {
+            session.set_shard(slot / self.poolsize, self.endpoints);
+        }
         entry.insert(session);
         Ok(token)
     }
@@ -191,13 +501,354 @@ impl Worker {
         let _ = session.deregister(&self.poll);
         let peer_addr = session.peer_addr();
         let ssl_session = session.ssl_session();
+        let slot = session.slot();
+        if session.warmup_start().is_some() {
+            session.set_warmup_start(None);
+            SLOW_START_WARMING.decrement();
+        }
+        if let (Some(established), Some(heatmap)) =
+            (session.established(), &self.session_lifetime_heatmap)
+        {
+            let now = Instant::now();
+            heatmap.increment(now, (now - established).as_nanos() / 1_000_000, 1);
+        }
+        if self.prefilling {
+            // responses for any outstanding prefill requests are lost
+            self.prefill_outstanding -= session.outstanding() - session.is_setting_up() as usize;
+            session.set_outstanding(0);
+        }
         session.close();
+        self.closed.push(token);
         if let Ok(addr) = peer_addr {
-            self.connect_queue.push_back((addr, ssl_session));
+            self.connect_queue.push_back((addr, ssl_session, slot));
         }
         Ok(())
     }
 
+    /// Remove the sessions which were closed from the slab, which closes their
+    /// sockets and frees their buffers. Their tokens are forgotten, so that
+    /// they are not taken for new sessions which reuse them. Sessions which
+    /// are not idle yet, such as those with io_uring operations in flight,
+    /// are kept until a later call.
+    fn remove_closed(&mut self, idle: impl Fn(Token) -> bool) {
+        if self.closed.is_empty() {
+            return;
+        }
+        let (removed, closed): (Vec<Token>, Vec<Token>) =
+            self.closed.drain(..).partition(|token| idle(*token));
+        self.closed = closed;
+        for token in &removed {
+            self.sessions.try_remove(token.0);
+        }
+        self.ready_queue.retain(|token| !removed.contains(token));
+        self.draining.retain(|(token, _)| !removed.contains(token));
+        self.timed_out.retain(|(token, _)| !removed.contains(token));
+        self.prefill_parked.retain(|token| !removed.contains(token));
+        self.unflushed.retain(|token| !removed.contains(token));
+    }
+
+    /// Close the session so that it reconnects. The session has just sent a
+    /// batch of requests, which are either abandoned or drained depending on
+    /// the reconnect mode.
+    fn reconnect(&mut self, token: Token) -> Result<(), Error> {
+        let session = get_session_mut!(self, token)?;
+        match self.reconnect_mode {
+            ReconnectMode::Abrupt => {
+                RECONNECT_ABANDONED.add(session.outstanding() as _);
+                self.disconnect(token)
+            }
+            ReconnectMode::Drain => {
+                session.set_draining();
+                if let Some(timeout) = self.reconnect_drain_timeout {
+                    self.draining.push((token, Instant::now() + timeout));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Close any draining sessions which have passed their deadline while
+    /// requests are still in flight. Sessions which have drained are closed
+    /// when they are taken from the ready queue.
+    fn expire_draining(&mut self) {
+        if self.draining.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.draining.retain(|(token, deadline)| {
+            if *deadline <= now {
+                expired.push(*token);
+                false
+            } else {
+                true
+            }
+        });
+        for token in expired {
+            let outstanding = match get_session!(self, token) {
+                Ok(session) if session.is_draining() && !session.is_closed() => {
+                    session.outstanding()
+                }
+                _ => continue,
+            };
+            if outstanding > 0 {
+                RECONNECT_DRAIN_TIMEOUT.increment();
+                RECONNECT_ABANDONED.add(outstanding as _);
+                let _ = self.disconnect(token);
+            }
+        }
+    }
+
+    /// Start warming up the session if its slot has connected before
+    fn start_warmup(&mut self, token: Token) -> Result<(), Error> {
+        let slow_start = self.slow_start.is_some();
+        let session = get_session_mut!(self, token)?;
+        let reconnected = std::mem::replace(&mut self.slot_connected[session.slot()], true);
+        if slow_start && reconnected {
+            session.set_warmup_start(Some(Instant::now()));
+            SLOW_START.increment();
+            SLOW_START_WARMING.increment();
+        }
+        Ok(())
+    }
+
+    /// Close any sessions whose oldest request has waited longer than the
+    /// timeout for its command. Its response, and those of any later
+    /// requests, are counted as timed out, as responses arrive in order. The
+    /// session reconnects, so late responses can not be taken for those of
+    /// new requests. With a grace period, which requests sent as datagrams
+    /// may have, the session stops sending but keeps reading until the period
+    /// ends, so that responses which arrive late are told apart from requests
+    /// which were never answered.
+    fn expire_requests(&mut self) {
+        if self
+            .request_timeouts
+            .iter()
+            .all(|timeout| timeout.is_none())
+        {
+            return;
+        }
+        let now = Instant::now();
+        // checking a few times within the timeout is precise enough
+        if now - self.timeouts_checked < Duration::from_millis(10) {
+            return;
+        }
+        self.timeouts_checked = now;
+        let timeouts = &self.request_timeouts;
+        let expired: Vec<Token> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| {
+                let timeout = session
+                    .command()
+                    .and_then(|verb| timeouts.get(verb as usize).copied().flatten());
+                !session.is_closed()
+                    && !session.is_timed_out()
+                    && session
+                        .oldest_sent()
+                        .zip(timeout)
+                        .is_some_and(|(sent, timeout)| now - sent >= timeout)
+            })
+            .map(|(key, _)| Token(key))
+            .collect();
+        for token in expired {
+            if let Ok(session) = get_session!(self, token) {
+                RESPONSE_TIMEOUT.add(session.outstanding() as _);
+                for verb in session.commands() {
+                    RESPONSE_COMMAND_TIMEOUT[verb as usize].increment();
+                }
+            }
+            match self.datagram_grace {
+                Some(grace) => {
+                    if let Ok(session) = get_session_mut!(self, token) {
+                        session.set_timed_out();
+                        self.timed_out.push((token, now + grace));
+                    }
+                }
+                None => {
+                    let _ = self.disconnect(token);
+                }
+            }
+        }
+        self.expire_timed_out(now);
+    }
+
+    /// Close the sessions which timed out once their grace period ends. The
+    /// requests which are still outstanding never had a response. Sessions
+    /// which read every late response are closed like draining sessions.
+    fn expire_timed_out(&mut self, now: Instant) {
+        let mut expired = Vec::new();
+        self.timed_out.retain(|(token, deadline)| {
+            if *deadline <= now {
+                expired.push(*token);
+                false
+            } else {
+                true
+            }
+        });
+        for token in expired {
+            let outstanding = match get_session!(self, token) {
+                Ok(session) if session.is_timed_out() && !session.is_closed() => {
+                    session.outstanding()
+                }
+                _ => continue,
+            };
+            if outstanding > 0 {
+                RESPONSE_MISSING.add(outstanding as _);
+                let _ = self.disconnect(token);
+            }
+        }
+    }
+
+    /// Note when the session was established and draw its lifetime
+    fn start_lifetime(&mut self, token: Token) -> Result<(), Error> {
+        let lifetime = self.lifetime.map(|lifetime| {
+            let mut rng = thread_rng();
+            let ms = match lifetime {
+                Lifetime::Fixed { lifetime } => lifetime as f64,
+                Lifetime::Uniform { min, max } => rng.gen_range(min..=max) as f64,
+                Lifetime::Exponential { mean } => {
+                    Exp::new(1.0 / mean as f64).unwrap().sample(&mut rng)
+                }
+            };
+            Duration::from_nanos((ms * 1_000_000.0) as u64)
+        });
+        let session = get_session_mut!(self, token)?;
+        let now = Instant::now();
+        session.set_established(now);
+        session.set_expires(lifetime.map(|lifetime| now + lifetime));
+        Ok(())
+    }
+
+    /// Whether the session should reconnect now that it has sent its
+    /// requests, either to make up the reconnect rate or because it has lived
+    /// out its lifetime
+    fn should_reconnect(&mut self, token: Token) -> bool {
+        if self.reconnects_pending > 0 {
+            self.reconnects_pending -= 1;
+            return true;
+        }
+        match get_session!(self, token).map(|session| session.expires()) {
+            Ok(Some(expires)) if expires <= Instant::now() => {
+                SESSION_EXPIRED.increment();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Take the next session which is ready to send. A session which is
+    /// warming up takes its turn with a probability of its current share of
+    /// the requests, otherwise it moves to the back of the queue so that
+    /// sessions with a full share take its requests. When every ready session
+    /// is warming up, the first is used. Sessions which were asked to retry
+    /// later are skipped until then, when retry-after is honored.
+    fn pop_ready(&mut self) -> Option<Token> {
+        self.balance();
+        if self.slow_start.is_none() && !self.honor_retry_after {
+            return self.ready_queue.pop_front();
+        }
+        let now = Instant::now();
+        let mut deferred = None;
+        for _ in 0..self.ready_queue.len() {
+            let token = self.ready_queue.pop_front()?;
+            let session = match get_session_mut!(self, token) {
+                Ok(session) => session,
+                Err(_) => return Some(token),
+            };
+            if self.honor_retry_after {
+                match session.retry_after() {
+                    Some(until) if until > now => {
+                        self.ready_queue.push_back(token);
+                        continue;
+                    }
+                    Some(_) => session.set_retry_after(None),
+                    None => {}
+                }
+            }
+            let (period, start) = match (self.slow_start, session.warmup_start()) {
+                (Some(period), Some(start)) => (period.as_nanos() as f64, start),
+                _ => return Some(token),
+            };
+            let warmed = (now - start).as_nanos() as f64 / period;
+            if warmed >= 1.0 {
+                session.set_warmup_start(None);
+                SLOW_START_WARMING.decrement();
+                return Some(token);
+            }
+            let share = self.slow_start_share + (1.0 - self.slow_start_share) * warmed;
+            if rand::random::<f64>() < share {
+                return Some(token);
+            }
+            SLOW_START_DEFERRED.increment();
+            deferred = deferred.or(Some(token));
+            self.ready_queue.push_back(token);
+        }
+        let deferred = deferred?;
+        let position = self.ready_queue.iter().position(|t| *t == deferred)?;
+        self.ready_queue.remove(position)
+    }
+
+    /// Move a ready session for the endpoint chosen by the balance to the
+    /// front of the ready queue. When the endpoint has no ready sessions, the
+    /// next endpoint in order which does is used. During a cutover, the
+    /// endpoint is chosen from either the target or the cutover endpoints
+    /// according to the progress of the cutover.
+    fn balance(&mut self) {
+        let (first, count) = match self.cutover {
+            Some(ref cutover) => {
+                if thread_rng().gen::<f64>() < cutover.share() {
+                    (cutover.first(), self.endpoints - cutover.first())
+                } else {
+                    (0, cutover.first())
+                }
+            }
+            None => (0, self.endpoints),
+        };
+        let endpoint = match self.balance {
+            Balance::KeyHash => return,
+            Balance::Ready if self.cutover.is_none() => return,
+            Balance::RoundRobin => {
+                let endpoint = self.next_endpoint % count;
+                self.next_endpoint = self.next_endpoint.wrapping_add(1);
+                endpoint
+            }
+            Balance::Ready | Balance::Random => thread_rng().gen_range(0..count),
+            Balance::Weighted => match self.endpoint_weights {
+                Some(ref weights) => weights.sample(&mut thread_rng()),
+                None => return,
+            },
+        };
+        for offset in 0..count {
+            let endpoint = first + (endpoint + offset) % count;
+            let position = self.ready_queue.iter().position(|token| {
+                self.sessions
+                    .get(token.0)
+                    .map(|session| session.slot() / self.poolsize == endpoint)
+                    .unwrap_or(false)
+            });
+            if let Some(position) = position {
+                if let Some(token) = self.ready_queue.remove(position) {
+                    self.ready_queue.push_front(token);
+                }
+                return;
+            }
+        }
+    }
+
+    /// Increment a counter for the session's endpoint
+    fn count_endpoint(&self, token: Token, counter: fn(&EndpointStats, usize)) {
+        if let (Some(stats), Some(session)) = (&self.endpoint_stats, self.sessions.get(token.0)) {
+            counter(stats, session.slot() / self.poolsize);
+        }
+    }
+
+    /// Check if the session is draining to reconnect
+    fn is_draining(&self, token: Token) -> Result<bool, Error> {
+        let session = get_session!(self, token)?;
+        Ok(session.is_draining())
+    }
+
     /// Check if the session is connecting
     fn is_connecting(&self, token: Token) -> Result<bool, Error> {
         let session = get_session!(self, token)?;
@@ -217,12 +868,33 @@ impl Worker {
         Ok(session.is_handshaking())
     }
 
-    /// Continue the handshake for the session
+    /// Continue the handshake for the session. The first call indicates that
+    /// the TCP connection is established, so the connect latency is recorded
+    /// and the handshake latency is measured from this point.
     fn handshake(&mut self, token: Token) -> Result<(), Error> {
         let session = get_session_mut!(self, token)?;
+        if session.handshake_start().is_none() {
+            let now = Instant::now();
+            session.set_handshake_start(now);
+            if let Some(ref heatmap) = self.connect_heatmap {
+                let us = (now - session.timestamp()).as_nanos() / 1_000;
+                heatmap.increment(now, us, 1);
+            }
+        }
         session.do_handshake()
     }
 
+    /// Record the handshake latency once the handshake has completed
+    fn handshake_complete(&mut self, token: Token) -> Result<(), Error> {
+        let session = get_session!(self, token)?;
+        if let (Some(start), Some(heatmap)) = (session.handshake_start(), &self.handshake_heatmap) {
+            let now = Instant::now();
+            let us = (now - start).as_nanos() / 1_000;
+            heatmap.increment(now, us, 1);
+        }
+        Ok(())
+    }
+
     /// Register the token with the event loop
     fn register(&mut self, token: Token) -> Result<(), Error> {
         let session = get_session_mut!(self, token)?;
@@ -235,21 +907,79 @@ impl Worker {
         session.reregister(&self.poll)
     }
 
+    /// Get the time the handshake started for the session, if it handshaked
+    fn handshake_start(&self, token: Token) -> Result<Option<Instant>, Error> {
+        let session = get_session!(self, token)?;
+        Ok(session.handshake_start())
+    }
+
     /// Get the timestamp for the session, used for latency calculations
     fn timestamp(&mut self, token: Token) -> Result<Instant, Error> {
         let session = get_session_mut!(self, token)?;
         Ok(session.timestamp())
     }
 
-    /// Generate and send a request over the session
-    fn send_request(&mut self, token: Token, count: usize) -> Result<(), Error> {
+    /// Generate requests and send them over the session together, in a single
+    /// write. When the requests are scheduled, they are taken from the front
+    /// of the open-loop backlog.
+    fn send_request(&mut self, token: Token, count: usize, scheduled: bool) -> Result<(), Error> {
         let session = get_session_mut!(self, token)?;
         for _ in 0..count {
             REQUEST.increment();
-            self.codec.encode(session);
+            let pending = session.write_pending();
+            let (verb, outcome) = self.codec.encode(session);
+            let edge_case = outcome.edge_case;
+            if edge_case {
+                EDGE_CASE_REQUEST.increment();
+            }
+            let bytes = session.write_pending() - pending;
+            REQUEST_COMMAND[verb as usize].increment();
+            REQUEST_COMMAND_BYTE[verb as usize].add(bytes as u64);
+            if verb.is_collection_write() {
+                COLLECTION_WRITE.increment();
+                if let Some(ref heatmap) = self.write_size_heatmap {
+                    heatmap.increment(Instant::now(), bytes as u64, 1);
+                }
+                if let Some(ref heatmap) = self.write_element_heatmap {
+                    heatmap.increment(Instant::now(), outcome.elements, 1);
+                }
+            }
+            session.push_command(verb, edge_case);
+            if let (Some(affinity), Some((shard, _))) = (&self.affinity, session.shard()) {
+                affinity.increment(shard);
+            }
         }
-        session.set_outstanding(count);
-        session.set_timestamp(Instant::now());
+        if let Some(ref stats) = self.endpoint_stats {
+            stats.request(session.slot() / self.poolsize, count);
+        }
+        let now = Instant::now();
+        session.set_outstanding(session.outstanding() + count);
+        session.set_timestamp(now);
+        for _ in 0..count {
+            let due = match scheduled.then(|| self.backlog.pop_front()).flatten() {
+                Some(due) => {
+                    REQUEST_BACKLOG.decrement();
+                    if let Some(ref heatmap) = self.queue_delay_heatmap {
+                        let us = (now - due).as_nanos() / 1_000;
+                        heatmap.increment(now, us, 1);
+                    }
+                    due
+                }
+                None => now,
+            };
+            session.push_sent(now, due);
+        }
+        self.flush(token)
+    }
+
+    /// Write the requests in the session's write buffer. With the io_uring
+    /// backend they are queued to be sent through the ring instead.
+    fn flush(&mut self, token: Token) -> Result<(), Error> {
+        if self.backend == Backend::IoUring {
+            self.unflushed.push(token);
+            return Ok(());
+        }
+        let session = get_session_mut!(self, token)?;
         let _ = session.flush();
         if session.write_pending() > 0 {
             self.reregister(token)
@@ -258,6 +988,115 @@ impl Worker {
         }
     }
 
+    /// Queue the requests which are due according to the ratelimit and send
+    /// as many as possible on sessions with room in their in-flight window.
+    /// Requests are sent on time regardless of how long earlier requests take
+    /// to complete, any delay in sending them is recorded as queue delay.
+    fn send_scheduled(&mut self) {
+        if let Some(schedule) = &self.schedule {
+            // only the requests which the ready sessions can take are moved
+            // from the shared schedule, leaving the rest to other workers
+            let room = self.pipeline * self.ready_queue.len();
+            while self.backlog.len() < room {
+                match schedule.pop() {
+                    Some(due) => self.backlog.push_back(due),
+                    None => break,
+                }
+            }
+        } else if let Some(r) = &self.request_ratelimit {
+            while r.try_wait().is_ok() {
+                self.backlog.push_back(Instant::now());
+                REQUEST_BACKLOG.increment();
+            }
+        }
+
+        while !self.backlog.is_empty() {
+            let token = match self.pop_ready() {
+                Some(token) => token,
+                None => break,
+            };
+            if let Some(r) = &self.reconnect_ratelimit {
+                if r.try_wait().is_ok() {
+                    self.reconnects_pending += 1;
+                }
+            }
+            if let Ok(true) = self.is_draining(token) {
+                // the session has received all of its responses
+                let _ = self.disconnect(token);
+                continue;
+            }
+            // the requests which are due are sent together, up to the
+            // pipeline depth and the room in the session's window
+            let room = match get_session!(self, token) {
+                Ok(session) => self.max_inflight.saturating_sub(session.outstanding()),
+                Err(_) => continue,
+            };
+            let count = self.pipeline.min(room).min(self.backlog.len()).max(1);
+            if self.send_request(token, count, true).is_err() {
+                if self.disconnect(token).is_ok() {
+                    REQUEST_EX.increment();
+                    self.count_endpoint(token, EndpointStats::request_ex);
+                }
+                continue;
+            }
+            if self.should_reconnect(token) {
+                let _ = self.reconnect(token);
+                continue;
+            }
+            // the session is returned to the ready queue by its responses if
+            // its window is now full
+            if let Ok(session) = get_session!(self, token) {
+                if session.outstanding() < self.max_inflight {
+                    self.ready_queue.push_back(token);
+                }
+            }
+        }
+    }
+
+    /// Send prefill requests over the session. If all keys have been claimed,
+    /// the session is parked until the prefill completes.
+    fn send_prefill(&mut self, token: Token) -> Result<(), Error> {
+        let prefill = match self.prefill {
+            Some(ref prefill) => prefill,
+            None => return Ok(()),
+        };
+        let session = get_session_mut!(self, token)?;
+        let mut count = 0;
+        while count < self.pipeline {
+            match prefill.next() {
+                Some((keyspace, index)) => {
+                    self.codec.prefill(session, keyspace, index);
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+        if count == 0 {
+            self.prefill_parked.push(token);
+            return Ok(());
+        }
+        self.prefill_outstanding += count;
+        session.set_outstanding(count);
+        self.flush(token)
+    }
+
+    /// Track the progress of the prefill, reporting when this worker has
+    /// finished and resuming parked sessions once all workers have finished.
+    fn update_prefill(&mut self) {
+        let prefill = match self.prefill {
+            Some(ref prefill) if self.prefilling => prefill,
+            _ => return,
+        };
+        if !self.prefill_reported && prefill.is_exhausted() && self.prefill_outstanding == 0 {
+            self.prefill_reported = true;
+            prefill.worker_done();
+        }
+        if prefill.is_complete() {
+            self.prefilling = false;
+            self.ready_queue.extend(self.prefill_parked.drain(..));
+        }
+    }
+
     /// Handle reading from the session
     fn do_read(&mut self, token: Token) -> Result<(), Error> {
         let session = get_session_mut!(self, token)?;
@@ -265,39 +1104,9 @@ impl Worker {
         match session.fill_buf().map(|b| b.len()) {
             Ok(0) => {
                 // server hangup
-                Err(Error::new(ErrorKind::Other, "server hangup"))
-            }
-            Ok(_) => {
-                // request parsing
-                while session.outstanding() > 0 {
-                    let response = self.codec.decode(session);
-                    match response {
-                        Ok(()) => {
-                            session.set_outstanding(session.outstanding() - 1);
-                            RESPONSE.increment();
-                            if let Some(ref heatmap) = self.request_heatmap {
-                                let now = Instant::now();
-                                let elapsed = now - session.timestamp();
-                                let us = elapsed.as_nanos() as u64 / 1_000;
-                                heatmap.increment(now, us, 1);
-                                if let Some(ref waterfall) = self.request_waterfall {
-                                    waterfall.increment(now, elapsed.as_nanos() as u64, 1);
-                                }
-                            }
-                        }
-                        Err(e) => match e {
-                            ParseError::Incomplete => {
-                                return Ok(());
-                            }
-                            _ => {
-                                return Err(Error::from(std::io::ErrorKind::InvalidData));
-                            }
-                        },
-                    }
-                }
-                self.ready_queue.push_back(token);
-                Ok(())
+                Err(Error::other("server hangup"))
             }
+            Ok(_) => self.handle_responses(token),
             Err(e) => {
                 match e.kind() {
                     ErrorKind::WouldBlock => {
@@ -315,6 +1124,177 @@ impl Worker {
         }
     }
 
+    /// Parse the responses which have been read into the session's buffer
+    fn handle_responses(&mut self, token: Token) -> Result<(), Error> {
+        let session = get_session_mut!(self, token)?;
+
+        // an open-loop session leaves the ready queue while its
+        // in-flight window is full, or while it is being set up
+        let full = session.outstanding() >= self.max_inflight || session.is_setting_up();
+        let mut setup_step = false;
+        let mut next_step = false;
+
+        // request parsing
+        while session.outstanding() > 0 {
+            let pending = session.read_pending();
+            let written = session.write_pending();
+            let response = self.codec.decode(session);
+            match response {
+                Ok(outcome) => {
+                    if !session.is_setting_up() && session.write_pending() > written {
+                        // a command sent in several steps, such as a
+                        // compare-and-swap, writes its next step while
+                        // decoding the reply to the last, and stays
+                        // outstanding until its last step is answered
+                        if let Some(verb) = session.command() {
+                            RESPONSE_COMMAND_HIT[verb as usize].add(outcome.hits);
+                            RESPONSE_COMMAND_EX[verb as usize].add(outcome.errors);
+                        }
+                        next_step = true;
+                        continue;
+                    }
+                    session.set_outstanding(session.outstanding() - 1);
+                    if session.is_setting_up() {
+                        // a setup with several steps, such as an
+                        // authentication exchange, writes its next request
+                        // while decoding the response to the last one
+                        if session.write_pending() > 0 {
+                            session.set_outstanding(1);
+                            setup_step = true;
+                            continue;
+                        }
+                        // the connection is ready for requests once set up
+                        session.set_setting_up(false);
+                        continue;
+                    }
+                    if self.prefilling {
+                        // prefill responses are excluded from the stats
+                        self.prefill_outstanding -= 1;
+                        continue;
+                    }
+                    if session.is_timed_out() {
+                        // the request was already counted as timed out
+                        RESPONSE_LATE.increment();
+                        let _ = session.pop_command();
+                        let _ = session.pop_sent();
+                        continue;
+                    }
+                    RESPONSE.increment();
+                    if let Some(ref stats) = self.endpoint_stats {
+                        stats.response(session.slot() / self.poolsize);
+                    }
+                    let command = session.pop_command();
+                    let verb = command.map(|(verb, _)| verb);
+                    if let Some((verb, edge_case)) = command {
+                        RESPONSE_COMMAND[verb as usize].increment();
+                        RESPONSE_COMMAND_BYTE[verb as usize]
+                            .add((pending - session.read_pending()) as u64);
+                        RESPONSE_COMMAND_HIT[verb as usize].add(outcome.hits);
+                        RESPONSE_COMMAND_EX[verb as usize].add(outcome.errors);
+                        if edge_case {
+                            EDGE_CASE_RESPONSE.increment();
+                            EDGE_CASE_RESPONSE_HIT.add(outcome.hits);
+                            EDGE_CASE_RESPONSE_EX.add(outcome.errors);
+                        }
+                    }
+                    let (sent, scheduled) = session
+                        .pop_sent()
+                        .unwrap_or_else(|| (session.timestamp(), session.timestamp()));
+                    if let (Some(Verb::Hgetall), Some(heatmap)) =
+                        (verb, &self.response_field_heatmap)
+                    {
+                        heatmap.increment(Instant::now(), outcome.fields, 1);
+                    }
+                    if let Some(ref heatmap) = self.request_heatmap {
+                        let now = Instant::now();
+                        let elapsed = now - sent;
+                        let us = elapsed.as_nanos() / 1_000;
+                        // the end-to-end latency of a command sent in several
+                        // steps is only recorded for the command, apart from
+                        // the latency of single requests
+                        let composite = verb.is_some_and(|verb| verb.is_multi_step());
+                        if !composite {
+                            heatmap.increment(now, us, 1);
+                            if let Some(ref heatmap) = self.corrected_heatmap {
+                                if self.open_loop {
+                                    let us = (now - scheduled).as_nanos() / 1_000;
+                                    heatmap.increment(now, us, 1);
+                                } else {
+                                    record_corrected(
+                                        heatmap,
+                                        now,
+                                        us,
+                                        expected_interval(
+                                            &self.request_ratelimit,
+                                            self.pipeline * self.connections,
+                                        ),
+                                    );
+                                }
+                            }
+                            // slots are assigned to endpoints in order
+                            if let Some(heatmap) =
+                                self.endpoint_heatmaps.get(session.slot() / self.poolsize)
+                            {
+                                heatmap.increment(now, us, 1);
+                            }
+                            if let Some(ref waterfall) = self.request_waterfall {
+                                waterfall.increment(now, elapsed.as_nanos(), 1);
+                            }
+                        }
+                        if let Some(verb) = verb {
+                            if let Some(Some(heatmap)) = self.command_heatmaps.get(verb as usize) {
+                                heatmap.increment(now, us, 1);
+                            }
+                            if let Some(Some(heatmap)) = self.summary_heatmaps.get(verb as usize) {
+                                heatmap.increment(now, us, 1);
+                            }
+                            if let Some(Some(waterfall)) =
+                                self.command_waterfalls.get(verb as usize)
+                            {
+                                waterfall.increment(now, elapsed.as_nanos(), 1);
+                            }
+                        }
+                    }
+                }
+                Err(e) => match e {
+                    ParseError::Incomplete => {
+                        break;
+                    }
+                    _ => {
+                        if session.is_setting_up() {
+                            SESSION_SETUP_EX.increment();
+                        } else if session.is_edge_case() {
+                            EDGE_CASE_CLOSE.increment();
+                        }
+                        if let Some(ref stats) = self.endpoint_stats {
+                            stats.response_ex(session.slot() / self.poolsize);
+                        }
+                        return Err(Error::from(std::io::ErrorKind::InvalidData));
+                    }
+                },
+            }
+        }
+        let outstanding = session.outstanding();
+        let ready = if self.open_loop && !self.prefilling {
+            // draining sessions are closed once they have no requests
+            // in flight
+            if session.is_draining() {
+                outstanding == 0
+            } else {
+                full && outstanding < self.max_inflight && !session.is_setting_up()
+            }
+        } else {
+            outstanding == 0
+        };
+        if ready {
+            self.ready_queue.push_back(token);
+        }
+        if setup_step || next_step {
+            return self.flush(token);
+        }
+        Ok(())
+    }
+
     /// Handle writing to the session
     fn do_write(&mut self, token: Token) -> Result<(), Error> {
         let session = get_session_mut!(self, token)?;
@@ -324,65 +1304,144 @@ impl Worker {
         Ok(())
     }
 
-    /// Starts the worker event loop. Typically used in a child thread.
-    pub fn run(&mut self) {
-        let mut events = Events::with_capacity(1024);
-        let mut credits = 0;
-
-        loop {
-            if let Some((addr, ssl_session)) = self.connect_queue.pop_front() {
-                let connect = if let Some(r) = &self.connect_ratelimit {
-                    r.try_wait().is_ok()
-                } else {
-                    true
-                };
-                if connect {
-                    match self.connect(addr, ssl_session) {
-                        Ok(token) => {
-                            self.register(token).unwrap();
-                        }
-                        Err(e) => {
-                            println!("connect error: {:?} {}", addr, e);
-                        }
-                    }
-                } else {
-                    self.connect_queue.push_front((addr, ssl_session));
-                }
+    /// Make the next connection in the connect queue, when the connect
+    /// ratelimit allows
+    fn connect_next(&mut self) -> Option<Token> {
+        let (addr, ssl_session, slot) = self.connect_queue.pop_front()?;
+        let connect = if let Some(r) = &self.connect_ratelimit {
+            r.try_wait().is_ok()
+        } else {
+            true
+        };
+        if !connect {
+            self.connect_queue.push_front((addr, ssl_session, slot));
+            return None;
+        }
+        match self.connect(addr.clone(), ssl_session, slot) {
+            Ok(token) => Some(token),
+            Err(e) => {
+                println!("connect error: {} {}", addr, e);
+                None
             }
+        }
+    }
 
+    /// Send the next requests, on the next ready session. Closed-loop
+    /// requests wait for the ratelimit to give credits for a full pipeline.
+    fn send_next(&mut self, credits: &mut usize) {
+        if self.prefilling {
             if let Some(token) = self.ready_queue.pop_front() {
-                let reconnect = if let Some(r) = &self.reconnect_ratelimit {
-                    r.try_wait().is_ok()
-                } else {
-                    false
-                };
-                if reconnect {
+                if self.send_prefill(token).is_err() {
                     let _ = self.disconnect(token);
-                } else {
-                    if let Some(r) = &self.request_ratelimit {
-                        while r.try_wait().is_ok() {
-                            credits += 1;
-                            if credits == self.pipeline {
-                                break;
-                            }
+                }
+            }
+        } else if self.open_loop {
+            self.send_scheduled();
+        } else if let Some(token) = self.pop_ready() {
+            if let Some(r) = &self.reconnect_ratelimit {
+                if r.try_wait().is_ok() {
+                    self.reconnects_pending += 1;
+                }
+            }
+            if let Ok(true) = self.is_draining(token) {
+                // the session has received all of its responses
+                let _ = self.disconnect(token);
+            } else {
+                if let Some(r) = &self.request_ratelimit {
+                    while r.try_wait().is_ok() {
+                        *credits += 1;
+                        if *credits == self.pipeline {
+                            break;
                         }
-                    } else {
-                        credits = self.pipeline;
-                    };
-                    if credits == self.pipeline {
-                        credits = 0;
-                        if self.send_request(token, self.pipeline).is_ok() {
-                            // yay, we sent a request
-                        } else if self.disconnect(token).is_ok() {
-                            REQUEST_EX.increment();
-                        } else {
-                            panic!("this shouldn't happen");
+                    }
+                } else {
+                    *credits = self.pipeline;
+                };
+                if *credits == self.pipeline {
+                    *credits = 0;
+                    if self.send_request(token, self.pipeline, false).is_ok() {
+                        // yay, we sent a request
+                        if self.should_reconnect(token) {
+                            let _ = self.reconnect(token);
                         }
+                    } else if self.disconnect(token).is_ok() {
+                        REQUEST_EX.increment();
+                        self.count_endpoint(token, EndpointStats::request_ex);
                     } else {
-                        self.ready_queue.push_front(token)
+                        panic!("this shouldn't happen");
                     }
+                } else {
+                    self.ready_queue.push_front(token)
                 }
             }
+        }
+    }
+
+    /// Start sending on a session once its connection is established
+    fn established(&mut self, token: Token) {
+        self.connected(token).unwrap();
+        let _ = self.start_warmup(token);
+        let _ = self.start_lifetime(token);
+        OPEN.increment();
+        SESSION.increment();
+        // sessions which handshake record connect latency when the handshake
+        // starts
+        let handshaked = matches!(self.handshake_start(token), Ok(Some(_)));
+        if let (false, Ok(prev)) = (handshaked, self.timestamp(token)) {
+            if let Some(ref heatmap) = self.connect_heatmap {
+                let now = Instant::now();
+                let elapsed = now - prev;
+                let us = elapsed.as_nanos() / 1_000;
+                heatmap.increment(now, us, 1);
+            }
+        }
+        // a session which is set up joins the ready queue once the response
+        // to its setup request is received
+        if !matches!(self.setup(token), Ok(true)) {
+            self.ready_queue.push_back(token);
+        }
+    }
+
+    /// Send the codec's setup request on a new session, if it has one
+    fn setup(&mut self, token: Token) -> Result<bool, Error> {
+        let session = get_session_mut!(self, token)?;
+        if !self.codec.setup(session) {
+            return Ok(false);
+        }
+        session.set_setting_up(true);
+        session.set_outstanding(1);
+        self.flush(token)?;
+        Ok(true)
+    }
+
+    /// Starts the worker event loop. Typically used in a child thread.
+    pub fn run(&mut self) {
+        if let Some(ref cpus) = self.cpus {
+            // the cpus are checked when the config is loaded
+            if let Err(e) = crate::placement::pin(cpus) {
+                fatal!("failed to pin worker to cpus: {:?}: {}", cpus, e);
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        if self.backend == Backend::IoUring {
+            return self.run_uring();
+        }
+
+        let mut events = Events::with_capacity(1024);
+        let mut credits = 0;
+
+        loop {
+            self.update_prefill();
+
+            if let Some(token) = self.connect_next() {
+                self.register(token).unwrap();
+            }
+
+            self.send_next(&mut credits);
+
+            self.expire_draining();
+            self.expire_requests();
 
             let _ = self
                 .poll
@@ -395,6 +1454,7 @@ impl Worker {
                 if event.is_error() {
                     if self.is_connecting(token).unwrap() {
                         CONNECT_EX.increment();
+                        self.count_endpoint(token, EndpointStats::connect_ex);
                     }
                     // increment_counter!(&Stat::WorkerEventError);
                     let _ = self.disconnect(token);
@@ -406,6 +1466,7 @@ impl Worker {
                     if let Err(e) = self.handshake(token) {
                         if e.kind() != ErrorKind::WouldBlock {
                             CONNECT_EX.increment();
+                            self.count_endpoint(token, EndpointStats::connect_ex);
                             let _ = self.disconnect(token);
                         }
                     }
@@ -416,9 +1477,11 @@ impl Worker {
                         }
                         Ok(false) => {
                             // finished handshaking
+                            let _ = self.handshake_complete(token);
                         }
                         Err(_) => {
                             CONNECT_EX.increment();
+                            self.count_endpoint(token, EndpointStats::connect_ex);
                             let _ = self.disconnect(token);
                             continue;
                         }
@@ -435,18 +1498,7 @@ impl Worker {
                     let connecting = self.is_connecting(token).unwrap();
                     let handshaking = self.is_handshaking(token).unwrap();
                     if connecting && !handshaking {
-                        self.connected(token).unwrap();
-                        OPEN.increment();
-                        SESSION.increment();
-                        if let Ok(prev) = self.timestamp(token) {
-                            if let Some(ref heatmap) = self.connect_heatmap {
-                                let now = Instant::now();
-                                let elapsed = now - prev;
-                                let us = elapsed.as_nanos() as u64 / 1_000;
-                                heatmap.increment(now, us, 1);
-                            }
-                        }
-                        self.ready_queue.push_back(token);
+                        self.established(token);
                     } else if connecting {
                         OPEN.increment();
                     }
@@ -458,11 +1510,39 @@ impl Worker {
 
                 let _ = self.reregister(token);
             }
+
+            self.remove_closed(|_| true);
         }
     }
 }
 
-pub fn ssl_connector(config: &Tls) -> Result<Option<SslConnector>, std::io::Error> {
+/// The expected time in microseconds between requests on each connection in
+/// closed-loop mode, given the current ratelimit and the number of requests
+/// which are in flight across the client at once
+fn expected_interval(ratelimit: &Option<Arc<Ratelimiter>>, concurrency: usize) -> u64 {
+    match ratelimit {
+        Some(r) if r.rate() > 0 => concurrency as u64 * 1_000_000 / r.rate(),
+        _ => 0,
+    }
+}
+
+/// Record a latency, and the latencies of the requests which should have been
+/// sent on the connection while waiting for this response. This is the
+/// correction for coordinated omission used by HdrHistogram's
+/// `recordValueWithExpectedInterval`.
+fn record_corrected(heatmap: &Latency, now: Instant, us: u64, interval: u64) {
+    heatmap.increment(now, us, 1);
+    if interval == 0 {
+        return;
+    }
+    let mut missing = us.saturating_sub(interval);
+    while missing >= interval {
+        heatmap.increment(now, missing, 1);
+        missing -= interval;
+    }
+}
+
+pub fn ssl_connector(config: &Tls) -> Result<SslConnector, std::io::Error> {
     let mut builder = SslConnector::builder(SslMethod::tls_client())?;
     if !config.verify() {
         builder.set_verify(SslVerifyMode::NONE);
@@ -472,18 +1552,16 @@ pub fn ssl_connector(config: &Tls) -> Result<Option<SslConnector>, std::io::Erro
     if config.private_key().is_some()
         ^ (config.certificate_chain().is_some() || config.certificate().is_some())
     {
-        return Err(Error::new(ErrorKind::Other, "incomplete tls configuration"));
+        return Err(Error::other("incomplete tls configuration"));
     }
 
     // load the private key
     //
-    // NOTE: this is required, so we return `Ok(None)` if it is not specified
+    // NOTE: this is only needed for client authentication
     if let Some(f) = config.private_key() {
         builder
             .set_private_key_file(f, SslFiletype::PEM)
-            .map_err(|_| Error::new(ErrorKind::Other, "bad private key"))?;
-    } else {
-        return Ok(None);
+            .map_err(|_| Error::other("bad private key"))?;
     }
 
     // load the ca file
@@ -493,7 +1571,7 @@ pub fn ssl_connector(config: &Tls) -> Result<Option<SslConnector>, std::io::Erro
     if let Some(f) = config.ca_file() {
         builder
             .set_ca_file(f)
-            .map_err(|_| Error::new(ErrorKind::Other, "bad ca file"))?;
+            .map_err(|_| Error::other("bad ca file"))?;
     }
 
     match (config.certificate_chain(), config.certificate()) {
@@ -504,17 +1582,17 @@ pub fn ssl_connector(config: &Tls) -> Result<Option<SslConnector>, std::io::Erro
             // first load the leaf
             builder
                 .set_certificate_file(cert, SslFiletype::PEM)
-                .map_err(|_| Error::new(ErrorKind::Other, "bad certificate file"))?;
+                .map_err(|_| Error::other("bad certificate file"))?;
 
             // append the rest of the chain
             let pem = std::fs::read(chain)
-                .map_err(|_| Error::new(ErrorKind::Other, "failed to read certificate chain"))?;
-            let chain = X509::stack_from_pem(&pem)
-                .map_err(|_| Error::new(ErrorKind::Other, "bad certificate chain"))?;
+                .map_err(|_| Error::other("failed to read certificate chain"))?;
+            let chain =
+                X509::stack_from_pem(&pem).map_err(|_| Error::other("bad certificate chain"))?;
             for cert in chain {
                 builder
                     .add_extra_chain_cert(cert)
-                    .map_err(|_| Error::new(ErrorKind::Other, "bad certificate in chain"))?;
+                    .map_err(|_| Error::other("bad certificate in chain"))?;
             }
         }
         (Some(chain), None) => {
@@ -524,18 +1602,17 @@ pub fn ssl_connector(config: &Tls) -> Result<Option<SslConnector>, std::io::Erro
             // load the entire chain
             builder
                 .set_certificate_chain_file(chain)
-                .map_err(|_| Error::new(ErrorKind::Other, "bad certificate chain"))?;
+                .map_err(|_| Error::other("bad certificate chain"))?;
         }
         (None, Some(cert)) => {
             // this will just load the leaf certificate from the file
             builder
                 .set_certificate_file(cert, SslFiletype::PEM)
-                .map_err(|_| Error::new(ErrorKind::Other, "bad certificate file"))?;
+                .map_err(|_| Error::other("bad certificate file"))?;
         }
         (None, None) => {
-            // if we have neither a chain nor a leaf cert to load, we return no
-            // ssl context
-            return Ok(None);
+            // no client certificate, the server is still authenticated unless
+            // verification is disabled
         }
     }
 
@@ -544,5 +1621,5 @@ pub fn ssl_connector(config: &Tls) -> Result<Option<SslConnector>, std::io::Erro
         builder.set_session_cache_size(size);
     }
 
-    Ok(Some(builder.build()))
+    Ok(builder.build())
 }
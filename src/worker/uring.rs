@@ -0,0 +1,585 @@
+// Copyright 2021 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! The io_uring backend. Sends and receives are queued on a ring shared with
+//! the kernel, and a single syscall both submits the queued operations and
+//! waits for the next completions. The mio backend makes a syscall for each
+//! read and write, and an extra read to find that a socket has been drained,
+//! which saturates the client before the server at high rates.
+//!
+//! Each session has at most one send and one receive in flight. While an
+//! operation is in flight, the kernel owns the part of the session's buffer
+//! which it was given. The read buffer is only parsed once its receive has
+//! completed, and in closed-loop mode requests are only encoded on a session
+//! once the responses to its previous requests have arrived, which can only
+//! happen after the send which carried them has completed. A session which is
+//! closed is only removed from the slab once its operations have completed,
+//! which closing its socket hurries along, so its buffers outlive them.
+
+use super::*;
+use core::sync::atomic::{AtomicU32, Ordering};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+
+const IORING_OP_POLL_ADD: u8 = 6;
+const IORING_OP_SEND: u8 = 26;
+const IORING_OP_RECV: u8 = 27;
+
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_ENTER_EXT_ARG: u32 = 8;
+
+const IORING_FEAT_EXT_ARG: u32 = 1 << 8;
+
+// the operation a completion is for, kept in the low bits of its user data
+// below the session's token
+const OP_CONNECT: u64 = 0;
+const OP_SEND: u64 = 1;
+const OP_RECV: u64 = 2;
+const OP_BITS: u64 = 2;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+/// A submission queue entry
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// A completion queue entry
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct GeteventsArg {
+    sigmask: u64,
+    sigmask_sz: u32,
+    pad: u32,
+    ts: u64,
+}
+
+#[repr(C)]
+struct KernelTimespec {
+    tv_sec: i64,
+    tv_nsec: i64,
+}
+
+/// A part of the ring which is mapped into the process
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: &OwnedFd, offset: libc::off_t, len: usize) -> Result<Self, Error> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd.as_raw_fd(),
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            Err(Error::last_os_error())
+        } else {
+            Ok(Self { ptr, len })
+        }
+    }
+
+    /// A pointer to the field at the offset into the mapping
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.cast::<u8>().add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// A minimal io_uring, with just the operations the worker needs
+pub struct Ring {
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sq_array: *mut u32,
+    sqes: *mut Sqe,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+    // entries which are queued but have not been submitted to the kernel
+    queued: u32,
+    _maps: [Mmap; 3],
+    fd: OwnedFd,
+}
+
+impl Ring {
+    pub fn new(entries: u32) -> Result<Self, Error> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        if params.features & IORING_FEAT_EXT_ARG == 0 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "the kernel is too old for the io_uring backend",
+            ));
+        }
+
+        let sq_ring = Mmap::new(
+            &fd,
+            IORING_OFF_SQ_RING,
+            params.sq_off.array as usize + params.sq_entries as usize * 4,
+        )?;
+        let cq_ring = Mmap::new(
+            &fd,
+            IORING_OFF_CQ_RING,
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>(),
+        )?;
+        let sqes = Mmap::new(
+            &fd,
+            IORING_OFF_SQES,
+            params.sq_entries as usize * std::mem::size_of::<Sqe>(),
+        )?;
+
+        Ok(Self {
+            sq_head: sq_ring.at(params.sq_off.head),
+            sq_tail: sq_ring.at(params.sq_off.tail),
+            sq_mask: unsafe { *sq_ring.at::<u32>(params.sq_off.ring_mask) },
+            sq_entries: params.sq_entries,
+            sq_array: sq_ring.at(params.sq_off.array),
+            sqes: sqes.at(0),
+            cq_head: cq_ring.at(params.cq_off.head),
+            cq_tail: cq_ring.at(params.cq_off.tail),
+            cq_mask: unsafe { *cq_ring.at::<u32>(params.cq_off.ring_mask) },
+            cqes: cq_ring.at(params.cq_off.cqes),
+            queued: 0,
+            _maps: [sq_ring, cq_ring, sqes],
+            fd,
+        })
+    }
+
+    /// Queue an entry, submitting the queued entries first if the submission
+    /// queue is full
+    fn push(&mut self, sqe: Sqe) -> Result<(), Error> {
+        let sq_head = unsafe { &*self.sq_head };
+        let sq_tail = unsafe { &*self.sq_tail };
+        let tail = sq_tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(sq_head.load(Ordering::Acquire)) == self.sq_entries {
+            self.enter(None)?;
+            if tail.wrapping_sub(sq_head.load(Ordering::Acquire)) == self.sq_entries {
+                return Err(Error::new(ErrorKind::WouldBlock, "submission queue full"));
+            }
+        }
+        let index = tail & self.sq_mask;
+        unsafe {
+            self.sqes.add(index as usize).write(sqe);
+            self.sq_array.add(index as usize).write(index);
+        }
+        sq_tail.store(tail.wrapping_add(1), Ordering::Release);
+        self.queued += 1;
+        Ok(())
+    }
+
+    /// Submit the queued entries, and wait for a completion if a timeout is
+    /// given
+    fn enter(&mut self, timeout: Option<std::time::Duration>) -> Result<(), Error> {
+        let ts = timeout.map(|timeout| KernelTimespec {
+            tv_sec: timeout.as_secs() as i64,
+            tv_nsec: timeout.subsec_nanos() as i64,
+        });
+        let arg = GeteventsArg {
+            sigmask: 0,
+            sigmask_sz: 0,
+            pad: 0,
+            ts: ts
+                .as_ref()
+                .map_or(0, |ts| ts as *const KernelTimespec as u64),
+        };
+        let (wait, flags) = if timeout.is_some() {
+            (1, IORING_ENTER_GETEVENTS | IORING_ENTER_EXT_ARG)
+        } else {
+            (0, IORING_ENTER_EXT_ARG)
+        };
+        let submitted = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.fd.as_raw_fd(),
+                self.queued,
+                wait,
+                flags,
+                &arg as *const GeteventsArg,
+                std::mem::size_of::<GeteventsArg>(),
+            )
+        };
+        if submitted < 0 {
+            let e = Error::last_os_error();
+            return match e.raw_os_error() {
+                // the wait timed out, was interrupted, or there are
+                // completions to reap before more can be submitted
+                Some(libc::ETIME) | Some(libc::EINTR) | Some(libc::EBUSY) => Ok(()),
+                _ => Err(e),
+            };
+        }
+        self.queued -= submitted as u32;
+        Ok(())
+    }
+
+    /// Submit the queued entries and wait up to the timeout for a completion
+    pub fn submit_and_wait(&mut self, timeout: std::time::Duration) -> Result<(), Error> {
+        self.enter(Some(timeout))
+    }
+
+    /// Take the next completion, with its user data and result
+    pub fn completion(&mut self) -> Option<(u64, i32)> {
+        let cq_head = unsafe { &*self.cq_head };
+        let head = cq_head.load(Ordering::Relaxed);
+        if head == unsafe { &*self.cq_tail }.load(Ordering::Acquire) {
+            return None;
+        }
+        let cqe = unsafe { &*self.cqes.add((head & self.cq_mask) as usize) };
+        let completion = (cqe.user_data, cqe.res);
+        cq_head.store(head.wrapping_add(1), Ordering::Release);
+        Some(completion)
+    }
+
+    /// Wait for the socket to become writable, as it does once it connects
+    pub fn poll_writable(&mut self, fd: RawFd, user_data: u64) -> Result<(), Error> {
+        self.push(Sqe {
+            opcode: IORING_OP_POLL_ADD,
+            fd,
+            op_flags: libc::POLLOUT as u32,
+            user_data,
+            ..Default::default()
+        })
+    }
+
+    /// Send the bytes on the socket.
+    ///
+    /// # Safety
+    ///
+    /// The bytes must not be moved or modified until the send completes.
+    pub unsafe fn send(&mut self, fd: RawFd, buf: &[u8], user_data: u64) -> Result<(), Error> {
+        self.push(Sqe {
+            opcode: IORING_OP_SEND,
+            fd,
+            addr: buf.as_ptr() as u64,
+            len: buf.len() as u32,
+            op_flags: libc::MSG_NOSIGNAL as u32,
+            user_data,
+            ..Default::default()
+        })
+    }
+
+    /// Receive from the socket into the buffer.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must not be moved, read, or modified until the receive
+    /// completes.
+    pub unsafe fn recv(&mut self, fd: RawFd, buf: &mut [u8], user_data: u64) -> Result<(), Error> {
+        self.push(Sqe {
+            opcode: IORING_OP_RECV,
+            fd,
+            addr: buf.as_mut_ptr() as u64,
+            len: buf.len() as u32,
+            user_data,
+            ..Default::default()
+        })
+    }
+}
+
+/// The ring, the sessions with a send in flight, and the number of operations
+/// in flight for each session, indexed by token
+struct Uring {
+    ring: Ring,
+    sending: Vec<bool>,
+    in_flight: Vec<usize>,
+}
+
+impl Uring {
+    fn is_sending(&self, token: Token) -> bool {
+        self.sending.get(token.0).copied().unwrap_or(false)
+    }
+
+    fn set_sending(&mut self, token: Token, sending: bool) {
+        if self.sending.len() <= token.0 {
+            self.sending.resize(token.0 + 1, false);
+        }
+        self.sending[token.0] = sending;
+    }
+
+    fn is_idle(&self, token: Token) -> bool {
+        self.in_flight.get(token.0).copied().unwrap_or(0) == 0
+    }
+
+    fn started(&mut self, token: Token) {
+        if self.in_flight.len() <= token.0 {
+            self.in_flight.resize(token.0 + 1, 0);
+        }
+        self.in_flight[token.0] += 1;
+    }
+
+    fn completed(&mut self, token: Token) {
+        if let Some(in_flight) = self.in_flight.get_mut(token.0) {
+            *in_flight = in_flight.saturating_sub(1);
+        }
+    }
+}
+
+fn user_data(token: Token, op: u64) -> u64 {
+    (token.0 as u64) << OP_BITS | op
+}
+
+impl Worker {
+    /// The event loop for the io_uring backend
+    pub(super) fn run_uring(&mut self) {
+        // each session has up to two operations in flight
+        let entries = (2 * self.slot_connected.len())
+            .next_power_of_two()
+            .clamp(64, 4096);
+        let mut uring = match Ring::new(entries as u32) {
+            Ok(ring) => Uring {
+                ring,
+                sending: Vec::new(),
+                in_flight: Vec::new(),
+            },
+            Err(e) => {
+                fatal!("failed to create the io_uring: {}", e);
+            }
+        };
+        let mut credits = 0;
+
+        loop {
+            self.update_prefill();
+
+            if let Some(token) = self.connect_next() {
+                let connecting = get_session!(self, token)
+                    .ok()
+                    .and_then(|session| session.plain_fd())
+                    .map(|fd| uring.ring.poll_writable(fd, user_data(token, OP_CONNECT)));
+                if matches!(connecting, Some(Ok(()))) {
+                    uring.started(token);
+                } else {
+                    CONNECT_EX.increment();
+                    self.count_endpoint(token, EndpointStats::connect_ex);
+                    let _ = self.disconnect(token);
+                }
+            }
+
+            self.send_next(&mut credits);
+
+            self.expire_draining();
+            self.expire_requests();
+
+            while let Some(token) = self.unflushed.pop() {
+                self.uring_send(&mut uring, token);
+            }
+
+            if let Err(e) = uring
+                .ring
+                .submit_and_wait(std::time::Duration::from_millis(10))
+            {
+                fatal!("failed to submit to the io_uring: {}", e);
+            }
+
+            while let Some((user_data, result)) = uring.ring.completion() {
+                self.uring_complete(&mut uring, user_data, result);
+            }
+
+            self.remove_closed(|token| uring.is_idle(token));
+        }
+    }
+
+    /// Send the unsent bytes in the session's write buffer. When a send is
+    /// already in flight, the rest is sent once it completes.
+    fn uring_send(&mut self, uring: &mut Uring, token: Token) {
+        if uring.is_sending(token) {
+            return;
+        }
+        let session = match get_session!(self, token) {
+            Ok(session) if !session.is_closed() => session,
+            _ => return,
+        };
+        let fd = match session.plain_fd() {
+            Some(fd) => fd,
+            None => return,
+        };
+        if session.unsent().is_empty() {
+            return;
+        }
+        // the write buffer is left alone until the send completes
+        match unsafe {
+            uring
+                .ring
+                .send(fd, session.unsent(), user_data(token, OP_SEND))
+        } {
+            Ok(()) => {
+                uring.set_sending(token, true);
+                uring.started(token);
+            }
+            Err(_) => {
+                let _ = self.disconnect(token);
+            }
+        }
+    }
+
+    /// Receive into the free space at the end of the session's read buffer
+    fn uring_recv(&mut self, uring: &mut Uring, token: Token) {
+        let session = match get_session_mut!(self, token) {
+            Ok(session) if !session.is_closed() => session,
+            _ => return,
+        };
+        let fd = match session.plain_fd() {
+            Some(fd) => fd,
+            None => return,
+        };
+        // the read buffer is left alone until the receive completes
+        let submitted = session
+            .read_space()
+            .and_then(|space| unsafe { uring.ring.recv(fd, space, user_data(token, OP_RECV)) });
+        match submitted {
+            Ok(()) => uring.started(token),
+            Err(_) => {
+                let _ = self.disconnect(token);
+            }
+        }
+    }
+
+    /// Handle the completion of an operation on a session. Operations which
+    /// complete after their session was closed are ignored.
+    fn uring_complete(&mut self, uring: &mut Uring, user_data: u64, result: i32) {
+        let token = Token((user_data >> OP_BITS) as usize);
+        let op = user_data & ((1 << OP_BITS) - 1);
+        uring.completed(token);
+        if op == OP_SEND {
+            uring.set_sending(token, false);
+        }
+        match get_session!(self, token) {
+            Ok(session) if !session.is_closed() => {}
+            _ => return,
+        }
+
+        match op {
+            OP_CONNECT => {
+                let failed = (libc::POLLERR | libc::POLLHUP) as i32;
+                if result < 0 || result & failed != 0 {
+                    CONNECT_EX.increment();
+                    self.count_endpoint(token, EndpointStats::connect_ex);
+                    let _ = self.disconnect(token);
+                } else {
+                    self.established(token);
+                    self.uring_recv(uring, token);
+                }
+            }
+            OP_SEND => {
+                if result < 0 {
+                    SESSION_SEND_EX.increment();
+                    let _ = self.disconnect(token);
+                    return;
+                }
+                if let Ok(session) = get_session_mut!(self, token) {
+                    session.mark_sent(result as usize);
+                }
+                // send the rest after a partial send
+                self.uring_send(uring, token);
+            }
+            _ => {
+                if result <= 0 {
+                    // an error, or the server hung up
+                    if result < 0 {
+                        SESSION_RECV_EX.increment();
+                    }
+                    let _ = self.disconnect(token);
+                    return;
+                }
+                if let Ok(session) = get_session_mut!(self, token) {
+                    session.mark_received(result as usize);
+                }
+                if self.handle_responses(token).is_err() {
+                    let _ = self.disconnect(token);
+                    return;
+                }
+                self.uring_recv(uring, token);
+            }
+        }
+    }
+}